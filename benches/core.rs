@@ -0,0 +1,75 @@
+// cargo bench --no-default-features --features bench
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rust_snes::{bench, PowerOnState, Snes};
+
+// A 32KB LoROM that turns on BG1, BG2 and OBJ in mode 1 and spins. Random power-on VRAM
+// gives the renderer real tile data to chew on.
+fn homebrew_rom() -> Vec<u8> {
+    #[rustfmt::skip]
+    const PROGRAM: [u8; 22] = [
+        0x78,             // SEI
+        0x18,             // CLC
+        0xFB,             // XCE
+        0xA9, 0x09,       // LDA #$09
+        0x8D, 0x05, 0x21, // STA $2105
+        0xA9, 0x13,       // LDA #$13
+        0x8D, 0x2C, 0x21, // STA $212C
+        0xA9, 0x0F,       // LDA #$0F
+        0x8D, 0x00, 0x21, // STA $2100
+        0x80, 0xFE,       // BRA *
+        0x00, 0x00,
+    ];
+
+    let mut rom = vec![0; 0x8000];
+    rom[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+
+    let header = 0x7FC0;
+    rom[header..header + 21].copy_from_slice(b"RUST-SNES BENCH      ");
+    rom[header + 0x15] = 0x20; // LoROM
+    rom[header + 0x17] = 0x05; // 32KB
+    rom[header + 0x19] = 0x01; // North America
+    rom[0x7FFC..0x7FFE].copy_from_slice(&0x8000u16.to_le_bytes()); // RESET
+
+    rom[header + 0x1C..header + 0x20].copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+    let checksum = rom.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+    rom[header + 0x1C..header + 0x1E].copy_from_slice(&(!checksum).to_le_bytes());
+    rom[header + 0x1E..header + 0x20].copy_from_slice(&checksum.to_le_bytes());
+    rom
+}
+
+fn new_snes() -> Snes {
+    Snes::new_with_power_on_state(homebrew_rom(), None, PowerOnState::Random { seed: 1 })
+        .expect("Failed to load ROM")
+}
+
+fn exec_frame(c: &mut Criterion) {
+    let mut snes = new_snes();
+    c.bench_function("exec_frame", |b| {
+        b.iter(|| snes.exec_frame().expect("Emulation halted"))
+    });
+}
+
+fn dsp_mixer(c: &mut Criterion) {
+    let mut snes = new_snes();
+    bench::start_all_voices(&mut snes);
+    // One frame worth of 32kHz samples
+    c.bench_function("dsp_mixer_534_samples", |b| {
+        b.iter(|| black_box(bench::mix_samples(&mut snes, 534)))
+    });
+}
+
+fn bg_render(c: &mut Criterion) {
+    let mut snes = new_snes();
+    // Let the program set up the PPU first
+    snes.exec_frame().expect("Emulation halted");
+    c.bench_function("render_224_lines", |b| {
+        b.iter(|| {
+            for y in 1..=224 {
+                bench::render_line(&mut snes, y);
+            }
+        })
+    });
+}
+
+criterion_group!(benches, exec_frame, dsp_mixer, bg_render);
+criterion_main!(benches);
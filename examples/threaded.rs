@@ -0,0 +1,81 @@
+// Runs the core on a dedicated emulation thread. The frontend only talks to it through
+// channels, so a slow present or a blocking event loop never stalls emulation
+//
+// cargo run --release --example threaded --no-default-features --features std,console -- game.sfc
+
+use std::sync::mpsc;
+use std::thread;
+
+use rust_snes::{Key, Snes};
+
+enum Command {
+    Keys(Vec<Key>),
+    Quit,
+}
+
+struct Frame {
+    number: u64,
+    pixels: Vec<u16>,
+    audio: Vec<(i16, i16)>,
+}
+
+fn main() {
+    let path = std::env::args().nth(1).expect("usage: threaded <rom>");
+    let rom = std::fs::read(&path).expect("failed to read the ROM");
+    // Snes is Send, so it can be built here and moved into the thread
+    let mut snes = Snes::new(rom, None).expect("unsupported ROM");
+
+    let (command_tx, command_rx) = mpsc::channel();
+    // Bounded, the emulation thread waits when the frontend falls 2 frames behind
+    let (frame_tx, frame_rx) = mpsc::sync_channel(2);
+
+    let emulation = thread::spawn(move || {
+        let mut keys = Vec::new();
+        for number in 0.. {
+            for command in command_rx.try_iter() {
+                match command {
+                    Command::Keys(new_keys) => keys = new_keys,
+                    Command::Quit => return snes.backup(),
+                }
+            }
+            snes.set_keys([keys.clone(), Vec::new(), Vec::new(), Vec::new()]);
+            if let Err(err) = snes.exec_frame() {
+                eprintln!("emulation stopped: {}", err);
+                return snes.backup();
+            }
+            let frame = Frame {
+                number,
+                pixels: snes.ppu().frame.to_vec(),
+                audio: snes.audio_buffer().to_vec(),
+            };
+            if frame_tx.send(frame).is_err() {
+                break;
+            }
+        }
+        snes.backup()
+    });
+
+    // Stand-in for a real frontend: press Start for a while and report what comes back
+    let mut samples = 0;
+    for frame in frame_rx.iter() {
+        samples += frame.audio.len();
+        match frame.number {
+            60 => command_tx.send(Command::Keys(vec![Key::Start])).unwrap(),
+            70 => command_tx.send(Command::Keys(Vec::new())).unwrap(),
+            _ => {}
+        }
+        if frame.number % 60 == 0 {
+            let lit = frame.pixels.iter().filter(|&&pixel| pixel != 0).count();
+            println!("frame {}: {} lit pixels, {} samples so far", frame.number, lit, samples);
+        }
+        if frame.number == 300 {
+            command_tx.send(Command::Quit).unwrap();
+            break;
+        }
+    }
+    // Unblock a send the emulation thread may be waiting in
+    drop(frame_rx);
+
+    let backup = emulation.join().unwrap();
+    println!("SRAM: {} bytes", backup.map_or(0, |backup| backup.len()));
+}
@@ -0,0 +1,71 @@
+// Minimal WebAssembly exports for examples/wasm.html (no wasm-bindgen required)
+//
+// cargo build --release --example wasm --target wasm32-unknown-unknown --no-default-features --features std,console
+
+use rust_snes::{Key, Snes};
+
+#[no_mangle]
+pub extern "C" fn rom_alloc(len: usize) -> *mut u8 {
+    let mut buf = Vec::<u8>::with_capacity(len);
+    let ptr = buf.as_mut_ptr();
+    std::mem::forget(buf);
+    ptr
+}
+
+/// # Safety
+/// `rom` must be a buffer of `len` bytes returned by `rom_alloc`. Ownership moves to the emulator.
+#[no_mangle]
+pub unsafe extern "C" fn snes_new(rom: *mut u8, len: usize) -> *mut Snes {
+    let rom = Vec::from_raw_parts(rom, len, len);
+    match Snes::new(rom, None) {
+        Ok(snes) => Box::into_raw(Box::new(snes)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `snes` must come from `snes_new` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn snes_free(snes: *mut Snes) {
+    if !snes.is_null() {
+        drop(Box::from_raw(snes));
+    }
+}
+
+/// # Safety
+/// `snes` must come from `snes_new`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_exec_frame(snes: *mut Snes, pad1: u16) -> bool {
+    let snes = &mut *snes;
+    let keys = Key::ALL
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| pad1 >> i & 1 == 1)
+        .map(|(_, &key)| key)
+        .collect();
+    snes.set_keys([keys, Vec::new(), Vec::new(), Vec::new()]);
+    snes.exec_frame().is_ok()
+}
+
+// 256x224 BGR555
+/// # Safety
+/// `snes` must come from `snes_new`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_frame(snes: *const Snes) -> *const u16 {
+    (*snes).ppu().frame.as_ptr()
+}
+
+// Interleaved stereo samples at 32kHz
+/// # Safety
+/// `snes` must come from `snes_new`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_audio(snes: *const Snes) -> *const i16 {
+    (*snes).audio_buffer().as_ptr() as *const i16
+}
+
+/// # Safety
+/// `snes` must come from `snes_new`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_audio_len(snes: *const Snes) -> usize {
+    (*snes).audio_buffer().len()
+}
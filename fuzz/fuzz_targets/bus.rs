@@ -0,0 +1,46 @@
+#![no_main]
+// Random I/O access patterns. Every 5 bytes of input are one access: a 24 bit address, the
+// data and a byte whose bit 0 picks write over read and whose other bits are the number of
+// instructions to run afterwards, so the PPU, APU and DMA act on it
+use libfuzzer_sys::fuzz_target;
+use rust_snes::{fuzz, Mapper, PowerOnState, Snes};
+
+// 32KB of ROM at $8000-$FFFF of every bank
+struct Rom(Box<[u8; 0x8000]>);
+
+impl Mapper for Rom {
+    fn read(&mut self, addr: u32) -> Option<u8> {
+        let offset = addr as u16;
+        (offset >= 0x8000).then(|| self.0[offset as usize & 0x7FFF])
+    }
+
+    fn write(&mut self, _addr: u32, _data: u8) {}
+
+    fn backup(&self) -> &[u8] {
+        &[]
+    }
+
+    fn backup_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // BRA * at the reset vector
+    let mut rom = Box::new([0; 0x8000]);
+    rom[..2].copy_from_slice(&[0x80, 0xFE]);
+    rom[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+    let mut snes = Snes::with_mapper(Box::new(Rom(rom)), 0x01, PowerOnState::Zero);
+
+    for access in data.chunks_exact(5) {
+        let addr = u32::from_le_bytes([access[0], access[1], access[2], 0]);
+        if access[4] & 1 == 0 {
+            fuzz::bus_read(&mut snes, addr);
+        } else {
+            fuzz::bus_write(&mut snes, addr, access[3]);
+        }
+        for _ in 0..access[4] >> 1 {
+            let _ = snes.step_instruction();
+        }
+    }
+});
@@ -0,0 +1,39 @@
+#![no_main]
+// Random programs. The input is a 32KB LoROM image run from $8000 for a few frames, padded
+// with zeros. The reset vector is forced, the other vectors come from the input
+use libfuzzer_sys::fuzz_target;
+use rust_snes::{Mapper, PowerOnState, Snes};
+
+const FRAMES: usize = 4;
+
+struct Rom(Box<[u8; 0x8000]>);
+
+impl Mapper for Rom {
+    fn read(&mut self, addr: u32) -> Option<u8> {
+        let offset = addr as u16;
+        (offset >= 0x8000).then(|| self.0[offset as usize & 0x7FFF])
+    }
+
+    fn write(&mut self, _addr: u32, _data: u8) {}
+
+    fn backup(&self) -> &[u8] {
+        &[]
+    }
+
+    fn backup_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut rom = Box::new([0; 0x8000]);
+    let len = data.len().min(rom.len());
+    rom[..len].copy_from_slice(&data[..len]);
+    rom[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+    let mut snes = Snes::with_mapper(Box::new(Rom(rom)), 0x01, PowerOnState::Zero);
+
+    for _ in 0..FRAMES {
+        // Unmapped accesses are errors for the frontend, not crashes
+        let _ = snes.exec_frame();
+    }
+});
@@ -0,0 +1,32 @@
+#![no_main]
+// ROM images through header detection and board setup, then a frame. The first byte picks
+// where the header goes, the padding after it and whether an odd sized backup is passed.
+// The next 64 bytes are the header at $FFC0 of that mapping and the rest is the start of
+// the ROM. The checksum complement is fixed up so headers get past the first check
+use libfuzzer_sys::fuzz_target;
+use rust_snes::Snes;
+
+const FRAMES: usize = 1;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&layout, data)) = data.split_first() else {
+        return;
+    };
+    // LoROM, HiROM and ExHiROM
+    let header_base = [0x7FC0, 0xFFC0, 0x40FFC0][layout as usize % 3];
+    let mut rom = vec![0; header_base + 0x40 + (layout as usize >> 2 & 7) * 0x8000];
+
+    let (header, body) = data.split_at(data.len().min(0x40));
+    rom[header_base..header_base + header.len()].copy_from_slice(header);
+    let len = body.len().min(header_base);
+    rom[..len].copy_from_slice(&body[..len]);
+    let checksum = u16::from_le_bytes([rom[header_base + 0x1E], rom[header_base + 0x1F]]);
+    rom[header_base + 0x1C..header_base + 0x1E].copy_from_slice(&(!checksum).to_le_bytes());
+
+    let backup = (layout & 0x80 != 0).then(|| vec![0; 0x7FF]);
+    if let Ok(mut snes) = Snes::new(rom, backup) {
+        for _ in 0..FRAMES {
+            let _ = snes.exec_frame();
+        }
+    }
+});
@@ -0,0 +1,55 @@
+#ifndef RUST_SNES_H
+#define RUST_SNES_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SNES_OK 0
+#define SNES_ERROR_INVALID_ARGUMENT -1
+#define SNES_ERROR_INVALID_ROM -2
+#define SNES_ERROR_NO_ROM -3
+#define SNES_ERROR_HALTED -4
+
+#define SNES_FRAME_WIDTH 256
+#define SNES_FRAME_HEIGHT 224
+
+/* Button bits for snes_set_input */
+#define SNES_BUTTON_B (1 << 0)
+#define SNES_BUTTON_Y (1 << 1)
+#define SNES_BUTTON_SELECT (1 << 2)
+#define SNES_BUTTON_START (1 << 3)
+#define SNES_BUTTON_UP (1 << 4)
+#define SNES_BUTTON_DOWN (1 << 5)
+#define SNES_BUTTON_LEFT (1 << 6)
+#define SNES_BUTTON_RIGHT (1 << 7)
+#define SNES_BUTTON_A (1 << 8)
+#define SNES_BUTTON_X (1 << 9)
+#define SNES_BUTTON_L (1 << 10)
+#define SNES_BUTTON_R (1 << 11)
+
+typedef struct SnesHandle SnesHandle;
+
+SnesHandle *snes_create(void);
+void snes_destroy(SnesHandle *handle);
+
+/* sram may be NULL. Both buffers are copied. */
+int snes_load_rom(SnesHandle *handle, const uint8_t *rom, size_t rom_len, const uint8_t *sram, size_t sram_len);
+int snes_run_frame(SnesHandle *handle);
+int snes_set_input(SnesHandle *handle, uint32_t port, uint16_t buttons);
+
+/* BGR555, valid until the next snes_run_frame. pitch is in bytes. */
+const uint16_t *snes_framebuffer(const SnesHandle *handle, uint32_t *width, uint32_t *height, uint32_t *pitch);
+/* Interleaved stereo samples at 32kHz. count is the number of stereo pairs. */
+const int16_t *snes_audio_samples(const SnesHandle *handle, size_t *count);
+/* Returns the size of the battery backed RAM, copying up to len bytes into buf. */
+size_t snes_save_ram(const SnesHandle *handle, uint8_t *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
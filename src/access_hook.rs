@@ -0,0 +1,55 @@
+// Reports bus accesses as they happen, e.g. for comparing I/O logs with other emulators,
+// see Snes::set_access_hook. 16 bit CPU accesses show up as two byte accesses like on the
+// real bus
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessSource {
+    Cpu,
+    Dma,
+    Hdma,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    // The B bus side of a DMA is at $2100-$21FF
+    pub addr: u32,
+    pub data: u8,
+    pub write: bool,
+    pub source: AccessSource,
+    // Master clock after the access
+    pub clock: u64,
+}
+
+pub trait AccessHook {
+    fn access(&mut self, access: MemoryAccess);
+}
+
+impl<F: FnMut(MemoryAccess)> AccessHook for F {
+    fn access(&mut self, access: MemoryAccess) {
+        self(access)
+    }
+}
+
+// A hook with the addresses it wants
+pub(crate) struct AccessFilter {
+    ranges: Vec<RangeInclusive<u32>>,
+    hook: Box<dyn AccessHook + Send>,
+}
+
+impl AccessFilter {
+    pub fn new(hook: Box<dyn AccessHook + Send>, ranges: &[RangeInclusive<u32>]) -> AccessFilter {
+        AccessFilter {
+            ranges: ranges.to_vec(),
+            hook,
+        }
+    }
+
+    pub fn report(&mut self, access: MemoryAccess) {
+        if self.ranges.iter().any(|range| range.contains(&access.addr)) {
+            self.hook.access(access);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// The SPC700 and DSP on their own, e.g. for SPC players. This is all that is built without
+// the `console` feature, which keeps WASM builds small:
+//
+// cargo build --release --lib --target wasm32-unknown-unknown --no-default-features
+use crate::power_on::PowerOnState;
+use crate::spc::{RegisterSnapshot, Spc};
+use alloc::boxed::Box;
+
+// The DSP makes a sample every 32 SPC700 clocks, which run at 102400/2147727 of the master clock
+const SAMPLE_SPC_CLOCKS: u64 = 32;
+
+pub struct Apu {
+    spc: Box<Spc>,
+    // Master clock the SPC700 has been run to
+    now: u64,
+    // Samples since power on
+    samples: u64,
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new(&PowerOnState::default())
+    }
+}
+
+impl Apu {
+    pub fn new(power_on: &PowerOnState) -> Apu {
+        let mut spc: Box<Spc> = Box::default();
+        spc.init_ram(power_on);
+        Apu {
+            spc,
+            now: 0,
+            samples: 0,
+        }
+    }
+
+    pub fn power_cycle(&mut self, power_on: &PowerOnState) {
+        self.spc.power_cycle(power_on, 0);
+        self.now = 0;
+        self.samples = 0;
+    }
+
+    pub fn reset(&mut self) {
+        self.spc.reset();
+    }
+
+    // $2140-$2143 as the CPU sees them, `port` is 0-3
+    pub fn write_port(&mut self, port: u16, data: u8) {
+        self.spc.write_port(port & 3, data);
+    }
+
+    pub fn read_port(&mut self, port: u16) -> u8 {
+        self.spc.read_port(port & 3)
+    }
+
+    // Upload a program without the IPL handshake, e.g. the RAM, DSP registers and CPU
+    // registers of an .spc file
+    pub fn write_ram(&mut self, addr: u16, data: u8) {
+        self.spc.write_ram(addr, data);
+    }
+
+    pub fn write_dsp_register(&mut self, addr: u8, data: u8) {
+        self.spc.write_dsp_register(addr, data);
+    }
+
+    pub fn set_registers(&mut self, registers: &RegisterSnapshot) {
+        self.spc.set_registers(registers);
+    }
+
+    // Run until the DSP has made `samples` more stereo samples at 32kHz and return them.
+    // They go to the audio sink instead if one is set
+    pub fn run(&mut self, samples: usize) -> &[(i16, i16)] {
+        self.spc.clear_audio_buffer();
+        self.samples += samples as u64;
+        // First master clock at which the SPC700 clock reaches the last sample
+        self.now = (self.samples * SAMPLE_SPC_CLOCKS * 2147727).div_ceil(102400);
+        self.spc.tick(self.now);
+        self.spc.audio_buffer()
+    }
+
+    // Master clock the APU has been run to
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn spc(&self) -> &Spc {
+        &self.spc
+    }
+
+    pub fn spc_mut(&mut self) -> &mut Spc {
+        &mut self.spc
+    }
+}
@@ -0,0 +1,314 @@
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use log::debug;
+
+use crate::dsp::AudioOutputMode;
+use crate::spc;
+
+// std's bounded channel is a lock-free array ring buffer
+const COMMAND_QUEUE_SIZE: usize = 1024;
+// Let the APU run ahead one scanline at a time instead of after every CPU instruction
+const RUN_QUANTUM: u64 = 1364;
+
+enum Command {
+    RunTo(u64),
+    Write(u64, u16, u8),
+    Sync(u64),
+    CopyRam(u64),
+    WriteRam(u64, u16, u8),
+    WriteDsp(u64, u8, u8),
+    SetAudioSink(Option<Box<dyn spc::AudioSink + Send>>),
+    SetAudioOutputMode(AudioOutputMode),
+    SetVolume(u16),
+    SetMuted(bool),
+    Shutdown,
+}
+
+// Output ports as of master clock `clock`, published after every command the worker runs
+// so port reads don't need a round trip through the command queue
+#[derive(Default)]
+struct Ports {
+    data: [AtomicU8; 4],
+    clock: AtomicU64,
+}
+
+impl Ports {
+    fn publish(&self, spc: &mut spc::Spc, clock: u64) {
+        for (port, data) in self.data.iter().enumerate() {
+            data.store(spc.read_port(port as u16), Ordering::Relaxed);
+        }
+        self.clock.store(clock, Ordering::Release);
+    }
+}
+
+struct SyncReply {
+    state: spc::SpcState,
+    dsp_registers: [u8; 0x80],
+    instructions: u64,
+}
+
+pub struct ApuThread {
+    command: SyncSender<Command>,
+    sync: Receiver<SyncReply>,
+    ram_copy: Receiver<Vec<u8>>,
+    audio: Receiver<(u64, Vec<(i16, i16)>)>,
+    handle: Option<JoinHandle<spc::Spc>>,
+    ports: Arc<Ports>,
+
+    last_run: u64,
+    // Latest clock handed to the worker
+    sent: u64,
+    audio_buffer: Vec<(i16, i16)>,
+    audio_buffer_clock: u64,
+    state: spc::SpcState,
+    dsp_registers: [u8; 0x80],
+    instructions: u64,
+    ram: Vec<u8>,
+}
+
+impl ApuThread {
+    pub fn spawn(mut spc: spc::Spc, now: u64) -> ApuThread {
+        let state = spc.state();
+        let dsp_registers = spc.dsp_registers();
+        let instructions = spc.instructions();
+        let (command_tx, command_rx) = mpsc::sync_channel(COMMAND_QUEUE_SIZE);
+        let (sync_tx, sync_rx) = mpsc::sync_channel(1);
+        let (ram_tx, ram_rx) = mpsc::sync_channel(1);
+        let (audio_tx, audio_rx) = mpsc::channel();
+        let ports = Arc::new(Ports::default());
+        ports.publish(&mut spc, now);
+
+        let worker_ports = ports.clone();
+        let handle = thread::Builder::new()
+            .name("apu".to_string())
+            .spawn(move || worker(spc, now, command_rx, sync_tx, ram_tx, audio_tx, worker_ports))
+            .expect("Failed to spawn APU thread");
+
+        ApuThread {
+            command: command_tx,
+            sync: sync_rx,
+            ram_copy: ram_rx,
+            audio: audio_rx,
+            handle: Some(handle),
+            ports,
+            last_run: now,
+            sent: now,
+            audio_buffer: Vec::new(),
+            audio_buffer_clock: 0,
+            state,
+            dsp_registers,
+            instructions,
+            ram: Vec::new(),
+        }
+    }
+
+    pub fn tick(&mut self, now: u64) {
+        if now - self.last_run >= RUN_QUANTUM {
+            self.last_run = now;
+            self.send(Command::RunTo(now));
+        }
+    }
+
+    pub fn write_port(&mut self, now: u64, port: u16, data: u8) {
+        self.send(Command::Write(now, port, data));
+    }
+
+    // Polling loops read the ports constantly, so only wait for the commands already queued
+    // instead of a full sync. The APU lags behind by at most one RUN_QUANTUM
+    pub fn read_port(&mut self, now: u64, port: u16) -> u8 {
+        self.tick(now);
+        while self.ports.clock.load(Ordering::Acquire) < self.sent {
+            let alive = self.handle.as_ref().is_some_and(|handle| !handle.is_finished());
+            assert!(alive, "APU thread terminated");
+            thread::yield_now();
+        }
+        self.ports.data[port as usize].load(Ordering::Relaxed)
+    }
+
+    // Wait for the APU to catch up with `now` and collect the audio it produced
+    pub fn sync(&mut self, now: u64) {
+        self.last_run = now;
+        self.send(Command::Sync(now));
+        let reply = self.sync.recv().expect("APU thread terminated");
+        while let Ok((clock, samples)) = self.audio.try_recv() {
+            if self.audio_buffer.is_empty() {
+                self.audio_buffer_clock = clock;
+            }
+            self.audio_buffer.extend(samples);
+        }
+        self.state = reply.state;
+        self.dsp_registers = reply.dsp_registers;
+        self.instructions = reply.instructions;
+    }
+
+    // Catch up with `now` and copy the audio RAM over
+    pub fn ram(&mut self, now: u64) -> &[u8] {
+        self.send(Command::CopyRam(now));
+        self.ram = self.ram_copy.recv().expect("APU thread terminated");
+        &self.ram
+    }
+
+    pub fn write_ram(&mut self, now: u64, addr: u16, data: u8) {
+        self.send(Command::WriteRam(now, addr, data));
+    }
+
+    pub fn write_dsp_register(&mut self, now: u64, addr: u8, data: u8) {
+        self.send(Command::WriteDsp(now, addr, data));
+    }
+
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn spc::AudioSink + Send>>) {
+        self.send(Command::SetAudioSink(sink));
+    }
+
+    pub fn set_audio_output_mode(&mut self, mode: AudioOutputMode) {
+        self.send(Command::SetAudioOutputMode(mode));
+    }
+
+    pub fn set_volume(&mut self, volume: u16) {
+        self.send(Command::SetVolume(volume));
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.send(Command::SetMuted(muted));
+    }
+
+    pub fn state(&self) -> spc::SpcState {
+        self.state
+    }
+
+    pub fn dsp_registers(&self) -> [u8; 0x80] {
+        self.dsp_registers
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn audio_buffer(&self) -> &[(i16, i16)] {
+        &self.audio_buffer
+    }
+
+    pub fn clear_audio_buffer(&mut self) {
+        self.audio_buffer.clear();
+    }
+
+    pub fn audio_timestamp(&self, index: usize) -> Option<u64> {
+        (index < self.audio_buffer.len())
+            .then(|| spc::sample_timestamp(self.audio_buffer_clock, index))
+    }
+
+    pub fn join(mut self) -> spc::Spc {
+        self.shutdown().expect("APU thread terminated")
+    }
+
+    fn send(&mut self, command: Command) {
+        if let Command::RunTo(clock)
+        | Command::Write(clock, ..)
+        | Command::Sync(clock)
+        | Command::CopyRam(clock)
+        | Command::WriteRam(clock, ..)
+        | Command::WriteDsp(clock, ..) = command
+        {
+            self.sent = self.sent.max(clock);
+        }
+        self.command.send(command).expect("APU thread terminated");
+    }
+
+    fn shutdown(&mut self) -> Option<spc::Spc> {
+        let handle = self.handle.take()?;
+        let _ = self.command.send(Command::Shutdown);
+        handle.join().ok()
+    }
+}
+
+impl Drop for ApuThread {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn worker(
+    mut spc: spc::Spc,
+    now: u64,
+    command: Receiver<Command>,
+    sync: SyncSender<SyncReply>,
+    ram_copy: SyncSender<Vec<u8>>,
+    audio: Sender<(u64, Vec<(i16, i16)>)>,
+    ports: Arc<Ports>,
+) -> spc::Spc {
+    debug!("APU thread started");
+    let mut now = now;
+
+    let run_to = |spc: &mut spc::Spc, now: &mut u64, clock: u64| {
+        *now = (*now).max(clock);
+        spc.tick(*now);
+        if !spc.audio_buffer().is_empty() {
+            let _ = audio.send((spc.audio_buffer_clock(), spc.audio_buffer().to_vec()));
+            spc.clear_audio_buffer();
+        }
+    };
+
+    while let Ok(cmd) = command.recv() {
+        let publish = !matches!(cmd, Command::Shutdown);
+        match cmd {
+            Command::RunTo(clock) => run_to(&mut spc, &mut now, clock),
+            Command::Write(clock, port, data) => {
+                run_to(&mut spc, &mut now, clock);
+                spc.write_port(port, data);
+            }
+            Command::Sync(clock) => {
+                run_to(&mut spc, &mut now, clock);
+                let reply = SyncReply {
+                    state: spc.state(),
+                    dsp_registers: spc.dsp_registers(),
+                    instructions: spc.instructions(),
+                };
+                if sync.send(reply).is_err() {
+                    break;
+                }
+            }
+            Command::CopyRam(clock) => {
+                run_to(&mut spc, &mut now, clock);
+                if ram_copy.send(spc.ram().to_vec()).is_err() {
+                    break;
+                }
+            }
+            Command::WriteRam(clock, addr, data) => {
+                run_to(&mut spc, &mut now, clock);
+                spc.write_ram(addr, data);
+            }
+            Command::WriteDsp(clock, addr, data) => {
+                run_to(&mut spc, &mut now, clock);
+                spc.write_dsp_register(addr, data);
+            }
+            Command::SetAudioSink(sink) => spc.set_audio_sink(sink),
+            Command::SetAudioOutputMode(mode) => spc.set_audio_output_mode(mode),
+            Command::SetVolume(volume) => spc.set_volume(volume),
+            Command::SetMuted(muted) => spc.set_muted(muted),
+            Command::Shutdown => break,
+        }
+        if publish {
+            ports.publish(&mut spc, now);
+        }
+    }
+    debug!("APU thread stopped");
+    spc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "APU thread terminated")]
+    fn read_port_fails_when_the_worker_is_gone() {
+        let mut apu = ApuThread::spawn(spc::Spc::default(), 0);
+        // A clock the worker never gets to publish
+        apu.command.send(Command::Shutdown).unwrap();
+        apu.sent = 1;
+        apu.read_port(0, 0);
+    }
+}
@@ -0,0 +1,65 @@
+// Lossless A/V capture, see Snes::start_avdump
+//
+// All integers are little endian.
+//
+// Header (24 bytes):
+//   magic        b"RSAV"
+//   version      u16 (1)
+//   width        u16 (256)
+//   height       u16 (224)
+//   reserved     u16
+//   sample_rate  u32 (32040)
+//   fps          u32 numerator / u32 denominator (master clock / (1364 * 262))
+//
+// Then one chunk pair per frame:
+//   b"VIDF" u32 length, width * height BGR555 pixels as u16
+//   b"AUDF" u32 length, interleaved stereo samples as i16 (left, right)
+
+use std::io::{self, Write};
+
+const VERSION: u16 = 1;
+const SAMPLE_RATE: u32 = 32_040;
+const FPS_NUMERATOR: u32 = 21_477_272;
+const FPS_DENOMINATOR: u32 = 1364 * 262;
+
+pub struct AvDump {
+    writer: Box<dyn Write + Send>,
+    buf: Vec<u8>,
+}
+
+impl AvDump {
+    pub fn new(mut writer: Box<dyn Write + Send>, width: u16, height: u16) -> io::Result<AvDump> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(b"RSAV");
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&width.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&FPS_NUMERATOR.to_le_bytes());
+        header.extend_from_slice(&FPS_DENOMINATOR.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(AvDump {
+            writer,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &[u16], audio: &[(i16, i16)]) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.extend_from_slice(b"VIDF");
+        self.buf.extend_from_slice(&(frame.len() as u32 * 2).to_le_bytes());
+        self.buf.extend(frame.iter().flat_map(|p| p.to_le_bytes()));
+        self.buf.extend_from_slice(b"AUDF");
+        self.buf.extend_from_slice(&(audio.len() as u32 * 4).to_le_bytes());
+        for (left, right) in audio {
+            self.buf.extend_from_slice(&left.to_le_bytes());
+            self.buf.extend_from_slice(&right.to_le_bytes());
+        }
+        self.writer.write_all(&self.buf)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,49 @@
+// Entry points for benches/ into otherwise private parts of the core. Not a stable API
+use crate::Snes;
+
+// Key on all 8 voices with a looping BRR sample so the mixer has work to do
+pub fn start_all_voices(snes: &mut Snes) {
+    let dsp = snes.context.inner1.inner2.spc.dsp_mut();
+
+    // Sample directory at $0200, one sample at $1000 that loops on itself
+    dsp.ram[0x0200..0x0204].copy_from_slice(&[0x00, 0x10, 0x00, 0x10]);
+    for block in 0..4 {
+        let addr = 0x1000 + block * 9;
+        // shift 12, filter 1, end + loop on the last block
+        dsp.ram[addr] = if block == 3 { 0xC7 } else { 0xC4 };
+        for (i, data) in dsp.ram[addr + 1..addr + 9].iter_mut().enumerate() {
+            *data = (block * 8 + i) as u8 * 0x1D;
+        }
+    }
+
+    for ch in 0..8u8 {
+        let base = ch << 4;
+        dsp.write(base, 0x7F); // VOL(L)
+        dsp.write(base | 0x1, 0x7F); // VOL(R)
+        dsp.write(base | 0x2, 0x00); // P(L)
+        dsp.write(base | 0x3, 0x10 + ch); // P(H)
+        dsp.write(base | 0x4, 0x00); // SRCN
+        dsp.write(base | 0x5, 0x8F); // ADSR(1)
+        dsp.write(base | 0x6, 0xE0); // ADSR(2)
+    }
+    dsp.write(0x0C, 0x7F); // MVOL(L)
+    dsp.write(0x1C, 0x7F); // MVOL(R)
+    dsp.write(0x5D, 0x02); // DIR
+    dsp.write(0x6C, 0x20); // FLG: echo writes off, unmuted, out of reset
+    dsp.write(0x4C, 0xFF); // KON
+}
+
+// Run the DSP alone for `count` samples and return the last one
+pub fn mix_samples(snes: &mut Snes, count: usize) -> (i16, i16) {
+    let dsp = snes.context.inner1.inner2.spc.dsp_mut();
+    let mut sample = (0, 0);
+    for _ in 0..count {
+        sample = dsp.tick();
+    }
+    sample
+}
+
+// Render line `y` (1..=224) in one go, outside of the dot timing
+pub fn render_line(snes: &mut Snes, y: u16) {
+    snes.context.inner1.inner2.ppu.render_line(y);
+}
@@ -0,0 +1,75 @@
+use rust_snes::Snes;
+use std::path::Path;
+use std::process::ExitCode;
+
+// Run test ROMs headless and compare the final framebuffer against known CRC32s.
+//
+// Each manifest line is `<rom path> <frames> <crc32>`, with ROM paths relative to the
+// manifest. Blank lines and lines starting with `#` are skipped. Pass `--update` to print
+// the manifest with the CRCs of the current build instead of checking them.
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let manifest_path = args
+        .next()
+        .expect("Usage: bin/compat <manifest> [--update]");
+    let update = args.next().is_some_and(|arg| arg == "--update");
+
+    let manifest = std::fs::read_to_string(&manifest_path).expect("Failed to read manifest");
+    let base = Path::new(&manifest_path).parent().unwrap_or(Path::new("."));
+
+    let mut failed = 0;
+    let mut total = 0;
+    for (i, line) in manifest.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [rom_path, frames, expected] = fields[..] else {
+            panic!("{}:{}: expected `<rom> <frames> <crc32>`", manifest_path, i + 1);
+        };
+        let frames: u32 = frames.parse().expect("Invalid frame count");
+
+        total += 1;
+        let result = run(&base.join(rom_path), frames);
+        if update {
+            match result {
+                Ok(crc) => println!("{} {} {:08X}", rom_path, frames, crc),
+                Err(err) => println!("# {}: {}", rom_path, err),
+            }
+            continue;
+        }
+
+        let expected = u32::from_str_radix(expected, 16).expect("Invalid CRC32");
+        match result {
+            Ok(crc) if crc == expected => println!("PASS {}", rom_path),
+            Ok(crc) => {
+                failed += 1;
+                println!("FAIL {}: expected {:08X}, got {:08X}", rom_path, expected, crc);
+            }
+            Err(err) => {
+                failed += 1;
+                println!("FAIL {}: {}", rom_path, err);
+            }
+        }
+    }
+
+    if update {
+        return ExitCode::SUCCESS;
+    }
+    println!("{}/{} passed", total - failed, total);
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn run(rom_path: &Path, frames: u32) -> Result<u32, String> {
+    let rom = std::fs::read(rom_path).map_err(|e| e.to_string())?;
+    let mut snes = Snes::new(rom, None).map_err(|e| e.to_string())?;
+    for _ in 0..frames {
+        snes.exec_frame().map_err(|e| e.to_string())?;
+    }
+    Ok(snes.frame_crc32())
+}
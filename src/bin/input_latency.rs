@@ -0,0 +1,34 @@
+use rust_snes::{InputEventKind, Snes};
+
+// Print where in each frame the game latches and reads the controllers
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let rom_path = args
+        .next()
+        .expect("Usage: bin/input_latency <path-to-rom> [frames]");
+    let frames = args.next().map_or(600, |n| n.parse().expect("Invalid frame count"));
+    let rom = std::fs::read(rom_path).expect("Failed to read ROM file");
+    let mut snes = Snes::new(rom, None).expect("Failed to load ROM");
+
+    for _ in 0..frames {
+        snes.exec_frame().expect("Emulation halted");
+
+        let events = snes.input_events();
+        let Some(frame) = events.first().map(|e| e.frame) else {
+            continue;
+        };
+        let latch = events.iter().find(|e| e.kind == InputEventKind::Latch);
+        let read = events.iter().find(|e| e.kind != InputEventKind::Latch);
+        let fmt = |e: Option<&rust_snes::InputEvent>| match e {
+            Some(e) => format!("{:3}:{:3}", e.y, e.x),
+            None => "   -   ".to_string(),
+        };
+        println!(
+            "frame {:5}: latch {}  first read {}  reads {}",
+            frame,
+            fmt(latch),
+            fmt(read),
+            events.len() - events.iter().filter(|e| e.kind == InputEventKind::Latch).count()
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use rust_snes::opcode_test::{CpuRegisters, CpuTest, SpcRegisters, SpcTest};
+use serde_json::Value;
+use std::process::ExitCode;
+
+// Run the single instruction JSON test suites (SingleStepTests 65816 and spc700) against
+// the cores with a flat memory bus.
+//
+// Each file is an array of `{ name, initial, final, cycles }` tests. The initial state is
+// loaded, one instruction is executed and the registers, the listed RAM bytes and the
+// number of cycles are compared against the final state. Prints the first failing test of
+// every file.
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let usage = "Usage: bin/opcode_tests <cpu|spc> <test.json>...";
+    let core = args.next().expect(usage);
+    let paths: Vec<String> = args.collect();
+    assert!(!paths.is_empty(), "{}", usage);
+
+    let mut cpu = CpuTest::default();
+    let mut spc = SpcTest::default();
+
+    let mut failed = 0;
+    let mut total = 0;
+    for path in paths.iter() {
+        let json = std::fs::read_to_string(path).expect("Failed to read test file");
+        let tests: Vec<Value> = serde_json::from_str(&json).expect("Invalid test file");
+
+        let mut first_failure = None;
+        let mut file_failed = 0;
+        for test in tests.iter() {
+            let result = match core.as_str() {
+                "cpu" => run_cpu(&mut cpu, test),
+                "spc" => run_spc(&mut spc, test),
+                _ => panic!("{}", usage),
+            };
+            if let Err(err) = result {
+                file_failed += 1;
+                first_failure.get_or_insert_with(|| format!("{}: {}", test["name"], err));
+            }
+        }
+
+        total += tests.len();
+        failed += file_failed;
+        match first_failure {
+            None => println!("PASS {}", path),
+            Some(err) => println!(
+                "FAIL {} ({}/{} failed), first: {}",
+                path,
+                file_failed,
+                tests.len(),
+                err
+            ),
+        }
+    }
+
+    println!("{}/{} passed", total - failed, total);
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn field(state: &Value, name: &str) -> u64 {
+    state[name]
+        .as_u64()
+        .unwrap_or_else(|| panic!("Missing field `{}`", name))
+}
+
+// `ram` is a list of `[address, value]` pairs
+fn ram(state: &Value) -> impl Iterator<Item = (u32, u8)> + '_ {
+    state["ram"]
+        .as_array()
+        .expect("Missing field `ram`")
+        .iter()
+        .map(|entry| (entry[0].as_u64().unwrap() as u32, entry[1].as_u64().unwrap() as u8))
+}
+
+fn cycle_count(test: &Value) -> u64 {
+    test["cycles"].as_array().map_or(0, |cycles| cycles.len() as u64)
+}
+
+fn cpu_registers(state: &Value) -> CpuRegisters {
+    CpuRegisters {
+        a: field(state, "a") as u16,
+        x: field(state, "x") as u16,
+        y: field(state, "y") as u16,
+        pc: field(state, "pc") as u16,
+        s: field(state, "s") as u16,
+        p: field(state, "p") as u8,
+        d: field(state, "d") as u16,
+        db: field(state, "dbr") as u8,
+        pb: field(state, "pbr") as u8,
+        e: field(state, "e") != 0,
+    }
+}
+
+fn run_cpu(cpu: &mut CpuTest, test: &Value) -> Result<(), String> {
+    let initial = &test["initial"];
+    let expected = &test["final"];
+
+    cpu.reset();
+    cpu.set_registers(&cpu_registers(initial));
+    for (addr, data) in ram(initial) {
+        cpu.write(addr, data);
+    }
+
+    let cycles = cpu.step();
+
+    let registers = cpu.registers();
+    let expected_registers = cpu_registers(expected);
+    if registers != expected_registers {
+        return Err(format!("expected {:X?}, got {:X?}", expected_registers, registers));
+    }
+    for (addr, data) in ram(expected) {
+        let actual = cpu.read(addr);
+        if actual != data {
+            return Err(format!("${:06X}: expected {:02X}, got {:02X}", addr, data, actual));
+        }
+    }
+    if cycles != cycle_count(test) {
+        return Err(format!("expected {} cycles, got {}", cycle_count(test), cycles));
+    }
+    Ok(())
+}
+
+fn spc_registers(state: &Value) -> SpcRegisters {
+    SpcRegisters {
+        a: field(state, "a") as u8,
+        x: field(state, "x") as u8,
+        y: field(state, "y") as u8,
+        sp: field(state, "sp") as u8,
+        psw: field(state, "psw") as u8,
+        pc: field(state, "pc") as u16,
+    }
+}
+
+fn run_spc(spc: &mut SpcTest, test: &Value) -> Result<(), String> {
+    let initial = &test["initial"];
+    let expected = &test["final"];
+
+    spc.reset();
+    spc.set_registers(&spc_registers(initial));
+    for (addr, data) in ram(initial) {
+        spc.write(addr as u16, data);
+    }
+
+    let cycles = spc.step();
+
+    let registers = spc.registers();
+    let expected_registers = spc_registers(expected);
+    if registers != expected_registers {
+        return Err(format!("expected {:X?}, got {:X?}", expected_registers, registers));
+    }
+    for (addr, data) in ram(expected) {
+        let actual = spc.read(addr as u16);
+        if actual != data {
+            return Err(format!("${:04X}: expected {:02X}, got {:02X}", addr, data, actual));
+        }
+    }
+    if cycles != cycle_count(test) {
+        return Err(format!("expected {} cycles, got {}", cycle_count(test), cycles));
+    }
+    Ok(())
+}
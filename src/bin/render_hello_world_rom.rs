@@ -11,7 +11,7 @@ fn main() -> Result<(), String> {
         .nth(1)
         .expect("Usage: bin/run_hello_world_rom <path-to-rom>");
     let rom = std::fs::read(rom_path).expect("Failed to read ROM file");
-    let mut snes = Snes::new(rom);
+    let mut snes = Snes::new(rom, None).map_err(|e| e.to_string())?;
 
     let sdl2_context = sdl2::init()?;
     let video_subsystem = sdl2_context.video()?;
@@ -110,8 +110,8 @@ fn main() -> Result<(), String> {
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
 
-        snes.exec_frame();
-        let screen = snes.context.inner1.inner2.ppu.frame;
+        snes.exec_frame().map_err(|e| e.to_string())?;
+        let screen = snes.ppu().frame;
 
         for x in 0..256 {
             for y in 0..224 {
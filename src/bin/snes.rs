@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use dirs::data_dir;
-use log::info;
-use rust_snes::{Key, Snes};
+use log::{info, warn};
+use rust_snes::{AudioPacer, Key, Snes};
 use sdl2::audio;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
@@ -33,7 +33,18 @@ fn main() -> Result<()> {
     // セーブデータをロード
     let backup = load_save_data(rom_name)?;
 
-    let mut snes = Snes::new(rom, backup);
+    let mut snes = Snes::new(rom, backup).context("Failed to load ROM")?;
+    // Coprocessor firmware is looked for next to the ROM
+    if let Some(chip) = snes.missing_firmware() {
+        let path = rom_path.with_file_name(chip.file_name());
+        if let Ok(firmware) = fs::read(&path) {
+            snes.add_firmware(chip, firmware)
+                .with_context(|| format!("Failed to load {:?}", path))?;
+        }
+    }
+    for diagnostic in snes.diagnostics() {
+        warn!("{}", diagnostic);
+    }
 
     let sdl2_context = sdl2::init()
         .map_err(|e| anyhow::anyhow!(e))
@@ -121,6 +132,7 @@ fn main() -> Result<()> {
 
     let mut keys = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
     let mut frame = 0;
+    let mut pacer = AudioPacer::new(1024, 4096);
     'running: loop {
         let start_time = std::time::Instant::now();
         for event in event_pump.poll_iter() {
@@ -204,8 +216,8 @@ fn main() -> Result<()> {
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
 
-        snes.exec_frame();
-        let screen = snes.context.inner1.inner2.ppu.frame;
+        snes.exec_frame().context("Emulation halted")?;
+        let screen = snes.ppu().frame;
 
         for x in 0..256 {
             for y in 0..224 {
@@ -229,10 +241,13 @@ fn main() -> Result<()> {
         // 描画をウィンドウに反映
         canvas.present();
 
-        let audio_buffer = snes.context.inner1.inner2.spc.audio_buffer();
+        let audio_buffer = snes.audio_buffer();
         // println!("audio_buffer len: {:?}", audio_buffer.len());
-        while audio_queue.size() > 1024 * 4 {
+        // 1 sample = 2ch * 2byte
+        pacer.report_queued(audio_queue.size() as usize / 4);
+        while !pacer.should_exec_frame() {
             std::thread::sleep(Duration::from_millis(1));
+            pacer.report_queued(audio_queue.size() as usize / 4);
         }
         audio_queue
             .queue_audio(
@@ -242,6 +257,7 @@ fn main() -> Result<()> {
                     .collect::<Vec<i16>>(),
             )
             .unwrap();
+        pacer.report_generated(audio_buffer.len());
 
         // 16ms待機して約60FPSを維持
         // let elapsed = start_time.elapsed();
@@ -5,9 +5,9 @@ fn main() {
         .nth(1)
         .expect("Usage: bin/run_hello_world_rom <path-to-rom>");
     let rom = std::fs::read(rom_path).expect("Failed to read ROM file");
-    let mut snes = Snes::new(rom);
+    let mut snes = Snes::new(rom, None).expect("Failed to load ROM");
     loop {
-        snes.exec_frame();
+        snes.exec_frame().expect("Emulation halted");
         println!("executed frame");
         std::thread::sleep(Duration::from_millis(1000));
     }
@@ -1,24 +1,45 @@
-use log::{debug, info, warn};
+use log::{debug, info, trace, warn};
 use modular_bitfield::bitfield;
 use modular_bitfield::prelude::*;
 
-use crate::controller::Key;
-use crate::{context, controller};
+use crate::access_hook::{AccessFilter, AccessSource, MemoryAccess};
+use crate::controller::{self, DeviceType, InputProvider, Key, SerialDevice};
+use crate::diagnostics::Diagnostic;
+use crate::error::SnesError;
+use crate::chip_versions::ChipVersions;
+use crate::power_on::PowerOnState;
+use crate::context;
+use crate::satellaview::Satellaview;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 trait Context:
-    context::Ppu + context::Timing + context::Cartridge + context::Interrupt + context::Spc
+    context::Ppu
+    + context::Timing
+    + context::Cartridge
+    + context::Interrupt
+    + context::Spc
+    + context::Diagnostics
 {
 }
 impl<
-        T: context::Ppu + context::Timing + context::Cartridge + context::Interrupt + context::Spc,
+        T: context::Ppu
+            + context::Timing
+            + context::Cartridge
+            + context::Interrupt
+            + context::Spc
+            + context::Diagnostics,
     > Context for T
 {
 }
 
 const CYCLE_FAST: u64 = 6;
 const CYCLE_SLOW: u64 = 8;
-const CYCLE_JOYPAD: u64 = 12;
+const CYCLE_XSLOW: u64 = 12;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bus {
+    #[cfg_attr(feature = "serde", serde(with = "serde_big_array::BigArray"))]
     wram: [u8; 0x20000],
     wram_addr: u32,
     access_cycle_for_memory2: u64, // 0x420D,
@@ -27,10 +48,38 @@ pub struct Bus {
     gdma_enable: u8,     // 0x420B
     hdma_enable: u8,     // 0x420C
     is_dma_active: bool, // flag for read/write bus in dma (for clock)
+    // The DMA running is HDMA, only for the access hook
+    #[cfg_attr(feature = "serde", serde(skip))]
+    in_hdma: bool,
+    dma_bytes: u64,
+
+    cpu_version: u8,
 
     joypad_enable: bool, // 0x4200
+    wrio: u8,            // 0x4201
     auto_joypad_read_busy: u64,
     controller: [controller::Controller; 2],
+    keys: [u16; 4],
+    #[cfg_attr(feature = "serde", serde(skip))]
+    input_provider: Option<Box<dyn InputProvider + Send>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    serial_devices: [Option<Box<dyn SerialDevice + Send>>; 2],
+    // Expansion port, see Snes::set_satellaview
+    #[cfg_attr(feature = "serde", serde(skip))]
+    satellaview: Option<Box<Satellaview>>,
+    #[cfg(feature = "instrumentation")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    input_events: Vec<controller::InputEvent>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    access_hook: Option<AccessFilter>,
+
+    // WRAM offsets whose writes are logged for scripts, one bit each
+    #[cfg(feature = "scripting")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    wram_write_watch: Vec<u64>,
+    #[cfg(feature = "scripting")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    wram_writes: Vec<(u32, u8)>,
 
     multiplicand: u8,                  // 0x4202
     multiplier: u8,                    // 0x4203
@@ -38,11 +87,25 @@ pub struct Bus {
     divisor: u8,                       // 0x4206
     div_result: u16,                   // 0x4214 0x4215
     div_remainder_or_mul_product: u16, // 0x4216 0x4217
+    // The multiplier/divider shifts one bit per CPU cycle, 8 steps to multiply and 16 to
+    // divide. $4214-$4217 show the intermediate values until it is done
+    alu_shift: u32,
+    alu_mul_steps: u8,
+    alu_div_steps: u8,
+    // CPU cycle the unit has been stepped to
+    alu_cycle: u64,
+    // Finish in the cycle of the $4203/$4206 write, see CompatHack::InstantMulDiv
+    #[cfg_attr(feature = "serde", serde(skip))]
+    instant_mul_div: bool,
 
     h_count: u16, // 0x4207 0x4208
     v_count: u16, // 0x4209 0x420A
 
     open_bus: u8,
+    error: Option<SnesError>,
+    // Accesses to $2000-$5FFF nothing handles by (offset, write), see Snes::diagnostics
+    #[cfg_attr(feature = "serde", serde(skip))]
+    unimplemented_accesses: BTreeMap<(u16, bool), u64>,
 }
 
 impl Default for Bus {
@@ -56,9 +119,25 @@ impl Default for Bus {
             gdma_enable: 0,
             hdma_enable: 0,
             is_dma_active: false,
+            in_hdma: false,
+            dma_bytes: 0,
+
+            cpu_version: ChipVersions::default().cpu_version,
 
             controller: Default::default(),
+            keys: [0; 4],
+            input_provider: None,
+            serial_devices: [None, None],
+            satellaview: None,
+            #[cfg(feature = "instrumentation")]
+            input_events: Vec::new(),
+            access_hook: None,
+            #[cfg(feature = "scripting")]
+            wram_write_watch: Vec::new(),
+            #[cfg(feature = "scripting")]
+            wram_writes: Vec::new(),
             joypad_enable: false,
+            wrio: 0xFF,
             auto_joypad_read_busy: 0,
 
             multiplicand: 0xFF,
@@ -67,107 +146,348 @@ impl Default for Bus {
             divisor: 0xFF,
             div_result: 0,
             div_remainder_or_mul_product: 0,
+            alu_shift: 0,
+            alu_mul_steps: 0,
+            alu_div_steps: 0,
+            alu_cycle: 0,
+            instant_mul_div: false,
 
             h_count: 0x01FF,
             v_count: 0x01FF,
 
             open_bus: 0,
+            error: None,
+            unimplemented_accesses: BTreeMap::new(),
         }
     }
 }
 
 impl Bus {
+    // Exchange what a savestate doesn't contain
+    pub fn swap_host_state(&mut self, other: &mut Bus) {
+        core::mem::swap(&mut self.input_provider, &mut other.input_provider);
+        core::mem::swap(&mut self.serial_devices, &mut other.serial_devices);
+        core::mem::swap(&mut self.satellaview, &mut other.satellaview);
+        core::mem::swap(&mut self.access_hook, &mut other.access_hook);
+        core::mem::swap(&mut self.unimplemented_accesses, &mut other.unimplemented_accesses);
+        core::mem::swap(&mut self.instant_mul_div, &mut other.instant_mul_div);
+        #[cfg(feature = "scripting")]
+        core::mem::swap(&mut self.wram_write_watch, &mut other.wram_write_watch);
+    }
+
+    pub fn set_satellaview(&mut self, satellaview: Option<Satellaview>) {
+        self.satellaview = satellaview.map(Box::new);
+    }
+
+    pub fn satellaview_mut(&mut self) -> Option<&mut Satellaview> {
+        self.satellaview.as_deref_mut()
+    }
+
+    pub fn set_access_hook(&mut self, hook: Option<AccessFilter>) {
+        self.access_hook = hook;
+    }
+
+    pub fn unimplemented_accesses(&self) -> &BTreeMap<(u16, bool), u64> {
+        &self.unimplemented_accesses
+    }
+
+    fn count_unimplemented(&mut self, offset: u16, write: bool) {
+        *self.unimplemented_accesses.entry((offset, write)).or_default() += 1;
+    }
+
+    fn report_access(&mut self, addr: u32, data: u8, write: bool, now: u64) {
+        let Some(hook) = &mut self.access_hook else {
+            return;
+        };
+        let source = match (self.is_dma_active, self.in_hdma) {
+            (false, _) => AccessSource::Cpu,
+            (true, false) => AccessSource::Dma,
+            (true, true) => AccessSource::Hdma,
+        };
+        hook.report(MemoryAccess {
+            addr,
+            data,
+            write,
+            source,
+            clock: now,
+        });
+    }
+
+    pub fn set_cpu_version(&mut self, version: u8) {
+        self.cpu_version = version;
+    }
+
+    pub fn set_instant_mul_div(&mut self, instant: bool) {
+        self.instant_mul_div = instant;
+    }
+
+    // Step the multiplier/divider through CPU cycle `cycle`
+    fn alu_catch_up(&mut self, cycle: u64) {
+        while self.alu_cycle < cycle && self.alu_mul_steps + self.alu_div_steps != 0 {
+            self.alu_cycle += 1;
+            self.alu_step();
+        }
+        self.alu_cycle = self.alu_cycle.max(cycle);
+    }
+
+    fn alu_step(&mut self) {
+        if self.alu_mul_steps != 0 {
+            // Shift the multiplicand out of $4214 and add the shifted multiplier to $4216
+            self.alu_mul_steps -= 1;
+            if self.div_result & 1 != 0 {
+                self.div_remainder_or_mul_product =
+                    self.div_remainder_or_mul_product.wrapping_add(self.alu_shift as u16);
+            }
+            self.div_result >>= 1;
+            self.alu_shift <<= 1;
+        }
+        if self.alu_div_steps != 0 {
+            // Restoring division, one quotient bit into $4214 per step
+            self.alu_div_steps -= 1;
+            self.div_result <<= 1;
+            self.alu_shift >>= 1;
+            if self.div_remainder_or_mul_product as u32 >= self.alu_shift {
+                self.div_remainder_or_mul_product -= self.alu_shift as u16;
+                self.div_result |= 1;
+            }
+        }
+    }
+
+    fn alu_busy(&self) -> bool {
+        self.alu_mul_steps + self.alu_div_steps != 0
+    }
+
+    pub fn init_ram(&mut self, power_on: &PowerOnState) {
+        power_on.fill(&mut self.wram, 0);
+    }
+
+    // Reset button: the CPU side registers go back to their power on values, WRAM and the
+    // DMA channel settings stay
+    pub fn reset(&mut self) {
+        self.access_cycle_for_memory2 = 8;
+        self.gdma_enable = 0;
+        self.hdma_enable = 0;
+        self.is_dma_active = false;
+        self.joypad_enable = false;
+        self.wrio = 0xFF;
+        self.auto_joypad_read_busy = 0;
+        self.h_count = 0x01FF;
+        self.v_count = 0x01FF;
+    }
+
+    // The pads stay plugged in
+    pub fn power_cycle(&mut self, power_on: &PowerOnState) {
+        let mut fresh: Box<Bus> = Box::default();
+        fresh.swap_host_state(self);
+        fresh.cpu_version = self.cpu_version;
+        fresh.keys = self.keys;
+        core::mem::swap(&mut fresh.controller, &mut self.controller);
+        fresh.init_ram(power_on);
+        core::mem::swap(self, &mut fresh);
+    }
+
+    // Used while no input provider is set. Pad i is on port i % 2, line i / 2
     pub fn set_keys(&mut self, keys: [Vec<Key>; 4]) {
         for i in 0..4 {
-            let mut data = 0;
-            for key in keys[i].iter() {
-                match key {
-                    Key::B => data |= 1 << 15,
-                    Key::Y => data |= 1 << 14,
-                    Key::Select => data |= 1 << 13,
-                    Key::Start => data |= 1 << 12,
-                    Key::Up => data |= 1 << 11,
-                    Key::Down => data |= 1 << 10,
-                    Key::Left => data |= 1 << 9,
-                    Key::Right => data |= 1 << 8,
-                    Key::A => data |= 1 << 7,
-                    Key::X => data |= 1 << 6,
-                    Key::L => data |= 1 << 5,
-                    Key::R => data |= 1 << 4,
-                }
+            self.keys[i] = controller::joypad_data(&keys[i]);
+        }
+    }
+
+    pub fn set_input_provider(&mut self, provider: Option<Box<dyn InputProvider + Send>>) {
+        self.input_provider = provider;
+    }
+
+    pub fn set_serial_device(&mut self, port: usize, device: Option<Box<dyn SerialDevice + Send>>) {
+        if let Some(slot) = self.serial_devices.get_mut(port) {
+            *slot = device;
+        }
+    }
+
+    pub fn set_turbo(&mut self, pad: usize, key: Key, rate: u8) {
+        self.controller[pad % 2].set_turbo(pad / 2, key, rate);
+    }
+
+    // As joypad_data, after turbo
+    pub fn last_polled_input(&self, pad: usize) -> u16 {
+        self.controller[pad % 2].data[pad / 2]
+    }
+
+    pub fn hdma_channels(&self) -> Vec<HdmaChannel> {
+        self.dma
+            .iter()
+            .enumerate()
+            .map(|(ch, dma)| HdmaChannel {
+                enabled: self.hdma_enable & (1 << ch) != 0,
+                completed: dma.is_hdma_completed,
+                transfer_this_line: dma.is_hdma_active,
+                indirect: dma.dma_params.hdma_addr_mode() == HdmaAddrMode::Indirect,
+                transfer_unit: dma.dma_params.transfer_unit(),
+                target: 0x2100 | dma.b_bus_address as u16,
+                table_start: (dma.a_bus_bank as u32) << 16 | dma.a_bus_address as u32,
+                table_address: (dma.a_bus_bank as u32) << 16
+                    | dma.hdma_table_current_address as u32,
+                line_counter: dma.hdma_line_counter,
+                indirect_address: (dma.indirect_hdma_bank as u32) << 16
+                    | dma.number_of_bytes_to_transfer as u32,
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "instrumentation")]
+    pub fn input_events(&self) -> &[controller::InputEvent] {
+        &self.input_events
+    }
+
+    #[cfg(feature = "instrumentation")]
+    pub fn clear_input_events(&mut self) {
+        self.input_events.clear();
+    }
+
+    #[cfg(feature = "instrumentation")]
+    fn record_input_event(&mut self, kind: controller::InputEventKind, ctx: &impl Context) {
+        let (frame, x, y) = ctx.ppu_position();
+        self.input_events.push(controller::InputEvent { frame, x, y, kind });
+    }
+
+    fn latch_inputs(&mut self) {
+        for port in 0..2 {
+            if self.serial_devices[port].is_some() {
+                continue;
+            }
+            let device = match &self.input_provider {
+                Some(provider) => provider.device_type(port),
+                None => DeviceType::Joypad,
+            };
+            let mut data = [0; 2];
+            for (line, data) in data.iter_mut().enumerate() {
+                *data = match (device, &mut self.input_provider) {
+                    (DeviceType::None, _) => 0,
+                    (DeviceType::Joypad, Some(provider)) => provider.poll(port, line),
+                    (DeviceType::Joypad, None) => self.keys[line * 2 + port],
+                };
             }
-            self.controller[i % 2].data[i / 2] = data;
+            self.controller[port].device = device;
+            self.controller[port].latch(data);
+        }
+    }
+
+    pub fn take_error(&mut self) -> Option<SnesError> {
+        self.error.take()
+    }
+
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
+    }
+
+    pub fn wram_mut(&mut self) -> &mut [u8] {
+        &mut self.wram
+    }
+
+    fn write_wram(&mut self, offset: usize, data: u8) {
+        self.wram[offset] = data;
+        #[cfg(feature = "scripting")]
+        if self.wram_write_watch.get(offset / 64).is_some_and(|bits| bits >> (offset % 64) & 1 == 1) {
+            self.wram_writes.push((offset as u32, data));
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    pub fn watch_wram_write(&mut self, offset: usize) {
+        if self.wram_write_watch.is_empty() {
+            self.wram_write_watch = vec![0; self.wram.len() / 64];
+        }
+        self.wram_write_watch[offset / 64] |= 1 << (offset % 64);
+    }
+
+    // Writes to watched offsets since the last call, in order
+    #[cfg(feature = "scripting")]
+    pub fn take_wram_writes(&mut self) -> Vec<(u32, u8)> {
+        core::mem::take(&mut self.wram_writes)
+    }
+
+    // Total bytes moved by GDMA and HDMA since power on
+    pub fn dma_bytes(&self) -> u64 {
+        self.dma_bytes
+    }
+
+    // Memory speed map in master cycles, as seen by the CPU
+    fn access_cycle(&self, addr: u32) -> u64 {
+        let bank = (addr >> 16) as u8;
+        let offset = addr as u16;
+        match bank {
+            0x00..=0x3F | 0x80..=0xBF => match offset {
+                0x0000..=0x1FFF => CYCLE_SLOW, // WRAM
+                0x2000..=0x3FFF => CYCLE_FAST, // B-Bus
+                0x4000..=0x41FF => CYCLE_XSLOW, // Old style joypad ports
+                0x4200..=0x5FFF => CYCLE_FAST,
+                0x6000..=0x7FFF => CYCLE_SLOW,
+                0x8000..=0xFFFF if bank >= 0x80 => self.access_cycle_for_memory2, // $420D
+                0x8000..=0xFFFF => CYCLE_SLOW,
+            },
+            0x40..=0x7F => CYCLE_SLOW,
+            0xC0..=0xFF => self.access_cycle_for_memory2, // $420D
         }
     }
 
     pub fn read(&mut self, addr: u32, ctx: &mut impl Context) -> u8 {
         let bank = addr >> 16;
         let offset = addr as u16;
+        if !self.is_dma_active {
+            ctx.elapse_cpu(self.access_cycle(addr));
+        }
         let data = match bank {
             00..=0x3F | 0x80..=0xBF => match offset {
                 0x0000..=0x1FFF => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_SLOW);
-                    }
                     self.wram[offset as usize]
                 }
                 0x2000..=0x20FF => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
-                    warn!(
+                    trace!(
                         "Read unused region (open_bus): bank: {:X}, offset: {:X}",
                         bank, offset
                     );
+                    ctx.report(Diagnostic::OpenBusRead { addr });
+                    self.count_unimplemented(offset, false);
                     self.open_bus
                 }
                 0x2100..=0x213F => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
                     ctx.ppu_read(addr as u16, self.open_bus)
                 }
                 0x2140..=0x217F => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
                     let port = addr as u16 & 3;
                     let ret = ctx.spc_read(port);
                     debug!("SPC {} -> {:02X} @ {}", addr & 3, ret, ctx.now());
                     ret
                 }
                 0x2180 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
                     let data = self.wram[self.wram_addr as usize];
                     self.wram_addr = (self.wram_addr + 1) & 0x1FFFF;
                     data
                 }
+                0x2188..=0x219F if self.satellaview.is_some() => {
+                    let now = ctx.now();
+                    self.satellaview.as_mut().unwrap().read(offset, now)
+                }
                 0x2181..=0x3FFF => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
-                    warn!(
+                    trace!(
                         "Read unused region (open_bus): bank: {:X}, offset: {:X}",
                         bank, offset
                     );
+                    ctx.report(Diagnostic::OpenBusRead { addr });
+                    self.count_unimplemented(offset, false);
                     self.open_bus
                 }
                 0x4000..=0x4015 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
-                    warn!(
+                    trace!(
                         "Read unused region (open_bus): bank: {:X}, offset: {:X}",
                         bank, offset
                     );
+                    ctx.report(Diagnostic::OpenBusRead { addr });
+                    self.count_unimplemented(offset, false);
                     self.open_bus
                 }
                 0x4016 | 0x4017 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_JOYPAD);
-                    }
+                    #[cfg(feature = "instrumentation")]
+                    self.record_input_event(controller::InputEventKind::SerialRead(offset), ctx);
                     let index = (offset - 0x4016) as usize;
                     // let b0 = self.controller[index as usize].controller_read(4);
                     // let b1 = self.controller[index as usize].controller_read(5);
@@ -176,7 +496,10 @@ impl Bus {
                     // TODO open bus
                     // let data = b0 as u8 | (b1 as u8) << 1;
 
-                    let data = self.controller[index].read();
+                    let data = match &mut self.serial_devices[index] {
+                        Some(device) => device.clock() & 3,
+                        None => self.controller[index].read(),
+                    };
                     if index == 0 {
                         self.open_bus & 0xFC | data
                     } else {
@@ -184,35 +507,29 @@ impl Bus {
                     }
                 }
                 0x4018..=0x420F => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
-                    warn!(
+                    trace!(
                         "Read unused region (open_bus): bank: {:X}, offset: {:X}",
                         bank, offset
                     );
+                    ctx.report(Diagnostic::OpenBusRead { addr });
+                    self.count_unimplemented(offset, false);
                     self.open_bus
                 }
                 0x4210 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
+                    // The flags are set by the PPU, which otherwise only catches up after the
+                    // instruction
+                    ctx.ppu_tick();
                     let nmi_flag = ctx.get_nmi_flag();
-                    let cpu_version = 2;
-                    (nmi_flag as u8) << 7 | cpu_version | self.open_bus & 0x70
+                    (nmi_flag as u8) << 7 | self.cpu_version & 0x0F | self.open_bus & 0x70
                 }
 
                 0x4211 => {
-                    // TODO open bus
-                    let ret = (ctx.irq_occurred() as u8) << 7;
-                    ctx.set_irq(false);
+                    ctx.ppu_tick();
+                    let ret = (ctx.get_irq_flag() as u8) << 7;
                     ret | self.open_bus & 0x7F
                 }
 
                 0x4212 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
                     let mut ret = 0;
                     ret |= (ctx.now() < self.auto_joypad_read_busy) as u8;
                     ret |= (ctx.is_hblank() as u8) << 6;
@@ -220,117 +537,99 @@ impl Bus {
                     ret | self.open_bus & 0x3E
                 }
                 0x4213 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
+                    // Open collector outputs, so the written value reads back as long as no
+                    // connected device pulls a line low
+                    let mut ret = self.wrio;
+                    for (port, device) in self.serial_devices.iter().enumerate() {
+                        if device.as_ref().is_some_and(|device| !device.io_bit()) {
+                            ret &= !(0x40 << port);
+                        }
                     }
-                    // let b6 = self.controller[0].controller_read(6) as u8;
-                    // let b7 = self.controller[1].controller_read(6) as u8;
-                    // b6 << 6 | b7 << 7
-                    0b1100_0000
+                    ret
                 }
 
+                0x4214..=0x4217 if self.alu_busy() => {
+                    // The read sees the steps of the cycles before it
+                    self.alu_catch_up(ctx.counter().cpu_cycles().saturating_sub(1));
+                    let value = match offset {
+                        0x4214 | 0x4215 => self.div_result,
+                        _ => self.div_remainder_or_mul_product,
+                    };
+                    (value >> ((offset & 1) * 8)) as u8
+                }
                 0x4214 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
                     self.div_result as u8
                 }
                 0x4215 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
                     (self.div_result >> 8) as u8
                 }
                 0x4216 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
                     self.div_remainder_or_mul_product as u8
                 }
                 0x4217 => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
                     (self.div_remainder_or_mul_product >> 8) as u8
                 }
                 0x4218..=0x421F => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
+                    #[cfg(feature = "instrumentation")]
+                    self.record_input_event(controller::InputEventKind::AutoReadRegister(offset), ctx);
                     let index = (offset as usize - 0x4218) / 2;
                     let pos = (offset as usize - 0x4218) % 2;
                     (self.controller[index % 2].data[index / 2] >> (8 * pos)) as u8
                 }
                 0x4220..=0x42FF => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
-                    warn!(
+                    trace!(
                         "Read unused region (open_bus): bank: {:X}, offset: {:X}",
                         bank, offset
                     );
+                    ctx.report(Diagnostic::OpenBusRead { addr });
+                    self.count_unimplemented(offset, false);
                     self.open_bus
                 }
                 0x4300..=0x437F => {
                     let ch = ((offset >> 4) & 0x7) as usize;
                     let index = offset as u8 & 0xF;
-                    self.dma_read(ch, index)
+                    self.dma_read(ctx, ch, index)
                 }
                 0x4380..=0x5FFF => {
-                    if !self.is_dma_active {
-                        ctx.elapse(CYCLE_FAST);
-                    }
-                    warn!(
+                    trace!(
                         "Read unused region (open_bus): bank: {:X}, offset: {:X}",
                         bank, offset
                     );
+                    ctx.report(Diagnostic::OpenBusRead { addr });
+                    self.count_unimplemented(offset, false);
                     self.open_bus
                 }
                 0x6000..=0xFFFF => {
-                    if !self.is_dma_active {
-                        // if (0x80..=0xBF).contains(&bank) {
-                        //     ctx.elapse(self.access_cycle_for_memory2);
-                        // } else {
-                        //     ctx.elapse(CYCLE_SLOW);
-                        // }
-                        if bank & 0x80 == 0 {
-                            ctx.elapse(CYCLE_SLOW);
-                        } else {
-                            ctx.elapse(self.access_cycle_for_memory2);
-                        }
-                    }
-
                     ctx.cartridge_read(addr).unwrap_or(self.open_bus)
                 }
                 // TODO
                 // _ => unimplemented!("Read unimplemeted, bank: {:x}, offset: {:x}", bank, offset),
                 _ => {
                     debug!("Read unimplemeted, bank: {:x}, offset: {:x}", bank, offset);
+                    self.count_unimplemented(offset, false);
                     0
                 }
             },
             0x40..=0x7D => {
-                if !self.is_dma_active {
-                    ctx.elapse(CYCLE_SLOW);
-                }
                 ctx.cartridge_read(addr).unwrap_or(self.open_bus)
             }
             0x7E..=0x7F => {
-                if !self.is_dma_active {
-                    ctx.elapse(CYCLE_SLOW);
-                }
                 self.wram[(addr & 0x1FFFF) as usize]
             }
             0xC0..=0xFF => {
-                // TODO CYCLE FASTの場合は？
-                if !self.is_dma_active {
-                    ctx.elapse(self.access_cycle_for_memory2);
-                }
                 ctx.cartridge_read(addr).unwrap_or(self.open_bus)
             }
-            _ => unimplemented!(),
+            _ => {
+                warn!("Read from unmapped address: {:X}", addr);
+                ctx.report(Diagnostic::UnmappedRead { addr });
+                self.error = Some(SnesError::UnmappedAddress(addr));
+                self.open_bus
+            }
         };
         self.open_bus = data;
+        if self.access_hook.is_some() {
+            self.report_access(addr, data, false, ctx.now());
+        }
         debug!(
             "Bus read  bank: {:X}, addr: 0x{:X}, data: 0x{:X} ",
             bank, offset, data
@@ -339,7 +638,7 @@ impl Bus {
         data
     }
 
-    fn dma_read(&mut self, ch: usize, offset: u8) -> u8 {
+    fn dma_read(&mut self, ctx: &mut impl Context, ch: usize, offset: u8) -> u8 {
         match offset {
             0 => self.dma[ch].dma_params.bytes[0],
             1 => self.dma[ch].b_bus_address,
@@ -355,6 +654,9 @@ impl Bus {
             0xB | 0xF => self.dma[ch].unused,
             0xC..=0xE => {
                 warn!("Invalid DMA read offset: {}", offset);
+                ctx.report(Diagnostic::InvalidDmaRegister {
+                    addr: 0x4300 | (ch as u16) << 4 | offset as u16,
+                });
                 self.open_bus
             }
             _ => unreachable!(),
@@ -377,66 +679,59 @@ impl Bus {
         );
         debug!("Bus cpu_open_bus: 0x{:X}", self.open_bus);
 
+        if !self.is_dma_active {
+            ctx.elapse_cpu(self.access_cycle(addr));
+        }
+        if self.access_hook.is_some() {
+            self.report_access(addr, data, true, ctx.now());
+        }
         match bank {
             0x00..=0x3F | 0x80..=0xBF => {
                 match offset {
                     0x0000..=0x1FFF => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_SLOW);
-                        }
-                        self.wram[offset as usize] = data;
+                        self.write_wram(offset as usize, data);
                     }
                     0x2100..=0x213F => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         ctx.ppu_write(addr as u16, data);
                     }
                     0x2140..=0x217F => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         debug!("SPC {} <- {:02X} @ {}", addr & 3, data, ctx.now());
                         let port = addr as u16 & 3;
                         ctx.spc_write(port, data);
                     }
                     0x2180 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
-                        self.wram[self.wram_addr as usize] = data;
+                        self.write_wram(self.wram_addr as usize, data);
                         self.wram_addr = (self.wram_addr + 1) & 0x1FFFF;
                     }
                     0x2181 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.wram_addr = (self.wram_addr & 0x1FF00) | data as u32;
                     }
                     0x2182 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.wram_addr = (self.wram_addr & 0x100FF) | ((data as u32) << 8);
                     }
                     0x2183 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.wram_addr = (self.wram_addr & 0x0FFFF) | ((data as u32 & 1) << 16);
                     }
+                    0x2188..=0x219F => {
+                        if let Some(satellaview) = &mut self.satellaview {
+                            satellaview.write(offset, data);
+                        }
+                    }
                     0x4016 => {
                         // self.controller[0].controller_write(3, data & 1 != 0);
                         // self.controller[1].controller_write(3, data & 1 != 0);
+                        for device in self.serial_devices.iter_mut().flatten() {
+                            device.set_latch(data & 1 == 1);
+                        }
                         if data & 1 == 1 {
+                            #[cfg(feature = "instrumentation")]
+                            self.record_input_event(controller::InputEventKind::Latch, ctx);
+                            self.latch_inputs();
                             self.controller[0].initialize();
                             self.controller[1].initialize();
                         }
                     }
                     0x4200 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         let joypad_enable = data & 1 == 1;
                         let hv_irq_enable = (data >> 4) & 3;
                         let nmi_enable = (data >> 7) & 1 == 1;
@@ -448,151 +743,110 @@ impl Bus {
                     }
 
                     0x4201 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
+                        // Bit6: IOBit of port 1, Bit7: IOBit of port 2 (also the PPU H/V latch)
+                        ctx.ppu_set_wrio_latch(data & 0x80 != 0);
+                        self.wrio = data;
+                        for (port, device) in self.serial_devices.iter_mut().enumerate() {
+                            if let Some(device) = device {
+                                device.set_io_bit(data & (0x40 << port) != 0);
+                            }
                         }
-                        debug!("Unimplemented: 0x{:x} = 0x{:x}", addr, data);
-                        // self.controller[0].controller_write(6, data & (1 << 6) != 0);
-                        // self.controller[1].controller_write(6, data & (1 << 7) != 0);
                     }
 
                     0x4202 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.multiplicand = data;
                     }
                     0x4203 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
+                        self.alu_catch_up(ctx.counter().cpu_cycles());
+                        self.div_remainder_or_mul_product = 0;
+                        // Ignored while an operation is in flight, except for clearing $4216
+                        if !self.alu_busy() {
+                            self.multiplier = data;
+                            self.div_result = (data as u16) << 8 | self.multiplicand as u16;
+                            self.alu_shift = data as u32;
+                            self.alu_mul_steps = 8;
+                            if self.instant_mul_div {
+                                (0..8).for_each(|_| self.alu_step());
+                            }
                         }
-                        self.multiplier = data;
-                        // TODO Wait 8 clk cycles, then read the 16bit result from Port 4216h-4217h.
-                        self.div_remainder_or_mul_product =
-                            (self.multiplicand as u16) * (self.multiplier as u16);
-                        self.div_result = data as u16;
                     }
                     0x4204 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.divident = (self.divident & 0xFF00) | data as u16;
                     }
                     0x4205 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.divident = ((data as u16) << 8) | (self.divident & 0x00FF);
                     }
                     0x4206 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
-                        self.divisor = data;
-                        // TODO Wait 16 clk cycles, then read the 16bit result from Port 4214h-4215h.
-                        if self.divisor == 0 {
-                            self.div_result = 0xFFFF;
-                            self.div_remainder_or_mul_product = self.divident;
-                        } else {
-                            self.div_result = self.divident / self.divisor as u16;
-                            self.div_remainder_or_mul_product = self.divident % self.divisor as u16;
+                        self.alu_catch_up(ctx.counter().cpu_cycles());
+                        self.div_remainder_or_mul_product = self.divident;
+                        if !self.alu_busy() {
+                            // A divisor of 0 gives $FFFF with the dividend as the remainder
+                            self.divisor = data;
+                            self.alu_shift = (data as u32) << 16;
+                            self.alu_div_steps = 16;
+                            if self.instant_mul_div {
+                                (0..16).for_each(|_| self.alu_step());
+                            }
                         }
                     }
                     0x4207 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.h_count = (self.h_count & 0x0100) | data as u16;
                         ctx.set_h_count(self.h_count);
                     }
                     0x4208 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.h_count = (data as u16) << 8 | (self.h_count & 0x00FF);
                         ctx.set_h_count(self.h_count);
                     }
                     0x4209 => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.v_count = (self.v_count & 0x0100) | data as u16;
                         ctx.set_v_count(self.v_count);
                     }
                     0x420A => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.v_count = (data as u16) << 8 | (self.v_count & 0x00FF);
                         ctx.set_v_count(self.v_count);
                     }
                     0x420B => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         self.gdma_enable = data;
                         debug!("GDMA Enable: {data:08b} @ y = {}", ctx.counter().y);
                     }
                     0x420C => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
+                        // Channels switch on or off between lines: run an HDMA that is due
+                        // before this write with the old enable bits
+                        ctx.ppu_tick();
+                        self.hdma_reload_and_exec(ctx);
                         self.hdma_enable = data;
                         // debug!("HDMA enable: 0x{:x}", data);
                         debug!("HDMA Enable: {data:08b} @ y = {}", ctx.counter().y);
                     }
                     0x420D => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
-                        self.access_cycle_for_memory2 = if data & 1 == 1 { 6 } else { 8 };
+                        self.access_cycle_for_memory2 = if data & 1 == 1 { CYCLE_FAST } else { CYCLE_SLOW };
                     }
                     0x4300..=0x437F => {
-                        if !self.is_dma_active {
-                            ctx.elapse(CYCLE_FAST);
-                        }
                         let ch = ((offset >> 4) & 0xF) as usize;
                         let index = offset as usize & 0xF;
                         self.dma_write(ctx, ch, index, data);
                     }
                     0x6000..=0xFFFF => {
-                        if !self.is_dma_active {
-                            if (0x80..=0xBF).contains(&bank) {
-                                ctx.elapse(self.access_cycle_for_memory2);
-                            } else {
-                                ctx.elapse(CYCLE_SLOW);
-                            }
-                        }
-
                         ctx.cartridge_write(addr, data);
                     }
                     // _ => unimplemented!(),
                     _ => {
-                        ctx.elapse(CYCLE_SLOW);
                         debug!(
                             "Write unimplemeted, bank: 0x{:x}, offset: 0x{:x} = data: 0x{0:x}",
                             bank, offset
                         );
+                        self.count_unimplemented(offset, true);
                     }
                 }
             }
             0x40..=0x7D => {
-                if !self.is_dma_active {
-                    ctx.elapse(CYCLE_SLOW);
-                }
                 ctx.cartridge_write(addr, data);
             }
             0x7E..=0x7F => {
-                if !self.is_dma_active {
-                    ctx.elapse(CYCLE_SLOW);
-                }
-                self.wram[(addr & 0x1FFFF) as usize] = data;
+                self.write_wram((addr & 0x1FFFF) as usize, data);
                 debug!("Write WRAM: {addr:04X} = {data:02X}");
             }
             0xC0..=0xFF => {
-                if !self.is_dma_active {
-                    ctx.elapse(self.access_cycle_for_memory2);
-                }
                 ctx.cartridge_write(addr, data);
             }
             // _ => unimplemented!(),
@@ -632,11 +886,18 @@ impl Bus {
                     (self.dma[ch].hdma_table_current_address & 0x00FF) | (data as u16) << 8
             }
             0xa => self.dma[ch].hdma_line_counter = data,
-            0xb => self.dma[ch].unused = data,
-            _ => warn!("Invalid DMA index: {}", index),
+            0xb | 0xf => self.dma[ch].unused = data,
+            _ => {
+                warn!("Invalid DMA index: {}", index);
+                ctx.report(Diagnostic::InvalidDmaRegister {
+                    addr: 0x4300 | (ch as u16) << 4 | index as u16,
+                });
+            }
         }
     }
 
+    // Channels run lowest first, each to completion, with the CPU paused until all are done.
+    // HDMA still happens on its dots in between
     fn gdma_exec(&mut self, ctx: &mut impl Context) {
         if self.gdma_enable == 0 {
             return;
@@ -645,9 +906,20 @@ impl Bus {
         debug!("gdma_enable: {:08b}", self.gdma_enable);
         debug!("GDMA Exec: start: {}", ctx.now());
         self.is_dma_active = true;
-        // ctx.elapse(8 - ctx.now() % 8);
-        let ch = self.gdma_enable.trailing_zeros() as usize;
-        // ctx.elapse(8);
+        // Starts on the next 8 clock boundary after 8 clocks of setup
+        ctx.elapse(8 - ctx.now() % 8);
+        ctx.elapse(8);
+        while self.gdma_enable != 0 {
+            let ch = self.gdma_enable.trailing_zeros() as usize;
+            ctx.elapse(8);
+            self.gdma_channel(ctx, ch);
+        }
+        self.is_dma_active = false;
+
+        debug!("GDMA Exec: end: {}", ctx.now());
+    }
+
+    fn gdma_channel(&mut self, ctx: &mut impl Context, ch: usize) {
         let transfer_unit = self.dma[ch].transfer_unit();
         let a_step = match self.dma[ch].dma_params.a_bus_address_step() {
             AbusAddressStep::Increment => 1,
@@ -655,38 +927,6 @@ impl Bus {
             AbusAddressStep::Decrement => (-1 as i16) as u16,
             AbusAddressStep::Fixed3 => 0,
         };
-        for i in 0..transfer_unit.len() {
-            ctx.elapse(8);
-            let a_bus = (self.dma[ch].a_bus_bank as u32) << 16 | self.dma[ch].a_bus_address as u32;
-            let b_bus = 0x2100 | self.dma[ch].b_bus_address.wrapping_add(transfer_unit[i]) as u32;
-
-            match self.dma[ch].dma_params.transfer_direction() {
-                TransferDirection::AtoB => {
-                    let data = self.read(a_bus, ctx);
-                    debug!("interval in read and write: {}", ctx.now());
-                    self.write(b_bus, data, ctx);
-                    debug!("after write: {}", ctx.now());
-                }
-                TransferDirection::BtoA => {
-                    let data = self.read(b_bus, ctx);
-                    debug!("interval in read and write: {}", ctx.now());
-                    self.write(a_bus, data, ctx);
-                    debug!("after write: {}", ctx.now());
-                }
-            }
-            debug!("now: {}", ctx.now());
-
-            self.dma[ch].a_bus_address = self.dma[ch].a_bus_address.wrapping_add(a_step);
-            self.dma[ch].number_of_bytes_to_transfer =
-                self.dma[ch].number_of_bytes_to_transfer.wrapping_sub(1);
-
-            if self.dma[ch].number_of_bytes_to_transfer == 0 {
-                self.gdma_enable &= !(1 << ch);
-                ctx.elapse(16);
-                break;
-            }
-            debug!("a_bus: {:06X}, b_bus: {:06X}", a_bus, b_bus);
-        }
         debug!(
             "GDMA[{ch}]: {:02X}:{:04X} {} 21{:02X}, trans: {:?}, count: {}, now: {}",
             self.dma[ch].a_bus_bank,
@@ -705,14 +945,71 @@ impl Bus {
             ctx.now()
         );
 
-        // ctx.elapse(16);
-        self.is_dma_active = false;
+        for &offset in transfer_unit.iter().cycle() {
+            ctx.elapse(8);
+            let a_bus = (self.dma[ch].a_bus_bank as u32) << 16 | self.dma[ch].a_bus_address as u32;
+            let b_bus = self.dma[ch].b_bus_address.wrapping_add(offset);
 
-        debug!("GDMA Exec: end: {}", ctx.now());
+            let direction = self.dma[ch].dma_params.transfer_direction();
+            self.dma_transfer(ctx, a_bus, b_bus, direction);
+
+            self.dma_bytes += 1;
+            self.dma[ch].a_bus_address = self.dma[ch].a_bus_address.wrapping_add(a_step);
+            self.dma[ch].number_of_bytes_to_transfer =
+                self.dma[ch].number_of_bytes_to_transfer.wrapping_sub(1);
+
+            if self.dma[ch].number_of_bytes_to_transfer == 0 {
+                self.gdma_enable &= !(1 << ch);
+                break;
+            }
+
+            // HDMA on the same channel cancels the rest of the transfer
+            ctx.ppu_tick();
+            self.hdma_reload_and_exec(ctx);
+            if self.gdma_enable >> ch & 1 == 0 {
+                debug!("GDMA[{ch}]: Stopped by HDMA");
+                break;
+            }
+        }
+    }
+
+    // Move one byte between the A-bus and $2100 + `b_bus`, wrapping within $21xx.
+    // The A-bus can't reach the B-bus or the CPU's own registers, and WRAM can't be both the
+    // source and destination through $2180. Those reads give open bus and writes are dropped
+    fn dma_transfer(
+        &mut self,
+        ctx: &mut impl Context,
+        a_bus: u32,
+        b_bus: u8,
+        direction: TransferDirection,
+    ) -> u8 {
+        let b_bus = 0x2100 | b_bus as u32;
+        let a_valid = is_dma_a_bus_valid(a_bus);
+        let b_valid = b_bus != 0x2180 || !is_wram(a_bus);
+
+        match direction {
+            TransferDirection::AtoB => {
+                let data = if a_valid { self.read(a_bus, ctx) } else { self.open_bus };
+                if b_valid {
+                    self.write(b_bus, data, ctx);
+                }
+                data
+            }
+            TransferDirection::BtoA => {
+                let data = if b_valid { self.read(b_bus, ctx) } else { self.open_bus };
+                if a_valid {
+                    self.write(a_bus, data, ctx);
+                }
+                data
+            }
+        }
     }
 
     fn hdma_reload_and_exec(&mut self, ctx: &mut impl Context) {
+        // Also runs in the middle of a GDMA
+        let is_dma_active = self.is_dma_active;
         self.is_dma_active = true;
+        self.in_hdma = true;
         if ctx.is_hdma_reload_triggered() {
             debug!(
                 "HDMA Reload, frame:x:y = {}:{}:{}, HDMA enable: {:08b}",
@@ -736,10 +1033,11 @@ impl Bus {
             }
         }
 
-        if ctx.is_hdma_transfer_triggered()
-            && self.hdma_enable != 0
-            && self.dma.iter().any(|d| !d.is_hdma_completed)
-        {
+        // A channel enabled mid-frame without the line 0 init runs from whatever is in
+        // $43x8-$43xA. One that reached the end of its table stays done until the next init,
+        // even if it is disabled and enabled again
+        let running = self.hdma_running();
+        if ctx.is_hdma_transfer_triggered() && running != 0 {
             debug!(
                 "HDMA Transfer, frame:x:y = {}:{}:{}, now = {}",
                 ctx.counter().frame,
@@ -749,29 +1047,36 @@ impl Bus {
             );
             ctx.elapse(18);
             for ch in 0..8 {
-                if self.hdma_enable >> ch & 1 == 1 {
+                if running >> ch & 1 == 1 {
                     self.hdma_exec(ctx, ch);
                 }
             }
         }
-        self.is_dma_active = false;
+        self.is_dma_active = is_dma_active;
+        self.in_hdma = false;
     }
 
-    fn hdma_reload(&mut self, ctx: &mut impl Context, ch: usize) {
-        // TODO Cancel GDMA channle that is using the same channel
+    fn hdma_running(&self) -> u8 {
+        (0..8)
+            .filter(|&ch| !self.dma[ch].is_hdma_completed)
+            .fold(0, |mask, ch| mask | 1 << ch)
+            & self.hdma_enable
+    }
 
+    fn hdma_reload(&mut self, ctx: &mut impl Context, ch: usize) {
+        self.gdma_enable &= !(1 << ch);
         debug!("HDMA{ch} Init: param = {:?}", self.dma[ch].dma_params);
         self.dma[ch].hdma_table_current_address = self.dma[ch].a_bus_address;
 
         let addr = self.dma[ch].hdma_direct_address(1);
         let data = self.read(addr, ctx);
+        self.dma[ch].hdma_line_counter = data;
 
         if data == 0 {
             info!("HDMA{ch}: Empty table");
             self.dma[ch].is_hdma_completed = true;
             return;
         }
-        self.dma[ch].hdma_line_counter = data;
 
         if self.dma[ch].dma_params.hdma_addr_mode() == HdmaAddrMode::Indirect {
             let addr = self.dma[ch].hdma_direct_address(2);
@@ -800,6 +1105,7 @@ impl Bus {
             ctx.counter().x
         );
         debug!("HDMA info: {:?}", self.dma[ch]);
+        self.gdma_enable &= !(1 << ch);
         if self.dma[ch].is_hdma_active {
             debug!(
                 "HDMA {ch}: Do trans {} bytes",
@@ -810,20 +1116,12 @@ impl Bus {
                     HdmaAddrMode::Direct => self.dma[ch].hdma_direct_address(1),
                     HdmaAddrMode::Indirect => self.dma[ch].hdma_indirect_address(1),
                 };
-                let b_bus_addr = 0x2100 | self.dma[ch].b_bus_address.wrapping_add(offset) as u32;
+                let b_bus_addr = self.dma[ch].b_bus_address.wrapping_add(offset);
 
-                match self.dma[ch].dma_params.transfer_direction() {
-                    TransferDirection::AtoB => {
-                        let data = self.read(a_bus_addr, ctx);
-                        self.write(b_bus_addr, data, ctx);
-                        debug!("HDMA: {a_bus_addr:06X} -> {b_bus_addr:04X} = {data:02X}");
-                    }
-                    TransferDirection::BtoA => {
-                        let data = self.read(b_bus_addr, ctx);
-                        self.write(a_bus_addr, data, ctx);
-                        debug!("HDMA: {b_bus_addr:06X} -> {a_bus_addr:04X} = {data:02X}");
-                    }
-                }
+                let direction = self.dma[ch].dma_params.transfer_direction();
+                let data = self.dma_transfer(ctx, a_bus_addr, b_bus_addr, direction);
+                debug!("HDMA: {a_bus_addr:06X} <-> 21{b_bus_addr:02X} = {data:02X}");
+                self.dma_bytes += 1;
                 ctx.elapse(8);
             }
         }
@@ -873,7 +1171,20 @@ impl Bus {
     }
 
     fn auto_joypad_read(&mut self) {
+        self.latch_inputs();
         for port in 0..2 {
+            if let Some(device) = &mut self.serial_devices[port] {
+                device.set_latch(true);
+                device.set_latch(false);
+                let mut data = [0; 2];
+                for _ in 0..16 {
+                    let bits = device.clock();
+                    data[0] = data[0] << 1 | (bits & 1) as u16;
+                    data[1] = data[1] << 1 | (bits >> 1 & 1) as u16;
+                }
+                self.controller[port].data = data;
+                continue;
+            }
             // self.controller[port].controller_write(3, true);
             self.controller[port].initialize();
             for _ in 0..16 {
@@ -886,6 +1197,8 @@ impl Bus {
 
     pub fn tick(&mut self, ctx: &mut impl Context) {
         if ctx.is_auto_joypad_read() && self.joypad_enable {
+            #[cfg(feature = "instrumentation")]
+            self.record_input_event(controller::InputEventKind::Latch, ctx);
             self.auto_joypad_read_busy = ctx.now() + 4224;
             self.auto_joypad_read();
         }
@@ -894,7 +1207,20 @@ impl Bus {
     }
 }
 
+fn is_wram(addr: u32) -> bool {
+    addr & 0xFE0000 == 0x7E0000 || addr & 0x40E000 == 0
+}
+
+// $2100-$21FF, $4000-$41FF, $4200-$421F and $4300-$437F in banks $00-$3F/$80-$BF
+fn is_dma_a_bus_valid(addr: u32) -> bool {
+    !(addr & 0x40FF00 == 0x2100
+        || addr & 0x40FE00 == 0x4000
+        || addr & 0x40FFE0 == 0x4200
+        || addr & 0x40FF80 == 0x4300)
+}
+
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Dma {
     dma_params: DmaParams,            // 0x43x0
     b_bus_address: u8,                // 0x43x1
@@ -939,8 +1265,34 @@ impl Dma {
     }
 }
 
+// HDMA registers of one channel as the frame has left them so far, for debugging raster
+// effects
+#[derive(Debug, Clone, Copy)]
+pub struct HdmaChannel {
+    // $420C
+    pub enabled: bool,
+    // The table ended for this frame
+    pub completed: bool,
+    // Bit 7 of the line counter or a new entry, data goes out at the next HBlank
+    pub transfer_this_line: bool,
+    pub indirect: bool,
+    // $43x0 bits 0-2
+    pub transfer_unit: u8,
+    // B bus register written, $2100-$21FF
+    pub target: u16,
+    // $43x2-$43x4, reloaded at the start of each frame
+    pub table_start: u32,
+    // $43x8-$43x9 in the bank of $43x4
+    pub table_address: u32,
+    // $43xA
+    pub line_counter: u8,
+    // $43x5-$43x7, only meaningful with `indirect`
+    pub indirect_address: u32,
+}
+
 #[bitfield(bits = 8)]
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct DmaParams {
     transfer_unit: B3,
     a_bus_address_step: AbusAddressStep,
@@ -972,3 +1324,138 @@ enum TransferDirection {
     AtoB = 0,
     BtoA = 1,
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::context::{Bus as _, Timing as _};
+    use std::sync::{Arc, Mutex};
+
+    const WMADD: u32 = 0x2000;
+
+    // A console with WRAM $1000 = $11, WRAM $2000 = $22 and WMADD at $2000
+    fn console() -> context::Context {
+        let mut ctx = context::Context::for_test();
+        let wram = ctx.inner1.wram_mut();
+        wram[0x1000] = 0x11;
+        wram[WMADD as usize] = 0x22;
+        for (i, byte) in WMADD.to_le_bytes()[..3].iter().enumerate() {
+            ctx.inner1.bus_write(0x2181 + i as u32, *byte);
+        }
+        ctx
+    }
+
+    // Channel 0 with `params` ($43x0) between `a_bus` and $2100 + `b_bus`. The $420B write
+    // leaves $01 on the open bus
+    fn run_dma(ctx: &mut context::Context, params: u8, a_bus: u32, b_bus: u8, len: u16) {
+        let bus = &mut ctx.inner1;
+        let [a_lo, a_hi, a_bank, _] = a_bus.to_le_bytes();
+        let [len_lo, len_hi] = len.to_le_bytes();
+        let registers = [params, b_bus, a_lo, a_hi, a_bank, len_lo, len_hi];
+        for (offset, data) in registers.into_iter().enumerate() {
+            bus.bus_write(0x4300 + offset as u32, data);
+        }
+        bus.bus_write(0x420B, 0x01);
+        bus.bus_tick();
+    }
+
+    #[test]
+    fn wram_to_wram_port_drops_the_write() {
+        for a_bus in [0x7E1000, 0x001000, 0xBF1000] {
+            let mut ctx = console();
+            run_dma(&mut ctx, 0x00, a_bus, 0x80, 1);
+            let wram = ctx.inner1.wram();
+            assert_eq!(wram[WMADD as usize], 0x22, "from {:06X}", a_bus);
+        }
+    }
+
+    #[test]
+    fn wram_port_to_wram_reads_open_bus() {
+        let mut ctx = console();
+        run_dma(&mut ctx, 0x80, 0x7E1000, 0x80, 1);
+        assert_eq!(ctx.inner1.wram()[0x1000], 0x01);
+    }
+
+    #[test]
+    fn invalid_a_bus_ranges() {
+        let invalid = [
+            0x002100, 0x0021FF, 0x004000, 0x0041FF, 0x004200, 0x00421F, 0x004300, 0x00437F,
+            0x802100, 0xBF437F,
+        ];
+        let valid = [
+            0x0020FF, 0x002200, 0x003FFF, 0x004220, 0x004380, 0x402100, 0x7E2100,
+        ];
+        for addr in invalid {
+            assert!(!is_dma_a_bus_valid(addr), "{:06X}", addr);
+        }
+        for addr in valid {
+            assert!(is_dma_a_bus_valid(addr), "{:06X}", addr);
+        }
+
+        // Reads give open bus instead of the register, while the ROM behind is readable
+        for a_bus in invalid {
+            let mut ctx = console();
+            run_dma(&mut ctx, 0x00, a_bus, 0x80, 1);
+            let wram = ctx.inner1.wram();
+            assert_eq!(wram[WMADD as usize], 0x01, "from {:06X}", a_bus);
+        }
+        let mut ctx = console();
+        run_dma(&mut ctx, 0x00, 0x008000, 0x80, 1);
+        assert_eq!(ctx.inner1.wram()[WMADD as usize], 0x80);
+    }
+
+    #[test]
+    fn b_bus_wraps_within_21xx() {
+        let mut ctx = console();
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let log = writes.clone();
+        let hook = move |access: MemoryAccess| {
+            if access.source == AccessSource::Dma && access.write {
+                log.lock().unwrap().push(access.addr);
+            }
+        };
+        let filter = AccessFilter::new(Box::new(hook), &[0x2100..=0x21FF]);
+        ctx.inner1.set_access_hook(Some(filter));
+
+        // Transfer unit 1 writes to B and B + 1
+        run_dma(&mut ctx, 0x01, 0x7E1000, 0xFF, 2);
+        assert_eq!(*writes.lock().unwrap(), [0x21FF, 0x2100]);
+    }
+
+    // Master clocks of a read at `addr`, with and without FastROM ($420D)
+    #[rustfmt::skip]
+    const SPEEDS: [(u32, u64, u64); 22] = [
+        (0x000000, 8, 8),   (0x001FFF, 8, 8),   // WRAM
+        (0x002100, 6, 6),   (0x003FFF, 6, 6),   // B-Bus
+        (0x004016, 12, 12), (0x0041FF, 12, 12), // Joypad ports
+        (0x004200, 6, 6),   (0x005FFF, 6, 6),
+        (0x006000, 8, 8),   (0x007FFF, 8, 8),
+        (0x008000, 8, 8),   (0x3FFFFF, 8, 8),
+        (0x400000, 8, 8),   (0x7FFFFF, 8, 8),
+        (0x800000, 8, 8),   (0x804016, 12, 12), (0xBF6000, 8, 8),
+        (0x808000, 8, 6),   (0xBFFFFF, 8, 6),
+        (0xC00000, 8, 6),   (0xFFFFFF, 8, 6),
+        (0x7E0000, 8, 8),
+    ];
+
+    fn read_cycles(ctx: &mut context::Context, addr: u32) -> u64 {
+        let start = ctx.inner1.now();
+        ctx.inner1.bus_read(addr);
+        ctx.inner1.now() - start
+    }
+
+    #[test]
+    fn access_cycles() {
+        let mut ctx = console();
+        for (memsel, fast) in [(0x00, false), (0x01, true), (0x00, false)] {
+            ctx.inner1.bus_write(0x420D, memsel);
+            for (addr, slow_rom, fast_rom) in SPEEDS {
+                let expected = if fast { fast_rom } else { slow_rom };
+                let cycles = read_cycles(&mut ctx, addr);
+                assert_eq!(cycles, expected, "{:06X}, $420D = {}", addr, memsel);
+            }
+        }
+    }
+}
@@ -1,145 +1,279 @@
 use log::{info, warn};
 
+use crate::diagnostics::VideoStandard;
+use crate::error::SnesError;
+use crate::firmware::Chip;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+// ROM image shared with the frontend, e.g. a Vec, an Arc<[u8]> or a memory mapped file
+pub type RomData = Box<dyn AsRef<[u8]> + Send + Sync>;
+
+// Board of a cartridge, decoding CPU addresses into ROM, SRAM and coprocessor registers.
+// Built in boards are picked from the ROM header, others can be passed to Snes::with_mapper
+pub trait Mapper: Send {
+    // `addr` is in $00-$3F/$80-$BF:$6000-$FFFF, $40-$7D or $C0-$FF. None is open bus
+    fn read(&mut self, addr: u32) -> Option<u8>;
+    fn write(&mut self, addr: u32, data: u8);
+    // Battery backed RAM, empty if the board has none. Savestates contain only this
+    fn backup(&self) -> &[u8];
+    fn backup_mut(&mut self) -> &mut [u8];
+    // Master clock after every CPU instruction and before accesses to `is_shared` addresses,
+    // for boards with chips of their own, see ClockDomain
+    fn tick(&mut self, _now: u64) {}
+    // Addresses the board's chips work on too, e.g. their registers or shared RAM
+    fn is_shared(&self, _addr: u32) -> bool {
+        false
+    }
+    // The console was power cycled. Backup RAM has to stay
+    fn power_cycle(&mut self) {}
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cartridge {
-    rom: Rom,
-    sram: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(rename = "sram", with = "backup_state"))]
+    mapper: Box<dyn Mapper>,
+    // Not part of savestates, the loaded ROM is kept
+    #[cfg_attr(feature = "serde", serde(skip))]
+    country: u8,
+    // Coprocessor needing firmware from the user, and what the user gave
+    #[cfg_attr(feature = "serde", serde(skip))]
+    firmware_chip: Option<Chip>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    firmware: BTreeMap<Chip, Vec<u8>>,
 }
 
 impl Cartridge {
-    pub fn new(rom: Vec<u8>, backup: Option<Vec<u8>>) -> Cartridge {
-        let rom = Rom::from_bytes(&rom).expect("Failed to parse ROM");
+    pub fn new(rom: RomData, backup: Option<Vec<u8>>) -> Result<Cartridge, SnesError> {
+        let Rom { header, rom } = Rom::new(rom).map_err(SnesError::InvalidRom)?;
         let sram = if let Some(backup) = backup {
             backup
         } else {
-            vec![0; rom.header.ram_size * 1024]
+            vec![0; header.ram_size * 1024]
         };
-        // let sram = vec![0; rom.header.ram_size * 1024];
-        Cartridge { rom, sram }
+        let mapper: Box<dyn Mapper> = match header.map_mode {
+            MapMode::LoRom => Box::new(LoRom { rom, sram }),
+            MapMode::HiRom => Box::new(HiRom { rom, sram }),
+            MapMode::ExHiRom => Box::new(ExHiRom { rom, sram }),
+            // The coprocessors of these boards are not emulated
+            map_mode => Box::new(UnsupportedBoard { map_mode, sram }),
+        };
+        Ok(Cartridge {
+            mapper,
+            country: header.country,
+            firmware_chip: header.firmware_chip,
+            firmware: BTreeMap::new(),
+        })
+    }
+
+    // `country` is the header code deciding the video standard, see VideoStandard::from_country
+    pub fn with_mapper(mapper: Box<dyn Mapper>, country: u8) -> Cartridge {
+        Cartridge {
+            mapper,
+            country,
+            firmware_chip: None,
+            firmware: BTreeMap::new(),
+        }
+    }
+
+    // Exchange what a savestate doesn't contain. `other` gets this board, with the backup
+    // RAM from the state
+    #[cfg(feature = "serde")]
+    pub fn swap_host_state(&mut self, other: &mut Cartridge) {
+        core::mem::swap(&mut self.mapper, &mut other.mapper);
+        core::mem::swap(&mut self.country, &mut other.country);
+        core::mem::swap(&mut self.firmware_chip, &mut other.firmware_chip);
+        core::mem::swap(&mut self.firmware, &mut other.firmware);
+        let saved = self.mapper.backup();
+        let backup = other.mapper.backup_mut();
+        let len = saved.len().min(backup.len());
+        backup[..len].copy_from_slice(&saved[..len]);
     }
 }
 
 impl Cartridge {
-    pub fn read(&self, addr: u32) -> Option<u8> {
-        match self.rom.header.map_mode {
-            MapMode::LoRom => {
-                let bank = (addr >> 16) as usize;
-                let offset = (addr & 0xFFFF) as usize;
-                match bank {
-                    0x00..=0x7D => self.read(addr + 0x800000),
-                    0x7E..=0x7F => {
-                        warn!(
-                            "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
-                            bank, offset
-                        );
-                        None
-                    }
-                    0x80..=0xFF => match offset {
-                        0x0000..=0x7FFF => match bank {
-                            0x80..=0xBF => {
-                                warn!(
-                                    "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
-                                    bank, offset
-                                );
-                                None
-                            }
-                            0xC0..=0xEF => self.read(addr + 0x8000),
-                            0xF0..=0xFF => {
-                                let sram_offset = (bank - 0xF0) * 1024 * 32 + offset;
-                                let sram_index = sram_offset % self.sram.len();
-                                Some(self.sram[sram_index])
-                            }
-                            _ => {
-                                warn!(
-                                    "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
-                                    bank, offset
-                                );
-                                None
-                            }
-                        },
-                        0x8000..=0xFFFF => {
-                            let rom_offset = (bank - 0x80) * 1024 * 32 + (offset - 0x8000);
-                            let rom_index = rom_offset % self.rom.rom.len();
-                            Some(self.rom.rom[rom_index])
-                        }
-                        _ => {
-                            warn!(
-                                "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
-                                bank, offset
-                            );
-                            None
-                        }
-                    },
+    pub fn read(&mut self, addr: u32, now: u64) -> Option<u8> {
+        self.sync(addr, now);
+        self.mapper.read(addr)
+    }
 
-                    _ => {
+    pub fn write(&mut self, addr: u32, data: u8, now: u64) {
+        self.sync(addr, now);
+        self.mapper.write(addr, data)
+    }
+
+    // The chips have to be where the CPU is before it sees what they work on
+    fn sync(&mut self, addr: u32, now: u64) {
+        if self.mapper.is_shared(addr) {
+            self.mapper.tick(now);
+        }
+    }
+
+    pub fn tick(&mut self, now: u64) {
+        self.mapper.tick(now)
+    }
+
+    pub fn power_cycle(&mut self) {
+        self.mapper.power_cycle()
+    }
+
+    pub fn firmware_chip(&self) -> Option<Chip> {
+        self.firmware_chip
+    }
+
+    // Kept for the chip's emulation, which none of the chips has yet
+    pub fn add_firmware(&mut self, chip: Chip, firmware: Vec<u8>) {
+        self.firmware.insert(chip, firmware);
+    }
+
+    pub fn has_firmware(&self, chip: Chip) -> bool {
+        self.firmware.contains_key(&chip)
+    }
+
+    pub fn video_standard(&self) -> VideoStandard {
+        VideoStandard::from_country(self.country)
+    }
+
+    pub fn sram_mut(&mut self) -> &mut [u8] {
+        self.mapper.backup_mut()
+    }
+
+    pub fn backup(&self) -> Option<Vec<u8>> {
+        let backup = self.mapper.backup();
+        if backup.is_empty() {
+            None
+        } else {
+            Some(backup.to_vec())
+        }
+    }
+}
+
+// Savestates keep the backup RAM in place of the board. A deserialized Cartridge holds it
+// until swap_host_state moves it into the running board
+#[cfg(feature = "serde")]
+mod backup_state {
+    use super::{DetachedBackup, Mapper};
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    #[allow(clippy::borrowed_box)]
+    pub fn serialize<S: serde::Serializer>(
+        mapper: &Box<dyn Mapper>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(mapper.backup(), serializer)
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Box<dyn Mapper>, D::Error> {
+        let backup: Vec<u8> = serde::Deserialize::deserialize(deserializer)?;
+        Ok(Box::new(DetachedBackup { backup }))
+    }
+}
+
+#[cfg(feature = "serde")]
+struct DetachedBackup {
+    backup: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl Mapper for DetachedBackup {
+    fn read(&mut self, _addr: u32) -> Option<u8> {
+        None
+    }
+
+    fn write(&mut self, _addr: u32, _data: u8) {}
+
+    fn backup(&self) -> &[u8] {
+        &self.backup
+    }
+
+    fn backup_mut(&mut self) -> &mut [u8] {
+        &mut self.backup
+    }
+}
+
+// ROM sizes that aren't a power of two repeat their last part: a 2.5MB ROM is the first
+// 2MB followed by the last 512KB four times, filling 4MB
+fn mirror(mut offset: usize, mut size: usize) -> usize {
+    let mut base = 0;
+    let mut mask = 1 << 23;
+    while offset >= size {
+        while offset & mask == 0 {
+            mask >>= 1;
+        }
+        offset -= mask;
+        if size > mask {
+            size -= mask;
+            base += mask;
+        }
+        mask >>= 1;
+    }
+    base + offset
+}
+
+// SRAM repeats every `sram.len()` bytes over the range it is mapped to, which is the size
+// from the header unless a save of another size was loaded. None without SRAM
+fn sram_index(sram: &[u8], offset: usize) -> Option<usize> {
+    if sram.is_empty() {
+        None
+    } else {
+        Some(offset % sram.len())
+    }
+}
+
+// ROM in the upper half of banks $00-$7D/$80-$FF, SRAM in $70-$7D/$F0-$FF
+pub(crate) struct LoRom {
+    rom: RomData,
+    sram: Vec<u8>,
+}
+
+impl LoRom {
+    // Board without SRAM for a ROM that goes into another board, e.g. the SGB BIOS.
+    // Returns the header's country code along with it
+    pub(crate) fn without_sram(rom: RomData) -> Result<(LoRom, u8), SnesError> {
+        let Rom { header, rom } = Rom::new(rom).map_err(SnesError::InvalidRom)?;
+        let sram = Vec::new();
+        Ok((LoRom { rom, sram }, header.country))
+    }
+
+    fn rom(&self) -> &[u8] {
+        (*self.rom).as_ref()
+    }
+}
+
+impl Mapper for LoRom {
+    fn read(&mut self, addr: u32) -> Option<u8> {
+        let bank = (addr >> 16) as usize;
+        let offset = (addr & 0xFFFF) as usize;
+        match bank {
+            0x00..=0x7D => self.read(addr + 0x800000),
+            0x7E..=0x7F => {
+                warn!(
+                    "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                    bank, offset
+                );
+                None
+            }
+            0x80..=0xFF => match offset {
+                0x0000..=0x7FFF => match bank {
+                    0x80..=0xBF => {
                         warn!(
                             "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
                             bank, offset
                         );
                         None
                     }
-                }
-            }
-            MapMode::HiRom => {
-                let bank = (addr >> 16) as usize;
-                let offset = (addr & 0xFFFF) as usize;
-                match bank {
-                    0x00..=0x3F => match offset {
-                        0x0000..=0x5FFF => {
-                            warn!(
-                                "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
-                                bank, offset
-                            );
-                            None
-                        }
-                        0x6000..=0x7FFF => {
-                            let sram_offset = bank * 1024 * 8 + (offset - 0x6000);
-                            let sram_index = sram_offset % self.sram.len();
-                            Some(self.sram[sram_index])
-                        }
-                        0x8000..=0xFFFF => {
-                            let rom_index = (addr as usize) % self.rom.rom.len();
-                            Some(self.rom.rom[rom_index])
-                        }
-                        _ => {
-                            warn!(
-                                "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
-                                bank, offset
-                            );
-                            None
-                        }
-                    },
-                    0x40..=0x7D => {
-                        let rom_index = (addr as usize - 0x400000) % self.rom.rom.len();
-                        Some(self.rom.rom[rom_index])
-                    }
-                    0x80..=0xBF => match offset {
-                        0x0000..=0x5FFF => {
-                            warn!(
-                                "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
-                                bank, offset
-                            );
-                            None
-                        }
-                        0x6000..=0x7FFF => {
-                            let sram_offset = (bank - 0x80) * 1024 * 8 + (offset - 0x6000);
-                            let sram_index = sram_offset % self.sram.len();
-                            Some(self.sram[sram_index])
-                        }
-                        0x8000..=0xFFFF => {
-                            let rom_index = (addr as usize - 0x800000) % self.rom.rom.len();
-                            Some(self.rom.rom[rom_index])
-                        }
-                        _ => {
-                            warn!(
-                                "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
-                                bank, offset
-                            );
-                            None
-                        }
-                    },
-                    0xC0..=0xFF => {
-                        let rom_index = (addr as usize - 0xC00000) % self.rom.rom.len();
-                        Some(self.rom.rom[rom_index])
+                    0xC0..=0xEF => self.read(addr + 0x8000),
+                    0xF0..=0xFF => {
+                        let sram_offset = (bank - 0xF0) * 1024 * 32 + offset;
+                        let sram_index = sram_index(&self.sram, sram_offset)?;
+                        Some(self.sram[sram_index])
                     }
                     _ => {
                         warn!(
@@ -148,111 +282,308 @@ impl Cartridge {
                         );
                         None
                     }
+                },
+                0x8000..=0xFFFF => {
+                    let rom_offset = (bank - 0x80) * 1024 * 32 + (offset - 0x8000);
+                    let rom_index = mirror(rom_offset, self.rom().len());
+                    Some(self.rom()[rom_index])
                 }
-            }
+                _ => {
+                    warn!(
+                        "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                        bank, offset
+                    );
+                    None
+                }
+            },
+
             _ => {
-                warn!("Unsupported map mode: {:?}", self.rom.header.map_mode);
+                warn!(
+                    "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                    bank, offset
+                );
                 None
             }
         }
     }
 
-    pub fn write(&mut self, addr: u32, data: u8) {
-        match self.rom.header.map_mode {
-            MapMode::LoRom => {
-                let bank = (addr >> 16) as usize;
-                let offset = (addr & 0xFFFF) as usize;
-                match bank {
-                    0x00..=0x7D => self.write(addr + 0x800000, data),
-                    0x7E..=0x7F => unreachable!(),
-                    0x80..=0xFF => match offset {
-                        0x0000..=0x7FFF => match bank {
-                            0x80..=0xBF => {
-                                // unreachable!("Invalid bank: {:02X}, offset: {:04X}", bank, offset)
-                            }
-                            0xC0..=0xEF => self.write(addr + 0x8000, data),
-                            0xF0..=0xFF => {
-                                let sram_offset = (bank - 0xF0) * 1024 * 32 + offset;
-                                let sram_index = sram_offset % self.sram.len();
-                                self.sram[sram_index] = data;
-                            }
-                            _ => unreachable!(),
-                        },
-                        0x8000..=0xFFFF => {
-                            let rom_offset = (bank - 0x80) * 1024 * 32 + (offset - 0x8000);
-                            let rom_index = rom_offset % self.rom.rom.len();
-                            self.rom.rom[rom_index] = data;
+    fn write(&mut self, addr: u32, data: u8) {
+        let bank = (addr >> 16) as usize;
+        let offset = (addr & 0xFFFF) as usize;
+        match bank {
+            0x00..=0x7D => self.write(addr + 0x800000, data),
+            0x7E..=0x7F => unreachable!(),
+            0x80..=0xFF => match offset {
+                0x0000..=0x7FFF => match bank {
+                    0x80..=0xBF => {
+                        // unreachable!("Invalid bank: {:02X}, offset: {:04X}", bank, offset)
+                    }
+                    0xC0..=0xEF => self.write(addr + 0x8000, data),
+                    0xF0..=0xFF => {
+                        let sram_offset = (bank - 0xF0) * 1024 * 32 + offset;
+                        if let Some(sram_index) = sram_index(&self.sram, sram_offset) {
+                            self.sram[sram_index] = data;
                         }
-                        _ => unreachable!(),
-                    },
-
+                    }
                     _ => unreachable!(),
+                },
+                0x8000..=0xFFFF => {
+                    // ROM is read only
                 }
+                _ => unreachable!(),
+            },
+
+            _ => unreachable!(),
+        }
+    }
+
+    fn backup(&self) -> &[u8] {
+        &self.sram
+    }
+
+    fn backup_mut(&mut self) -> &mut [u8] {
+        &mut self.sram
+    }
+}
+
+// ROM in 64KB banks at $40-$7D/$C0-$FF, mirrored into the upper half of $00-$3F/$80-$BF.
+// SRAM in $6000-$7FFF of $20-$3F/$A0-$BF
+struct HiRom {
+    rom: RomData,
+    sram: Vec<u8>,
+}
+
+impl HiRom {
+    fn rom(&self) -> &[u8] {
+        (*self.rom).as_ref()
+    }
+
+    // $6000-$7FFF of banks $20-$3F/$A0-$BF, open bus in $00-$1F/$80-$9F
+    fn sram_index(&self, bank: usize, offset: usize) -> Option<usize> {
+        match bank & 0x7F {
+            0x20..=0x3F => sram_index(&self.sram, (bank & 0x1F) * 0x2000 + offset - 0x6000),
+            _ => None,
+        }
+    }
+}
+
+impl Mapper for HiRom {
+    fn read(&mut self, addr: u32) -> Option<u8> {
+        let bank = (addr >> 16) as usize;
+        let offset = (addr & 0xFFFF) as usize;
+        match bank {
+            0x00..=0x3F => match offset {
+                0x0000..=0x5FFF => {
+                    warn!(
+                        "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                        bank, offset
+                    );
+                    None
+                }
+                0x6000..=0x7FFF => {
+                    let sram_index = self.sram_index(bank, offset)?;
+                    Some(self.sram[sram_index])
+                }
+                0x8000..=0xFFFF => {
+                    let rom_index = mirror(addr as usize, self.rom().len());
+                    Some(self.rom()[rom_index])
+                }
+                _ => {
+                    warn!(
+                        "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                        bank, offset
+                    );
+                    None
+                }
+            },
+            0x40..=0x7D => {
+                let rom_index = mirror(addr as usize - 0x400000, self.rom().len());
+                Some(self.rom()[rom_index])
             }
-            MapMode::HiRom => {
-                let bank = (addr >> 16) as usize;
-                let offset = (addr & 0xFFFF) as usize;
-                match bank {
-                    0x00..=0x3F => match offset {
-                        0x0000..=0x5FFF => unreachable!(),
-                        0x6000..=0x7FFF => {
-                            if self.sram.is_empty() {
-                                return;
-                            }
-                            let sram_offset = bank * 1024 * 8 + (offset - 0x6000);
-                            let sram_index = sram_offset % self.sram.len();
-                            self.sram[sram_index] = data;
-                        }
-                        0x8000..=0xFFFF => {
-                            let rom_index = (addr as usize) % self.rom.rom.len();
-                            self.rom.rom[rom_index] = data;
-                        }
-                        _ => unreachable!(),
-                    },
-                    0x40..=0x7D => {
-                        let rom_index = (addr as usize - 0x400000) % self.rom.rom.len();
-                        self.rom.rom[rom_index] = data;
+            0x80..=0xBF => match offset {
+                0x0000..=0x5FFF => {
+                    warn!(
+                        "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                        bank, offset
+                    );
+                    None
+                }
+                0x6000..=0x7FFF => {
+                    let sram_index = self.sram_index(bank, offset)?;
+                    Some(self.sram[sram_index])
+                }
+                0x8000..=0xFFFF => {
+                    let rom_index = mirror(addr as usize - 0x800000, self.rom().len());
+                    Some(self.rom()[rom_index])
+                }
+                _ => {
+                    warn!(
+                        "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                        bank, offset
+                    );
+                    None
+                }
+            },
+            0xC0..=0xFF => {
+                let rom_index = mirror(addr as usize - 0xC00000, self.rom().len());
+                Some(self.rom()[rom_index])
+            }
+            _ => {
+                warn!(
+                    "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                    bank, offset
+                );
+                None
+            }
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        let bank = (addr >> 16) as usize;
+        let offset = (addr & 0xFFFF) as usize;
+        match bank {
+            0x00..=0x3F => match offset {
+                0x0000..=0x5FFF => unreachable!(),
+                0x6000..=0x7FFF => {
+                    if let Some(sram_index) = self.sram_index(bank, offset) {
+                        self.sram[sram_index] = data;
                     }
-                    0x80..=0xBF => match offset {
-                        0x0000..=0x5FFF => unreachable!(),
-                        0x6000..=0x7FFF => {
-                            let sram_offset = (bank - 0x80) * 1024 * 8 + (offset - 0x6000);
-                            let sram_index = sram_offset % self.sram.len();
-                            self.sram[sram_index] = data;
-                        }
-                        0x8000..=0xFFFF => {
-                            let rom_index = (addr as usize - 0x800000) % self.rom.rom.len();
-                            self.rom.rom[rom_index] = data;
-                        }
-                        _ => unreachable!(),
-                    },
-                    0xC0..=0xFF => {
-                        let rom_index = (addr as usize - 0xC00000) % self.rom.rom.len();
-                        self.rom.rom[rom_index] = data;
+                }
+                0x8000..=0xFFFF => {
+                    // ROM is read only
+                }
+                _ => unreachable!(),
+            },
+            0x40..=0x7D => {} // ROM is read only
+            0x80..=0xBF => match offset {
+                0x0000..=0x5FFF => unreachable!(),
+                0x6000..=0x7FFF => {
+                    if let Some(sram_index) = self.sram_index(bank, offset) {
+                        self.sram[sram_index] = data;
                     }
-                    _ => unreachable!(),
                 }
+                0x8000..=0xFFFF => {
+                    // ROM is read only
+                }
+                _ => unreachable!(),
+            },
+            0xC0..=0xFF => {} // ROM is read only
+            _ => unreachable!(),
+        }
+    }
+
+    fn backup(&self) -> &[u8] {
+        &self.sram
+    }
+
+    fn backup_mut(&mut self) -> &mut [u8] {
+        &mut self.sram
+    }
+}
+
+// HiROM up to 8MB: the first 4MB at $C0-$FF, the rest at $40-$7D, each mirrored into the
+// upper half of $80-$BF and $00-$3F. SRAM as on HiROM
+struct ExHiRom {
+    rom: RomData,
+    sram: Vec<u8>,
+}
+
+impl ExHiRom {
+    fn rom(&self) -> &[u8] {
+        (*self.rom).as_ref()
+    }
+
+    fn rom_offset(addr: u32) -> usize {
+        let bank = (addr >> 16) as usize;
+        let offset = (addr & 0xFFFF) as usize;
+        // Bit 7 of the bank selects the lower 4MB
+        let high = if bank & 0x80 == 0 { 0x400000 } else { 0 };
+        high | (bank & 0x3F) << 16 | offset
+    }
+
+    fn sram_index(&self, addr: u32) -> Option<usize> {
+        let bank = (addr >> 16) as usize;
+        let offset = (addr & 0xFFFF) as usize;
+        match (bank & 0x7F, offset) {
+            (0x20..=0x3F, 0x6000..=0x7FFF) => {
+                sram_index(&self.sram, (bank & 0x1F) * 0x2000 + offset - 0x6000)
             }
-            _ => unimplemented!(),
+            _ => None,
         }
     }
+}
 
-    pub fn backup(&self) -> Option<Vec<u8>> {
-        if self.sram.is_empty() {
-            None
-        } else {
-            Some(self.sram.clone())
+impl Mapper for ExHiRom {
+    fn read(&mut self, addr: u32) -> Option<u8> {
+        let bank = (addr >> 16) as usize;
+        let offset = (addr & 0xFFFF) as usize;
+        if let Some(index) = self.sram_index(addr) {
+            return Some(self.sram[index]);
+        }
+        match (bank, offset) {
+            (0x00..=0x3F | 0x80..=0xBF, 0x0000..=0x7FFF) => {
+                warn!(
+                    "Reading from invalid reagion bank: {:02X}, offset: {:04X}",
+                    bank, offset
+                );
+                None
+            }
+            _ => {
+                let rom = self.rom();
+                Some(rom[mirror(Self::rom_offset(addr), rom.len())])
+            }
         }
     }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        // ROM is read only
+        if let Some(index) = self.sram_index(addr) {
+            self.sram[index] = data;
+        }
+    }
+
+    fn backup(&self) -> &[u8] {
+        &self.sram
+    }
+
+    fn backup_mut(&mut self) -> &mut [u8] {
+        &mut self.sram
+    }
+}
+
+// SA-1, S-DD1 and SPC7110 boards, whose address decoding is done by the coprocessor
+struct UnsupportedBoard {
+    map_mode: MapMode,
+    sram: Vec<u8>,
+}
+
+impl Mapper for UnsupportedBoard {
+    fn read(&mut self, _addr: u32) -> Option<u8> {
+        warn!("Unsupported map mode: {:?}", self.map_mode);
+        None
+    }
+
+    fn write(&mut self, _addr: u32, _data: u8) {
+        warn!("Unsupported map mode: {:?}", self.map_mode);
+    }
+
+    fn backup(&self) -> &[u8] {
+        &self.sram
+    }
+
+    fn backup_mut(&mut self) -> &mut [u8] {
+        &mut self.sram
+    }
 }
 
 struct Rom {
     header: Header,
-    rom: Vec<u8>,
+    rom: RomData,
 }
 
 impl Rom {
-    fn from_bytes(bytes: &[u8]) -> Result<Rom, String> {
+    fn new(rom: RomData) -> Result<Rom, String> {
+        let bytes = (*rom).as_ref();
         for &base in [0x007F00, 0x00FF00, 0x40FF00].iter() {
             if base + 0x100 > bytes.len() {
                 continue;
@@ -263,6 +594,9 @@ impl Rom {
                 info!("ROM speed: {:?}", header.speed);
                 info!("ROM map mode: {:?}", header.map_mode);
                 info!("ROM chipset: {:02X}", header.chipset);
+                if let Some(chip) = header.firmware_chip {
+                    info!("Firmware: {} ({})", chip, chip.file_name());
+                }
                 info!("ROM size: {}KB", header.rom_size);
                 info!("RAM size: {}KB", header.ram_size);
                 info!("Country: {:02X}", header.country);
@@ -271,10 +605,7 @@ impl Rom {
                 info!("Checksum complement: {:04X}", header.checksum_complement);
                 info!("Checksum: {:04X}", header.checksum);
 
-                return Ok(Rom {
-                    header,
-                    rom: bytes.to_vec(),
-                });
+                return Ok(Rom { header, rom });
             }
         }
         Err("Failed to parse ROM".to_string())
@@ -290,21 +621,27 @@ fn parse_header(bytes: &[u8], base: usize) -> Result<Header, String> {
         return Err("Checksum error".to_string());
     }
 
-    let title = match std::str::from_utf8(&bytes[base + 0xC0..base + 0xC0 + 21]) {
+    let title = match core::str::from_utf8(&bytes[base + 0xC0..base + 0xC0 + 21]) {
         Ok(title) => title.trim().to_string(),
         Err(_) => "Invalid Title".to_string(),
     };
 
     let speed = Speed::from((bytes[base + 0xD5] >> 4) & 1);
-    let map_mode = MapMode::from(bytes[base + 0xD5] & 0xF);
+    let map_mode = MapMode::try_from(bytes[base + 0xD5] & 0xF)?;
 
     let chipset = bytes[base + 0xD6];
+    let firmware_chip = Chip::detect(&bytes[base + 0xC0..base + 0xD5], chipset, bytes[base + 0xBF]);
 
-    let rom_size = 1 << bytes[base + 0xD7] as usize;
+    // Up to 8MB of ROM and 256KB of RAM, anything larger isn't a real header
+    let rom_size = match bytes[base + 0xD7] {
+        n @ 0..=0x0D => 1 << n as usize,
+        n => return Err(format!("Invalid ROM size: {:02X}", n)),
+    };
 
     let ram_size = match bytes[base + 0xD8] {
         0 => 0,
-        n => 1 << n as usize,
+        n @ 1..=0x08 => 1 << n as usize,
+        n => return Err(format!("Invalid RAM size: {:02X}", n)),
     };
 
     let country = bytes[base + 0xD9];
@@ -318,6 +655,7 @@ fn parse_header(bytes: &[u8], base: usize) -> Result<Header, String> {
         speed,
         map_mode,
         chipset,
+        firmware_chip,
         rom_size,
         ram_size,
         country,
@@ -328,11 +666,13 @@ fn parse_header(bytes: &[u8], base: usize) -> Result<Header, String> {
     })
 }
 
+#[derive(Default)]
 struct Header {
     title: String,
     speed: Speed,
     map_mode: MapMode,
     chipset: u8,
+    firmware_chip: Option<Chip>,
     rom_size: usize,
     ram_size: usize,
     country: u8,
@@ -342,8 +682,9 @@ struct Header {
     checksum: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 enum Speed {
+    #[default]
     Slow,
     Fast,
 }
@@ -358,8 +699,9 @@ impl From<u8> for Speed {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 enum MapMode {
+    #[default]
     LoRom,
     HiRom,
     SDd1,
@@ -368,16 +710,91 @@ enum MapMode {
     Spc7110,
 }
 
-impl From<u8> for MapMode {
-    fn from(val: u8) -> MapMode {
+impl TryFrom<u8> for MapMode {
+    type Error = String;
+
+    fn try_from(val: u8) -> Result<MapMode, String> {
         match val {
-            0 => MapMode::LoRom,
-            1 => MapMode::HiRom,
-            2 => MapMode::SDd1,
-            3 => MapMode::SA1,
-            4 => MapMode::ExHiRom,
-            5 => MapMode::Spc7110,
-            _ => unreachable!("Unknown map mode: {}", val),
+            0 => Ok(MapMode::LoRom),
+            1 => Ok(MapMode::HiRom),
+            2 => Ok(MapMode::SDd1),
+            3 => Ok(MapMode::SA1),
+            4 => Ok(MapMode::ExHiRom),
+            5 => Ok(MapMode::Spc7110),
+            _ => Err(format!("Unknown map mode: {}", val)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lorom(sram_size: usize) -> LoRom {
+        LoRom {
+            rom: Box::new(vec![0; 0x8000]),
+            sram: vec![0; sram_size],
+        }
+    }
+
+    fn hirom(sram_size: usize) -> HiRom {
+        HiRom {
+            rom: Box::new(vec![0; 0x10000]),
+            sram: vec![0; sram_size],
+        }
+    }
+
+    #[test]
+    fn lorom_sram_mirrors_by_size() {
+        let mut board = lorom(0x800);
+        board.write(0x700000, 0xAA);
+        for addr in [0x700800, 0x707800, 0x710000, 0x7D7800, 0xF00000, 0xFD7800] {
+            assert_eq!(board.read(addr), Some(0xAA), "{:06X}", addr);
         }
+        // Where the header size ends the mirrors start, the SRAM doesn't grow
+        board.write(0x700801, 0xBB);
+        assert_eq!(board.read(0x700001), Some(0xBB));
+        board.write(0xFD7FFF, 0xCC);
+        assert_eq!(board.read(0x7007FF), Some(0xCC));
+        assert_eq!(board.backup().len(), 0x800);
+    }
+
+    #[test]
+    fn lorom_without_sram() {
+        let mut board = lorom(0);
+        board.write(0x700000, 0xAA);
+        assert_eq!(board.read(0x700000), None);
+        assert_eq!(board.read(0xFD7FFF), None);
+    }
+
+    #[test]
+    fn hirom_sram_mirrors_by_size() {
+        let mut board = hirom(0x800);
+        board.write(0x206000, 0xAA);
+        for addr in [0x206800, 0x207800, 0x216000, 0x3F7800, 0xA06000, 0xBF7800] {
+            assert_eq!(board.read(addr), Some(0xAA), "{:06X}", addr);
+        }
+        // Banks $00-$1F/$80-$9F have no SRAM
+        for addr in [0x006000, 0x1F7FFF, 0x806000, 0x9F7FFF] {
+            assert_eq!(board.read(addr), None, "{:06X}", addr);
+        }
+        board.write(0x3F7FFF, 0xBB);
+        assert_eq!(board.read(0x2067FF), Some(0xBB));
+        assert_eq!(board.backup().len(), 0x800);
+
+        // Larger SRAM continues into the next bank
+        let mut board = hirom(0x8000);
+        board.write(0x206000, 0xAA);
+        board.write(0x216000, 0xBB);
+        assert_eq!(board.read(0x206000), Some(0xAA));
+        assert_eq!(board.read(0x246000), Some(0xAA));
+        assert_eq!(board.read(0x256000), Some(0xBB));
+    }
+
+    #[test]
+    fn hirom_without_sram() {
+        let mut board = hirom(0);
+        board.write(0x206000, 0xAA);
+        assert_eq!(board.read(0x206000), None);
     }
 }
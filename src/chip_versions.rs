@@ -0,0 +1,29 @@
+// Chip revisions reported by $4210 (S-CPU), $213E (5C77 PPU1) and $213F (5C78 PPU2).
+// Only the version bits change, revision specific hardware behavior isn't emulated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipVersions {
+    pub cpu_version: u8,
+    pub ppu1_version: u8,
+    pub ppu2_version: u8,
+}
+
+impl ChipVersions {
+    // Version bits of launch units
+    pub const V1: ChipVersions = ChipVersions {
+        cpu_version: 1,
+        ppu1_version: 1,
+        ppu2_version: 1,
+    };
+    // Version bits of most later units
+    pub const V2: ChipVersions = ChipVersions {
+        cpu_version: 2,
+        ppu1_version: 1,
+        ppu2_version: 3,
+    };
+}
+
+impl Default for ChipVersions {
+    fn default() -> Self {
+        ChipVersions::V2
+    }
+}
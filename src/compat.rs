@@ -0,0 +1,43 @@
+// Timing hacks for games that hang with accurate timing, e.g. unlicensed titles that were only
+// tested on lenient emulators. Frontends turn them on per game with Snes::set_compat_hack,
+// all of them are off by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompatHack {
+    // Don't catch the APU up before the CPU accesses $2140-$2143, it is then only synced
+    // after every instruction. No effect with the threaded APU
+    RelaxedApuSync,
+    // Skip the 40 master clocks the CPU is paused on every line for the DRAM refresh
+    NoDramRefresh,
+    // Same as Snes::set_relaxed_memory_access
+    RelaxedMemoryAccess,
+    // Same as Snes::set_fast_apu_boot
+    FastApuBoot,
+    // Have the $4203/$4206 results ready right after the write instead of 8/16 CPU cycles
+    // later, for games that read them too early on lenient emulators
+    InstantMulDiv,
+}
+
+impl CompatHack {
+    pub const ALL: [CompatHack; 5] = [
+        CompatHack::RelaxedApuSync,
+        CompatHack::NoDramRefresh,
+        CompatHack::RelaxedMemoryAccess,
+        CompatHack::FastApuBoot,
+        CompatHack::InstantMulDiv,
+    ];
+
+    // Stable name for per game settings files
+    pub fn name(self) -> &'static str {
+        match self {
+            CompatHack::RelaxedApuSync => "relaxed-apu-sync",
+            CompatHack::NoDramRefresh => "no-dram-refresh",
+            CompatHack::RelaxedMemoryAccess => "relaxed-memory-access",
+            CompatHack::FastApuBoot => "fast-apu-boot",
+            CompatHack::InstantMulDiv => "instant-mul-div",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CompatHack> {
+        CompatHack::ALL.into_iter().find(|hack| hack.name() == name)
+    }
+}
@@ -0,0 +1,92 @@
+// Options for Snes::new_with_config. New options go here instead of into the constructors,
+// so their signatures stay stable. Everything defaults to what Snes::new does:
+//
+// let config = SnesConfig::new()
+//     .power_on_state(PowerOnState::Random { seed: 1 })
+//     .compat_hack(CompatHack::FastApuBoot, true);
+use crate::compat::CompatHack;
+use crate::chip_versions::ChipVersions;
+use crate::dsp::AudioOutputMode;
+use crate::power_on::PowerOnState;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnesConfig {
+    pub(crate) power_on: PowerOnState,
+    pub(crate) chip_versions: ChipVersions,
+    pub(crate) compat_hacks: Vec<CompatHack>,
+    pub(crate) no_sprite_limit: bool,
+    pub(crate) cpu_overclock: u8,
+    pub(crate) audio_output_mode: AudioOutputMode,
+    pub(crate) ipl_rom: Option<[u8; 0x40]>,
+    #[cfg(feature = "std")]
+    pub(crate) threaded_apu: bool,
+}
+
+impl Default for SnesConfig {
+    fn default() -> Self {
+        SnesConfig {
+            power_on: PowerOnState::default(),
+            chip_versions: ChipVersions::default(),
+            compat_hacks: Vec::new(),
+            no_sprite_limit: false,
+            cpu_overclock: 1,
+            audio_output_mode: AudioOutputMode::default(),
+            ipl_rom: None,
+            #[cfg(feature = "std")]
+            threaded_apu: false,
+        }
+    }
+}
+
+impl SnesConfig {
+    pub fn new() -> SnesConfig {
+        SnesConfig::default()
+    }
+
+    // Initial contents of WRAM, VRAM and APU RAM, also used by Snes::power_cycle
+    pub fn power_on_state(mut self, power_on: PowerOnState) -> SnesConfig {
+        self.power_on = power_on;
+        self
+    }
+
+    pub fn chip_versions(mut self, versions: ChipVersions) -> SnesConfig {
+        self.chip_versions = versions;
+        self
+    }
+
+    pub fn compat_hack(mut self, hack: CompatHack, enabled: bool) -> SnesConfig {
+        self.compat_hacks.retain(|&h| h != hack);
+        if enabled {
+            self.compat_hacks.push(hack);
+        }
+        self
+    }
+
+    pub fn no_sprite_limit(mut self, no_limit: bool) -> SnesConfig {
+        self.no_sprite_limit = no_limit;
+        self
+    }
+
+    pub fn cpu_overclock(mut self, multiplier: u8) -> SnesConfig {
+        self.cpu_overclock = multiplier;
+        self
+    }
+
+    pub fn audio_output_mode(mut self, mode: AudioOutputMode) -> SnesConfig {
+        self.audio_output_mode = mode;
+        self
+    }
+
+    // Boot the SPC700 from a custom 64 byte IPL image, e.g. for research or homebrew
+    pub fn ipl_rom(mut self, rom: [u8; 0x40]) -> SnesConfig {
+        self.ipl_rom = Some(rom);
+        self
+    }
+
+    #[cfg(feature = "std")]
+    pub fn threaded_apu(mut self, threaded: bool) -> SnesConfig {
+        self.threaded_apu = threaded;
+        self
+    }
+}
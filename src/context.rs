@@ -1,5 +1,16 @@
-use crate::controller::Key;
+use crate::access_hook::AccessFilter;
+use crate::controller::{InputProvider, Key, SerialDevice};
+use crate::diagnostics::{Diagnostic, DiagnosticSink};
+use crate::dsp::AudioOutputMode;
+use crate::error::SnesError;
+use crate::power_on::PowerOnState;
+use crate::satellaview::Satellaview;
+#[cfg(feature = "std")]
+use crate::apu_thread;
 use crate::{bus, cartridge, counter, cpu, interrupt, ppu, spc};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use log::debug;
 
 // struct Context {
@@ -10,25 +21,38 @@ use log::debug;
 //     cartridge: cartridge::Cartridge,
 // }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Context {
     cpu: cpu::Cpu,
     pub inner1: Inner1,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Inner1 {
-    bus: bus::Bus,
+    // Bus, PPU and SPC are boxed so that deserializing a savestate fits in the stack
+    bus: Box<bus::Bus>,
     pub inner2: Inner2,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Inner2 {
-    pub ppu: ppu::Ppu,
+    pub ppu: Box<ppu::Ppu>,
     pub cartridge: cartridge::Cartridge,
-    pub spc: spc::Spc,
+    pub spc: Box<spc::Spc>,
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    apu_thread: Option<apu_thread::ApuThread>,
+    // CompatHack::RelaxedApuSync, not part of savestates
+    #[cfg_attr(feature = "serde", serde(skip))]
+    relaxed_apu_sync: bool,
     pub inner: Inner3,
 }
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Inner3 {
     timing: counter::Counter,
     interrupt: interrupt::Interrupt,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    diagnostic_sink: Option<Box<dyn DiagnosticSink + Send>>,
 }
 
 // impl Context {
@@ -44,26 +68,164 @@ struct Inner3 {
 // }
 
 impl Context {
-    pub fn new(rom: Vec<u8>, backup: Option<Vec<u8>>) -> Context {
+    pub fn new(cartridge: cartridge::Cartridge, power_on: PowerOnState) -> Context {
         let mut ctx = Context {
             cpu: cpu::Cpu::default(),
             inner1: Inner1 {
-                bus: bus::Bus::default(),
+                bus: Box::default(),
                 inner2: Inner2 {
-                    ppu: ppu::Ppu::default(),
-                    spc: spc::Spc::default(),
-                    cartridge: cartridge::Cartridge::new(rom, backup),
+                    ppu: Box::default(),
+                    spc: Box::default(),
+                    #[cfg(feature = "std")]
+                    apu_thread: None,
+                    relaxed_apu_sync: false,
+                    cartridge,
                     inner: Inner3 {
                         timing: counter::Counter::default(),
                         interrupt: interrupt::Interrupt::default(),
+                        diagnostic_sink: None,
                     },
                 },
             },
         };
+        ctx.inner1.bus.init_ram(&power_on);
+        ctx.inner1.inner2.ppu.init_ram(&power_on);
+        ctx.inner1.inner2.spc.init_ram(&power_on);
         ctx.cpu.reset(&mut ctx.inner1);
         debug!("PC: {:04X}", ctx.cpu.pc);
         ctx
     }
+
+    // The threaded APU is joined first so the SPC700 state is up to date
+    #[cfg(feature = "serde")]
+    pub fn save_state<S: serde::Serializer>(&mut self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[cfg(feature = "std")]
+        let threaded = self.inner1.inner2.apu_thread.is_some();
+        #[cfg(feature = "std")]
+        self.inner1.inner2.set_apu_threaded(false);
+
+        let ret = serde::Serialize::serialize(&*self, serializer);
+
+        #[cfg(feature = "std")]
+        self.inner1.inner2.set_apu_threaded(threaded);
+        ret
+    }
+
+    // Replace the emulated state, keeping the ROM and everything set by the frontend
+    #[cfg(feature = "serde")]
+    pub fn load_state(&mut self, mut state: Context) {
+        #[cfg(feature = "std")]
+        let threaded = self.inner1.inner2.apu_thread.is_some();
+        #[cfg(feature = "std")]
+        self.inner1.inner2.set_apu_threaded(false);
+
+        let (old, new) = (&mut self.inner1, &mut state.inner1);
+        old.bus.swap_host_state(&mut new.bus);
+        old.inner2.ppu.swap_host_state(&mut new.inner2.ppu);
+        old.inner2.spc.swap_host_state(&mut new.inner2.spc);
+        old.inner2.cartridge.swap_host_state(&mut new.inner2.cartridge);
+        core::mem::swap(
+            &mut old.inner2.inner.diagnostic_sink,
+            &mut new.inner2.inner.diagnostic_sink,
+        );
+        new.inner2.relaxed_apu_sync = old.inner2.relaxed_apu_sync;
+        old.inner2.inner.timing.swap_host_state(&mut new.inner2.inner.timing);
+        *self = state;
+
+        #[cfg(feature = "std")]
+        self.inner1.inner2.set_apu_threaded(threaded);
+    }
+
+    // Reset button. The CPU restarts from the reset vector and the APU from the IPL ROM,
+    // memory and most PPU registers are kept
+    pub fn reset(&mut self) {
+        #[cfg(feature = "std")]
+        let threaded = self.inner1.inner2.apu_thread.is_some();
+        #[cfg(feature = "std")]
+        self.inner1.inner2.set_apu_threaded(false);
+
+        let inner2 = &mut self.inner1.inner2;
+        self.inner1.bus.reset();
+        inner2.ppu.reset();
+        inner2.spc.reset();
+        inner2.inner.interrupt = interrupt::Interrupt::default();
+        self.cpu.reset(&mut self.inner1);
+
+        #[cfg(feature = "std")]
+        self.inner1.inner2.set_apu_threaded(threaded);
+    }
+
+    // Everything but the cartridge and what the frontend set starts over, with RAM filled
+    // per `power_on`. The master clock keeps counting so sinks see steady timestamps
+    pub fn power_cycle(&mut self, power_on: &PowerOnState) {
+        #[cfg(feature = "std")]
+        let threaded = self.inner1.inner2.apu_thread.is_some();
+        #[cfg(feature = "std")]
+        self.inner1.inner2.set_apu_threaded(false);
+
+        let inner2 = &mut self.inner1.inner2;
+        let now = inner2.inner.timing.now();
+        self.inner1.bus.power_cycle(power_on);
+        inner2.ppu.power_cycle(power_on, now);
+        inner2.spc.power_cycle(power_on, now);
+        inner2.cartridge.power_cycle();
+        let timing = &mut inner2.inner.timing;
+        (timing.frame, timing.x, timing.y) = (0, 0, 0);
+        inner2.inner.interrupt = interrupt::Interrupt::default();
+        self.cpu = cpu::Cpu::default();
+        self.cpu.reset(&mut self.inner1);
+
+        #[cfg(feature = "std")]
+        self.inner1.inner2.set_apu_threaded(threaded);
+    }
+
+    pub fn cpu(&self) -> &cpu::Cpu {
+        &self.cpu
+    }
+
+    pub fn ppu(&self) -> &ppu::Ppu {
+        &self.inner1.inner2.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut ppu::Ppu {
+        &mut self.inner1.inner2.ppu
+    }
+
+    // None while the APU runs on its own thread, where this copy is stale
+    pub fn spc(&self) -> Option<&spc::Spc> {
+        #[cfg(feature = "std")]
+        if self.inner1.inner2.apu_thread.is_some() {
+            return None;
+        }
+        Some(&self.inner1.inner2.spc)
+    }
+
+    pub fn spc_mut(&mut self) -> Option<&mut spc::Spc> {
+        #[cfg(feature = "std")]
+        if self.inner1.inner2.apu_thread.is_some() {
+            return None;
+        }
+        Some(&mut self.inner1.inner2.spc)
+    }
+}
+
+// 32KB of LoROM spinning on BRA *, for testing the chips through the bus
+#[cfg(test)]
+pub fn test_cartridge() -> cartridge::Cartridge {
+    let mut rom = alloc::vec![0; 0x8000];
+    rom[..2].copy_from_slice(&[0x80, 0xFE]);
+    rom[0x7FD5] = 0x20; // LoROM
+    rom[0x7FD7] = 0x05; // 32KB
+    rom[0x7FDC..0x7FE0].copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+    rom[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+    cartridge::Cartridge::new(Box::new(rom), None).unwrap()
+}
+
+#[cfg(test)]
+impl Context {
+    pub fn for_test() -> Context {
+        Context::new(test_cartridge(), PowerOnState::Zero)
+    }
 }
 
 impl Cpu for Context {
@@ -88,9 +250,89 @@ impl Bus for Inner1 {
         self.bus.set_keys(keys)
     }
 
+    fn set_input_provider(&mut self, provider: Option<Box<dyn InputProvider + Send>>) {
+        self.bus.set_input_provider(provider)
+    }
+
+    fn set_serial_device(&mut self, port: usize, device: Option<Box<dyn SerialDevice + Send>>) {
+        self.bus.set_serial_device(port, device)
+    }
+
+    fn set_turbo(&mut self, pad: usize, key: Key, rate: u8) {
+        self.bus.set_turbo(pad, key, rate)
+    }
+
+    fn last_polled_input(&self, pad: usize) -> u16 {
+        self.bus.last_polled_input(pad)
+    }
+
+    fn hdma_channels(&self) -> Vec<bus::HdmaChannel> {
+        self.bus.hdma_channels()
+    }
+
+    fn unimplemented_accesses(&self) -> &BTreeMap<(u16, bool), u64> {
+        self.bus.unimplemented_accesses()
+    }
+
+    fn set_cpu_version(&mut self, version: u8) {
+        self.bus.set_cpu_version(version)
+    }
+
+    fn set_instant_mul_div(&mut self, instant: bool) {
+        self.bus.set_instant_mul_div(instant)
+    }
+
+    fn set_satellaview(&mut self, satellaview: Option<Satellaview>) {
+        self.bus.set_satellaview(satellaview)
+    }
+
+    fn satellaview_mut(&mut self) -> Option<&mut Satellaview> {
+        self.bus.satellaview_mut()
+    }
+
+    fn set_access_hook(&mut self, hook: Option<AccessFilter>) {
+        self.bus.set_access_hook(hook)
+    }
+
+    #[cfg(feature = "instrumentation")]
+    fn input_events(&self) -> &[crate::controller::InputEvent] {
+        self.bus.input_events()
+    }
+
+    #[cfg(feature = "instrumentation")]
+    fn clear_input_events(&mut self) {
+        self.bus.clear_input_events()
+    }
+
     fn bus_tick(&mut self) {
         self.bus.tick(&mut self.inner2);
     }
+
+    fn take_bus_error(&mut self) -> Option<SnesError> {
+        self.bus.take_error()
+    }
+
+    fn dma_bytes(&self) -> u64 {
+        self.bus.dma_bytes()
+    }
+
+    fn wram(&self) -> &[u8] {
+        self.bus.wram()
+    }
+
+    fn wram_mut(&mut self) -> &mut [u8] {
+        self.bus.wram_mut()
+    }
+
+    #[cfg(feature = "scripting")]
+    fn watch_wram_write(&mut self, offset: usize) {
+        self.bus.watch_wram_write(offset)
+    }
+
+    #[cfg(feature = "scripting")]
+    fn take_wram_writes(&mut self) -> Vec<(u32, u8)> {
+        self.bus.take_wram_writes()
+    }
 }
 
 impl Timing for Inner1 {
@@ -116,12 +358,12 @@ impl Interrupt for Inner1 {
         self.inner2.get_nmi_flag()
     }
 
-    fn set_nmi_flag(&mut self, flag: bool) {
-        self.inner2.set_nmi_flag(flag)
+    fn set_nmi_flag(&mut self, flag: bool, at: u64) {
+        self.inner2.set_nmi_flag(flag, at)
     }
 
-    fn nmi_occurred(&mut self) -> bool {
-        self.inner2.nmi_occurred()
+    fn nmi_occurred(&mut self, poll: u64) -> bool {
+        self.inner2.nmi_occurred(poll)
     }
 
     fn set_nmi_enable(&mut self, flag: bool) {
@@ -152,12 +394,16 @@ impl Interrupt for Inner1 {
         self.inner2.get_v_count()
     }
 
-    fn set_irq(&mut self, flag: bool) {
-        self.inner2.set_irq(flag)
+    fn set_irq(&mut self, flag: bool, at: u64) {
+        self.inner2.set_irq(flag, at)
     }
 
-    fn irq_occurred(&self) -> bool {
-        self.inner2.irq_occurred()
+    fn irq_occurred(&self, poll: u64) -> bool {
+        self.inner2.irq_occurred(poll)
+    }
+
+    fn get_irq_flag(&mut self) -> bool {
+        self.inner2.get_irq_flag()
     }
 }
 
@@ -174,6 +420,15 @@ impl Ppu for Inner2 {
         self.ppu.tick(&mut self.inner)
     }
 
+    fn ppu_position(&self) -> (u64, u16, u16) {
+        self.ppu.position()
+    }
+
+    fn ppu_set_wrio_latch(&mut self, high: bool) {
+        self.ppu.tick(&mut self.inner);
+        self.ppu.set_wrio_latch(high)
+    }
+
     fn is_hblank(&self) -> bool {
         self.ppu.is_hblank()
     }
@@ -196,29 +451,193 @@ impl Ppu for Inner2 {
 
 impl Spc for Inner2 {
     fn spc_read(&mut self, port: u16) -> u8 {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.read_port(self.inner.timing.now(), port);
+        }
+        // Catch up first so handshake loops see the exact order of port accesses
+        if !self.relaxed_apu_sync {
+            self.spc.tick(self.inner.timing.now());
+        }
         self.spc.read_port(port)
     }
 
     fn spc_write(&mut self, port: u16, data: u8) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.write_port(self.inner.timing.now(), port, data);
+        }
+        if !self.relaxed_apu_sync {
+            self.spc.tick(self.inner.timing.now());
+        }
         self.spc.write_port(port, data);
     }
 
     fn spc_tick(&mut self) {
-        self.spc.tick(&mut self.inner);
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.tick(self.inner.timing.now());
+        }
+        self.spc.tick(self.inner.timing.now());
+    }
+
+    fn spc_sync(&mut self) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            apu.sync(self.inner.timing.now());
+        }
+    }
+
+    fn spc_state(&self) -> spc::SpcState {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &self.apu_thread {
+            return apu.state();
+        }
+        self.spc.state()
+    }
+
+    fn spc_dsp_registers(&self) -> [u8; 0x80] {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &self.apu_thread {
+            return apu.dsp_registers();
+        }
+        self.spc.dsp_registers()
+    }
+
+    fn spc_ram(&mut self) -> &[u8] {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.ram(self.inner.timing.now());
+        }
+        self.spc.tick(self.inner.timing.now());
+        self.spc.ram()
+    }
+
+    fn spc_write_ram(&mut self, addr: u16, data: u8) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.write_ram(self.inner.timing.now(), addr, data);
+        }
+        self.spc.tick(self.inner.timing.now());
+        self.spc.write_ram(addr, data);
+    }
+
+    fn spc_write_dsp(&mut self, addr: u8, data: u8) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.write_dsp_register(self.inner.timing.now(), addr, data);
+        }
+        self.spc.tick(self.inner.timing.now());
+        self.spc.write_dsp_register(addr, data);
+    }
+
+    fn spc_instructions(&self) -> u64 {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &self.apu_thread {
+            return apu.instructions();
+        }
+        self.spc.instructions()
+    }
+
+    fn audio_buffer(&self) -> &[(i16, i16)] {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &self.apu_thread {
+            return apu.audio_buffer();
+        }
+        self.spc.audio_buffer()
     }
 
     fn clear_audio_buffer(&mut self) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.clear_audio_buffer();
+        }
         self.spc.clear_audio_buffer();
     }
+
+    fn audio_timestamp(&self, index: usize) -> Option<u64> {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &self.apu_thread {
+            return apu.audio_timestamp(index);
+        }
+        self.spc.audio_timestamp(index)
+    }
+
+    fn set_audio_sink(&mut self, sink: Option<Box<dyn spc::AudioSink + Send>>) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.set_audio_sink(sink);
+        }
+        self.spc.set_audio_sink(sink);
+    }
+
+    fn set_audio_output_mode(&mut self, mode: AudioOutputMode) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.set_audio_output_mode(mode);
+        }
+        self.spc.set_audio_output_mode(mode);
+    }
+
+    fn set_volume(&mut self, volume: u16) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.set_volume(volume);
+        }
+        self.spc.set_volume(volume);
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        #[cfg(feature = "std")]
+        if let Some(apu) = &mut self.apu_thread {
+            return apu.set_muted(muted);
+        }
+        self.spc.set_muted(muted);
+    }
+
+    fn set_apu_fast_boot(&mut self, fast_boot: bool) {
+        #[cfg(feature = "std")]
+        let threaded = self.apu_thread.is_some();
+        #[cfg(feature = "std")]
+        self.set_apu_threaded(false);
+
+        self.spc.set_fast_boot(fast_boot);
+
+        #[cfg(feature = "std")]
+        self.set_apu_threaded(threaded);
+    }
+
+    fn set_apu_relaxed_sync(&mut self, relaxed: bool) {
+        self.relaxed_apu_sync = relaxed;
+    }
+
+    #[cfg(feature = "std")]
+    fn set_apu_threaded(&mut self, threaded: bool) {
+        match (self.apu_thread.take(), threaded) {
+            (None, true) => {
+                let spc = core::mem::take(&mut *self.spc);
+                self.apu_thread = Some(apu_thread::ApuThread::spawn(spc, self.inner.timing.now()));
+            }
+            (Some(mut apu), false) => {
+                apu.sync(self.inner.timing.now());
+                *self.spc = apu.join();
+            }
+            (apu, _) => self.apu_thread = apu,
+        }
+    }
 }
 
 impl Cartridge for Inner2 {
     fn cartridge_read(&mut self, addr: u32) -> Option<u8> {
-        self.cartridge.read(addr)
+        self.cartridge.read(addr, self.inner.timing.now())
     }
 
     fn cartridge_write(&mut self, addr: u32, data: u8) {
-        self.cartridge.write(addr, data)
+        self.cartridge.write(addr, data, self.inner.timing.now())
+    }
+
+    fn cartridge_tick(&mut self) {
+        self.cartridge.tick(self.inner.timing.now())
     }
 }
 
@@ -241,15 +660,15 @@ impl Timing for Inner2 {
 
 impl Interrupt for Inner2 {
     fn get_nmi_flag(&mut self) -> bool {
-        self.inner.interrupt.get_nmi_flag()
+        self.inner.get_nmi_flag()
     }
 
-    fn set_nmi_flag(&mut self, flag: bool) {
-        self.inner.interrupt.set_nmi_flag(flag)
+    fn set_nmi_flag(&mut self, flag: bool, at: u64) {
+        self.inner.interrupt.set_nmi_flag(flag, at)
     }
 
-    fn nmi_occurred(&mut self) -> bool {
-        self.inner.interrupt.nmi_occurred()
+    fn nmi_occurred(&mut self, poll: u64) -> bool {
+        self.inner.interrupt.nmi_occurred(poll)
     }
 
     fn get_hv_irq_enable(&self) -> u8 {
@@ -257,7 +676,7 @@ impl Interrupt for Inner2 {
     }
 
     fn set_nmi_enable(&mut self, flag: bool) {
-        self.inner.interrupt.set_nmi_enable(flag)
+        self.inner.set_nmi_enable(flag)
     }
 
     fn set_hv_irq_enable(&mut self, val: u8) {
@@ -280,12 +699,48 @@ impl Interrupt for Inner2 {
         self.inner.interrupt.get_v_count()
     }
 
-    fn set_irq(&mut self, flag: bool) {
-        self.inner.interrupt.set_irq(flag)
+    fn set_irq(&mut self, flag: bool, at: u64) {
+        self.inner.interrupt.set_irq(flag, at)
+    }
+
+    fn irq_occurred(&self, poll: u64) -> bool {
+        self.inner.interrupt.irq_occurred(poll)
+    }
+
+    fn get_irq_flag(&mut self) -> bool {
+        self.inner.get_irq_flag()
+    }
+}
+
+impl Diagnostics for Inner1 {
+    fn report(&mut self, diagnostic: Diagnostic) {
+        self.inner2.report(diagnostic)
+    }
+
+    fn set_diagnostic_sink(&mut self, sink: Option<Box<dyn DiagnosticSink + Send>>) {
+        self.inner2.set_diagnostic_sink(sink)
+    }
+}
+
+impl Diagnostics for Inner2 {
+    fn report(&mut self, diagnostic: Diagnostic) {
+        self.inner.report(diagnostic)
+    }
+
+    fn set_diagnostic_sink(&mut self, sink: Option<Box<dyn DiagnosticSink + Send>>) {
+        self.inner.set_diagnostic_sink(sink)
+    }
+}
+
+impl Diagnostics for Inner3 {
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if let Some(sink) = &mut self.diagnostic_sink {
+            sink.report(diagnostic);
+        }
     }
 
-    fn irq_occurred(&self) -> bool {
-        self.inner.interrupt.irq_occurred()
+    fn set_diagnostic_sink(&mut self, sink: Option<Box<dyn DiagnosticSink + Send>>) {
+        self.diagnostic_sink = sink;
     }
 }
 
@@ -308,19 +763,19 @@ impl Timing for Inner3 {
 
 impl Interrupt for Inner3 {
     fn get_nmi_flag(&mut self) -> bool {
-        self.interrupt.get_nmi_flag()
+        self.interrupt.get_nmi_flag(self.timing.now())
     }
 
-    fn set_nmi_flag(&mut self, flag: bool) {
-        self.interrupt.set_nmi_flag(flag)
+    fn set_nmi_flag(&mut self, flag: bool, at: u64) {
+        self.interrupt.set_nmi_flag(flag, at)
     }
 
-    fn nmi_occurred(&mut self) -> bool {
-        self.interrupt.nmi_occurred()
+    fn nmi_occurred(&mut self, poll: u64) -> bool {
+        self.interrupt.nmi_occurred(poll)
     }
 
     fn set_nmi_enable(&mut self, flag: bool) {
-        self.interrupt.set_nmi_enable(flag)
+        self.interrupt.set_nmi_enable(flag, self.timing.now())
     }
 
     fn set_hv_irq_enable(&mut self, val: u8) {
@@ -347,12 +802,16 @@ impl Interrupt for Inner3 {
         self.interrupt.get_v_count()
     }
 
-    fn set_irq(&mut self, flag: bool) {
-        self.interrupt.set_irq(flag)
+    fn set_irq(&mut self, flag: bool, at: u64) {
+        self.interrupt.set_irq(flag, at)
     }
 
-    fn irq_occurred(&self) -> bool {
-        self.interrupt.irq_occurred()
+    fn irq_occurred(&self, poll: u64) -> bool {
+        self.interrupt.irq_occurred(poll)
+    }
+
+    fn get_irq_flag(&mut self) -> bool {
+        self.interrupt.get_irq_flag(self.timing.now())
     }
 }
 
@@ -402,7 +861,30 @@ pub trait Bus {
     fn bus_write(&mut self, addr: u32, data: u8);
 
     fn bus_tick(&mut self);
+    fn take_bus_error(&mut self) -> Option<SnesError>;
+    fn dma_bytes(&self) -> u64;
+    fn wram(&self) -> &[u8];
+    fn wram_mut(&mut self) -> &mut [u8];
+    #[cfg(feature = "scripting")]
+    fn watch_wram_write(&mut self, offset: usize);
+    #[cfg(feature = "scripting")]
+    fn take_wram_writes(&mut self) -> Vec<(u32, u8)>;
     fn set_keys(&mut self, keys: [Vec<Key>; 4]);
+    fn set_input_provider(&mut self, provider: Option<Box<dyn InputProvider + Send>>);
+    fn set_serial_device(&mut self, port: usize, device: Option<Box<dyn SerialDevice + Send>>);
+    fn set_turbo(&mut self, pad: usize, key: Key, rate: u8);
+    fn last_polled_input(&self, pad: usize) -> u16;
+    fn hdma_channels(&self) -> Vec<bus::HdmaChannel>;
+    fn unimplemented_accesses(&self) -> &BTreeMap<(u16, bool), u64>;
+    fn set_cpu_version(&mut self, version: u8);
+    fn set_instant_mul_div(&mut self, instant: bool);
+    fn set_satellaview(&mut self, satellaview: Option<Satellaview>);
+    fn satellaview_mut(&mut self) -> Option<&mut Satellaview>;
+    fn set_access_hook(&mut self, hook: Option<AccessFilter>);
+    #[cfg(feature = "instrumentation")]
+    fn input_events(&self) -> &[crate::controller::InputEvent];
+    #[cfg(feature = "instrumentation")]
+    fn clear_input_events(&mut self);
 }
 
 pub trait Ppu {
@@ -410,6 +892,8 @@ pub trait Ppu {
     fn ppu_write(&mut self, addr: u16, data: u8);
 
     fn ppu_tick(&mut self);
+    fn ppu_position(&self) -> (u64, u16, u16);
+    fn ppu_set_wrio_latch(&mut self, high: bool);
 
     fn is_hblank(&self) -> bool;
     fn is_vblank(&self) -> bool;
@@ -422,19 +906,30 @@ pub trait Timing {
     fn elapse(&mut self, clock: u64);
     fn now(&self) -> u64;
 
+    // Time spent by the CPU itself, affected by overclocking
+    fn elapse_cpu(&mut self, clock: u64) {
+        self.counter_mut().elapse_cpu(clock)
+    }
+
     fn counter(&self) -> &counter::Counter;
     fn counter_mut(&mut self) -> &mut counter::Counter;
 }
 
+pub trait Diagnostics {
+    fn report(&mut self, diagnostic: Diagnostic);
+    fn set_diagnostic_sink(&mut self, sink: Option<Box<dyn DiagnosticSink + Send>>);
+}
+
 pub trait Cartridge {
     fn cartridge_read(&mut self, addr: u32) -> Option<u8>;
     fn cartridge_write(&mut self, addr: u32, data: u8);
+    fn cartridge_tick(&mut self);
 }
 
 pub trait Interrupt {
     fn get_nmi_flag(&mut self) -> bool;
-    fn set_nmi_flag(&mut self, flag: bool);
-    fn nmi_occurred(&mut self) -> bool;
+    fn set_nmi_flag(&mut self, flag: bool, at: u64);
+    fn nmi_occurred(&mut self, poll: u64) -> bool;
     fn set_nmi_enable(&mut self, flag: bool);
     fn set_hv_irq_enable(&mut self, val: u8);
     fn get_hv_irq_enable(&self) -> u8;
@@ -442,14 +937,32 @@ pub trait Interrupt {
     fn get_h_count(&self) -> u16;
     fn set_v_count(&mut self, val: u16);
     fn get_v_count(&self) -> u16;
-    fn set_irq(&mut self, flag: bool);
-    fn irq_occurred(&self) -> bool;
+    fn set_irq(&mut self, flag: bool, at: u64);
+    fn irq_occurred(&self, poll: u64) -> bool;
+    fn get_irq_flag(&mut self) -> bool;
 }
 
 pub trait Spc {
     fn spc_read(&mut self, addr: u16) -> u8;
     fn spc_write(&mut self, addr: u16, data: u8);
     fn spc_tick(&mut self);
-
+    fn spc_sync(&mut self);
+    fn spc_state(&self) -> spc::SpcState;
+    fn spc_dsp_registers(&self) -> [u8; 0x80];
+    fn spc_ram(&mut self) -> &[u8];
+    fn spc_write_ram(&mut self, addr: u16, data: u8);
+    fn spc_write_dsp(&mut self, addr: u8, data: u8);
+    fn spc_instructions(&self) -> u64;
+
+    fn audio_buffer(&self) -> &[(i16, i16)];
     fn clear_audio_buffer(&mut self);
+    fn audio_timestamp(&self, index: usize) -> Option<u64>;
+    fn set_audio_sink(&mut self, sink: Option<Box<dyn spc::AudioSink + Send>>);
+    fn set_audio_output_mode(&mut self, mode: AudioOutputMode);
+    fn set_volume(&mut self, volume: u16);
+    fn set_muted(&mut self, muted: bool);
+    fn set_apu_fast_boot(&mut self, fast_boot: bool);
+    fn set_apu_relaxed_sync(&mut self, relaxed: bool);
+    #[cfg(feature = "std")]
+    fn set_apu_threaded(&mut self, threaded: bool);
 }
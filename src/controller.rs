@@ -1,4 +1,3 @@
-use sdl2::controller;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Key {
@@ -16,20 +15,143 @@ pub enum Key {
     R,
 }
 
+impl Key {
+    // Same order as the bits shifted out of the joypad, starting at bit 15
+    pub const ALL: [Key; 12] = [
+        Key::B,
+        Key::Y,
+        Key::Select,
+        Key::Start,
+        Key::Up,
+        Key::Down,
+        Key::Left,
+        Key::Right,
+        Key::A,
+        Key::X,
+        Key::L,
+        Key::R,
+    ];
+
+    // Bit in the joypad data (bit15: B ... bit4: R), as read from $4218-$421F
+    pub fn joypad_bit(&self) -> u16 {
+        match self {
+            Key::B => 1 << 15,
+            Key::Y => 1 << 14,
+            Key::Select => 1 << 13,
+            Key::Start => 1 << 12,
+            Key::Up => 1 << 11,
+            Key::Down => 1 << 10,
+            Key::Left => 1 << 9,
+            Key::Right => 1 << 8,
+            Key::A => 1 << 7,
+            Key::X => 1 << 6,
+            Key::L => 1 << 5,
+            Key::R => 1 << 4,
+        }
+    }
+}
+
+pub fn joypad_data(keys: &[Key]) -> u16 {
+    keys.iter().fold(0, |data, key| data | key.joypad_bit())
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeviceType {
+    None,
+    #[default]
+    Joypad,
+}
+
+// Polled by the bus whenever the controllers are latched (auto-joypad read and $4016 strobe)
+pub trait InputProvider {
+    fn device_type(&self, _port: usize) -> DeviceType {
+        DeviceType::Joypad
+    }
+
+    // Joypad data for `port` (0: $4016, 1: $4017). `line` 1 is the second data line used by multitaps
+    fn poll(&mut self, port: usize, line: usize) -> u16;
+}
+
+// A peripheral on a controller port driven through the raw lines, for what the joypad
+// emulation doesn't cover. Plugged in with Snes::set_serial_device, it replaces the joypad
+pub trait SerialDevice {
+    // Latch line, $4016 bit0 for both ports. Auto joypad read pulses it too
+    fn set_latch(&mut self, high: bool);
+
+    // One clock pulse, on every $4016/$4017 read and 16 times per auto joypad read. Returns
+    // data lines 1 and 2 in bit0 and bit1 as they were before the pulse
+    fn clock(&mut self) -> u8;
+
+    // Pin 6, driven by $4201 bit6 (port 1) or bit7 (port 2)
+    fn set_io_bit(&mut self, _high: bool) {}
+
+    // Pin 6 as $4213 reads it. It is open collector, so the device can only pull it low
+    fn io_bit(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "instrumentation")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEventKind {
+    // Inputs sampled from the provider ($4016 strobe or auto-joypad read)
+    Latch,
+    // $4016/$4017 serial read
+    SerialRead(u16),
+    // $4218-$421F read
+    AutoReadRegister(u16),
+}
+
+#[cfg(feature = "instrumentation")]
+#[derive(Debug, Clone, Copy)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub x: u16,
+    pub y: u16,
+    pub kind: InputEventKind,
+}
+
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Controller {
     pub data: [u16; 2],
+    pub device: DeviceType,
     pos: usize,
     clk: bool,
+
+    // Latches per on/off phase, indexed by Key (0: disabled)
+    turbo_rate: [[u8; 12]; 2],
+    latch_count: u32,
 }
 
 impl Controller {
+    pub fn set_turbo(&mut self, line: usize, key: Key, rate: u8) {
+        self.turbo_rate[line][key as usize] = rate;
+    }
+
+    pub fn latch(&mut self, data: [u16; 2]) {
+        self.latch_count = self.latch_count.wrapping_add(1);
+        for (line, mut data) in data.into_iter().enumerate() {
+            for key in Key::ALL {
+                let rate = self.turbo_rate[line][key as usize] as u32;
+                if rate != 0 && (self.latch_count / rate) % 2 == 1 {
+                    data &= !key.joypad_bit();
+                }
+            }
+            self.data[line] = data;
+        }
+    }
+
     pub fn initialize(&mut self) {
         self.pos = 0;
         // self.flag = false;
     }
 
     pub fn read(&mut self) -> u8 {
+        if self.device == DeviceType::None {
+            return 0;
+        }
         let ret = if self.pos > 15 {
             0b0000_0011
         } else {
@@ -0,0 +1,66 @@
+// Scheduling of chips next to the CPU, e.g. the coprocessor of a cartridge board. A chip
+// runs on its own clock and is caught up lazily from Mapper::tick, which is called after
+// every CPU instruction and before every access to an address in Mapper::is_shared. The
+// CPU therefore never sees shared memory or registers from a chip that is behind it
+pub struct ClockDomain {
+    // `cycles` chip cycles every `master_clocks` master clocks
+    cycles: u64,
+    master_clocks: u64,
+    last_tick: u64,
+    // Master clocks times `cycles` not yet converted to chip cycles
+    residual: u64,
+    // Cycles the chip ran beyond what it was asked for
+    ahead: u64,
+}
+
+impl ClockDomain {
+    // E.g. 1 and 2 for a chip at half the 21.477MHz master clock. `now` is the master clock
+    // the chip starts at
+    pub fn new(cycles: u64, master_clocks: u64, now: u64) -> ClockDomain {
+        assert!(cycles != 0 && master_clocks != 0, "invalid clock ratio");
+        ClockDomain {
+            cycles,
+            master_clocks,
+            last_tick: now,
+            residual: 0,
+            ahead: 0,
+        }
+    }
+
+    // For chips with a programmable clock, takes effect from the next tick
+    pub fn set_ratio(&mut self, cycles: u64, master_clocks: u64) {
+        assert!(cycles != 0 && master_clocks != 0, "invalid clock ratio");
+        self.residual = self.residual / self.cycles * cycles;
+        self.cycles = cycles;
+        self.master_clocks = master_clocks;
+    }
+
+    // Chip cycles to run to reach master clock `now`, less what it already ran ahead
+    pub fn advance(&mut self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.last_tick);
+        self.last_tick = now;
+        self.residual += elapsed * self.cycles;
+        let owed = self.residual / self.master_clocks;
+        self.residual -= owed * self.master_clocks;
+
+        let skipped = owed.min(self.ahead);
+        self.ahead -= skipped;
+        owed - skipped
+    }
+
+    // Run the chip up to `now`. `run` gets the cycles to run and returns how many it did,
+    // which can be more when the chip only stops between instructions
+    pub fn catch_up(&mut self, now: u64, mut run: impl FnMut(u64) -> u64) {
+        let owed = self.advance(now);
+        if owed != 0 {
+            self.ahead += run(owed).saturating_sub(owed);
+        }
+    }
+
+    // Let time pass without running the chip, e.g. while it is held in reset
+    pub fn idle(&mut self, now: u64) {
+        self.last_tick = now;
+        self.residual = 0;
+        self.ahead = 0;
+    }
+}
@@ -1,18 +1,75 @@
-#[derive(Debug, Default)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Counter {
     counter: u64,
 
     pub frame: u64,
     pub x: u64,
     pub y: u64,
+
+    // Frontend setting, not part of savestates
+    #[cfg_attr(feature = "serde", serde(skip, default = "default_cpu_multiplier"))]
+    cpu_multiplier: u64,
+    cpu_remainder: u64,
+    // Start of the last CPU cycle, where the CPU polls for interrupts
+    cpu_cycle_start: u64,
+    // CPU cycles since power on, not counting DMA
+    cpu_cycles: u64,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Counter {
+            counter: 0,
+            frame: 0,
+            x: 0,
+            y: 0,
+            cpu_multiplier: 1,
+            cpu_remainder: 0,
+            cpu_cycle_start: 0,
+            cpu_cycles: 0,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn default_cpu_multiplier() -> u64 {
+    1
 }
 
 impl Counter {
+    #[cfg(feature = "serde")]
+    pub fn swap_host_state(&mut self, other: &mut Counter) {
+        core::mem::swap(&mut self.cpu_multiplier, &mut other.cpu_multiplier);
+    }
+
     pub fn elapse(&mut self, clock: u64) {
         self.counter += clock;
     }
 
+    // One call per CPU cycle, which takes 1/cpu_multiplier of `clock`
+    pub fn elapse_cpu(&mut self, clock: u64) {
+        self.cpu_cycle_start = self.counter;
+        self.cpu_cycles += 1;
+        let clock = clock + self.cpu_remainder;
+        self.counter += clock / self.cpu_multiplier;
+        self.cpu_remainder = clock % self.cpu_multiplier;
+    }
+
+    pub fn set_cpu_multiplier(&mut self, multiplier: u64) {
+        self.cpu_multiplier = multiplier.max(1);
+        self.cpu_remainder = 0;
+    }
+
     pub fn now(&self) -> u64 {
         self.counter
     }
+
+    pub fn cpu_cycle_start(&self) -> u64 {
+        self.cpu_cycle_start
+    }
+
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu_cycles
+    }
 }
@@ -1,12 +1,13 @@
 use crate::context;
 
 use log::{debug, info};
-trait Context: context::Bus + context::Timing + context::Interrupt {}
+pub(crate) trait Context: context::Bus + context::Timing + context::Interrupt {}
 impl<T: context::Bus + context::Timing + context::Interrupt> Context for T {}
 
 const CPU_CYCLE: u64 = 6;
 const RESET_VECTOR: u16 = 0xFFFC;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cpu {
     a: u16,
     x: u16,
@@ -52,7 +53,54 @@ impl Default for Cpu {
     }
 }
 
+// Register file for debuggers and the single instruction tests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    pub a: u16,
+    pub x: u16,
+    pub y: u16,
+    pub pc: u16,
+    pub s: u16,
+    pub p: u8,
+    pub d: u16,
+    pub db: u8,
+    pub pb: u8,
+    pub e: bool,
+}
+
+impl Cpu {
+    pub fn registers(&self) -> RegisterSnapshot {
+        RegisterSnapshot {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            pc: self.pc,
+            s: self.s,
+            p: self.p.into(),
+            d: self.d,
+            db: self.db,
+            pb: self.pb,
+            e: self.e,
+        }
+    }
+
+    #[cfg(feature = "opcode-tests")]
+    pub fn set_registers(&mut self, registers: &RegisterSnapshot) {
+        self.a = registers.a;
+        self.x = registers.x;
+        self.y = registers.y;
+        self.pc = registers.pc;
+        self.s = registers.s;
+        self.p = registers.p.into();
+        self.d = registers.d;
+        self.db = registers.db;
+        self.pb = registers.pb;
+        self.e = registers.e;
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Status {
     c: bool,
     z: bool,
@@ -265,7 +313,7 @@ enum BranchType {
 }
 
 impl Cpu {
-    pub fn reset(&mut self, ctx: &mut impl Context) {
+    pub(crate) fn reset(&mut self, ctx: &mut impl Context) {
         self.pc = WarpAddress {
             addr: RESET_VECTOR as u32,
             mode: WarpMode::NoWarp,
@@ -280,6 +328,8 @@ impl Cpu {
         self.db = 0;
         self.pb = 0;
         self.e = true;
+        self.stop = false;
+        self.halt = false;
         ctx.elapse(170);
     }
 
@@ -336,14 +386,23 @@ impl Cpu {
 
     fn set_e(&mut self, data: bool) {
         self.e = data;
+        if self.e {
+            self.s = 0x100 | self.s & 0xFF;
+        }
+        self.set_p(self.p.into());
+    }
+
+    // Every write of the whole P goes through here. M and X stay set in emulation mode, and
+    // setting X drops the high bytes of the index registers right away
+    fn set_p(&mut self, data: u8) {
+        self.p = data.into();
         if self.e {
             self.p.m = true;
             self.p.x = true;
-
-            self.x = self.x & 0xFF;
-            self.y = self.y & 0xFF;
-
-            self.s = 0x100 | self.s & 0xFF;
+        }
+        if self.p.x {
+            self.x &= 0xFF;
+            self.y &= 0xFF;
         }
     }
 
@@ -444,15 +503,45 @@ impl Cpu {
         &mut self,
         addressing_mode: AddressingMode,
         ctx: &mut impl Context,
+    ) -> WarpAddress {
+        self.effective_address(addressing_mode, false, ctx)
+    }
+
+    // For stores and read-modify-write instructions, which always spend the indexing cycle
+    fn get_write_address(
+        &mut self,
+        addressing_mode: AddressingMode,
+        ctx: &mut impl Context,
+    ) -> WarpAddress {
+        self.effective_address(addressing_mode, true, ctx)
+    }
+
+    // Indexing takes an extra cycle when it crosses a page or the index registers are
+    // 16 bit, and always for writes
+    fn index_cycle(&self, ctx: &mut impl Context, base: u32, indexed: u32, write: bool) {
+        if write || !self.is_xy_register_8bit() || base >> 8 != indexed >> 8 {
+            ctx.elapse_cpu(CPU_CYCLE);
+        }
+    }
+
+    fn effective_address(
+        &mut self,
+        addressing_mode: AddressingMode,
+        write: bool,
+        ctx: &mut impl Context,
     ) -> WarpAddress {
         match addressing_mode {
-            // AddressingMode::Immediate => {
-            //     let addr = self.get_pc23();
-            //     WarpAddress {
-            //         addr,
-            //         mode: WarpMode::NoWarp,
-            //     }
-            // }
+            // Immediate operands have no address, see read_operand_8/16
+            AddressingMode::Immediate
+            | AddressingMode::Accumulator
+            | AddressingMode::Implied
+            | AddressingMode::Stack
+            | AddressingMode::BlockMove => {
+                unreachable!(
+                    "AddressingMode {:?} has no effective address",
+                    addressing_mode
+                )
+            }
             AddressingMode::Absolute => {
                 // let addr = (self.pb as u32) << 16 | self.fetch_16(ctx) as u32;
                 let addr = (self.db as u32) << 16 | self.fetch_16(ctx) as u32;
@@ -477,7 +566,7 @@ impl Cpu {
                     }
                 } else {
                     if self.d & 0xFF != 0 {
-                        ctx.elapse(CPU_CYCLE);
+                        ctx.elapse_cpu(CPU_CYCLE);
                     }
                     WarpAddress {
                         addr: self.d as u32,
@@ -486,8 +575,6 @@ impl Cpu {
                     .offset(offset)
                 }
             }
-            // Accumulator
-            // Implied
             AddressingMode::DirectIndirectIndexedY => {
                 let offset = self.fetch_8(ctx);
                 let direct_addr = if self.is_wrap8() {
@@ -498,7 +585,7 @@ impl Cpu {
                     .read_16(ctx)
                 } else {
                     if self.d & 0xFF != 0 {
-                        ctx.elapse(CPU_CYCLE);
+                        ctx.elapse_cpu(CPU_CYCLE);
                     }
                     WarpAddress {
                         addr: self.d as u32,
@@ -507,11 +594,13 @@ impl Cpu {
                     .offset(offset as u16)
                     .read_16(ctx)
                 };
-                WarpAddress {
+                let base = WarpAddress {
                     addr: (self.db as u32) << 16 | direct_addr as u32,
                     mode: WarpMode::NoWarp,
-                }
-                .offset(self.y)
+                };
+                let indexed = base.offset(self.y);
+                self.index_cycle(ctx, base.addr, indexed.addr, write);
+                indexed
             }
             AddressingMode::DirectIndirectIndexedLongY => {
                 let offset = self.fetch_8(ctx);
@@ -523,7 +612,7 @@ impl Cpu {
                     .read_24(ctx)
                 } else {
                     if self.d & 0xFF != 0 {
-                        ctx.elapse(CPU_CYCLE);
+                        ctx.elapse_cpu(CPU_CYCLE);
                     }
                     WarpAddress {
                         addr: self.d as u32,
@@ -541,7 +630,7 @@ impl Cpu {
             AddressingMode::DirectIndexedIndirect => {
                 let offset = self.fetch_8(ctx);
                 if self.d & 0xFF != 0 {
-                    ctx.elapse(CPU_CYCLE);
+                    ctx.elapse_cpu(CPU_CYCLE);
                 }
                 let mid_addr = if self.is_wrap8() {
                     WarpAddress {
@@ -567,9 +656,9 @@ impl Cpu {
             }
             AddressingMode::DirectX => {
                 let offset = self.fetch_8(ctx) as u16;
-                ctx.elapse(CPU_CYCLE);
+                ctx.elapse_cpu(CPU_CYCLE);
                 if self.d & 0xFF != 0 {
-                    ctx.elapse(CPU_CYCLE);
+                    ctx.elapse_cpu(CPU_CYCLE);
                 }
                 if self.is_wrap8() {
                     WarpAddress {
@@ -589,8 +678,9 @@ impl Cpu {
             }
             AddressingMode::DirectY => {
                 let offset = self.fetch_8(ctx) as u16;
+                ctx.elapse_cpu(CPU_CYCLE);
                 if self.d & 0xFF != 0 {
-                    ctx.elapse(CPU_CYCLE);
+                    ctx.elapse_cpu(CPU_CYCLE);
                 }
                 if self.is_wrap8() {
                     WarpAddress {
@@ -610,11 +700,13 @@ impl Cpu {
             }
             AddressingMode::AbsoluteX => {
                 let addr = self.fetch_16(ctx);
-                WarpAddress {
+                let base = WarpAddress {
                     addr: (self.db as u32) << 16 | addr as u32,
                     mode: WarpMode::NoWarp,
-                }
-                .offset(self.x)
+                };
+                let indexed = base.offset(self.x);
+                self.index_cycle(ctx, base.addr, indexed.addr, write);
+                indexed
             }
             AddressingMode::AbsoluteLongX => {
                 let addr = self.fetch_24(ctx);
@@ -626,20 +718,43 @@ impl Cpu {
             }
             AddressingMode::AbsoluteY => {
                 let addr = self.fetch_16(ctx);
-                WarpAddress {
+                let base = WarpAddress {
                     addr: (self.db as u32) << 16 | addr as u32,
                     mode: WarpMode::NoWarp,
+                };
+                let indexed = base.offset(self.y);
+                self.index_cycle(ctx, base.addr, indexed.addr, write);
+                indexed
+            }
+            // Branch target. The displacement is relative to the next instruction and the
+            // target stays in the program bank
+            AddressingMode::Relative => {
+                let disp = self.fetch_8(ctx) as i8 as u16;
+                WarpAddress {
+                    addr: (self.pb as u32) << 16 | self.pc.wrapping_add(disp) as u32,
+                    mode: WarpMode::Warp16bit,
+                }
+            }
+            AddressingMode::RelativeLong => {
+                let disp = self.fetch_16(ctx);
+                WarpAddress {
+                    addr: (self.pb as u32) << 16 | self.pc.wrapping_add(disp) as u32,
+                    mode: WarpMode::Warp16bit,
+                }
+            }
+            // Location of the pointer, which is always in bank 0. JMP reads 16 bits, JML 24
+            AddressingMode::AbsoluteIndirect => {
+                let addr = self.fetch_16(ctx);
+                WarpAddress {
+                    addr: addr as u32,
+                    mode: WarpMode::Warp16bit,
                 }
-                .offset(self.y)
             }
-            // Relative
-            // RelativeLong
-            // AbsoluteIndirect
             AddressingMode::DirectIndirect => {
                 let offset = self.fetch_8(ctx) as u16;
 
                 if self.d & 0xFF != 0 {
-                    ctx.elapse(CPU_CYCLE);
+                    ctx.elapse_cpu(CPU_CYCLE);
                 }
                 // let addr = WarpAddress {
                 //     addr: self.d as u32,
@@ -670,7 +785,7 @@ impl Cpu {
             AddressingMode::DirectIndirectLong => {
                 let offset = self.fetch_8(ctx) as u16;
                 if self.d & 0xFF != 0 {
-                    ctx.elapse(CPU_CYCLE);
+                    ctx.elapse_cpu(CPU_CYCLE);
                 }
                 let addr = if self.is_wrap8() {
                     WarpAddress {
@@ -699,7 +814,6 @@ impl Cpu {
                     mode: WarpMode::Warp16bit,
                 }
             }
-            // Stack
             AddressingMode::StackRelative => {
                 let offset = self.fetch_8(ctx) as u16;
                 WarpAddress {
@@ -709,7 +823,7 @@ impl Cpu {
             }
             AddressingMode::StackRelativeIndirectIndexed => {
                 let offset = self.fetch_8(ctx) as u16;
-                ctx.elapse(CPU_CYCLE);
+                ctx.elapse_cpu(CPU_CYCLE);
                 let addr = WarpAddress {
                     addr: self.s.wrapping_add(offset) as u32,
                     mode: WarpMode::Warp16bit,
@@ -721,41 +835,57 @@ impl Cpu {
                 }
                 .offset(self.y)
             }
-            // BlockMove
-            _ => unimplemented!("AddressingMode: {:?}", addressing_mode),
         }
     }
 
-    pub fn excecute_instruction(&mut self, ctx: &mut impl Context) {
+    // Operand of a read instruction, fetched after the opcode for Immediate
+    fn read_operand_8(&mut self, addressing_mode: AddressingMode, ctx: &mut impl Context) -> u8 {
+        if addressing_mode == AddressingMode::Immediate {
+            self.fetch_8(ctx)
+        } else {
+            self.get_warp_address(addressing_mode, ctx).read_8(ctx)
+        }
+    }
+
+    fn read_operand_16(&mut self, addressing_mode: AddressingMode, ctx: &mut impl Context) -> u16 {
+        if addressing_mode == AddressingMode::Immediate {
+            self.fetch_16(ctx)
+        } else {
+            self.get_warp_address(addressing_mode, ctx).read_16(ctx)
+        }
+    }
+
+    pub(crate) fn excecute_instruction(&mut self, ctx: &mut impl Context) {
         self.excecute_instruction_(ctx);
         self.prev_counter = ctx.now();
     }
 
-    pub fn excecute_instruction_(&mut self, ctx: &mut impl Context) {
+    pub(crate) fn excecute_instruction_(&mut self, ctx: &mut impl Context) {
         if self.prev_counter < ctx.now() {
             // debug!("prev_couter: {}, now: {}", self.prev_counter, ctx.now());
             return;
         }
 
-        if ctx.nmi_occurred() {
+        let poll = ctx.counter().cpu_cycle_start();
+        if ctx.nmi_occurred(poll) {
             let _ = ctx.bus_read(self.get_pc24());
-            ctx.elapse(CPU_CYCLE);
+            ctx.elapse_cpu(CPU_CYCLE);
             self.exeption(Exeption::Nmi, ctx);
             return;
         }
 
-        if ctx.irq_occurred() && !self.p.i {
+        if ctx.irq_occurred(poll) && !self.p.i {
             let _ = ctx.bus_read(self.get_pc24());
-            ctx.elapse(CPU_CYCLE);
+            ctx.elapse_cpu(CPU_CYCLE);
             self.exeption(Exeption::Irq, ctx);
             return;
         }
 
         if self.halt {
-            if ctx.irq_occurred() {
+            if ctx.irq_occurred(ctx.now()) {
                 self.halt = false;
             } else {
-                ctx.elapse(CPU_CYCLE);
+                ctx.elapse_cpu(CPU_CYCLE);
                 return;
             }
         }
@@ -962,9 +1092,9 @@ impl Cpu {
             0x9E => self.stz(ctx, AddressingMode::AbsoluteX),
             0x9F => self.sta(ctx, AddressingMode::AbsoluteLongX),
 
-            0xA0 => self.ldy_imm(ctx),
+            0xA0 => self.ldy(ctx, AddressingMode::Immediate),
             0xA1 => self.lda(ctx, AddressingMode::DirectIndexedIndirect),
-            0xA2 => self.ldx_imm(ctx),
+            0xA2 => self.ldx(ctx, AddressingMode::Immediate),
             0xA3 => self.lda(ctx, AddressingMode::StackRelative),
             0xA4 => self.ldy(ctx, AddressingMode::Direct),
             0xA5 => self.lda(ctx, AddressingMode::Direct),
@@ -972,7 +1102,7 @@ impl Cpu {
             0xA7 => self.lda(ctx, AddressingMode::DirectIndirectLong),
 
             0xA8 => self.tay(ctx),
-            0xA9 => self.lda_imm(ctx),
+            0xA9 => self.lda(ctx, AddressingMode::Immediate),
             0xAA => self.tax(ctx),
             0xAB => self.plb(ctx),
             0xAC => self.ldy(ctx, AddressingMode::Absolute),
@@ -1134,7 +1264,7 @@ impl Cpu {
             self.y = data;
             self.set_nz(data);
         }
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode: AA
@@ -1148,7 +1278,7 @@ impl Cpu {
             self.x = data;
             self.set_nz(data);
         }
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode BA
@@ -1162,7 +1292,7 @@ impl Cpu {
             self.x = data;
             self.set_nz(data);
         }
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode 98
@@ -1180,7 +1310,7 @@ impl Cpu {
         // let data = if self.p.m { self.y & 0xFF } else { self.y };
         // self.a = data;
         // self.set_nz(data);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode 8A
@@ -1195,7 +1325,7 @@ impl Cpu {
             self.a = data;
             self.set_nz(data);
         }
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode 9A
@@ -1205,7 +1335,7 @@ impl Cpu {
         } else {
             self.s = self.x;
         }
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode 9B
@@ -1223,7 +1353,7 @@ impl Cpu {
         // let data = if self.p.x { self.x & 0xFF } else { self.x };
         // self.y = data;
         // self.set_nz(data);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode BB
@@ -1240,28 +1370,28 @@ impl Cpu {
         // let data = if self.p.x { self.y & 0xFF } else { self.y };
         // self.x = data;
         // self.set_nz(data);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode 7B
     fn tdc(&mut self, ctx: &mut impl Context) {
         self.a = self.d;
         self.set_nz(self.a);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode 5B
     fn tcd(&mut self, ctx: &mut impl Context) {
         self.d = self.a;
         self.set_nz(self.d);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode 3B
     fn tsc(&mut self, ctx: &mut impl Context) {
         self.a = self.s;
         self.set_nz(self.a);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     // opcode 1B
@@ -1271,90 +1401,47 @@ impl Cpu {
         } else {
             self.s = self.a;
         }
-        ctx.elapse(CPU_CYCLE);
-    }
-
-    // opcode A9
-    fn lda_imm(&mut self, ctx: &mut impl Context) {
-        if self.is_a_register_8bit() {
-            let data = self.fetch_8(ctx);
-            self.set_nz(data);
-            // self.a = data as u16;
-            self.a = (self.a & 0xFF00) | data as u16;
-        } else {
-            let data = self.fetch_16(ctx);
-            self.set_nz(data);
-            self.a = data;
-        }
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     fn lda(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
-        // ctx.elapse(CPU_CYCLE);
         if self.is_a_register_8bit() {
-            let data = addr.read_8(ctx);
+            let data = self.read_operand_8(addressing_mode, ctx);
             self.set_nz(data);
-            // self.a = data as u16;
             self.a = (self.a & 0xFF00) | data as u16;
         } else {
-            let data = addr.read_16(ctx);
+            let data = self.read_operand_16(addressing_mode, ctx);
             self.set_nz(data);
             self.a = data;
         }
     }
 
-    fn ldx_imm(&mut self, ctx: &mut impl Context) {
-        if self.is_xy_register_8bit() {
-            let data = self.fetch_8(ctx);
-            self.set_nz(data);
-            self.x = data as u16;
-        } else {
-            let data = self.fetch_16(ctx);
-            self.set_nz(data);
-            self.x = data;
-        }
-    }
-
     fn ldx(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
         if self.is_xy_register_8bit() {
-            let data = addr.read_8(ctx);
+            let data = self.read_operand_8(addressing_mode, ctx);
             self.set_nz(data);
             self.x = data as u16;
         } else {
-            let data = addr.read_16(ctx);
+            let data = self.read_operand_16(addressing_mode, ctx);
             self.set_nz(data);
             self.x = data;
         }
     }
 
-    fn ldy_imm(&mut self, ctx: &mut impl Context) {
-        if self.is_xy_register_8bit() {
-            let data = self.fetch_8(ctx);
-            self.set_nz(data);
-            self.y = data as u16;
-        } else {
-            let data = self.fetch_16(ctx);
-            self.set_nz(data);
-            self.y = data;
-        }
-    }
-
     fn ldy(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
         if self.is_xy_register_8bit() {
-            let data = addr.read_8(ctx);
+            let data = self.read_operand_8(addressing_mode, ctx);
             self.set_nz(data);
             self.y = data as u16;
         } else {
-            let data = addr.read_16(ctx);
+            let data = self.read_operand_16(addressing_mode, ctx);
             self.set_nz(data);
             self.y = data;
         }
     }
 
     fn stz(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let addr = self.get_warp_address(addressing_mode, ctx);
+        let addr = self.get_write_address(addressing_mode, ctx);
         if self.is_memory_8bit() {
             addr.write_8(ctx, 0);
         } else {
@@ -1363,7 +1450,7 @@ impl Cpu {
     }
 
     fn sta(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let addr = self.get_warp_address(addressing_mode, ctx);
+        let addr = self.get_write_address(addressing_mode, ctx);
         if self.is_memory_8bit() {
             addr.write_8(ctx, self.a as u8);
         } else {
@@ -1372,7 +1459,7 @@ impl Cpu {
     }
 
     fn stx(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let addr = self.get_warp_address(addressing_mode, ctx);
+        let addr = self.get_write_address(addressing_mode, ctx);
         if self.is_xy_register_8bit() {
             addr.write_8(ctx, self.x as u8);
         } else {
@@ -1381,7 +1468,7 @@ impl Cpu {
     }
 
     fn sty(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let addr = self.get_warp_address(addressing_mode, ctx);
+        let addr = self.get_write_address(addressing_mode, ctx);
         if self.is_xy_register_8bit() {
             addr.write_8(ctx, self.y as u8);
         } else {
@@ -1390,7 +1477,7 @@ impl Cpu {
     }
 
     fn pha(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_a_register_8bit() {
             self.push_8(ctx, self.a as u8);
         } else {
@@ -1399,7 +1486,7 @@ impl Cpu {
     }
 
     fn phx(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_xy_register_8bit() {
             self.push_8(ctx, self.x as u8);
         } else {
@@ -1408,7 +1495,7 @@ impl Cpu {
     }
 
     fn phy(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_xy_register_8bit() {
             self.push_8(ctx, self.y as u8);
         } else {
@@ -1417,22 +1504,22 @@ impl Cpu {
     }
 
     fn php(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.push_8(ctx, self.p.into());
     }
 
     fn phb(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.push_8(ctx, self.db);
     }
 
     fn phk(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.push_8(ctx, self.pb);
     }
 
     fn phd(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.push_16(ctx, self.d);
     }
 
@@ -1448,13 +1535,15 @@ impl Cpu {
     }
 
     fn per(&mut self, ctx: &mut impl Context) {
-        let disp = self.fetch_16(ctx);
-        ctx.elapse(CPU_CYCLE);
-        self.push_16(ctx, self.pc.wrapping_add(disp));
+        let addr = self
+            .get_warp_address(AddressingMode::RelativeLong, ctx)
+            .unwrap() as u16;
+        ctx.elapse_cpu(CPU_CYCLE);
+        self.push_16(ctx, addr);
     }
 
     fn pla(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 2);
+        (0..2).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
         if self.is_a_register_8bit() {
             let data = self.pop_8(ctx);
             self.set_nz(data);
@@ -1468,7 +1557,7 @@ impl Cpu {
     }
 
     fn plx(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 2);
+        (0..2).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
         if self.is_xy_register_8bit() {
             let data = self.pop_8(ctx);
             self.set_nz(data);
@@ -1481,7 +1570,7 @@ impl Cpu {
     }
 
     fn ply(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 2);
+        (0..2).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
         if self.is_xy_register_8bit() {
             let data = self.pop_8(ctx);
             self.set_nz(data);
@@ -1494,31 +1583,28 @@ impl Cpu {
     }
 
     fn pld(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 2);
+        (0..2).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
         self.d = self.pop_16(ctx);
         self.set_nz(self.d);
     }
 
     fn plb(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 2);
+        (0..2).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
         self.db = self.pop_8(ctx);
         self.set_nz(self.db);
     }
 
     fn plp(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 2);
-        self.p = self.pop_8(ctx).into();
+        (0..2).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
+        let p = self.pop_8(ctx);
+        self.set_p(p);
     }
 
     fn alu(&mut self, ctx: &mut impl Context, alu_type: AluType, addressing_mode: AddressingMode) {
         // TODO alu_sub_xの実装を見直す
         if self.is_a_register_8bit() {
             let a = self.a as u8;
-            let b = if addressing_mode == AddressingMode::Immediate {
-                self.fetch_8(ctx)
-            } else {
-                self.get_warp_address(addressing_mode, ctx).read_8(ctx)
-            };
+            let b = self.read_operand_8(addressing_mode, ctx);
             let c = match alu_type {
                 AluType::Or => a | b,
                 AluType::And => a & b,
@@ -1534,12 +1620,7 @@ impl Cpu {
             self.set_nz(c);
         } else {
             let a = self.a;
-            // let b = self.get_warp_address(addressing_mode, ctx).read_16(ctx);
-            let b = if addressing_mode == AddressingMode::Immediate {
-                self.fetch_16(ctx)
-            } else {
-                self.get_warp_address(addressing_mode, ctx).read_16(ctx)
-            };
+            let b = self.read_operand_16(addressing_mode, ctx);
             let c = match alu_type {
                 AluType::Or => a | b,
                 AluType::And => a & b,
@@ -1793,12 +1874,7 @@ impl Cpu {
                 Register::Y => self.y as u8,
                 _ => unreachable!(),
             };
-            // let b = self.get_warp_address(addressing_mode, ctx).read_8(ctx);
-            let b = if addressing_mode == AddressingMode::Immediate {
-                self.fetch_8(ctx)
-            } else {
-                self.get_warp_address(addressing_mode, ctx).read_8(ctx)
-            };
+            let b = self.read_operand_8(addressing_mode, ctx);
             let (c, carry) = a.overflowing_sub(b);
             self.p.c = !carry;
             self.set_nz(c);
@@ -1808,12 +1884,7 @@ impl Cpu {
                 Register::Y => self.y,
                 _ => unreachable!(),
             };
-            // let b = self.get_warp_address(addressing_mode, ctx).read_16(ctx);
-            let b = if addressing_mode == AddressingMode::Immediate {
-                self.fetch_16(ctx)
-            } else {
-                self.get_warp_address(addressing_mode, ctx).read_16(ctx)
-            };
+            let b = self.read_operand_16(addressing_mode, ctx);
             let (c, carry) = a.overflowing_sub(b);
             self.p.c = !carry;
             self.set_nz(c);
@@ -1825,23 +1896,14 @@ impl Cpu {
             // TODO check for debug mode
             // let data = self.get_warp_address(addressing_mode, ctx).read_8(ctx);
             // info!("addr: {:06x}", addr.addr);
-            let data = if addressing_mode == AddressingMode::Immediate {
-                self.fetch_8(ctx)
-            } else {
-                self.get_warp_address(addressing_mode, ctx).read_8(ctx)
-            };
+            let data = self.read_operand_8(addressing_mode, ctx);
             if addressing_mode != AddressingMode::Immediate {
                 self.p.n = (data >> 7) & 1 == 1;
                 self.p.v = (data >> 6) & 1 == 1;
             }
             self.p.z = (self.a as u8) & data == 0;
         } else {
-            // let data = self.get_warp_address(addressing_mode, ctx).read_16(ctx);
-            let data = if addressing_mode == AddressingMode::Immediate {
-                self.fetch_16(ctx)
-            } else {
-                self.get_warp_address(addressing_mode, ctx).read_16(ctx)
-            };
+            let data = self.read_operand_16(addressing_mode, ctx);
             if addressing_mode != AddressingMode::Immediate {
                 self.p.n = (data >> 15) & 1 == 1;
                 self.p.v = (data >> 14) & 1 == 1;
@@ -1851,8 +1913,8 @@ impl Cpu {
     }
 
     fn inc(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
-        ctx.elapse(CPU_CYCLE);
+        let mut addr = self.get_write_address(addressing_mode, ctx);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_memory_8bit() {
             let data = addr.read_8(ctx);
             let result = data.wrapping_add(1);
@@ -1867,7 +1929,7 @@ impl Cpu {
     }
 
     fn inx(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_xy_register_8bit() {
             let data = self.x as u8;
             let result = data.wrapping_add(1);
@@ -1882,7 +1944,7 @@ impl Cpu {
     }
 
     fn iny(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_xy_register_8bit() {
             let data = self.y as u8;
             let result = data.wrapping_add(1);
@@ -1897,7 +1959,7 @@ impl Cpu {
     }
 
     fn ina(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_a_register_8bit() {
             let data = self.a as u8;
             let result = data.wrapping_add(1);
@@ -1913,8 +1975,8 @@ impl Cpu {
     }
 
     fn dec(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
-        ctx.elapse(CPU_CYCLE);
+        let mut addr = self.get_write_address(addressing_mode, ctx);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_memory_8bit() {
             let data = addr.read_8(ctx);
             let result = data.wrapping_sub(1);
@@ -1929,7 +1991,7 @@ impl Cpu {
     }
 
     fn dex(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_xy_register_8bit() {
             let data = self.x as u8;
             let result = data.wrapping_sub(1);
@@ -1944,7 +2006,7 @@ impl Cpu {
     }
 
     fn dey(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_xy_register_8bit() {
             let data = self.y as u8;
             let result = data.wrapping_sub(1);
@@ -1959,7 +2021,7 @@ impl Cpu {
     }
 
     fn dea(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_a_register_8bit() {
             let data = self.a as u8;
             let result = data.wrapping_sub(1);
@@ -1975,7 +2037,7 @@ impl Cpu {
     }
 
     fn tsb(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
+        let mut addr = self.get_write_address(addressing_mode, ctx);
         if self.is_a_register_8bit() {
             let data = addr.read_8(ctx);
             self.p.z = (self.a as u8) & data == 0;
@@ -1988,7 +2050,7 @@ impl Cpu {
     }
 
     fn trb(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
+        let mut addr = self.get_write_address(addressing_mode, ctx);
         if self.is_a_register_8bit() {
             let data = addr.read_8(ctx);
             self.p.z = (self.a as u8) & data == 0;
@@ -2001,7 +2063,7 @@ impl Cpu {
     }
 
     fn asl_a(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_a_register_8bit() {
             let data = self.a as u8;
             self.p.c = (data >> 7) & 1 == 1;
@@ -2019,8 +2081,8 @@ impl Cpu {
     }
 
     fn asl_with_addressing(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        ctx.elapse(CPU_CYCLE);
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
+        ctx.elapse_cpu(CPU_CYCLE);
+        let mut addr = self.get_write_address(addressing_mode, ctx);
         if self.is_memory_8bit() {
             let data = addr.read_8(ctx);
             self.p.c = (data >> 7) & 1 == 1;
@@ -2037,7 +2099,7 @@ impl Cpu {
     }
 
     fn lsr_a(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_a_register_8bit() {
             let data = self.a as u8;
             self.p.c = data & 1 == 1;
@@ -2055,8 +2117,8 @@ impl Cpu {
     }
 
     fn lsr_with_addressing(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        ctx.elapse(CPU_CYCLE);
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
+        ctx.elapse_cpu(CPU_CYCLE);
+        let mut addr = self.get_write_address(addressing_mode, ctx);
         if self.is_memory_8bit() {
             let data = addr.read_8(ctx);
             self.p.c = data & 1 == 1;
@@ -2073,7 +2135,7 @@ impl Cpu {
     }
 
     fn rol_a(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_a_register_8bit() {
             let data = self.a as u8;
             let c = self.p.c as u8;
@@ -2093,8 +2155,8 @@ impl Cpu {
     }
 
     fn rol_with_addressing(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        ctx.elapse(CPU_CYCLE);
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
+        ctx.elapse_cpu(CPU_CYCLE);
+        let mut addr = self.get_write_address(addressing_mode, ctx);
         if self.is_memory_8bit() {
             let data = addr.read_8(ctx);
             let c = self.p.c as u8;
@@ -2113,7 +2175,7 @@ impl Cpu {
     }
 
     fn ror_a(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         if self.is_a_register_8bit() {
             let data = self.a as u8;
             let c = self.p.c as u8;
@@ -2133,8 +2195,8 @@ impl Cpu {
     }
 
     fn ror_with_addressing(&mut self, ctx: &mut impl Context, addressing_mode: AddressingMode) {
-        ctx.elapse(CPU_CYCLE);
-        let mut addr = self.get_warp_address(addressing_mode, ctx);
+        ctx.elapse_cpu(CPU_CYCLE);
+        let mut addr = self.get_write_address(addressing_mode, ctx);
         if self.is_memory_8bit() {
             let data = addr.read_8(ctx);
             let c = self.p.c as u8;
@@ -2153,18 +2215,27 @@ impl Cpu {
     }
 
     fn jmp_disp_8(&mut self, ctx: &mut impl Context) {
-        let disp = self.fetch_8(ctx) as i8 as u16;
-        ctx.elapse(CPU_CYCLE);
-        if self.e && (self.pc & 0xFF) + (disp & 0xFF) >= 0x100 {
-            ctx.elapse(CPU_CYCLE);
-        }
-        self.pc = self.pc.wrapping_add(disp);
+        let target = self
+            .get_warp_address(AddressingMode::Relative, ctx)
+            .unwrap() as u16;
+        self.branch_to(ctx, target);
     }
 
     fn jmp_disp_16(&mut self, ctx: &mut impl Context) {
-        let disp = self.fetch_16(ctx);
-        ctx.elapse(CPU_CYCLE);
-        self.pc = self.pc.wrapping_add(disp);
+        let target = self
+            .get_warp_address(AddressingMode::RelativeLong, ctx)
+            .unwrap() as u16;
+        ctx.elapse_cpu(CPU_CYCLE);
+        self.pc = target;
+    }
+
+    // Taken branches cost a cycle, and one more when crossing a page in emulation mode
+    fn branch_to(&mut self, ctx: &mut impl Context, target: u16) {
+        ctx.elapse_cpu(CPU_CYCLE);
+        if self.e && self.pc & 0xFF00 != target & 0xFF00 {
+            ctx.elapse_cpu(CPU_CYCLE);
+        }
+        self.pc = target;
     }
 
     fn jmp_abs(&mut self, ctx: &mut impl Context) {
@@ -2179,16 +2250,14 @@ impl Cpu {
     }
 
     fn jmp_nnnn(&mut self, ctx: &mut impl Context) {
-        let addr = WarpAddress {
-            addr: self.fetch_16(ctx) as u32,
-            mode: WarpMode::Warp16bit,
-        }
-        .read_16(ctx);
+        let addr = self
+            .get_warp_address(AddressingMode::AbsoluteIndirect, ctx)
+            .read_16(ctx);
         self.pc = addr;
     }
 
     fn jmp_nnnn_x(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         let addr = WarpAddress {
             addr: (self.pb as u32) << 16 | self.fetch_16(ctx) as u32,
             mode: WarpMode::Warp16bit,
@@ -2200,24 +2269,22 @@ impl Cpu {
     }
 
     fn jmp_far(&mut self, ctx: &mut impl Context) {
-        let addr = WarpAddress {
-            addr: self.fetch_16(ctx) as u32,
-            mode: WarpMode::Warp16bit,
-        }
-        .read_24(ctx);
+        let addr = self
+            .get_warp_address(AddressingMode::AbsoluteIndirect, ctx)
+            .read_24(ctx);
         self.pc = addr as u16;
         self.pb = (addr >> 16) as u8;
     }
 
     fn jsr_abs(&mut self, ctx: &mut impl Context) {
         let addr = self.fetch_16(ctx);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.push_16(ctx, self.pc.wrapping_sub(1));
         self.pc = addr;
     }
 
     fn jsl_far(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         let pc = self.fetch_16(ctx);
         let pb = self.fetch_8(ctx);
         self.push_8(ctx, self.pb);
@@ -2230,41 +2297,39 @@ impl Cpu {
         let mut addr = self
             .get_warp_address(AddressingMode::AbsoluteIndexedIndirect, ctx)
             .read_16(ctx);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.push_16(ctx, self.pc.wrapping_sub(1));
         self.pc = addr;
     }
 
     fn rti(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
-        self.p = self.pop_8(ctx).into();
+        ctx.elapse_cpu(CPU_CYCLE);
+        let p = self.pop_8(ctx);
+        self.set_p(p);
         self.pc = self.pop_16(ctx);
         if !self.e {
             self.pb = self.pop_8(ctx);
-            ctx.elapse(CPU_CYCLE);
+            ctx.elapse_cpu(CPU_CYCLE);
         }
     }
 
     fn rtl(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 2);
+        (0..2).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
         self.pc = self.pop_16(ctx).wrapping_add(1);
         self.pb = self.pop_8(ctx);
     }
 
     fn rts(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 3);
+        (0..3).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
         self.pc = self.pop_16(ctx).wrapping_add(1);
     }
 
     fn cond_branch(&mut self, ctx: &mut impl Context, condition: BranchType) {
-        let disp = self.fetch_8(ctx) as i8 as u16;
+        let target = self
+            .get_warp_address(AddressingMode::Relative, ctx)
+            .unwrap() as u16;
         if self.check_branch_condition(condition) {
-            ctx.elapse(CPU_CYCLE);
-            let prev_pc = self.pc;
-            self.pc = self.pc.wrapping_add(disp);
-            if self.e && prev_pc & 0xFF00 != self.pc & 0xFF00 {
-                ctx.elapse(CPU_CYCLE);
-            }
+            self.branch_to(ctx, target);
         }
     }
 
@@ -2282,56 +2347,56 @@ impl Cpu {
     }
 
     fn clc(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.p.c = false;
     }
 
     fn cli(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.p.i = false;
     }
 
     fn cld(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.p.d = false;
     }
 
     fn clv(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.p.v = false;
     }
 
     fn sec(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.p.c = true;
     }
 
     fn sei(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.p.i = true;
     }
 
     fn sed(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.p.d = true;
     }
 
     fn rep(&mut self, ctx: &mut impl Context) {
         let data = self.fetch_8(ctx);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         let p: u8 = self.p.into();
-        self.p = (p & !data).into();
+        self.set_p(p & !data);
     }
 
     fn sep(&mut self, ctx: &mut impl Context) {
         let data = self.fetch_8(ctx);
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         let p: u8 = self.p.into();
-        self.p = (p | data).into();
+        self.set_p(p | data);
     }
 
     fn xce(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         // std::mem::swap(&mut self.p.c, &mut self.e);
         let prev_c = self.p.c;
         self.p.c = self.e;
@@ -2339,18 +2404,18 @@ impl Cpu {
     }
 
     fn stp(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.stop = true;
     }
 
     fn xba(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
         self.a = self.a.rotate_right(8);
         self.set_nz(self.a as u8);
     }
 
     fn wai(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE * 2);
+        (0..2).for_each(|_| ctx.elapse_cpu(CPU_CYCLE));
         self.halt = true;
     }
 
@@ -2359,42 +2424,21 @@ impl Cpu {
     }
 
     fn nop(&mut self, ctx: &mut impl Context) {
-        ctx.elapse(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
     }
 
     fn mvp(&mut self, ctx: &mut impl Context) {
-        let dst_bank = self.fetch_8(ctx);
-        let src_bank = self.fetch_8(ctx);
-        self.db = dst_bank;
-
-        let data = WarpAddress {
-            addr: (src_bank as u32) << 16 | self.x as u32,
-            mode: WarpMode::NoWarp,
-        }
-        .read_8(ctx);
-
-        WarpAddress {
-            addr: (dst_bank as u32) << 16 | self.y as u32,
-            mode: WarpMode::NoWarp,
-        }
-        .write_8(ctx, data);
-
-        if self.is_xy_register_8bit() {
-            self.x = (self.x as u8).wrapping_sub(1) as u16;
-            self.y = (self.y as u8).wrapping_sub(1) as u16;
-        } else {
-            self.x = self.x.wrapping_sub(1);
-            self.y = self.y.wrapping_sub(1);
-        }
-
-        self.a = self.a.wrapping_sub(1);
-        if self.a != 0xFFFF {
-            self.pc = self.pc.wrapping_sub(3);
-        }
-        ctx.elapse(CPU_CYCLE * 2);
+        self.block_move(ctx, 0xFFFF);
     }
 
     fn mvn(&mut self, ctx: &mut impl Context) {
+        self.block_move(ctx, 1);
+    }
+
+    // Moves one byte and steps back onto the opcode until A wraps, so an IRQ or NMI can come
+    // between any two bytes and RTI resumes the move. Each byte takes all 7 cycles, with
+    // the operands fetched again. X and Y wrap within the bank, and within the page when 8 bit
+    fn block_move(&mut self, ctx: &mut impl Context, step: u16) {
         let dst_bank = self.fetch_8(ctx);
         let src_bank = self.fetch_8(ctx);
         self.db = dst_bank;
@@ -2412,17 +2456,137 @@ impl Cpu {
         .write_8(ctx, data);
 
         if self.is_xy_register_8bit() {
-            self.x = (self.x as u8).wrapping_add(1) as u16;
-            self.y = (self.y as u8).wrapping_add(1) as u16;
+            self.x = (self.x as u8).wrapping_add(step as u8) as u16;
+            self.y = (self.y as u8).wrapping_add(step as u8) as u16;
         } else {
-            self.x = self.x.wrapping_add(1);
-            self.y = self.y.wrapping_add(1);
+            self.x = self.x.wrapping_add(step);
+            self.y = self.y.wrapping_add(step);
         }
 
         self.a = self.a.wrapping_sub(1);
         if self.a != 0xFFFF {
             self.pc = self.pc.wrapping_sub(3);
         }
-        ctx.elapse(CPU_CYCLE * 2);
+        // Separate cycles, so interrupts are polled on the last one
+        ctx.elapse_cpu(CPU_CYCLE);
+        ctx.elapse_cpu(CPU_CYCLE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Bus as _;
+    use AddressingMode::*;
+
+    type Setup = fn(&mut Cpu, &mut [u8]);
+
+    // Resolve `mode` in native mode for an opcode at $7E:1000 followed by `operand`. `setup`
+    // adjusts the registers and WRAM, whose first 8KB are also bank 0
+    fn resolve(mode: AddressingMode, operand: &[u8], setup: Setup) -> u32 {
+        let mut ctx = context::Context::for_test();
+        let mut cpu = Cpu {
+            pb: 0x7E,
+            pc: 0x1001,
+            e: false,
+            ..Cpu::default()
+        };
+        let wram = ctx.inner1.wram_mut();
+        wram[0x1001..][..operand.len()].copy_from_slice(operand);
+        setup(&mut cpu, wram);
+        cpu.effective_address(mode, false, &mut ctx.inner1).addr
+    }
+
+    #[test]
+    fn effective_address() {
+        #[rustfmt::skip]
+        let cases: &[(AddressingMode, &[u8], Setup, u32)] = &[
+            (Absolute, &[0x56, 0x34], |cpu, _| cpu.db = 0x12, 0x123456),
+            (AbsoluteLong, &[0x56, 0x34, 0x12], |_, _| {}, 0x123456),
+            (AbsoluteX, &[0xFF, 0xFF], |cpu, _| (cpu.db, cpu.x) = (0x7E, 2), 0x7F0001),
+            (AbsoluteY, &[0x00, 0x80], |cpu, _| (cpu.db, cpu.y) = (0x12, 0x10), 0x128010),
+            (AbsoluteLongX, &[0xFF, 0xFF, 0x7E], |cpu, _| cpu.x = 2, 0x7F0001),
+            (Direct, &[0x20], |cpu, _| cpu.d = 0x0100, 0x000120),
+            (Direct, &[0x20], |cpu, _| cpu.d = 0xFFF0, 0x000010),
+            (DirectX, &[0xFE], |cpu, _| (cpu.d, cpu.x) = (0x0100, 5), 0x000203),
+            (DirectY, &[0xFE], |cpu, _| (cpu.d, cpu.y) = (0x0100, 5), 0x000203),
+            // Emulation mode with the direct page on a page boundary wraps within the page
+            (DirectX, &[0xFE], |cpu, _| (cpu.e, cpu.d, cpu.x) = (true, 0x0100, 5), 0x000103),
+            (DirectY, &[0xFE], |cpu, _| (cpu.e, cpu.d, cpu.y) = (true, 0x0100, 5), 0x000103),
+            (
+                DirectIndirect,
+                &[0x10],
+                |cpu, wram| {
+                    cpu.db = 0x7E;
+                    wram[0x10..0x12].copy_from_slice(&[0x34, 0x12]);
+                },
+                0x7E1234,
+            ),
+            (
+                DirectIndirectLong,
+                &[0x10],
+                |_, wram| wram[0x10..0x13].copy_from_slice(&[0x56, 0x34, 0x12]),
+                0x123456,
+            ),
+            (
+                DirectIndexedIndirect,
+                &[0x10],
+                |cpu, wram| {
+                    (cpu.db, cpu.x) = (0x7E, 4);
+                    wram[0x14..0x16].copy_from_slice(&[0x34, 0x12]);
+                },
+                0x7E1234,
+            ),
+            (
+                DirectIndirectIndexedY,
+                &[0x10],
+                |cpu, wram| {
+                    (cpu.db, cpu.y) = (0x7E, 0x10);
+                    wram[0x10..0x12].copy_from_slice(&[0xF8, 0xFF]);
+                },
+                0x7F0008,
+            ),
+            (
+                DirectIndirectIndexedLongY,
+                &[0x10],
+                |cpu, wram| {
+                    cpu.y = 1;
+                    wram[0x10..0x13].copy_from_slice(&[0xFF, 0xFF, 0x7E]);
+                },
+                0x7F0000,
+            ),
+            // Relative to the next instruction, within the program bank
+            (Relative, &[0xFE], |_, _| {}, 0x7E1000),
+            (Relative, &[0x10], |_, _| {}, 0x7E1012),
+            (
+                Relative,
+                &[],
+                |cpu, wram| {
+                    cpu.pc = 0xFFFF;
+                    wram[0xFFFF] = 0x10;
+                },
+                0x7E0010,
+            ),
+            (RelativeLong, &[0xFD, 0xFF], |_, _| {}, 0x7E1000),
+            (RelativeLong, &[0x00, 0xF0], |_, _| {}, 0x7E0003),
+            // The pointer is in bank 0 for JMP (a) and in the program bank for JMP (a,X)
+            (AbsoluteIndirect, &[0x34, 0x12], |cpu, _| cpu.db = 0x12, 0x001234),
+            (AbsoluteIndexedIndirect, &[0xFE, 0xFF], |cpu, _| cpu.x = 4, 0x7E0002),
+            (StackRelative, &[0x05], |cpu, _| cpu.s = 0x1FF0, 0x001FF5),
+            (StackRelative, &[0x20], |cpu, _| cpu.s = 0xFFF0, 0x000010),
+            (
+                StackRelativeIndirectIndexed,
+                &[0x02],
+                |cpu, wram| {
+                    (cpu.s, cpu.db, cpu.y) = (0x1FF0, 0x7E, 2);
+                    wram[0x1FF2..0x1FF4].copy_from_slice(&[0x34, 0x12]);
+                },
+                0x7E1236,
+            ),
+        ];
+        for (i, &(mode, operand, setup, expected)) in cases.iter().enumerate() {
+            let addr = resolve(mode, operand, setup);
+            assert_eq!(addr, expected, "case {}: {:?}, got {:06X}", i, mode, addr);
+        }
     }
 }
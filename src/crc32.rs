@@ -0,0 +1,15 @@
+// CRC-32 (IEEE), as used by zip and PNG, for Snes::frame_crc32 and Snes::audio_crc32
+pub fn crc32(data: impl Iterator<Item = u8>) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,95 @@
+use crate::firmware::Chip;
+use crate::spc::SpcState;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoStandard {
+    Ntsc,
+    Pal,
+}
+
+impl VideoStandard {
+    // Country code at $FFD9 of the ROM header
+    pub fn from_country(country: u8) -> VideoStandard {
+        match country {
+            // Europe, Scandinavia, France, Netherlands, Spain, Germany, Italy, China,
+            // Indonesia and Australia
+            0x02..=0x0C | 0x11 => VideoStandard::Pal,
+            // Japan, North America, Korea, international, Canada and Brazil (PAL-M is 60Hz)
+            _ => VideoStandard::Ntsc,
+        }
+    }
+}
+
+impl fmt::Display for VideoStandard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoStandard::Ntsc => write!(f, "NTSC"),
+            VideoStandard::Pal => write!(f, "PAL"),
+        }
+    }
+}
+
+// Problems that don't stop emulation but likely make it inaccurate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    RegionMismatch {
+        rom: VideoStandard,
+        console: VideoStandard,
+    },
+    // Read of an address nothing drives, returning open bus
+    OpenBusRead { addr: u32 },
+    // Also stops exec_frame with SnesError::UnmappedAddress
+    UnmappedRead { addr: u32 },
+    InvalidDmaRegister { addr: u16 },
+    ReadOnlyRegisterWrite { addr: u16, data: u8 },
+    // The sound driver executed SLEEP or STOP
+    ApuHalted(SpcState),
+    // $2000-$5FFF accesses nothing handles since power on, one per address and direction.
+    // Only listed by Snes::diagnostics
+    UnimplementedRegister { addr: u16, write: bool, count: u64 },
+    // The game's coprocessor needs firmware that wasn't given to Snes::add_firmware
+    MissingFirmware(Chip),
+}
+
+// Receives diagnostics as they happen during emulation
+pub trait DiagnosticSink {
+    fn report(&mut self, diagnostic: Diagnostic);
+}
+
+impl<F: FnMut(Diagnostic)> DiagnosticSink for F {
+    fn report(&mut self, diagnostic: Diagnostic) {
+        self(diagnostic)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::RegionMismatch { rom, console } => write!(
+                f,
+                "ROM targets {} but the console runs at {} speed",
+                rom, console
+            ),
+            Diagnostic::OpenBusRead { addr } => write!(f, "Open bus read: {:06X}", addr),
+            Diagnostic::UnmappedRead { addr } => write!(f, "Read from unmapped address: {:06X}", addr),
+            Diagnostic::InvalidDmaRegister { addr } => {
+                write!(f, "Access to invalid DMA register: {:04X}", addr)
+            }
+            Diagnostic::ReadOnlyRegisterWrite { addr, data } => {
+                write!(f, "Write to read only register {:04X}: {:02X}", addr, data)
+            }
+            Diagnostic::ApuHalted(state) => write!(f, "SPC700 halted: {:?}", state),
+            Diagnostic::UnimplementedRegister { addr, write, count } => write!(
+                f,
+                "{} {} of unimplemented register {:04X}",
+                count,
+                if *write { "writes" } else { "reads" },
+                addr
+            ),
+            Diagnostic::MissingFirmware(chip) => {
+                write!(f, "{} firmware missing, expected {}", chip, chip.file_name())
+            }
+        }
+    }
+}
@@ -10,7 +10,75 @@ const RATE_TABLE: [u16; 32] = [
      10,    8,    6,    5,    4,   3,   2,   1,
 ];
 
+// Phase of each rate against the shared counter
+#[rustfmt::skip]
+const COUNTER_OFFSET: [u16; 32] = [
+      1, 0, 1040, 536, 0, 1040, 536, 0,
+   1040, 536, 0, 1040, 536, 0, 1040, 536,
+      0, 1040, 536, 0, 1040, 536, 0, 1040,
+    536, 0, 1040, 536, 0, 1040,   0,   0,
+];
+
+const COUNTER_RANGE: u16 = 2048 * 5 * 3;
+
+fn counter_poll(counter: u16, rate: u8) -> bool {
+    let rate = rate as usize;
+    rate != 0 && (counter + COUNTER_OFFSET[rate]).is_multiple_of(RATE_TABLE[rate])
+}
+
+// How the two DSP channels reach the output, see Snes::set_audio_output_mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AudioOutputMode {
+    #[default]
+    Stereo,
+    // Both channels get the average of left and right
+    Mono,
+    SwappedStereo,
+}
+
+// Applied to the mixed samples for the frontend, not part of savestates
+#[derive(Debug, Clone, Copy)]
+pub struct OutputSettings {
+    pub mode: AudioOutputMode,
+    // 0x100 is the level the game mixed at
+    pub volume: u16,
+    pub muted: bool,
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        OutputSettings {
+            mode: AudioOutputMode::Stereo,
+            volume: 0x100,
+            muted: false,
+        }
+    }
+}
+
+impl OutputSettings {
+    fn apply(&self, left: i16, right: i16) -> (i16, i16) {
+        if self.muted {
+            return (0, 0);
+        }
+        let (left, right) = match self.mode {
+            AudioOutputMode::Stereo => (left, right),
+            AudioOutputMode::Mono => {
+                let mono = ((left as i32 + right as i32) >> 1) as i16;
+                (mono, mono)
+            }
+            AudioOutputMode::SwappedStereo => (right, left),
+        };
+        // Clips like the DSP's own mixer
+        let scale = |sample: i16| {
+            ((sample as i32 * self.volume as i32) >> 8).clamp(-0x8000, 0x7FFF) as i16
+        };
+        (scale(left), scale(right))
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Dsp {
+    #[cfg_attr(feature = "serde", serde(with = "serde_big_array::BigArray"))]
     pub ram: [u8; 0x10000], // 64KB
     voice: [Voice; 8],
 
@@ -22,26 +90,43 @@ pub struct Dsp {
     sample_table_address: u8, // 0x5D
     echo_buffer_address: u8,  // 0x6D
     echo_buffer_size: u8,     // 0x7D
+    key_on: u8,               // 0x4C (last written value)
     echo_buffer_index: usize,
     echo_remain: u16,
     fir_buffer: [[i16; 2]; 8],
     fir_buffer_index: usize,
+    counter: u16,
 
     noise: Noise,
 
-    audio_buffer: Vec<(i16, i16)>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub output: OutputSettings,
 }
 
 impl Dsp {
-    pub fn tick(&mut self) {
+    pub fn tick(&mut self) -> (i16, i16) {
         let noise = self.noise.generate_noise();
+        self.counter = self.counter.checked_sub(1).unwrap_or(COUNTER_RANGE - 1);
+        // Soft reset holds every voice in release with a zero envelope
+        if self.flag.enable_reset() {
+            for ch in 0..8 {
+                self.voice[ch].envelopes.state = EnvelopeState::Release;
+                self.voice[ch].envelopes.envelope = 0;
+            }
+        }
         for ch in 0..8 {
             let prev_voice = if ch > 0 {
                 Some(self.voice[ch - 1].voice_params.sample)
             } else {
                 None
             };
-            self.voice[ch].tick(&self.ram, self.sample_table_address, prev_voice, noise);
+            self.voice[ch].tick(
+                &self.ram,
+                self.sample_table_address,
+                prev_voice,
+                noise,
+                self.counter,
+            );
         }
 
         let mut output = [0; 2];
@@ -70,7 +155,7 @@ impl Dsp {
         }
         self.update_echo_and_fir_indices();
 
-        self.audio_buffer.push((output[0], output[1]));
+        self.output.apply(output[0], output[1])
     }
 
     fn get_normal_voice(&self, i: usize) -> i32 {
@@ -136,18 +221,11 @@ impl Dsp {
             self.echo_buffer_index = 0;
         }
     }
-
-    pub fn clear_audio_buffer(&mut self) {
-        self.audio_buffer.clear();
-    }
-
-    pub fn get_audio_buffer(&self) -> &[(i16, i16)] {
-        &self.audio_buffer
-    }
 }
 
 #[bitfield(bits = 8)]
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Flags {
     noise_frequency: B5,
     disable_echo_buffer_write: bool,
@@ -179,14 +257,16 @@ impl Default for Dsp {
             sample_table_address: 0,
             echo_buffer_address: 0,
             echo_buffer_size: 0,
+            key_on: 0,
             echo_remain: 0,
             echo_buffer_index: 0,
             fir_buffer: [[0; 2]; 8],
             fir_buffer_index: 0,
+            counter: 0,
 
             noise: Default::default(),
 
-            audio_buffer: Vec::new(),
+            output: OutputSettings::default(),
         }
     }
 }
@@ -198,15 +278,7 @@ impl Dsp {
             0x1C => self.master_volume[1] as u8,
             0x2C => self.echo_volume[0] as u8,
             0x3C => self.echo_volume[1] as u8,
-            0x4C => {
-                let mut ret = 0;
-                for ch in 0..8 {
-                    if self.voice[ch].voice_status.key_on {
-                        ret |= 1 << ch;
-                    }
-                }
-                ret
-            }
+            0x4C => self.key_on,
             0x5C => {
                 let mut ret = 0;
                 for ch in 0..8 {
@@ -265,13 +337,28 @@ impl Dsp {
         }
     }
 
+    // Snapshot of the whole register file, e.g. for .spc export
+    pub fn registers(&self) -> [u8; 0x80] {
+        let mut ret = [0; 0x80];
+        for (addr, reg) in ret.iter_mut().enumerate() {
+            *reg = self.read(addr as u8);
+        }
+        ret
+    }
+
     pub fn write(&mut self, addr: u8, data: u8) {
-        match addr & 0x7F {
+        // 0x80..=0xFF mirrors 0x00..=0x7F for reads only
+        if addr >= 0x80 {
+            return;
+        }
+
+        match addr {
             0x0C => self.master_volume[0] = data as i8,
             0x1C => self.master_volume[1] = data as i8,
             0x2C => self.echo_volume[0] = data as i8,
             0x3C => self.echo_volume[1] = data as i8,
             0x4C => {
+                self.key_on = data;
                 for ch in 0..8 {
                     self.voice[ch].voice_status.key_on = data & (1 << ch) != 0;
                 }
@@ -283,15 +370,7 @@ impl Dsp {
             }
             0x6C => {
                 self.flag.bytes[0] = data;
-
                 self.noise.set_frequency(self.flag.noise_frequency());
-                if self.flag.enable_reset() {
-                    for ch in 0..8 {
-                        self.voice[ch].voice_status.key_off = true;
-                        self.voice[ch].envelopes.envelope = 0;
-                    }
-                    self.flag.set_enable_reset(false);
-                }
             }
             0x7C => {
                 for ch in 0..8 {
@@ -327,28 +406,51 @@ impl Dsp {
 }
 
 #[derive(Debug, Default, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Voice {
     voice_params: VoiceParams,
     voice_status: VoiceStatus,
     brr: BrrParams,
     brr_block: BrrBlock,
     envelopes: Envelopes,
+    key_on_delay: u8,
     na: [u8; 3], // 0xXA, 0xXB, 0xXE
 }
 
 impl Voice {
-    fn tick(&mut self, ram: &[u8], sample_table_address: u8, prev_voice: Option<i16>, noise: i16) {
+    fn tick(
+        &mut self,
+        ram: &[u8],
+        sample_table_address: u8,
+        prev_voice: Option<i16>,
+        noise: i16,
+        counter: u16,
+    ) {
         if self.voice_status.is_key_on() {
+            self.key_on_delay = 5;
             self.envelopes.reset_envelope_on_key_on();
-            self.voice_params.gaussian_sample_points.fill(0);
-            self.set_brr_address(ram, sample_table_address, false);
-            self.decode_brr(ram);
+            self.voice_status.voice_end = false;
         }
 
-        if self.voice_status.is_key_off() {
+        // KOF is level triggered and keeps the voice released while set
+        if self.voice_status.key_off {
             self.envelopes.state = EnvelopeState::Release;
         }
 
+        // The voice stays silent for 5 samples after key-on while the first block is fetched
+        if self.key_on_delay > 0 {
+            if self.key_on_delay == 5 {
+                self.brr.pitch_counter = 0;
+                self.voice_params.gaussian_sample_points.fill(0);
+                self.set_brr_address(ram, sample_table_address, false);
+                self.decode_brr(ram);
+            }
+            self.key_on_delay -= 1;
+            self.envelopes.envelope = 0;
+            self.voice_params.sample = 0;
+            return;
+        }
+
         let mut step = self.voice_params.sample_rate & 0x3FFF;
         if self.voice_status.enable_pitch_modulation && prev_voice.is_some() {
             let factor = (prev_voice.unwrap() >> 4) + 0x400;
@@ -357,8 +459,8 @@ impl Voice {
         }
 
         let mut prev_brr_index = ((self.brr.pitch_counter >> 12) & 0xF) as usize;
-        let (counter, overflow) = self.brr.pitch_counter.overflowing_add(step);
-        self.brr.pitch_counter = counter;
+        let (pitch_counter, overflow) = self.brr.pitch_counter.overflowing_add(step);
+        self.brr.pitch_counter = pitch_counter;
 
         if overflow {
             for i in prev_brr_index + 1..16 {
@@ -376,11 +478,11 @@ impl Voice {
         let sample = if self.voice_status.enable_noise {
             noise
         } else {
-            let gaussian_index = ((counter >> 4) & 0xFF) as usize;
+            let gaussian_index = ((pitch_counter >> 4) & 0xFF) as usize;
             self.apply_gaussian_interpolation(gaussian_index)
         };
 
-        self.envelopes.update_envelope();
+        self.envelopes.update_envelope(counter);
         // self.voice_params.sample = sample;
         self.voice_params.sample = ((sample as i32 * self.envelopes.envelope as i32) >> 11) as i16;
     }
@@ -404,17 +506,17 @@ impl Voice {
     }
 
     fn load_next_brr(&mut self, ram: &[u8], sample_table_address: u8) {
-        if !self.brr_block.header.end() {
-            self.decode_brr(ram);
-        } else if self.brr_block.header.repeat() {
-            self.set_brr_address(ram, sample_table_address, true);
-            self.decode_brr(ram);
-        } else {
-            self.envelopes.state = EnvelopeState::Release;
-            self.envelopes.envelope = 0;
+        // ENDX is set once the end block has been played through. The filter history carries
+        // over into the loop block
+        if self.brr_block.header.end() {
+            self.voice_status.voice_end = true;
+            if !self.brr_block.header.repeat() {
+                self.envelopes.state = EnvelopeState::Release;
+                self.envelopes.envelope = 0;
+            }
             self.set_brr_address(ram, sample_table_address, true);
-            self.decode_brr(ram);
         }
+        self.decode_brr(ram);
     }
 
     fn decode_brr(&mut self, ram: &[u8]) {
@@ -433,10 +535,6 @@ impl Voice {
             header.shift()
         );
 
-        if header.end() {
-            self.voice_status.voice_end = true;
-        }
-
         let mut data = [0; 16];
         for i in 0..16 {
             let nibble = ram[self.brr.address as usize] >> ((i & 1 ^ 1) * 4);
@@ -540,6 +638,7 @@ impl Voice {
 }
 
 #[derive(Debug, Default, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct VoiceParams {
     volume: [i8; 2],     // 0xX0, 0xX1
     sample_rate: u16,    // 0xX2, 0xX3
@@ -560,8 +659,9 @@ impl VoiceParams {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct VoiceStatus {
-    key_on: bool,                  // 0x4C
+    key_on: bool,                  // 0x4C (pending until the next sample)
     key_off: bool,                 // 0x5C
     voice_end: bool,               // 0x7C
     enable_pitch_modulation: bool, // 0x2D
@@ -588,15 +688,10 @@ impl VoiceStatus {
         self.key_on = false;
         ret
     }
-
-    fn is_key_off(&mut self) -> bool {
-        let ret = self.key_off;
-        self.key_off = false;
-        ret
-    }
 }
 
 #[derive(Debug, Default, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct BrrBlock {
     header: BrrBlockHeader,
     data: [i16; 16],
@@ -604,6 +699,7 @@ struct BrrBlock {
 
 #[bitfield(bits = 8)]
 #[derive(Default, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct BrrBlockHeader {
     end: bool,
     repeat: bool,
@@ -612,6 +708,7 @@ struct BrrBlockHeader {
 }
 
 #[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct BrrParams {
     source_number: u8, // 0xX4
     pitch_counter: u16,
@@ -619,128 +716,109 @@ struct BrrParams {
 }
 
 #[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Envelopes {
     adsr_settings: AdsrSettings, // 0xX5, 0xX6
     gain_settings: u8,           // 0xX7
     envelope: u16,               // 0xX8
-    counter: u16,
+    // Value computed every sample, even when the rate doesn't let it through
+    hidden_envelope: i32,
     state: EnvelopeState,
 }
 
 impl Envelopes {
     fn reset_envelope_on_key_on(&mut self) {
         self.envelope = 0;
-        self.counter = 0;
+        self.hidden_envelope = 0;
         self.state = EnvelopeState::Attack;
     }
 
-    fn update_envelope(&mut self) {
-        self.envelope &= 0x7FF;
-        if self.state != EnvelopeState::Release && !self.adsr_settings.use_adsr() {
-            self.update_gain_envelope();
-        } else {
-            self.update_adsr_envelope();
+    fn update_envelope(&mut self, counter: u16) {
+        let mut env = self.envelope as i32;
+
+        if self.state == EnvelopeState::Release {
+            self.envelope = (env - 8).max(0) as u16;
+            return;
         }
-    }
 
-    fn update_gain_envelope(&mut self) {
-        if self.gain_settings & 0x80 == 0 {
-            self.envelope = (self.gain_settings & 0x7F) as u16 * 16;
-        } else {
-            let rate = self.gain_settings & 0x1F;
-            let mode = (self.gain_settings >> 5) & 3;
-
-            self.counter += 1;
-
-            if rate == 0 {
-                self.counter = 0;
-            } else if self.counter >= RATE_TABLE[rate as usize] {
-                self.counter = 0;
-
-                match mode {
-                    0 => self.envelope = self.envelope.saturating_sub(32),
-                    1 => {
-                        if self.envelope > 0 {
-                            let step = ((self.envelope - 1) >> 8) + 1;
-                            self.envelope = self.envelope.saturating_sub(step);
-                        }
-                    }
-                    2 => self.envelope = (self.envelope + 32).min(0x7FF),
-                    3 => {
-                        if self.envelope < 0x600 {
-                            self.envelope += 32;
-                        } else {
-                            self.envelope = (self.envelope + 8).min(0x7FF)
-                        }
-                    }
-                    _ => unreachable!(),
+        let (rate, sustain_level) = if self.adsr_settings.use_adsr() {
+            let rate = match self.state {
+                EnvelopeState::Attack => {
+                    let rate = self.adsr_settings.attack_rate() * 2 + 1;
+                    env += if rate < 31 { 0x20 } else { 0x400 };
+                    rate
                 }
-            }
-        }
-    }
+                EnvelopeState::Decay => {
+                    env -= 1;
+                    env -= env >> 8;
+                    self.adsr_settings.decay_rate() * 2 + 16
+                }
+                EnvelopeState::Sustain => {
+                    env -= 1;
+                    env -= env >> 8;
+                    self.adsr_settings.sustain_rate()
+                }
+                EnvelopeState::Release => unreachable!(),
+            };
+            (rate, self.adsr_settings.sustain_level())
+        } else {
+            (self.update_gain_envelope(&mut env), self.gain_settings >> 5)
+        };
 
-    fn update_adsr_envelope(&mut self) {
-        match self.state {
-            EnvelopeState::Attack => self.process_attack(),
-            EnvelopeState::Decay => self.process_decay(),
-            EnvelopeState::Sustain => self.process_sustain(),
-            EnvelopeState::Release => self.process_release(),
+        // Decay ends on the exact sustain level. In GAIN mode the level comes from the GAIN
+        // register instead of ADSR2
+        if self.state == EnvelopeState::Decay && (env >> 8) as u8 == sustain_level {
+            self.state = EnvelopeState::Sustain;
         }
-    }
 
-    fn process_attack(&mut self) {
-        let rate = self.adsr_settings.attack_rate() * 2 + 1;
-        self.counter += 1;
-        if self.counter >= RATE_TABLE[rate as usize] {
-            self.counter = 0;
-            self.envelope = (self.envelope + if rate != 31 { 32 } else { 1024 }).min(0x7FF);
-            if self.envelope >= 0x7E0 {
+        self.hidden_envelope = env;
+
+        if !(0..=0x7FF).contains(&env) {
+            env = env.clamp(0, 0x7FF);
+            if self.state == EnvelopeState::Attack {
                 self.state = EnvelopeState::Decay;
             }
         }
-    }
 
-    fn process_decay(&mut self) {
-        let rate = self.adsr_settings.decay_rate() * 2 + 16;
-        self.counter += 1;
-        if self.counter >= RATE_TABLE[rate as usize] {
-            self.counter = 0;
-            let step = ((self.envelope - 1) >> 8) + 1;
-            self.envelope -= step;
-        }
-        let boundary = (self.adsr_settings.sustain_level() as u16 + 1) * 0x100;
-        if self.envelope <= boundary {
-            self.state = EnvelopeState::Sustain;
+        if counter_poll(counter, rate) {
+            self.envelope = env as u16;
         }
     }
 
-    fn process_sustain(&mut self) {
-        let rate = self.adsr_settings.sustain_rate();
-        if rate == 0 {
-            self.counter = 0;
-        } else {
-            self.counter += 1;
-            if self.counter >= RATE_TABLE[rate as usize] {
-                self.counter = 0;
-                if self.envelope > 0 {
-                    let step = ((self.envelope - 1) >> 8) + 1;
-                    self.envelope -= step;
-                }
-            }
+    fn update_gain_envelope(&self, env: &mut i32) -> u8 {
+        if self.gain_settings & 0x80 == 0 {
+            // Direct
+            *env = (self.gain_settings & 0x7F) as i32 * 16;
+            return 31;
         }
-    }
 
-    fn process_release(&mut self) {
-        self.counter += 1;
-        if self.counter >= RATE_TABLE[31] {
-            self.counter = 0;
-            self.envelope = self.envelope.saturating_sub(8);
+        match (self.gain_settings >> 5) & 3 {
+            // Linear decrease
+            0 => *env -= 0x20,
+            // Exponential decrease
+            1 => {
+                *env -= 1;
+                *env -= *env >> 8;
+            }
+            // Linear increase
+            2 => *env += 0x20,
+            // Bent line increase
+            3 => {
+                *env += if (self.hidden_envelope as u32) < 0x600 {
+                    0x20
+                } else {
+                    0x08
+                }
+            }
+            _ => unreachable!(),
         }
+        self.gain_settings & 0x1F
     }
 }
 
 #[bitfield(bits = 16)]
 #[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct AdsrSettings {
     attack_rate: B4,
     decay_rate: B3,
@@ -750,6 +828,7 @@ struct AdsrSettings {
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum EnvelopeState {
     #[default]
     Attack,
@@ -758,6 +837,7 @@ enum EnvelopeState {
     Release,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Noise {
     noise: i16,
     frequency: usize,
@@ -833,3 +913,124 @@ const GAUSS_TABLE: [u16; 512] = [
     0x502, 0x503, 0x504, 0x506, 0x507, 0x508, 0x50A, 0x50B, 0x50C, 0x50D, 0x50E, 0x50F, 0x510, 0x511, 0x511, 0x512,
     0x513, 0x514, 0x514, 0x515, 0x516, 0x516, 0x517, 0x517, 0x517, 0x518, 0x518, 0x518, 0x518, 0x518, 0x519, 0x519,
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // Envelope after each sample, with the shared counter counting down from 0 like Dsp::tick
+    fn run(env: &mut Envelopes, samples: usize) -> Vec<u16> {
+        let mut counter: u16 = 0;
+        (0..samples)
+            .map(|_| {
+                counter = counter.checked_sub(1).unwrap_or(COUNTER_RANGE - 1);
+                env.update_envelope(counter);
+                env.envelope
+            })
+            .collect()
+    }
+
+    fn adsr(adsr1: u8, adsr2: u8) -> Envelopes {
+        let mut env = Envelopes::default();
+        env.adsr_settings.bytes = [adsr1, adsr2];
+        env.reset_envelope_on_key_on();
+        env
+    }
+
+    fn gain(gain: u8, envelope: u16) -> Envelopes {
+        Envelopes {
+            gain_settings: gain,
+            envelope,
+            hidden_envelope: envelope as i32,
+            ..Envelopes::default()
+        }
+    }
+
+    #[test]
+    fn rates_divide_the_counter_range() {
+        assert!((0..COUNTER_RANGE).all(|counter| !counter_poll(counter, 0)));
+        for rate in 1..32u8 {
+            let polls: Vec<u16> = (0..COUNTER_RANGE)
+                .filter(|&counter| counter_poll(counter, rate))
+                .collect();
+            let period = RATE_TABLE[rate as usize];
+            assert_eq!(
+                polls.len(),
+                (COUNTER_RANGE / period) as usize,
+                "rate {}",
+                rate
+            );
+            assert!(
+                polls.windows(2).all(|w| w[1] - w[0] == period),
+                "rate {}",
+                rate
+            );
+        }
+        // Phases from SPC_DSP's counter_offsets: 0, 1040 and 536 cycling from rate 1
+        assert!(counter_poll(0, 1) && counter_poll(0, 4) && counter_poll(0, 31));
+        assert!(counter_poll(1536 - 1040, 2) && !counter_poll(0, 2));
+        assert!(counter_poll(1280 - 536, 3) && !counter_poll(0, 3));
+    }
+
+    #[test]
+    fn adsr_attack() {
+        // Attack rate $F adds $400 every sample
+        let mut env = adsr(0x8F, 0xE0);
+        assert_eq!(run(&mut env, 2), [0x400, 0x7FF]);
+        assert_eq!(env.state, EnvelopeState::Decay);
+
+        // Others add $20 at rate 2A+1, $E is every 3 samples
+        let mut env = adsr(0x8E, 0xE0);
+        let steps = run(&mut env, 3 * 64);
+        assert!(steps
+            .windows(2)
+            .all(|w| w[1] == w[0] || w[1] == w[0] + 0x20));
+        assert_eq!(steps.iter().filter(|&&e| e == 0x20).count(), 3);
+    }
+
+    #[test]
+    fn adsr_decay_to_sustain_level() {
+        // Decay rate 7 is counter rate 30, sustain level 3 ends the decay below $400
+        let mut env = adsr(0xFF, 0x60);
+        let steps = run(&mut env, 2 + 2 * 0x100);
+        assert_eq!(env.state, EnvelopeState::Sustain);
+        // Exponential: the first step from $7FF is -1 then -($7FE >> 8)
+        assert_eq!(steps[3], 0x7F7);
+        // The level is compared with the value computed every sample, so the envelope holds
+        // the last step the rate let through, one above the level here
+        assert_eq!(env.hidden_envelope >> 8, 3);
+        assert!(steps.ends_with(&[0x401; 16]));
+    }
+
+    #[test]
+    fn release() {
+        let mut env = adsr(0x8F, 0xE0);
+        run(&mut env, 2);
+        env.state = EnvelopeState::Release;
+        assert_eq!(run(&mut env, 3), [0x7F7, 0x7EF, 0x7E7]);
+        assert_eq!(run(&mut env, 0x100).last(), Some(&0));
+    }
+
+    #[test]
+    fn gain_modes() {
+        // Direct sets the envelope to the value * 16 right away
+        assert_eq!(run(&mut gain(0x40, 0), 1), [0x400]);
+        // Linear decrease by $20, clamped at 0
+        assert_eq!(run(&mut gain(0x9F, 0x40), 3), [0x20, 0, 0]);
+        // Exponential decrease: -1, then -(env >> 8)
+        assert_eq!(run(&mut gain(0xBF, 0x400), 2), [0x3FC, 0x3F8]);
+        // Linear increase by $20, clamped at $7FF
+        assert_eq!(run(&mut gain(0xDF, 0x7C0), 3), [0x7E0, 0x7FF, 0x7FF]);
+    }
+
+    #[test]
+    fn gain_bent_line() {
+        // $20 per step below $600, then 8 up to $7FF
+        let steps = run(&mut gain(0xFF, 0), 48 + 64);
+        assert_eq!(steps[47], 0x600);
+        assert_eq!(steps[48..51], [0x608, 0x610, 0x618]);
+        assert_eq!(steps[47 + 63], 0x7F8);
+        assert_eq!(steps[47 + 64], 0x7FF);
+    }
+}
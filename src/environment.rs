@@ -0,0 +1,114 @@
+// Gym style wrapper for reinforcement learning
+//
+// let mut env = Environment::new(snes, vec![0x0DBF])?; // e.g. the score in WRAM
+// let mut obs = env.reset()?;
+// loop {
+//     obs = env.step([vec![Key::Right], vec![], vec![], vec![]])?;
+// }
+
+use std::fmt;
+
+use crate::{Key, Snes, SnesError};
+
+#[derive(Debug)]
+pub enum EnvironmentError {
+    Emulation(SnesError),
+    State(bincode::Error),
+}
+
+impl fmt::Display for EnvironmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvironmentError::Emulation(err) => write!(f, "{}", err),
+            EnvironmentError::State(err) => write!(f, "Savestate: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for EnvironmentError {}
+
+impl From<SnesError> for EnvironmentError {
+    fn from(err: SnesError) -> Self {
+        EnvironmentError::Emulation(err)
+    }
+}
+
+impl From<bincode::Error> for EnvironmentError {
+    fn from(err: bincode::Error) -> Self {
+        EnvironmentError::State(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Observation {
+    // BGR555, FRAME_WIDTH x FRAME_HEIGHT. Stale while headless
+    pub frame: Vec<u16>,
+    pub audio: Vec<(i16, i16)>,
+    // Values of the watched addresses, in the order given to Environment::new
+    pub ram: Vec<u8>,
+    pub frame_number: u64,
+}
+
+pub struct Environment {
+    snes: Snes,
+    // Offsets into WRAM ($7E0000 is 0)
+    watched: Vec<usize>,
+    reset_state: Vec<u8>,
+}
+
+impl Environment {
+    // The current state of `snes` becomes the state `reset` returns to
+    pub fn new(mut snes: Snes, watched: Vec<usize>) -> Result<Environment, EnvironmentError> {
+        let reset_state = save(&mut snes)?;
+        Ok(Environment {
+            snes,
+            watched,
+            reset_state,
+        })
+    }
+
+    pub fn reset(&mut self) -> Result<Observation, EnvironmentError> {
+        let options = bincode::DefaultOptions::new();
+        self.snes
+            .load_state(&mut bincode::Deserializer::from_slice(&self.reset_state, options))?;
+        Ok(self.observe())
+    }
+
+    // Run one frame with `inputs` held on the four pads
+    pub fn step(&mut self, inputs: [Vec<Key>; 4]) -> Result<Observation, EnvironmentError> {
+        self.snes.set_keys(inputs);
+        self.snes.exec_frame()?;
+        Ok(self.observe())
+    }
+
+    // Make the current state the new reset point, e.g. after skipping the title screen
+    pub fn set_reset_point(&mut self) -> Result<(), EnvironmentError> {
+        self.reset_state = save(&mut self.snes)?;
+        Ok(())
+    }
+
+    pub fn snes(&self) -> &Snes {
+        &self.snes
+    }
+
+    pub fn snes_mut(&mut self) -> &mut Snes {
+        &mut self.snes
+    }
+
+    fn observe(&self) -> Observation {
+        let wram = self.snes.wram();
+        Observation {
+            frame: self.snes.context.inner1.inner2.ppu.frame.to_vec(),
+            audio: self.snes.audio_buffer().to_vec(),
+            ram: self.watched.iter().map(|&addr| wram[addr % wram.len()]).collect(),
+            frame_number: self.snes.frame_info().frame_number,
+        }
+    }
+}
+
+fn save(snes: &mut Snes) -> Result<Vec<u8>, bincode::Error> {
+    let mut buf = Vec::new();
+    let options = bincode::DefaultOptions::new();
+    snes.save_state(&mut bincode::Serializer::new(&mut buf, options))?;
+    Ok(buf)
+}
@@ -0,0 +1,30 @@
+use alloc::string::String;
+use core::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SnesError {
+    InvalidRom(String),
+    InvalidFirmware(String),
+    UnmappedAddress(u32),
+    // Snes::exec_until_scanline was given a line past the end of the frame
+    InvalidScanline(u16),
+    // Snes::exec_frame_into was given a buffer or pitch too small for a frame, in bytes
+    InvalidFrameBuffer { len: usize, pitch: usize },
+}
+
+impl fmt::Display for SnesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnesError::InvalidRom(msg) => write!(f, "Invalid ROM: {}", msg),
+            SnesError::InvalidFirmware(msg) => write!(f, "Invalid firmware: {}", msg),
+            SnesError::UnmappedAddress(addr) => write!(f, "Access to unmapped address: {:06X}", addr),
+            SnesError::InvalidScanline(y) => write!(f, "No line {} in a frame", y),
+            SnesError::InvalidFrameBuffer { len, pitch } => {
+                write!(f, "Frame doesn't fit in {} bytes with a pitch of {}", len, pitch)
+            }
+        }
+    }
+}
+
+impl core::error::Error for SnesError {}
@@ -0,0 +1,175 @@
+// C ABI. See include/rust_snes.h
+//
+// cargo rustc --release --lib --no-default-features --features ffi --crate-type cdylib
+
+use std::os::raw::c_int;
+use std::ptr;
+use std::slice;
+
+use crate::{Key, Snes};
+
+pub const SNES_OK: c_int = 0;
+pub const SNES_ERROR_INVALID_ARGUMENT: c_int = -1;
+pub const SNES_ERROR_INVALID_ROM: c_int = -2;
+pub const SNES_ERROR_NO_ROM: c_int = -3;
+pub const SNES_ERROR_HALTED: c_int = -4;
+
+pub const SNES_FRAME_WIDTH: u32 = 256;
+pub const SNES_FRAME_HEIGHT: u32 = 224;
+
+pub struct SnesHandle {
+    snes: Option<Snes>,
+    input: [u16; 4],
+}
+
+fn to_keys(buttons: u16) -> Vec<Key> {
+    Key::ALL
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| buttons >> i & 1 == 1)
+        .map(|(_, &key)| key)
+        .collect()
+}
+
+#[no_mangle]
+pub extern "C" fn snes_create() -> *mut SnesHandle {
+    Box::into_raw(Box::new(SnesHandle {
+        snes: None,
+        input: [0; 4],
+    }))
+}
+
+/// # Safety
+/// `handle` must come from `snes_create` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn snes_destroy(handle: *mut SnesHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// # Safety
+/// `handle` must come from `snes_create`. `rom` must point to `rom_len` bytes, and `sram`
+/// to `sram_len` bytes unless it is NULL. Both buffers are copied.
+#[no_mangle]
+pub unsafe extern "C" fn snes_load_rom(
+    handle: *mut SnesHandle,
+    rom: *const u8,
+    rom_len: usize,
+    sram: *const u8,
+    sram_len: usize,
+) -> c_int {
+    let Some(handle) = handle.as_mut() else {
+        return SNES_ERROR_INVALID_ARGUMENT;
+    };
+    if rom.is_null() {
+        return SNES_ERROR_INVALID_ARGUMENT;
+    }
+    let rom = slice::from_raw_parts(rom, rom_len).to_vec();
+    let backup = if sram.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(sram, sram_len).to_vec())
+    };
+
+    match Snes::new(rom, backup) {
+        Ok(snes) => {
+            handle.snes = Some(snes);
+            SNES_OK
+        }
+        Err(_) => {
+            handle.snes = None;
+            SNES_ERROR_INVALID_ROM
+        }
+    }
+}
+
+/// # Safety
+/// `handle` must come from `snes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_run_frame(handle: *mut SnesHandle) -> c_int {
+    let Some(handle) = handle.as_mut() else {
+        return SNES_ERROR_INVALID_ARGUMENT;
+    };
+    let input = handle.input;
+    let Some(snes) = handle.snes.as_mut() else {
+        return SNES_ERROR_NO_ROM;
+    };
+    snes.set_keys(input.map(to_keys));
+    match snes.exec_frame() {
+        Ok(()) => SNES_OK,
+        Err(_) => SNES_ERROR_HALTED,
+    }
+}
+
+// Button bits follow the order of `Key` (bit0: B ... bit11: R)
+/// # Safety
+/// `handle` must come from `snes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_set_input(handle: *mut SnesHandle, port: u32, buttons: u16) -> c_int {
+    match handle.as_mut() {
+        Some(handle) if port < 4 => {
+            handle.input[port as usize] = buttons;
+            SNES_OK
+        }
+        _ => SNES_ERROR_INVALID_ARGUMENT,
+    }
+}
+
+// BGR555 pixels. `pitch` is in bytes
+/// # Safety
+/// `handle` must come from `snes_create`. Output pointers may be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn snes_framebuffer(
+    handle: *const SnesHandle,
+    width: *mut u32,
+    height: *mut u32,
+    pitch: *mut u32,
+) -> *const u16 {
+    let Some(snes) = handle.as_ref().and_then(|h| h.snes.as_ref()) else {
+        return ptr::null();
+    };
+    if let Some(width) = width.as_mut() {
+        *width = SNES_FRAME_WIDTH;
+    }
+    if let Some(height) = height.as_mut() {
+        *height = SNES_FRAME_HEIGHT;
+    }
+    if let Some(pitch) = pitch.as_mut() {
+        *pitch = SNES_FRAME_WIDTH * 2;
+    }
+    snes.context.inner1.inner2.ppu.frame.as_ptr()
+}
+
+// Interleaved stereo samples at 32kHz produced by the last frame
+/// # Safety
+/// `handle` must come from `snes_create`. `count` may be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn snes_audio_samples(handle: *const SnesHandle, count: *mut usize) -> *const i16 {
+    let Some(snes) = handle.as_ref().and_then(|h| h.snes.as_ref()) else {
+        if let Some(count) = count.as_mut() {
+            *count = 0;
+        }
+        return ptr::null();
+    };
+    let buffer = snes.audio_buffer();
+    if let Some(count) = count.as_mut() {
+        *count = buffer.len();
+    }
+    buffer.as_ptr() as *const i16
+}
+
+// Copies up to `len` bytes of battery backed RAM and returns its full size
+/// # Safety
+/// `handle` must come from `snes_create`. `buf` must point to `len` writable bytes unless it is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn snes_save_ram(handle: *const SnesHandle, buf: *mut u8, len: usize) -> usize {
+    let Some(sram) = handle.as_ref().and_then(|h| h.snes.as_ref()).and_then(|s| s.backup()) else {
+        return 0;
+    };
+    if !buf.is_null() {
+        let n = len.min(sram.len());
+        ptr::copy_nonoverlapping(sram.as_ptr(), buf, n);
+    }
+    sram.len()
+}
@@ -0,0 +1,77 @@
+// Coprocessors running a program from their own mask ROM, which isn't part of the game ROM
+// dumps. The user supplies it through Snes::add_firmware, and Snes::missing_firmware tells
+// which one a game needs
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Chip {
+    Dsp1,
+    Dsp2,
+    Dsp3,
+    Dsp4,
+    St010,
+    St011,
+    St018,
+}
+
+impl Chip {
+    // Program ROM followed by data ROM, as in the usual firmware dumps
+    pub fn firmware_size(self) -> usize {
+        match self {
+            Chip::Dsp1 | Chip::Dsp2 | Chip::Dsp3 | Chip::Dsp4 => 0x1800 + 0x800,
+            Chip::St010 | Chip::St011 => 0xC000 + 0x1000,
+            Chip::St018 => 0x20000 + 0x8000,
+        }
+    }
+
+    // Name of the dump most emulators look for
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Chip::Dsp1 => "dsp1.rom",
+            Chip::Dsp2 => "dsp2.rom",
+            Chip::Dsp3 => "dsp3.rom",
+            Chip::Dsp4 => "dsp4.rom",
+            Chip::St010 => "st010.rom",
+            Chip::St011 => "st011.rom",
+            Chip::St018 => "st018.rom",
+        }
+    }
+
+    // From the header's title, chipset ($FFD6) and chipset subtype ($FFBF). Which DSP a
+    // board carries isn't in the header, so the few DSP-2/3/4 games are told by title
+    pub(crate) fn detect(title: &[u8], chipset: u8, subtype: u8) -> Option<Chip> {
+        match (chipset, subtype) {
+            (0x03..=0x05, _) => Some(if title.starts_with(b"DUNGEON MASTER") {
+                Chip::Dsp2
+            } else if title.starts_with(b"SD\xB6\xDE\xDD\xC0\xDE\xD1GX") {
+                Chip::Dsp3
+            } else if title.starts_with(b"TOP GEAR 3000") {
+                Chip::Dsp4
+            } else {
+                Chip::Dsp1
+            }),
+            (0xF5 | 0xF6, 0x01) => Some(if title.starts_with(b"2DAN MORITA SHOUGI") {
+                Chip::St011
+            } else {
+                Chip::St010
+            }),
+            (0xF5 | 0xF6, 0x02) => Some(Chip::St018),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Chip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Chip::Dsp1 => "DSP-1",
+            Chip::Dsp2 => "DSP-2",
+            Chip::Dsp3 => "DSP-3",
+            Chip::Dsp4 => "DSP-4",
+            Chip::St010 => "ST010",
+            Chip::St011 => "ST011",
+            Chip::St018 => "ST018",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,12 @@
+// Entry points for the fuzz targets into otherwise private parts of the core. Not a stable API
+use crate::context::Bus;
+use crate::Snes;
+
+// A CPU access, without the CPU. The PPU, APU and DMA catch up on the next step_instruction
+pub fn bus_read(snes: &mut Snes, addr: u32) -> u8 {
+    snes.context.inner1.bus_read(addr)
+}
+
+pub fn bus_write(snes: &mut Snes, addr: u32, data: u8) {
+    snes.context.inner1.bus_write(addr, data)
+}
@@ -1,49 +1,66 @@
+// Reading $4210/$4211 this many master cycles after the flag was set returns it without
+// clearing it
+const FLAG_HOLD: u64 = 4;
+
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Interrupt {
     // Nmi
     nmi_flag: bool,
     nmi_enable: bool,
-    nmi: bool,
-    nmi_raise: bool,
+    nmi_flag_set_at: u64,
+    // Master clock of an NMI edge the CPU hasn't taken yet
+    nmi_edge: Option<u64>,
 
     // irq
     hv_irq_enable: u8, // 0x4200.4-5 0=Disable, 1=At H=H + V=Any, 2=At V=V + H=0, 3=At H=H + V=V
     h_count: u16,      // 0x4207, 0x4208
     v_count: u16,      // 0x4209, 0x420A
     irq: bool,
+    irq_set_at: u64,
 
     // JoyPad
     joypad_enable: bool,
 }
 
 impl Interrupt {
-    pub fn get_nmi_flag(&mut self) -> bool {
+    pub fn get_nmi_flag(&mut self, now: u64) -> bool {
         let ret = self.nmi_flag;
-        self.nmi_flag = false;
+        if now >= self.nmi_flag_set_at + FLAG_HOLD {
+            self.nmi_flag = false;
+        }
         ret
     }
 
-    pub fn set_nmi_flag(&mut self, flag: bool) {
+    pub fn set_nmi_flag(&mut self, flag: bool, at: u64) {
         let prev = self.nmi_flag & self.nmi_enable;
         self.nmi_flag = flag;
+        if flag {
+            self.nmi_flag_set_at = at;
+        }
         if !prev && self.nmi_enable && self.nmi_flag {
-            self.nmi_raise = true;
+            self.nmi_edge = Some(at);
         }
     }
 
-    pub fn set_nmi_enable(&mut self, flag: bool) {
+    pub fn set_nmi_enable(&mut self, flag: bool, at: u64) {
         let prev = self.nmi_flag & self.nmi_enable;
         self.nmi_enable = flag;
         if !prev && self.nmi_enable && self.nmi_flag {
-            self.nmi_raise = true;
+            self.nmi_edge = Some(at);
         }
     }
 
-    pub fn nmi_occurred(&mut self) -> bool {
-        let ret = self.nmi;
-        self.nmi = self.nmi_raise;
-        self.nmi_raise = false;
-        ret
+    // The CPU polls at the start of the last cycle of every instruction, so an edge during
+    // that cycle is taken one instruction later
+    pub fn nmi_occurred(&mut self, poll: u64) -> bool {
+        match self.nmi_edge {
+            Some(at) if at <= poll => {
+                self.nmi_edge = None;
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn set_hv_irq_enable(&mut self, val: u8) {
@@ -57,12 +74,24 @@ impl Interrupt {
         self.hv_irq_enable
     }
 
-    pub fn set_irq(&mut self, flag: bool) {
+    pub fn set_irq(&mut self, flag: bool, at: u64) {
+        if flag && !self.irq {
+            self.irq_set_at = at;
+        }
         self.irq = flag;
     }
 
-    pub fn irq_occurred(&self) -> bool {
-        self.irq
+    // Level triggered, polled like NMI
+    pub fn irq_occurred(&self, poll: u64) -> bool {
+        self.irq && self.irq_set_at <= poll
+    }
+
+    pub fn get_irq_flag(&mut self, now: u64) -> bool {
+        let ret = self.irq;
+        if now >= self.irq_set_at + FLAG_HOLD {
+            self.irq = false;
+        }
+        ret
     }
 
     pub fn set_h_count(&mut self, val: u16) {
@@ -0,0 +1,286 @@
+// Hardware registers in $2100-$43FF as handled by bus.rs and ppu.rs, for labeling them in
+// debuggers. Keep in sync when a register gains or loses an implementation, the test below
+// checks the addresses against what the bus decodes
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterSupport {
+    Implemented,
+    // Works for common uses, `note` says what is missing
+    Partial,
+    // Writes are stored but have no effect
+    Stubbed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoRegister {
+    pub addr: u16,
+    pub name: &'static str,
+    pub access: RegisterAccess,
+    pub support: RegisterSupport,
+    pub note: &'static str,
+}
+
+use RegisterAccess::*;
+use RegisterSupport::*;
+
+const fn reg(
+    addr: u16,
+    name: &'static str,
+    access: RegisterAccess,
+    support: RegisterSupport,
+    note: &'static str,
+) -> IoRegister {
+    IoRegister {
+        addr,
+        name,
+        access,
+        support,
+        note,
+    }
+}
+
+// Sorted by address. The DMA channels are generated by dma_register
+#[rustfmt::skip]
+const REGISTERS: &[IoRegister] = &[
+    reg(0x2100, "INIDISP", Write, Implemented, ""),
+    reg(0x2101, "OBSEL", Write, Implemented, ""),
+    reg(0x2102, "OAMADDL", Write, Implemented, ""),
+    reg(0x2103, "OAMADDH", Write, Implemented, ""),
+    reg(0x2104, "OAMDATA", Write, Partial, "Writes during active display are dropped"),
+    reg(0x2105, "BGMODE", Write, Implemented, ""),
+    reg(0x2106, "MOSAIC", Write, Stubbed, "Mosaic is not rendered"),
+    reg(0x2107, "BG1SC", Write, Implemented, ""),
+    reg(0x2108, "BG2SC", Write, Implemented, ""),
+    reg(0x2109, "BG3SC", Write, Implemented, ""),
+    reg(0x210A, "BG4SC", Write, Implemented, ""),
+    reg(0x210B, "BG12NBA", Write, Implemented, ""),
+    reg(0x210C, "BG34NBA", Write, Implemented, ""),
+    reg(0x210D, "BG1HOFS", Write, Implemented, "Also M7HOFS"),
+    reg(0x210E, "BG1VOFS", Write, Implemented, "Also M7VOFS"),
+    reg(0x210F, "BG2HOFS", Write, Implemented, ""),
+    reg(0x2110, "BG2VOFS", Write, Implemented, ""),
+    reg(0x2111, "BG3HOFS", Write, Implemented, ""),
+    reg(0x2112, "BG3VOFS", Write, Implemented, ""),
+    reg(0x2113, "BG4HOFS", Write, Implemented, ""),
+    reg(0x2114, "BG4VOFS", Write, Implemented, ""),
+    reg(0x2115, "VMAIN", Write, Implemented, ""),
+    reg(0x2116, "VMADDL", Write, Implemented, ""),
+    reg(0x2117, "VMADDH", Write, Implemented, ""),
+    reg(0x2118, "VMDATAL", Write, Implemented, ""),
+    reg(0x2119, "VMDATAH", Write, Implemented, ""),
+    reg(0x211A, "M7SEL", Write, Implemented, ""),
+    reg(0x211B, "M7A", Write, Implemented, ""),
+    reg(0x211C, "M7B", Write, Implemented, ""),
+    reg(0x211D, "M7C", Write, Implemented, ""),
+    reg(0x211E, "M7D", Write, Implemented, ""),
+    reg(0x211F, "M7X", Write, Implemented, ""),
+    reg(0x2120, "M7Y", Write, Implemented, ""),
+    reg(0x2121, "CGADD", Write, Implemented, ""),
+    reg(0x2122, "CGDATA", Write, Partial, "Writes during active display are dropped"),
+    reg(0x2123, "W12SEL", Write, Implemented, ""),
+    reg(0x2124, "W34SEL", Write, Implemented, ""),
+    reg(0x2125, "WOBJSEL", Write, Implemented, ""),
+    reg(0x2126, "WH0", Write, Implemented, ""),
+    reg(0x2127, "WH1", Write, Implemented, ""),
+    reg(0x2128, "WH2", Write, Implemented, ""),
+    reg(0x2129, "WH3", Write, Implemented, ""),
+    reg(0x212A, "WBGLOG", Write, Implemented, ""),
+    reg(0x212B, "WOBJLOG", Write, Implemented, ""),
+    reg(0x212C, "TM", Write, Implemented, ""),
+    reg(0x212D, "TS", Write, Implemented, ""),
+    reg(0x212E, "TMW", Write, Implemented, ""),
+    reg(0x212F, "TSW", Write, Implemented, ""),
+    reg(0x2130, "CGWSEL", Write, Implemented, ""),
+    reg(0x2131, "CGADSUB", Write, Implemented, ""),
+    reg(0x2132, "COLDATA", Write, Implemented, ""),
+    reg(0x2133, "SETINI", Write, Partial, "OBJ interlace is rendered, EXTBG, BG interlace, overscan and pseudo hires are only reported by Snes::frame_info"),
+    reg(0x2134, "MPYL", Read, Implemented, ""),
+    reg(0x2135, "MPYM", Read, Implemented, ""),
+    reg(0x2136, "MPYH", Read, Implemented, ""),
+    reg(0x2137, "SLHV", Read, Implemented, ""),
+    reg(0x2138, "OAMDATAREAD", Read, Partial, "Reads during active display return open bus"),
+    reg(0x2139, "VMDATALREAD", Read, Implemented, ""),
+    reg(0x213A, "VMDATAHREAD", Read, Implemented, ""),
+    reg(0x213B, "CGDATAREAD", Read, Partial, "Reads during active display return 0"),
+    reg(0x213C, "OPHCT", Read, Implemented, ""),
+    reg(0x213D, "OPVCT", Read, Implemented, ""),
+    reg(0x213E, "STAT77", Read, Implemented, ""),
+    reg(0x213F, "STAT78", Read, Implemented, ""),
+    reg(0x2140, "APUIO0", ReadWrite, Implemented, "Mirrored up to $217F"),
+    reg(0x2141, "APUIO1", ReadWrite, Implemented, "Mirrored up to $217F"),
+    reg(0x2142, "APUIO2", ReadWrite, Implemented, "Mirrored up to $217F"),
+    reg(0x2143, "APUIO3", ReadWrite, Implemented, "Mirrored up to $217F"),
+    reg(0x2180, "WMDATA", ReadWrite, Implemented, ""),
+    reg(0x2181, "WMADDL", Write, Implemented, ""),
+    reg(0x2182, "WMADDM", Write, Implemented, ""),
+    reg(0x2183, "WMADDH", Write, Implemented, ""),
+    reg(0x2188, "BSX1CHL", ReadWrite, Implemented, "Stream 1 channel, with Snes::set_satellaview"),
+    reg(0x2189, "BSX1CHH", ReadWrite, Implemented, "Stream 1 channel, with Snes::set_satellaview"),
+    reg(0x218A, "BSX1QUEUE", Read, Implemented, "Stream 1 pending packets, with Snes::set_satellaview"),
+    reg(0x218B, "BSX1PREFIX", Read, Implemented, "Stream 1 packet prefix, with Snes::set_satellaview"),
+    reg(0x218C, "BSX1DATA", Read, Implemented, "Stream 1 packet data, with Snes::set_satellaview"),
+    reg(0x218D, "BSX1STAT", Read, Implemented, "Stream 1 status, with Snes::set_satellaview"),
+    reg(0x218E, "BSX2CHL", ReadWrite, Implemented, "Stream 2 channel, with Snes::set_satellaview"),
+    reg(0x218F, "BSX2CHH", ReadWrite, Implemented, "Stream 2 channel, with Snes::set_satellaview"),
+    reg(0x2190, "BSX2QUEUE", Read, Implemented, "Stream 2 pending packets, with Snes::set_satellaview"),
+    reg(0x2191, "BSX2PREFIX", Read, Implemented, "Stream 2 packet prefix, with Snes::set_satellaview"),
+    reg(0x2192, "BSX2DATA", Read, Implemented, "Stream 2 packet data, with Snes::set_satellaview"),
+    reg(0x2193, "BSX2STAT", Read, Implemented, "Stream 2 status, with Snes::set_satellaview"),
+    reg(0x2194, "BSXCTRL", ReadWrite, Stubbed, "$2194-$219F with Snes::set_satellaview"),
+    reg(0x4016, "JOYSER0", ReadWrite, Implemented, ""),
+    reg(0x4017, "JOYSER1", Read, Implemented, ""),
+    reg(0x4200, "NMITIMEN", Write, Implemented, ""),
+    reg(0x4201, "WRIO", Write, Implemented, ""),
+    reg(0x4202, "WRMPYA", Write, Implemented, ""),
+    reg(0x4203, "WRMPYB", Write, Implemented, "The product takes 8 CPU cycles, unless CompatHack::InstantMulDiv"),
+    reg(0x4204, "WRDIVL", Write, Implemented, ""),
+    reg(0x4205, "WRDIVH", Write, Implemented, ""),
+    reg(0x4206, "WRDIVB", Write, Implemented, "The quotient takes 16 CPU cycles, unless CompatHack::InstantMulDiv"),
+    reg(0x4207, "HTIMEL", Write, Implemented, ""),
+    reg(0x4208, "HTIMEH", Write, Implemented, ""),
+    reg(0x4209, "VTIMEL", Write, Implemented, ""),
+    reg(0x420A, "VTIMEH", Write, Implemented, ""),
+    reg(0x420B, "MDMAEN", Write, Implemented, ""),
+    reg(0x420C, "HDMAEN", Write, Implemented, ""),
+    reg(0x420D, "MEMSEL", Write, Implemented, ""),
+    reg(0x4210, "RDNMI", Read, Implemented, ""),
+    reg(0x4211, "TIMEUP", Read, Implemented, ""),
+    reg(0x4212, "HVBJOY", Read, Implemented, ""),
+    reg(0x4213, "RDIO", Read, Implemented, ""),
+    reg(0x4214, "RDDIVL", Read, Implemented, ""),
+    reg(0x4215, "RDDIVH", Read, Implemented, ""),
+    reg(0x4216, "RDMPYL", Read, Implemented, ""),
+    reg(0x4217, "RDMPYH", Read, Implemented, ""),
+    reg(0x4218, "JOY1L", Read, Implemented, ""),
+    reg(0x4219, "JOY1H", Read, Implemented, ""),
+    reg(0x421A, "JOY2L", Read, Implemented, ""),
+    reg(0x421B, "JOY2H", Read, Implemented, ""),
+    reg(0x421C, "JOY3L", Read, Implemented, ""),
+    reg(0x421D, "JOY3H", Read, Implemented, ""),
+    reg(0x421E, "JOY4L", Read, Implemented, ""),
+    reg(0x421F, "JOY4H", Read, Implemented, ""),
+];
+
+macro_rules! dma_names {
+    ($($ch:literal),*) => {
+        [$([
+            concat!("DMAP", $ch),
+            concat!("BBAD", $ch),
+            concat!("A1T", $ch, "L"),
+            concat!("A1T", $ch, "H"),
+            concat!("A1B", $ch),
+            concat!("DAS", $ch, "L"),
+            concat!("DAS", $ch, "H"),
+            concat!("DASB", $ch),
+            concat!("A2A", $ch, "L"),
+            concat!("A2A", $ch, "H"),
+            concat!("NTRL", $ch),
+            concat!("UNUSED", $ch),
+        ]),*]
+    };
+}
+
+const DMA_NAMES: [[&str; 12]; 8] = dma_names!(0, 1, 2, 3, 4, 5, 6, 7);
+
+// $43x0-$43xB, with $43xF mirroring $43xB
+fn dma_register(addr: u16) -> Option<IoRegister> {
+    let ch = (addr >> 4 & 7) as usize;
+    let index = match addr & 0xF {
+        0xF => 0xB,
+        0xC..=0xE => return None,
+        index => index as usize,
+    };
+    Some(reg(addr, DMA_NAMES[ch][index], ReadWrite, Implemented, ""))
+}
+
+// Look up a register by its 24bit CPU address. Mirrors in other banks and the APU port
+// mirrors resolve to the register they mirror, keeping `addr` as given
+pub fn io_register(addr: u32) -> Option<IoRegister> {
+    let bank = addr >> 16 & 0xFF;
+    let offset = addr as u16;
+    if bank & 0x40 != 0 {
+        return None;
+    }
+    let canonical = match offset {
+        0x2140..=0x217F => 0x2140 | offset & 3,
+        0x2194..=0x219F => 0x2194,
+        0x4300..=0x437F => return dma_register(offset),
+        _ => offset,
+    };
+    let index = REGISTERS.binary_search_by_key(&canonical, |r| r.addr).ok()?;
+    Some(IoRegister {
+        addr: offset,
+        ..REGISTERS[index]
+    })
+}
+
+// Every register once, in address order
+pub fn io_registers() -> impl Iterator<Item = IoRegister> {
+    REGISTERS
+        .iter()
+        .copied()
+        .chain((0x4300..0x4380).filter_map(dma_register))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{Bus, Context, Diagnostics};
+    use crate::diagnostics::Diagnostic;
+    use crate::satellaview::Satellaview;
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    // Every address the bus handles in $2100-$21FF and $4000-$43FF is listed, in the
+    // directions it is handled, and nothing else is
+    #[test]
+    fn matches_bus_decoding() {
+        let mut ctx = Context::for_test();
+        ctx.inner1.set_satellaview(Some(Satellaview::new()));
+        let invalid_dma = Arc::new(AtomicU64::new(0));
+        let counter = invalid_dma.clone();
+        let sink = move |diagnostic| {
+            if let Diagnostic::InvalidDmaRegister { .. } = diagnostic {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        ctx.inner1.set_diagnostic_sink(Some(Box::new(sink)));
+
+        for offset in (0x2100..=0x21FF).chain(0x4000..=0x43FF) {
+            let invalid = invalid_dma.load(Ordering::Relaxed);
+            ctx.inner1.bus_read(offset as u32);
+            let read_invalid = invalid_dma.load(Ordering::Relaxed) != invalid;
+            let invalid = invalid_dma.load(Ordering::Relaxed);
+            ctx.inner1.bus_write(offset as u32, 0);
+            let write_invalid = invalid_dma.load(Ordering::Relaxed) != invalid;
+
+            let accesses = ctx.inner1.unimplemented_accesses();
+            let read = !read_invalid && !accesses.contains_key(&(offset, false));
+            let write = !write_invalid && !accesses.contains_key(&(offset, true));
+            match io_register(offset as u32) {
+                Some(register) => {
+                    if register.access != Write {
+                        assert!(read, "{} at {:04X} isn't read", register.name, offset);
+                    }
+                    if register.access != Read {
+                        assert!(write, "{} at {:04X} isn't written", register.name, offset);
+                    }
+                }
+                None => assert!(!read && !write, "{:04X} is handled but not listed", offset),
+            }
+        }
+    }
+
+    #[test]
+    fn sorted() {
+        assert!(REGISTERS.windows(2).all(|w| w[0].addr < w[1].addr));
+    }
+}
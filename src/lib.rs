@@ -1,25 +1,230 @@
-use context::{Bus, Cpu, Ppu, Spc};
-pub use controller::Key;
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+#[cfg(feature = "console")]
+use alloc::boxed::Box;
+#[cfg(feature = "console")]
+use alloc::format;
+#[cfg(feature = "console")]
+use alloc::vec::Vec;
+#[cfg(feature = "console")]
+use core::ops::RangeInclusive;
+#[cfg(feature = "console")]
+use context::{Bus, Cartridge as _, Cpu as _, Diagnostics, Ppu as _, Spc as _, Timing};
+pub use apu::Apu;
+#[cfg(feature = "console")]
+pub use access_hook::{AccessHook, AccessSource, MemoryAccess};
+#[cfg(feature = "console")]
+pub use bus::HdmaChannel;
+#[cfg(feature = "console")]
+pub use compat::CompatHack;
+#[cfg(feature = "console")]
+pub use cartridge::Mapper;
+#[cfg(feature = "console")]
+pub use config::SnesConfig;
+#[cfg(feature = "console")]
+pub use chip_versions::ChipVersions;
+#[cfg(feature = "console")]
+pub use coprocessor::ClockDomain;
+#[cfg(feature = "console")]
+pub use cpu::{Cpu, RegisterSnapshot as CpuRegisters};
+#[cfg(feature = "instrumentation")]
+pub use controller::{InputEvent, InputEventKind};
+#[cfg(feature = "console")]
+pub use controller::{joypad_data, DeviceType, InputProvider, Key, SerialDevice};
+#[cfg(feature = "console")]
+pub use diagnostics::{Diagnostic, DiagnosticSink, VideoStandard};
+pub use dsp::AudioOutputMode;
+#[cfg(feature = "gym")]
+pub use environment::{Environment, EnvironmentError, Observation};
+#[cfg(feature = "console")]
+pub use error::SnesError;
+#[cfg(feature = "console")]
+pub use firmware::Chip;
+#[cfg(feature = "console")]
+pub use io_map::{io_register, io_registers, IoRegister, RegisterAccess, RegisterSupport};
+#[cfg(feature = "console")]
+pub use pacing::AudioPacer;
+#[cfg(feature = "console")]
+pub use perf::PerfStats;
+pub use power_on::PowerOnState;
+#[cfg(feature = "console")]
+pub use satellaview::Satellaview;
+#[cfg(feature = "console")]
+pub use ppu::{DebugRender, DisplayInfo, FrameInfo, Ppu, LayerMask, PixelFormat, SpriteInfo, TilemapEntry, TilemapView, VblankCallback};
+#[cfg(feature = "scripting")]
+pub use scripting::{Script, ScriptError};
+#[cfg(feature = "console")]
+pub use sgb::{GameBoy, GameBoyIo, SuperGameBoy};
+pub use spc::{AudioSink, RegisterSnapshot as SpcRegisters, Spc, SpcState};
+#[cfg(feature = "console")]
+pub use video_filter::VideoFilter;
+
+#[cfg(feature = "console")]
+mod access_hook;
+mod apu;
+#[cfg(all(feature = "console", feature = "std"))]
+mod apu_thread;
+#[cfg(all(feature = "console", feature = "std"))]
+mod avdump;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+#[cfg(feature = "console")]
 mod bus;
+#[cfg(feature = "console")]
 mod cartridge;
+#[cfg(feature = "console")]
+mod chip_versions;
+#[cfg(feature = "console")]
+mod compat;
+#[cfg(feature = "console")]
+mod config;
+#[cfg(feature = "console")]
 mod context;
+#[cfg(feature = "console")]
+mod coprocessor;
+#[cfg(feature = "console")]
 mod controller;
+#[cfg(feature = "console")]
 mod counter;
+#[cfg(feature = "console")]
 mod cpu;
+#[cfg(feature = "console")]
+mod crc32;
+#[cfg(feature = "console")]
+mod diagnostics;
 mod dsp;
+#[cfg(feature = "gym")]
+pub mod environment;
+#[cfg(feature = "console")]
+mod error;
+#[cfg(feature = "console")]
+mod firmware;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+#[cfg(feature = "console")]
 mod interrupt;
+#[cfg(feature = "console")]
+mod io_map;
+#[cfg(feature = "opcode-tests")]
+#[doc(hidden)]
+pub mod opcode_test;
+#[cfg(feature = "console")]
+mod overlay;
+#[cfg(feature = "console")]
+mod pacing;
+#[cfg(feature = "console")]
+mod perf;
+mod power_on;
+#[cfg(feature = "console")]
 mod ppu;
+#[cfg(feature = "console")]
+mod satellaview;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+#[cfg(feature = "console")]
+mod sgb;
 mod spc;
+#[cfg(feature = "console")]
+mod video_filter;
 
+// Snes is Send, so it can be moved to an emulation thread and driven through channels, see
+// examples/threaded.rs. Every callback it takes has to be Send for this. It isn't Sync, so
+// share it through a Mutex rather than a plain reference
+#[cfg(feature = "console")]
 pub struct Snes {
-    pub context: context::Context,
+    pub(crate) context: context::Context,
+    // For power_cycle
+    power_on: PowerOnState,
+    perf: PerfStats,
+    #[cfg(feature = "std")]
+    profiling: bool,
+    #[cfg(feature = "std")]
+    avdump: Option<avdump::AvDump>,
+    #[cfg(feature = "std")]
+    avdump_error: Option<std::io::Error>,
 }
 
+#[cfg(feature = "console")]
+const _: () = {
+    fn assert_send<T: Send>() {}
+    let _ = assert_send::<Snes>;
+};
+
+#[cfg(feature = "console")]
 impl Snes {
-    pub fn new(rom: Vec<u8>, backup: Option<Vec<u8>>) -> Snes {
+    // `rom` is kept as is, so a Vec is moved in without copying and an Arc<[u8]> or a
+    // memory mapped file can be shared with the frontend
+    pub fn new(
+        rom: impl AsRef<[u8]> + Send + Sync + 'static,
+        backup: Option<Vec<u8>>,
+    ) -> Result<Snes, SnesError> {
+        Self::new_with_config(rom, backup, SnesConfig::default())
+    }
+
+    pub fn new_with_power_on_state(
+        rom: impl AsRef<[u8]> + Send + Sync + 'static,
+        backup: Option<Vec<u8>>,
+        power_on: PowerOnState,
+    ) -> Result<Snes, SnesError> {
+        Self::new_with_config(rom, backup, SnesConfig::new().power_on_state(power_on))
+    }
+
+    pub fn new_with_config(
+        rom: impl AsRef<[u8]> + Send + Sync + 'static,
+        backup: Option<Vec<u8>>,
+        config: SnesConfig,
+    ) -> Result<Snes, SnesError> {
+        let cartridge = cartridge::Cartridge::new(Box::new(rom), backup)?;
+        let mut snes = Self::with_cartridge(cartridge, config.power_on);
+        snes.set_chip_versions(config.chip_versions);
+        for &hack in &config.compat_hacks {
+            snes.set_compat_hack(hack, true);
+        }
+        snes.set_no_sprite_limit(config.no_sprite_limit);
+        snes.set_cpu_overclock(config.cpu_overclock);
+        snes.set_audio_output_mode(config.audio_output_mode);
+        snes.context.inner1.inner2.spc.set_ipl_rom(config.ipl_rom);
+        #[cfg(feature = "std")]
+        snes.set_threaded_apu(config.threaded_apu);
+        Ok(snes)
+    }
+
+    // Run on a board the header detection doesn't know, e.g. for homebrew hardware. The
+    // mapper owns the ROM and SRAM. `country` is the header's country code, 0x01 for NTSC
+    pub fn with_mapper(mapper: Box<dyn Mapper>, country: u8, power_on: PowerOnState) -> Snes {
+        Self::with_cartridge(cartridge::Cartridge::with_mapper(mapper, country), power_on)
+    }
+
+    // Super Game Boy with `bios` as the SNES side and `gameboy` running the GB cartridge
+    pub fn with_super_game_boy(
+        bios: impl AsRef<[u8]> + Send + Sync + 'static,
+        gameboy: Box<dyn GameBoy>,
+        power_on: PowerOnState,
+    ) -> Result<Snes, SnesError> {
+        let sgb = SuperGameBoy::new(Box::new(bios), gameboy)?;
+        let country = sgb.country();
+        Ok(Self::with_mapper(Box::new(sgb), country, power_on))
+    }
+
+    fn with_cartridge(cartridge: cartridge::Cartridge, power_on: PowerOnState) -> Snes {
         Snes {
-            context: context::Context::new(rom, backup),
+            context: context::Context::new(cartridge, power_on),
+            power_on,
+            perf: PerfStats::default(),
+            #[cfg(feature = "std")]
+            profiling: false,
+            #[cfg(feature = "std")]
+            avdump: None,
+            #[cfg(feature = "std")]
+            avdump_error: None,
         }
     }
 
@@ -29,22 +234,631 @@ impl Snes {
         }
     }
 
+    // Like pressing the console's reset button. RAM keeps its contents
+    pub fn reset(&mut self) {
+        self.context.reset();
+    }
+
+    // Like switching the console off and on. RAM is initialized again from the PowerOnState
+    // given at creation, a Random one with its seed advanced on every power cycle so the
+    // contents differ but replay the same. Backup RAM and everything set through Snes is kept
+    pub fn power_cycle(&mut self) {
+        self.power_on = self.power_on.next();
+        self.context.power_cycle(&self.power_on);
+    }
+
+    // Shorthand for frontends that don't need an input provider. Takes effect on the next latch
     pub fn set_keys(&mut self, keys: [Vec<Key>; 4]) {
         self.context.inner1.set_keys(keys);
     }
 
-    pub fn exec_frame(&mut self) {
+    pub fn set_input_provider(&mut self, provider: Option<Box<dyn InputProvider + Send>>) {
+        self.context.inner1.set_input_provider(provider);
+    }
+
+    // Replaces the joypad on `port` (0: $4016, 1: $4017) until set back to None, for
+    // peripherals like the Barcode Battler or exercise bikes. Other ports are ignored. Not
+    // part of savestates
+    pub fn set_serial_device(&mut self, port: usize, device: Option<Box<dyn SerialDevice + Send>>) {
+        self.context.inner1.set_serial_device(port, device);
+    }
+
+    // Autofire `key` on pad `port` (0..4), toggling every `rate` controller latches. 0 disables
+    pub fn set_turbo(&mut self, port: usize, key: Key, rate: u8) {
+        self.context.inner1.set_turbo(port, key, rate);
+    }
+
+    // Buttons of pad `port` (0..4) as the game last latched them, by auto joypad read or
+    // strobing $4016, for input displays. Bits as in joypad_data, with turbo applied
+    pub fn last_polled_input(&self, port: usize) -> u16 {
+        self.context.inner1.last_polled_input(port)
+    }
+
+    // Attach a BS-X receiver to the expansion port, fed with broadcasts through
+    // Satellaview::schedule. Not part of savestates
+    pub fn set_satellaview(&mut self, satellaview: Option<Satellaview>) {
+        self.context.inner1.set_satellaview(satellaview);
+    }
+
+    // For scheduling more broadcasts while running
+    pub fn satellaview_mut(&mut self) -> Option<&mut Satellaview> {
+        self.context.inner1.satellaview_mut()
+    }
+
+    // Report every bus access to an address in `ranges`, by the CPU and by (H)DMA on both the
+    // A and the B bus. Other accesses only cost the range check
+    pub fn set_access_hook(
+        &mut self,
+        hook: Option<Box<dyn AccessHook + Send>>,
+        ranges: &[RangeInclusive<u32>],
+    ) {
+        let filter = hook.map(|hook| access_hook::AccessFilter::new(hook, ranges));
+        self.context.inner1.set_access_hook(filter);
+    }
+
+    // Called with the line number at the start of every rendered line (1..=224), before HDMA
+    pub fn set_scanline_callback(&mut self, callback: Box<dyn FnMut(u16) + Send>) {
+        self.context.inner1.inner2.ppu.set_scanline_callback(callback);
+    }
+
+    // Called with the finished frame when the PPU enters vblank, before the NMI. That is 37
+    // lines before exec_frame returns, so frontends can start uploading the picture early
+    pub fn set_vblank_callback(&mut self, callback: Option<VblankCallback>) {
+        self.context.inner1.inner2.ppu.set_vblank_callback(callback);
+    }
+
+    // Version bits of $4210, $213E and $213F. Takes effect immediately
+    pub fn set_chip_versions(&mut self, versions: ChipVersions) {
+        self.context.inner1.set_cpu_version(versions.cpu_version);
+        self.context.inner1.inner2.ppu.set_version(versions.ppu1_version, versions.ppu2_version);
+    }
+
+    pub fn set_layer_enable(&mut self, mask: LayerMask) {
+        self.context.inner1.inner2.ppu.set_layer_enable(mask);
+    }
+
+    // Aim a light gun (Super Scope, Justifier) on port 2 at pixel (x, y). The H/V counters
+    // latch when the beam passes it, provided the game holds WRIO bit7 high. None while the
+    // gun points off screen
+    pub fn set_light_gun(&mut self, target: Option<(u16, u16)>) {
+        self.context.inner1.inner2.ppu.set_light_gun(target);
+    }
+
+    pub fn set_debug_render(&mut self, mode: DebugRender) {
+        self.context.inner1.inner2.ppu.set_debug_render(mode);
+    }
+
+    // Allow VRAM, OAM and CGRAM access during active display, for ROM hacks that rely on
+    // lenient emulators
+    pub fn set_relaxed_memory_access(&mut self, relaxed: bool) {
+        self.context.inner1.inner2.ppu.set_relaxed_memory_access(relaxed);
+    }
+
+    // Trade accuracy for compatibility with a game that hangs otherwise
+    pub fn set_compat_hack(&mut self, hack: CompatHack, enabled: bool) {
+        let inner2 = &mut self.context.inner1.inner2;
+        match hack {
+            CompatHack::RelaxedApuSync => inner2.set_apu_relaxed_sync(enabled),
+            CompatHack::NoDramRefresh => inner2.ppu.set_no_dram_refresh(enabled),
+            CompatHack::RelaxedMemoryAccess => self.set_relaxed_memory_access(enabled),
+            CompatHack::FastApuBoot => self.set_fast_apu_boot(enabled),
+            CompatHack::InstantMulDiv => self.context.inner1.set_instant_mul_div(enabled),
+        }
+    }
+
+    // Draw every sprite on a line instead of the first 32 and 34 slivers, which removes the
+    // flicker games use to show more. $213E still reports the overflows
+    pub fn set_no_sprite_limit(&mut self, no_limit: bool) {
+        self.context.inner1.inner2.ppu.set_no_sprite_limit(no_limit);
+    }
+
+    // Text and rectangles drawn over the picture (BGR555 colors, 3x5 glyphs in 4x6 cells).
+    // They stay on every frame until clear_overlay
+    pub fn draw_text(&mut self, x: i32, y: i32, text: &str, color: u16) {
+        self.context.inner1.inner2.ppu.draw_overlay(|o| o.draw_text(x, y, text, color));
+    }
+
+    pub fn draw_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: u16) {
+        self.context.inner1.inner2.ppu.draw_overlay(|o| o.draw_rect(x, y, width, height, color));
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: u16) {
+        self.context.inner1.inner2.ppu.draw_overlay(|o| o.fill_rect(x, y, width, height, color));
+    }
+
+    pub fn clear_overlay(&mut self) {
+        self.context.inner1.inner2.ppu.clear_overlay();
+    }
+
+    // Post-processing for exec_frame_into and screenshot_png, the raw frame is not affected
+    pub fn set_video_filter(&mut self, filter: VideoFilter) {
+        self.context.inner1.inner2.ppu.set_video_filter(filter);
+    }
+
+    // Skip rendering entirely, e.g. for training agents or automated tests. Emulation and
+    // timing are unaffected, `frame` keeps the last rendered picture
+    pub fn set_headless(&mut self, headless: bool) {
+        self.context.inner1.inner2.ppu.set_headless(headless);
+    }
+
+    // Emulate the IPL ROM's upload handshake instead of running it, so the sound driver is in
+    // APU RAM as soon as the CPU has sent it. Diverges from hardware timing, so off by default.
+    // Call before the first frame to also skip the IPL's initialization at power on
+    pub fn set_fast_apu_boot(&mut self, fast_boot: bool) {
+        self.context.inner1.inner2.set_apu_fast_boot(fast_boot);
+    }
+
+    // Run the CPU `multiplier` times faster relative to the PPU and APU to remove slowdown.
+    // 1 is the real hardware speed
+    pub fn set_cpu_overclock(&mut self, multiplier: u8) {
+        self.context.inner1.counter_mut().set_cpu_multiplier(multiplier as u64);
+    }
+
+    // Run to the start of the next frame. Finishes the current frame when called after
+    // exec_until_scanline
+    pub fn exec_frame(&mut self) -> Result<(), SnesError> {
         let frame = self.context.inner1.inner2.ppu.frame_number;
-        self.context.inner1.inner2.clear_audio_buffer();
+        if self.at_frame_start() {
+            self.context.inner1.inner2.clear_audio_buffer();
+            #[cfg(feature = "instrumentation")]
+            self.context.inner1.clear_input_events();
+        }
+        let dma_bytes = self.context.inner1.dma_bytes();
+        let spc_instructions = self.context.inner1.inner2.spc_instructions();
+        let spc_state = self.context.inner1.inner2.spc_state();
+        let mut perf = PerfStats::default();
+
         while frame == self.context.inner1.inner2.ppu.frame_number {
-            self.context.exce_one();
-            self.context.inner1.inner2.ppu_tick();
-            self.context.inner1.inner2.spc_tick();
-            self.context.inner1.bus_tick();
+            #[cfg(feature = "std")]
+            if self.profiling {
+                self.step_profiled(&mut perf);
+            } else {
+                self.step();
+            }
+            #[cfg(not(feature = "std"))]
+            self.step();
+            perf.cpu_instructions += 1;
+        }
+        self.context.inner1.inner2.spc_sync();
+
+        #[cfg(feature = "std")]
+        if let Some(dump) = &mut self.avdump {
+            let ppu = &self.context.inner1.inner2.ppu;
+            if let Err(err) = dump.write_frame(&ppu.frame, self.context.inner1.inner2.audio_buffer()) {
+                log::warn!("A/V dump stopped: {}", err);
+                self.avdump = None;
+                self.avdump_error = Some(err);
+            }
+        }
+
+        perf.dma_bytes = self.context.inner1.dma_bytes() - dma_bytes;
+        perf.spc_instructions = self.context.inner1.inner2.spc_instructions() - spc_instructions;
+        self.perf = perf;
+
+        let state = self.context.inner1.inner2.spc_state();
+        if state != spc_state {
+            self.context.inner1.report(Diagnostic::ApuHalted(state));
+        }
+
+        if let Some(err) = self.context.inner1.take_bus_error() {
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // exec_frame, then write the frame straight into e.g. a texture staging buffer.
+    // `pitch` is in bytes. Nothing runs if the frame doesn't fit in `buf`
+    pub fn exec_frame_into(
+        &mut self,
+        buf: &mut [u8],
+        format: PixelFormat,
+        pitch: usize,
+    ) -> Result<(), SnesError> {
+        if !format.fits(buf.len(), pitch) {
+            return Err(SnesError::InvalidFrameBuffer {
+                len: buf.len(),
+                pitch,
+            });
+        }
+        let ret = self.exec_frame();
+        self.context.inner1.inner2.ppu.write_frame(buf, format, pitch);
+        ret
+    }
+
+    // Run until the PPU starts line `y` (1..262), for beam racing: the lines before `y` are
+    // in `frame` by then. Finish each frame with exec_frame, which keeps the samples of the
+    // whole frame in audio_buffer and does the A/V dump. Nothing runs for a `y` past the frame
+    pub fn exec_until_scanline(&mut self, y: u16) -> Result<(), SnesError> {
+        if y >= ppu::LINES_PER_FRAME {
+            return Err(SnesError::InvalidScanline(y));
+        }
+        if self.at_frame_start() {
+            self.context.inner1.inner2.clear_audio_buffer();
+            #[cfg(feature = "instrumentation")]
+            self.context.inner1.clear_input_events();
+        }
+        // At least one line, so asking for the current line runs up to it in the next frame
+        let start = self.context.inner1.inner2.ppu_position().2;
+        while self.context.inner1.inner2.ppu_position().2 == start {
+            self.step();
+        }
+        while self.context.inner1.inner2.ppu_position().2 != y {
+            self.step();
+        }
+        self.context.inner1.inner2.spc_sync();
+        match self.context.inner1.take_bus_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
         }
     }
 
+    fn at_frame_start(&self) -> bool {
+        self.context.inner1.inner2.ppu_position().2 == 0
+    }
+
+    // Execute one CPU instruction and catch everything else up to it, for debuggers. The
+    // threaded APU is synced so its state can be inspected right away. Unlike exec_frame
+    // this doesn't start a new audio_buffer
+    pub fn step_instruction(&mut self) -> Result<(), SnesError> {
+        self.step();
+        self.context.inner1.inner2.spc_sync();
+        match self.context.inner1.take_bus_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Step instructions until the PPU reaches the next line
+    pub fn step_scanline(&mut self) -> Result<(), SnesError> {
+        let line = |snes: &Snes| {
+            let (frame, _, y) = snes.context.inner1.inner2.ppu_position();
+            (frame, y)
+        };
+        let start = line(self);
+        while line(self) == start {
+            self.step();
+        }
+        self.context.inner1.inner2.spc_sync();
+        match self.context.inner1.take_bus_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn step(&mut self) {
+        self.context.exce_one();
+        self.context.inner1.inner2.cartridge_tick();
+        self.context.inner1.inner2.ppu_tick();
+        self.context.inner1.inner2.spc_tick();
+        self.context.inner1.bus_tick();
+    }
+
+    #[cfg(feature = "std")]
+    fn step_profiled(&mut self, perf: &mut PerfStats) {
+        use std::time::Instant;
+
+        let start = Instant::now();
+        self.context.exce_one();
+        self.context.inner1.inner2.cartridge_tick();
+        let cpu = Instant::now();
+        self.context.inner1.inner2.ppu_tick();
+        let ppu = Instant::now();
+        self.context.inner1.inner2.spc_tick();
+        let apu = Instant::now();
+        self.context.inner1.bus_tick();
+        let dma = Instant::now();
+
+        perf.cpu_time += cpu - start;
+        perf.ppu_time += ppu - cpu;
+        perf.apu_time += apu - ppu;
+        perf.dma_time += dma - apu;
+    }
+
+    // Write every following frame and its audio to `writer`, see src/avdump.rs for the
+    // format. Audio is missing while an audio sink is set
+    #[cfg(feature = "std")]
+    pub fn start_avdump(&mut self, writer: Box<dyn std::io::Write + Send>) -> std::io::Result<()> {
+        self.stop_avdump()?;
+        self.avdump = Some(avdump::AvDump::new(writer, 256, 224)?);
+        Ok(())
+    }
+
+    // Also returns the error that stopped the dump early, if any
+    #[cfg(feature = "std")]
+    pub fn stop_avdump(&mut self) -> std::io::Result<()> {
+        if let Some(err) = self.avdump_error.take() {
+            return Err(err);
+        }
+        match self.avdump.take() {
+            Some(dump) => dump.finish(),
+            None => Ok(()),
+        }
+    }
+
+    // The current frame as a 256x224 PNG
+    #[cfg(feature = "image")]
+    pub fn screenshot_png(&self) -> image::ImageResult<Vec<u8>> {
+        let mut rgba = vec![0; 256 * 224 * 4];
+        self.context.inner1.inner2.ppu.write_frame(&mut rgba, PixelFormat::Rgba8888, 256 * 4);
+        let mut png = Vec::new();
+        image::png::PngEncoder::new(&mut png).encode(&rgba, 256, 224, image::ColorType::Rgba8)?;
+        Ok(png)
+    }
+
+    // Counters of the last frame
+    pub fn perf_stats(&self) -> PerfStats {
+        self.perf
+    }
+
+    // Also measure the time spent per subsystem. Adds timer overhead to every instruction, and
+    // the clock isn't available on wasm32-unknown-unknown
+    #[cfg(feature = "std")]
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiling = enabled;
+    }
+
+    // PPU state at the end of the last frame
+    pub fn frame_info(&self) -> FrameInfo {
+        self.context.inner1.inner2.ppu.frame_info()
+    }
+
+    // Master clock at which the last vblank started, on the same time base as
+    // audio_timestamp and the audio sink, so recorders can line the streams up exactly
+    pub fn vblank_timestamp(&self) -> u64 {
+        self.context.inner1.inner2.ppu.vblank_timestamp()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        self.context.cpu()
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        self.context.ppu()
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        self.context.ppu_mut()
+    }
+
+    // None with the threaded APU, see set_threaded_apu
+    pub fn apu(&self) -> Option<&Spc> {
+        self.context.spc()
+    }
+
+    pub fn apu_mut(&mut self) -> Option<&mut Spc> {
+        self.context.spc_mut()
+    }
+
+    // Pixel aspect and crop for presenting the last frame
+    pub fn display_info(&self) -> DisplayInfo {
+        self.context.inner1.inner2.ppu.display_info()
+    }
+
+    // Run the SPC700 and DSP on a worker thread. Port reads see the APU as of up to one
+    // scanline ago instead of the exact master clock, so timing differs slightly from the
+    // default single-threaded mode.
+    #[cfg(feature = "std")]
+    pub fn set_threaded_apu(&mut self, threaded: bool) {
+        self.context.inner1.inner2.set_apu_threaded(threaded);
+    }
+
+    // The 8 DMA channels' HDMA state at this point of the frame
+    pub fn hdma_channels(&self) -> Vec<HdmaChannel> {
+        self.context.inner1.hdma_channels()
+    }
+
+    // Controller latches and reads during the last frame
+    #[cfg(feature = "instrumentation")]
+    pub fn input_events(&self) -> &[InputEvent] {
+        self.context.inner1.input_events()
+    }
+
+    // Emulation keeps running after the sound driver halts the SPC700
+    pub fn apu_state(&self) -> SpcState {
+        self.context.inner1.inner2.spc_state()
+    }
+
+    // DSP registers $00-$7F. In threaded mode this is as of the last APU sync
+    pub fn dsp_registers(&self) -> [u8; 0x80] {
+        self.context.inner1.inner2.spc_dsp_registers()
+    }
+
+    // The 64KB of audio RAM, e.g. for showing sample directories, BRR data and the echo
+    // buffer. The APU is caught up first, in threaded mode by copying the RAM over
+    pub fn apu_ram(&mut self) -> &[u8] {
+        self.context.inner1.inner2.spc_ram()
+    }
+
+    // For debuggers and trackers. Writes to $F0-$FF only change RAM, not the I/O registers
+    pub fn write_apu_ram(&mut self, addr: u16, data: u8) {
+        self.context.inner1.inner2.spc_write_ram(addr, data);
+    }
+
+    pub fn write_dsp_register(&mut self, addr: u8, data: u8) {
+        self.context.inner1.inner2.spc_write_dsp(addr, data);
+    }
+
+    // Samples of the last frame. Stays empty while an audio sink is set
+    pub fn audio_buffer(&self) -> &[(i16, i16)] {
+        self.context.inner1.inner2.audio_buffer()
+    }
+
+    // Master clock at which the DSP made audio_buffer()[index]. Samples are 32 APU clocks
+    // apart, about 671 master clocks
+    pub fn audio_timestamp(&self, index: usize) -> Option<u64> {
+        self.context.inner1.inner2.audio_timestamp(index)
+    }
+
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink + Send>>) {
+        self.context.inner1.inner2.set_audio_sink(sink);
+    }
+
+    // Applies to both audio_buffer and the audio sink
+    pub fn set_audio_output_mode(&mut self, mode: AudioOutputMode) {
+        self.context.inner1.inner2.set_audio_output_mode(mode);
+    }
+
+    // Scales the output after the DSP, 1.0 is the game's own level. Louder clips at the i16
+    // range like the DSP mixer does
+    pub fn set_volume(&mut self, volume: f32) {
+        let volume = (volume.clamp(0.0, 16.0) * 256.0) as u16;
+        self.context.inner1.inner2.set_volume(volume);
+    }
+
+    // Silence without touching the volume
+    pub fn set_muted(&mut self, muted: bool) {
+        self.context.inner1.inner2.set_muted(muted);
+    }
+
+    // CRC-32 of the last frame as little endian BGR555, for checking output in tests
+    pub fn frame_crc32(&self) -> u32 {
+        let frame = &self.context.inner1.inner2.ppu.frame;
+        crc32::crc32(frame.iter().flat_map(|pixel| pixel.to_le_bytes()))
+    }
+
+    // CRC-32 of audio_buffer as little endian left, right pairs. 0 while an audio sink is set
+    pub fn audio_crc32(&self) -> u32 {
+        let samples = self.audio_buffer().iter();
+        crc32::crc32(samples.flat_map(|&(left, right)| {
+            let [l0, l1] = left.to_le_bytes();
+            let [r0, r1] = right.to_le_bytes();
+            [l0, l1, r0, r1]
+        }))
+    }
+
+    // Problems known at load, and the unimplemented registers the game touched so far with
+    // how often, for compatibility reports
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut ret = Vec::new();
+
+        let rom = self.context.inner1.inner2.cartridge.video_standard();
+        if rom != ppu::VIDEO_STANDARD {
+            ret.push(Diagnostic::RegionMismatch {
+                rom,
+                console: ppu::VIDEO_STANDARD,
+            });
+        }
+
+        if let Some(chip) = self.missing_firmware() {
+            ret.push(Diagnostic::MissingFirmware(chip));
+        }
+
+        let accesses = self.context.inner1.unimplemented_accesses();
+        ret.extend(accesses.iter().map(|(&(addr, write), &count)| {
+            Diagnostic::UnimplementedRegister { addr, write, count }
+        }));
+
+        ret
+    }
+
+    // Firmware dump of a coprocessor, e.g. dsp1.rom. Frontends can pass every dump they have,
+    // only the one of the game's chip is used
+    pub fn add_firmware(&mut self, chip: Chip, firmware: Vec<u8>) -> Result<(), SnesError> {
+        if firmware.len() != chip.firmware_size() {
+            return Err(SnesError::InvalidFirmware(format!(
+                "{} has to be {} bytes, got {}",
+                chip.file_name(),
+                chip.firmware_size(),
+                firmware.len()
+            )));
+        }
+        self.context.inner1.inner2.cartridge.add_firmware(chip, firmware);
+        Ok(())
+    }
+
+    // The coprocessor firmware the game needs and hasn't been added, to prompt the user for
+    pub fn missing_firmware(&self) -> Option<Chip> {
+        let cartridge = &self.context.inner1.inner2.cartridge;
+        cartridge.firmware_chip().filter(|&chip| !cartridge.has_firmware(chip))
+    }
+
+    // Problems found while running, as they happen. `diagnostics` covers the ones known at load
+    pub fn set_diagnostic_sink(&mut self, sink: Option<Box<dyn DiagnosticSink + Send>>) {
+        self.context.inner1.set_diagnostic_sink(sink);
+    }
+
+    // Savestate of everything but the ROM, in any serde format
+    #[cfg(feature = "serde")]
+    pub fn save_state<S: serde::Serializer>(&mut self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.context.save_state(serializer)
+    }
+
+    // Only valid for the ROM the state was saved with. Callbacks, sinks, the input provider
+    // and settings like compat hacks and the overclock are kept
+    #[cfg(feature = "serde")]
+    pub fn load_state<'de, D: serde::Deserializer<'de>>(
+        &mut self,
+        deserializer: D,
+    ) -> Result<(), D::Error> {
+        let state = <context::Context as serde::Deserialize>::deserialize(deserializer)?;
+        self.context.load_state(state);
+        Ok(())
+    }
+
     pub fn backup(&self) -> Option<Vec<u8>> {
         self.context.inner1.inner2.cartridge.backup()
     }
+
+    // 128KB of work RAM ($7E0000-$7FFFFF), e.g. for reading scores or positions
+    pub fn wram(&self) -> &[u8] {
+        self.context.inner1.wram()
+    }
+
+    pub fn wram_mut(&mut self) -> &mut [u8] {
+        self.context.inner1.wram_mut()
+    }
+
+    pub fn sram_mut(&mut self) -> &mut [u8] {
+        self.context.inner1.inner2.cartridge.sram_mut()
+    }
+}
+
+#[cfg(all(test, feature = "console"))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn snes() -> Snes {
+        Snes::with_cartridge(context::test_cartridge(), PowerOnState::Zero)
+    }
+
+    #[test]
+    fn exec_until_scanline_rejects_lines_past_the_frame() {
+        let mut snes = snes();
+        let last = ppu::LINES_PER_FRAME - 1;
+        assert_eq!(snes.exec_until_scanline(last), Ok(()));
+        let position = snes.context.inner1.inner2.ppu_position();
+        for y in [ppu::LINES_PER_FRAME, u16::MAX] {
+            assert_eq!(
+                snes.exec_until_scanline(y),
+                Err(SnesError::InvalidScanline(y))
+            );
+        }
+        assert_eq!(snes.context.inner1.inner2.ppu_position(), position);
+    }
+
+    #[test]
+    fn exec_frame_into_rejects_small_buffers() {
+        let mut snes = snes();
+        let format = PixelFormat::Xrgb8888;
+        let pitch = 256 * 4;
+        let mut buf = vec![0; pitch * 224];
+        for (len, pitch) in [
+            (pitch * 224 - 1, pitch),
+            (pitch * 224, pitch - 1),
+            (0, usize::MAX),
+        ] {
+            let err = SnesError::InvalidFrameBuffer { len, pitch };
+            assert_eq!(
+                snes.exec_frame_into(&mut buf[..len], format, pitch),
+                Err(err)
+            );
+        }
+        assert_eq!(snes.ppu().frame_number, 0);
+        // The last row doesn't need the padding up to the pitch
+        let padded = pitch + 64;
+        let mut buf = vec![0; padded * 223 + pitch];
+        assert_eq!(snes.exec_frame_into(&mut buf, format, padded), Ok(()));
+        assert_eq!(snes.ppu().frame_number, 1);
+    }
 }
@@ -0,0 +1,380 @@
+// libretro core
+//
+// cargo rustc --release --lib --no-default-features --features libretro --crate-type cdylib
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use bincode::Options;
+use log::warn;
+
+use crate::{Key, PixelFormat, Snes};
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_PIXEL_FORMAT_RGB565: c_uint = 2;
+
+const FRAME_WIDTH: usize = 256;
+const FRAME_HEIGHT: usize = 224;
+// 21.477MHz / (1364 * 262)
+const FPS: f64 = 21_477_272.0 / (1364.0 * 262.0);
+const SAMPLE_RATE: f64 = 32_040.0;
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    geometry: GameGeometry,
+    timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[derive(Default)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+struct Core {
+    snes: Snes,
+    frame: Vec<u8>,
+    audio: Vec<i16>,
+}
+
+thread_local! {
+    static CALLBACKS: RefCell<Callbacks> = RefCell::new(Callbacks::default());
+    static CORE: RefCell<Option<Core>> = const { RefCell::new(None) };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: EnvironmentFn) {
+    CALLBACKS.with(|c| c.borrow_mut().environment = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: VideoRefreshFn) {
+    CALLBACKS.with(|c| c.borrow_mut().video_refresh = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: AudioSampleBatchFn) {
+    CALLBACKS.with(|c| c.borrow_mut().audio_sample_batch = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: InputPollFn) {
+    CALLBACKS.with(|c| c.borrow_mut().input_poll = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: InputStateFn) {
+    CALLBACKS.with(|c| c.borrow_mut().input_state = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    CORE.with(|c| *c.borrow_mut() = None);
+}
+
+/// # Safety
+/// `info` must point to a writable `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: c"rust-snes".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: c"sfc|smc".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to a writable `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: FRAME_WIDTH as c_uint,
+            base_height: FRAME_HEIGHT as c_uint,
+            max_width: FRAME_WIDTH as c_uint,
+            max_height: FRAME_HEIGHT as c_uint,
+            aspect_ratio: 4.0 / 3.0,
+        },
+        timing: SystemTiming {
+            fps: FPS,
+            sample_rate: SAMPLE_RATE,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    CORE.with(|c| {
+        if let Some(core) = c.borrow_mut().as_mut() {
+            core.snes.reset();
+        }
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = CALLBACKS.with(|c| {
+        let c = c.borrow();
+        (c.video_refresh, c.audio_sample_batch, c.input_poll, c.input_state)
+    });
+    let (video_refresh, audio_sample_batch, input_poll, input_state) = callbacks;
+
+    CORE.with(|c| {
+        let mut core = c.borrow_mut();
+        let Some(core) = core.as_mut() else {
+            return;
+        };
+
+        let mut keys: [Vec<Key>; 4] = Default::default();
+        unsafe {
+            if let Some(input_poll) = input_poll {
+                input_poll();
+            }
+            if let Some(input_state) = input_state {
+                for (port, keys) in keys.iter_mut().enumerate() {
+                    for (id, &key) in Key::ALL.iter().enumerate() {
+                        if input_state(port as c_uint, RETRO_DEVICE_JOYPAD, 0, id as c_uint) != 0 {
+                            keys.push(key);
+                        }
+                    }
+                }
+            }
+        }
+        core.snes.set_keys(keys);
+
+        // Keep presenting the last frame while halted
+        if let Err(err) = core.snes.exec_frame_into(&mut core.frame, PixelFormat::Rgb565, FRAME_WIDTH * 2) {
+            warn!("{}", err);
+        }
+
+        core.audio.clear();
+        core.audio.extend(core.snes.audio_buffer().iter().flat_map(|s| [s.0, s.1]));
+
+        unsafe {
+            if let Some(video_refresh) = video_refresh {
+                video_refresh(
+                    core.frame.as_ptr() as *const c_void,
+                    FRAME_WIDTH as c_uint,
+                    FRAME_HEIGHT as c_uint,
+                    FRAME_WIDTH * 2,
+                );
+            }
+            if let Some(audio_sample_batch) = audio_sample_batch {
+                let mut samples = &core.audio[..];
+                while !samples.is_empty() {
+                    let written = audio_sample_batch(samples.as_ptr(), samples.len() / 2);
+                    if written == 0 {
+                        break;
+                    }
+                    samples = &samples[(written * 2).min(samples.len())..];
+                }
+            }
+        }
+    });
+}
+
+// Fixed size integers, so the size only changes with the game's own state
+fn state_options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+fn save_state(snes: &mut Snes) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    snes.save_state(&mut bincode::Serializer::new(&mut buf, state_options())).ok()?;
+    Some(buf)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    CORE.with(|c| match c.borrow_mut().as_mut() {
+        Some(core) => save_state(&mut core.snes).map_or(0, |state| state.len()),
+        None => 0,
+    })
+}
+
+/// # Safety
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    CORE.with(|c| {
+        let mut core = c.borrow_mut();
+        let Some(state) = core.as_mut().and_then(|core| save_state(&mut core.snes)) else {
+            return false;
+        };
+        if state.len() > size {
+            return false;
+        }
+        // The rest of the buffer is zeroed, trailing bytes are ignored when loading
+        let buf = slice::from_raw_parts_mut(data as *mut u8, size);
+        buf[..state.len()].copy_from_slice(&state);
+        buf[state.len()..].fill(0);
+        true
+    })
+}
+
+/// # Safety
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let state = slice::from_raw_parts(data as *const u8, size);
+    CORE.with(|c| match c.borrow_mut().as_mut() {
+        Some(core) => {
+            let mut deserializer = bincode::Deserializer::from_slice(state, state_options());
+            match core.snes.load_state(&mut deserializer) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!("Failed to load state: {}", err);
+                    false
+                }
+            }
+        }
+        None => false,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+/// `game` must be NULL or point to a valid `retro_game_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    let Some(game) = game.as_ref() else {
+        return false;
+    };
+    if game.data.is_null() {
+        return false;
+    }
+
+    let environment = CALLBACKS.with(|c| c.borrow().environment);
+    if let Some(environment) = environment {
+        let mut format = RETRO_PIXEL_FORMAT_RGB565;
+        if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+            return false;
+        }
+    }
+
+    let rom: Arc<[u8]> = Arc::from(slice::from_raw_parts(game.data as *const u8, game.size));
+    let Ok(snes) = Snes::new(rom, None) else {
+        return false;
+    };
+    CORE.with(|c| {
+        *c.borrow_mut() = Some(Core {
+            snes,
+            frame: vec![0; FRAME_WIDTH * FRAME_HEIGHT * 2],
+            audio: Vec::new(),
+        })
+    });
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const GameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    CORE.with(|c| *c.borrow_mut() = None);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    if id != RETRO_MEMORY_SAVE_RAM {
+        return ptr::null_mut();
+    }
+    CORE.with(|c| match c.borrow_mut().as_mut() {
+        Some(core) => core.snes.sram_mut().as_mut_ptr() as *mut c_void,
+        None => ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    if id != RETRO_MEMORY_SAVE_RAM {
+        return 0;
+    }
+    CORE.with(|c| match c.borrow_mut().as_mut() {
+        Some(core) => core.snes.sram_mut().len(),
+        None => 0,
+    })
+}
@@ -0,0 +1,268 @@
+// Single instruction harness for the 65C816 and SPC700 JSON test suites, see
+// src/bin/opcode_tests.rs. Not a stable API
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::access_hook::AccessFilter;
+use crate::bus::HdmaChannel;
+use crate::controller::{InputProvider, Key, SerialDevice};
+use crate::cpu::Cpu;
+use crate::error::SnesError;
+use crate::satellaview::Satellaview;
+use crate::spc::Spc;
+use crate::{context, counter};
+
+pub use crate::cpu::RegisterSnapshot as CpuRegisters;
+pub use crate::spc::RegisterSnapshot as SpcRegisters;
+
+// Master clocks per internal CPU cycle, same as cpu::CPU_CYCLE
+const CPU_CYCLE: u64 = 6;
+
+// 16MB of plain RAM behind the whole 24bit address space. Every access is one cycle
+struct FlatBus {
+    memory: Vec<u8>,
+    touched: Vec<u32>,
+    accesses: u64,
+    timing: counter::Counter,
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        FlatBus {
+            memory: vec![0; 0x100_0000],
+            touched: Vec::new(),
+            accesses: 0,
+            timing: counter::Counter::default(),
+        }
+    }
+}
+
+impl FlatBus {
+    fn clear(&mut self) {
+        for addr in self.touched.drain(..) {
+            self.memory[addr as usize] = 0;
+        }
+        self.accesses = 0;
+        self.timing = counter::Counter::default();
+    }
+
+    fn cycles(&self) -> u64 {
+        self.accesses + self.timing.now() / CPU_CYCLE
+    }
+}
+
+impl context::Bus for FlatBus {
+    fn bus_read(&mut self, addr: u32) -> u8 {
+        self.accesses += 1;
+        self.memory[(addr & 0xFF_FFFF) as usize]
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) {
+        self.accesses += 1;
+        let addr = addr & 0xFF_FFFF;
+        self.memory[addr as usize] = data;
+        self.touched.push(addr);
+    }
+
+    fn bus_tick(&mut self) {}
+
+    fn take_bus_error(&mut self) -> Option<SnesError> {
+        None
+    }
+
+    fn dma_bytes(&self) -> u64 {
+        0
+    }
+
+    fn wram(&self) -> &[u8] {
+        &self.memory[0x7E_0000..0x80_0000]
+    }
+
+    fn wram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory[0x7E_0000..0x80_0000]
+    }
+
+    #[cfg(feature = "scripting")]
+    fn watch_wram_write(&mut self, _offset: usize) {}
+
+    #[cfg(feature = "scripting")]
+    fn take_wram_writes(&mut self) -> Vec<(u32, u8)> {
+        Vec::new()
+    }
+
+    fn set_keys(&mut self, _keys: [Vec<Key>; 4]) {}
+
+    fn set_input_provider(&mut self, _provider: Option<Box<dyn InputProvider + Send>>) {}
+
+    fn set_serial_device(&mut self, _port: usize, _device: Option<Box<dyn SerialDevice + Send>>) {}
+
+    fn set_turbo(&mut self, _pad: usize, _key: Key, _rate: u8) {}
+
+    fn last_polled_input(&self, _pad: usize) -> u16 {
+        0
+    }
+
+    fn hdma_channels(&self) -> Vec<HdmaChannel> {
+        Vec::new()
+    }
+
+    fn unimplemented_accesses(&self) -> &BTreeMap<(u16, bool), u64> {
+        static NONE: BTreeMap<(u16, bool), u64> = BTreeMap::new();
+        &NONE
+    }
+
+    fn set_cpu_version(&mut self, _version: u8) {}
+    fn set_instant_mul_div(&mut self, _instant: bool) {}
+
+    fn set_satellaview(&mut self, _satellaview: Option<Satellaview>) {}
+
+    fn satellaview_mut(&mut self) -> Option<&mut Satellaview> {
+        None
+    }
+
+    fn set_access_hook(&mut self, _hook: Option<AccessFilter>) {}
+
+    #[cfg(feature = "instrumentation")]
+    fn input_events(&self) -> &[crate::controller::InputEvent] {
+        &[]
+    }
+
+    #[cfg(feature = "instrumentation")]
+    fn clear_input_events(&mut self) {}
+}
+
+impl context::Timing for FlatBus {
+    fn elapse(&mut self, clock: u64) {
+        self.timing.elapse(clock);
+    }
+
+    fn now(&self) -> u64 {
+        self.timing.now()
+    }
+
+    fn counter(&self) -> &counter::Counter {
+        &self.timing
+    }
+
+    fn counter_mut(&mut self) -> &mut counter::Counter {
+        &mut self.timing
+    }
+}
+
+// No interrupt sources, the tests only cover the instruction itself
+impl context::Interrupt for FlatBus {
+    fn get_nmi_flag(&mut self) -> bool {
+        false
+    }
+
+    fn set_nmi_flag(&mut self, _flag: bool, _at: u64) {}
+
+    fn nmi_occurred(&mut self, _poll: u64) -> bool {
+        false
+    }
+
+    fn set_nmi_enable(&mut self, _flag: bool) {}
+
+    fn set_hv_irq_enable(&mut self, _val: u8) {}
+
+    fn get_hv_irq_enable(&self) -> u8 {
+        0
+    }
+
+    fn set_h_count(&mut self, _val: u16) {}
+
+    fn get_h_count(&self) -> u16 {
+        0
+    }
+
+    fn set_v_count(&mut self, _val: u16) {}
+
+    fn get_v_count(&self) -> u16 {
+        0
+    }
+
+    fn set_irq(&mut self, _flag: bool, _at: u64) {}
+
+    fn irq_occurred(&self, _poll: u64) -> bool {
+        false
+    }
+
+    fn get_irq_flag(&mut self) -> bool {
+        false
+    }
+}
+
+#[derive(Default)]
+pub struct CpuTest {
+    cpu: Cpu,
+    bus: FlatBus,
+}
+
+impl CpuTest {
+    // Start a new test case: zeroed memory and a fresh CPU
+    pub fn reset(&mut self) {
+        self.cpu = Cpu::default();
+        self.bus.clear();
+    }
+
+    pub fn set_registers(&mut self, registers: &CpuRegisters) {
+        self.cpu.set_registers(registers);
+    }
+
+    pub fn registers(&self) -> CpuRegisters {
+        self.cpu.registers()
+    }
+
+    pub fn write(&mut self, addr: u32, data: u8) {
+        let addr = addr & 0xFF_FFFF;
+        self.bus.memory[addr as usize] = data;
+        self.bus.touched.push(addr);
+    }
+
+    pub fn read(&self, addr: u32) -> u8 {
+        self.bus.memory[(addr & 0xFF_FFFF) as usize]
+    }
+
+    // Execute one instruction and return the CPU cycles it took
+    pub fn step(&mut self) -> u64 {
+        self.cpu.excecute_instruction(&mut self.bus);
+        self.bus.cycles()
+    }
+}
+
+#[derive(Default)]
+pub struct SpcTest {
+    spc: Spc,
+}
+
+impl SpcTest {
+    // Start a new test case: zeroed RAM, no IPL ROM and a fresh SPC700
+    pub fn reset(&mut self) {
+        self.spc = Spc::default();
+        self.spc.flat_ram();
+    }
+
+    pub fn set_registers(&mut self, registers: &SpcRegisters) {
+        self.spc.set_registers(registers);
+    }
+
+    pub fn registers(&self) -> SpcRegisters {
+        self.spc.registers()
+    }
+
+    // $F0-$FF are the SPC700 I/O registers, tests touching them are not meaningful here
+    pub fn write(&mut self, addr: u16, data: u8) {
+        self.spc.flat_ram()[addr as usize] = data;
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        self.spc.flat_ram()[addr as usize]
+    }
+
+    // Execute one instruction and return the SPC700 cycles it took
+    pub fn step(&mut self) -> u64 {
+        self.spc.step()
+    }
+}
@@ -0,0 +1,160 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+const WIDTH: i32 = 256;
+const HEIGHT: i32 = 224;
+// Set on drawn pixels, the rest of the overlay is transparent
+const OPAQUE: u16 = 0x8000;
+
+// Glyph cell of draw_text, the glyphs themselves are 3x5
+pub const GLYPH_WIDTH: i32 = 4;
+pub const GLYPH_HEIGHT: i32 = 6;
+
+// Drawn over the picture after color math and brightness. Stays until cleared
+pub struct Overlay {
+    pixels: Vec<u16>,
+    is_empty: bool,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Overlay {
+            pixels: vec![0; (WIDTH * HEIGHT) as usize],
+            is_empty: true,
+        }
+    }
+}
+
+impl Overlay {
+    pub fn clear(&mut self) {
+        self.pixels.fill(0);
+        self.is_empty = true;
+    }
+
+    // Colors are BGR555. Anything outside the 256x224 frame is clipped
+    pub fn draw_pixel(&mut self, x: i32, y: i32, color: u16) {
+        if (0..WIDTH).contains(&x) && (0..HEIGHT).contains(&y) {
+            self.pixels[(y * WIDTH + x) as usize] = color & 0x7FFF | OPAQUE;
+            self.is_empty = false;
+        }
+    }
+
+    pub fn draw_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: u16) {
+        for i in x..x + width {
+            self.draw_pixel(i, y, color);
+            self.draw_pixel(i, y + height - 1, color);
+        }
+        for j in y..y + height {
+            self.draw_pixel(x, j, color);
+            self.draw_pixel(x + width - 1, j, color);
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: u16) {
+        for j in y..y + height {
+            for i in x..x + width {
+                self.draw_pixel(i, j, color);
+            }
+        }
+    }
+
+    // Lowercase letters are drawn as uppercase, '\n' starts a new line
+    pub fn draw_text(&mut self, x: i32, y: i32, text: &str, color: u16) {
+        let (mut cx, mut cy) = (x, y);
+        for c in text.chars() {
+            if c == '\n' {
+                cx = x;
+                cy += GLYPH_HEIGHT;
+                continue;
+            }
+            let glyph = glyph(c.to_ascii_uppercase());
+            for row in 0..5 {
+                for col in 0..3 {
+                    if glyph >> (14 - row * 3 - col) & 1 == 1 {
+                        self.draw_pixel(cx + col, cy + row, color);
+                    }
+                }
+            }
+            cx += GLYPH_WIDTH;
+        }
+    }
+
+    // `span` indexes both the overlay and the 256x224 `frame`
+    pub fn composite(&self, frame: &mut [u16], span: Range<usize>) {
+        if self.is_empty {
+            return;
+        }
+        for i in span {
+            if self.pixels[i] & OPAQUE != 0 {
+                frame[i] = self.pixels[i] & 0x7FFF;
+            }
+        }
+    }
+}
+
+// 3x5 glyph, 3 bits per row with the top row in bits 14..=12
+fn glyph(c: char) -> u16 {
+    let rows: [u8; 5] = match c {
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        ';' => [0b000, 0b010, 0b000, 0b010, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '*' => [0b101, 0b010, 0b101, 0b000, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '[' => [0b011, 0b010, 0b010, 0b010, 0b011],
+        ']' => [0b110, 0b010, 0b010, 0b010, 0b110],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '"' => [0b101, 0b101, 0b000, 0b000, 0b000],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010], // '?'
+    };
+    rows.iter().fold(0, |glyph, &row| glyph << 3 | row as u16)
+}
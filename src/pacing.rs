@@ -0,0 +1,61 @@
+// Audio-clocked frame pacing.
+// The frontend reports how many stereo samples its audio device has consumed, and the
+// pacer tells whether another frame is needed to keep the queue near the target latency.
+
+pub struct AudioPacer {
+    generated: u64,
+    consumed: u64,
+    target_latency: u64,
+    max_latency: u64,
+}
+
+impl AudioPacer {
+    // Latencies are in stereo samples (32000 per second)
+    pub fn new(target_latency: u64, max_latency: u64) -> AudioPacer {
+        assert!(target_latency <= max_latency);
+        AudioPacer {
+            generated: 0,
+            consumed: 0,
+            target_latency,
+            max_latency,
+        }
+    }
+
+    pub fn report_generated(&mut self, samples: usize) {
+        self.generated += samples as u64;
+    }
+
+    pub fn report_consumed(&mut self, samples: usize) {
+        self.consumed = (self.consumed + samples as u64).min(self.generated);
+    }
+
+    // For backends that expose the queued amount rather than a consumption callback
+    pub fn report_queued(&mut self, samples: usize) {
+        self.consumed = self.generated.saturating_sub(samples as u64).max(self.consumed);
+    }
+
+    pub fn queued(&self) -> u64 {
+        self.generated - self.consumed
+    }
+
+    pub fn should_exec_frame(&self) -> bool {
+        self.queued() < self.target_latency
+    }
+
+    // More than this is queued, so the frontend may drop samples to recover from drift
+    pub fn is_overrun(&self) -> bool {
+        self.queued() > self.max_latency
+    }
+
+    pub fn reset(&mut self) {
+        self.generated = 0;
+        self.consumed = 0;
+    }
+}
+
+impl Default for AudioPacer {
+    fn default() -> Self {
+        // About 2 and 6 frames worth of samples
+        AudioPacer::new(1067, 3200)
+    }
+}
@@ -0,0 +1,17 @@
+use core::time::Duration;
+
+// Work done during the last `Snes::exec_frame`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PerfStats {
+    pub cpu_instructions: u64,
+    pub spc_instructions: u64,
+    // Bytes moved by GDMA and HDMA
+    pub dma_bytes: u64,
+
+    // Wall clock time, only measured while profiling is enabled (see Snes::set_profiling)
+    pub cpu_time: Duration,
+    pub ppu_time: Duration,
+    pub apu_time: Duration,
+    // HDMA, GDMA and auto joypad read
+    pub dma_time: Duration,
+}
@@ -0,0 +1,58 @@
+// Initial contents of WRAM, VRAM and APU RAM. Some games depend on them, so keep this fixed
+// (or keep the seed) when recording movies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PowerOnState {
+    #[default]
+    Zero,
+    Pattern55,
+    Random { seed: u64 },
+}
+
+impl PowerOnState {
+    // State for the next power cycle. A Random seed advances, so RAM doesn't come back with
+    // the same contents, but the sequence is the same for a given first seed
+    pub(crate) fn next(&self) -> PowerOnState {
+        match *self {
+            PowerOnState::Random { seed } => PowerOnState::Random {
+                seed: seed.wrapping_add(0x9E37_79B9_7F4A_7C15),
+            },
+            state => state,
+        }
+    }
+
+    // `salt` gives each memory its own random stream for the same seed
+    pub(crate) fn fill(&self, buf: &mut [u8], salt: u64) {
+        match *self {
+            PowerOnState::Zero => buf.fill(0),
+            PowerOnState::Pattern55 => buf.fill(0x55),
+            PowerOnState::Random { seed } => {
+                // xorshift64*
+                let mut state = (seed ^ salt.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1;
+                for b in buf.iter_mut() {
+                    state ^= state >> 12;
+                    state ^= state << 25;
+                    state ^= state >> 27;
+                    *b = (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_advances_random_seeds() {
+        let first = PowerOnState::Random { seed: 1 };
+        let [mut a, mut b] = [[0; 64]; 2];
+        first.fill(&mut a, 0);
+        first.next().fill(&mut b, 0);
+        assert_ne!(a, b);
+        assert_eq!(first.next(), first.next());
+        for state in [PowerOnState::Zero, PowerOnState::Pattern55] {
+            assert_eq!(state.next(), state);
+        }
+    }
+}
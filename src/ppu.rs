@@ -1,9 +1,21 @@
 use crate::context;
+use crate::diagnostics::{Diagnostic, VideoStandard};
+use crate::chip_versions::ChipVersions;
+use crate::overlay::Overlay;
+use crate::power_on::PowerOnState;
+use crate::video_filter::{self, VideoFilter};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
 use modular_bitfield::prelude::*;
 
 use log::{debug,info, warn};
-trait Context: context::Timing + context::Interrupt  {}
-impl<T: context::Timing + context::Interrupt> Context for T {}
+pub(crate) trait Context: context::Timing + context::Interrupt + context::Diagnostics {}
+impl<T: context::Timing + context::Interrupt + context::Diagnostics> Context for T {}
+
+// Receives the finished frame at the start of vblank, see Snes::set_vblank_callback
+pub type VblankCallback = Box<dyn FnMut(&[u16]) + Send>;
 
 const FRAME_HEIGHT: usize = 224;
 const FRAME_WIDTH: usize = 256;
@@ -20,14 +32,54 @@ const BG_MODE_BPP: [&[usize]; 8] = [
     &[8],           // Mode7 
 ];
 
+// Lower values are drawn in front
 const OBJ_PRIORITY: [u8; 4] = [10, 7, 4, 1];
+const BACKDROP_PRIORITY: u8 = 13;
+
+// [mode][bg][low, high], against OBJ_PRIORITY
+//  Mode0   : OBJ3 BG1H BG2H OBJ2 BG1L BG2L OBJ1 BG3H BG4H OBJ0 BG3L BG4L
+//  Mode1   : OBJ3 BG1H BG2H OBJ2 BG1L BG2L OBJ1 BG3H OBJ0 BG3L
+//  Mode2..5: OBJ3 BG1H OBJ2 BG2H OBJ1 BG1L OBJ0 BG2L
+//  Mode6   : OBJ3 BG1H OBJ2 OBJ1 BG1L OBJ0
+//  Mode7   : OBJ3 OBJ2 BG2H OBJ1 BG1 OBJ0 BG2L (BG2 is EXTBG)
+#[rustfmt::skip]
+const BG_PRIORITY: [[[u8; 2]; 4]; 8] = [
+    [[5, 2], [ 6, 3], [11, 8], [12, 9]], // Mode0
+    [[5, 2], [ 6, 3], [12, 8], [ 0, 0]], // Mode1
+    [[8, 2], [11, 5], [ 0, 0], [ 0, 0]], // Mode2
+    [[8, 2], [11, 5], [ 0, 0], [ 0, 0]], // Mode3
+    [[8, 2], [11, 5], [ 0, 0], [ 0, 0]], // Mode4
+    [[8, 2], [11, 5], [ 0, 0], [ 0, 0]], // Mode5
+    [[8, 2], [ 0, 0], [ 0, 0], [ 0, 0]], // Mode6
+    [[8, 8], [11, 5], [ 0, 0], [ 0, 0]], // Mode7
+];
+
+// Mode1 with the BG3 priority bit ($2105.3): high BG3 tiles go in front of everything
+const MODE1_BG3_PRIORITY: [u8; 2] = [12, 0];
+
+// Sprites and 8 pixel sprite slivers per line
+const OBJ_RANGE_LIMIT: usize = 32;
+const OBJ_TIME_LIMIT: usize = 34;
 
+// Pixel 0 of a line is output at this dot
+const FIRST_VISIBLE_DOT: u16 = 22;
+
+// Only 60Hz timing (262 lines, $213F bit4 = 0) is emulated
+pub const VIDEO_STANDARD: VideoStandard = VideoStandard::Ntsc;
+pub const LINES_PER_FRAME: u16 = 262;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ppu {
+    #[cfg_attr(feature = "serde", serde(with = "serde_big_array::BigArray"))]
     pub frame: [u16; FRAME_WIDTH * FRAME_HEIGHT],
     pub frame_number: u64,
     counter: u64,
+    #[cfg_attr(feature = "serde", serde(with = "serde_big_array::BigArray"))]
     main_screen: [PixelInfo; FRAME_WIDTH],
+    #[cfg_attr(feature = "serde", serde(with = "serde_big_array::BigArray"))]
     sub_screen: [PixelInfo; FRAME_WIDTH],
+    // Pixels of the current line already rendered. FRAME_WIDTH outside of active display
+    render_x: usize,
 
     x: u16,
     y: u16,
@@ -37,11 +89,22 @@ pub struct Ppu {
     is_hdma_reload: bool,
     is_hdma_transfer: bool,
 
-    pub vram: [u8; 0x10000], // 64KB
+    #[cfg_attr(feature = "serde", serde(with = "serde_big_array::BigArray"))]
+    vram: [u8; 0x10000], // 64KB
+    // Every write to `vram` has to invalidate its tiles, see write_vram
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tile_cache: TileCache,
+    // Scratch lines of render_bg, one per BG
+    #[cfg_attr(feature = "serde", serde(skip, default = "BgPixel::lines"))]
+    bg_lines: Box<[[BgPixel; FRAME_WIDTH]; 4]>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_big_array::BigArray"))]
     cgram: [u16; 0x100], // 512B
+    #[cfg_attr(feature = "serde", serde(with = "serde_big_array::BigArray"))]
     pub oam: [u8; 0x220],    // 544B
     
     open_bus1: u8,
+    ppu1_version: u8,
+    ppu2_version: u8,
     open_bus2: u8,
 
     // Ppu control registers
@@ -66,6 +129,10 @@ pub struct Ppu {
     oam_addr_and_priority_rotation: OamAddrAndPriorityRotation, // $2102, $2103
     oam_addr: u16,
     oam_lsb: u8, //
+    // Sprites for the next line are evaluated during the current one, which force blank skips
+    obj_evaluated: bool,
+    // OAM index of each sprite on the next line and which of its 8 pixel columns are drawn
+    obj_line: Vec<(u8, u8)>,
 
     // I/O port registers
     vram_mode: VramAddrIncMode, // $2115
@@ -91,6 +158,9 @@ pub struct Ppu {
 
     // Math Multiply and Devide registers
     mpy: i32, // $2134, $2135, $2136
+    // Product before the last M7A/M7B write, read until the new one is ready
+    mpy_prev: i32,
+    mpy_ready_at: u64,
 
     // Timers and Status
     h_counter_latch: u16, //$213C
@@ -98,14 +168,49 @@ pub struct Ppu {
     hv_latched: bool,
     h_flipflopped: bool,
     v_flipflopped: bool,
+    // Pin 6 of controller port 2 as driven by WRIO ($4201) bit7. The counters latch when the
+    // line goes low, which a light gun can only do while WRIO keeps it high
+    wrio_latch: bool,
+    // Pixel a light gun on port 2 is aimed at, see Snes::set_light_gun
+    #[cfg_attr(feature = "serde", serde(skip))]
+    light_gun: Option<(u16, u16)>,
     obj_time_overflow: bool,
     obj_range_overflow: bool,
 
     auto_joypad_read: bool,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    scanline_callback: Option<Box<dyn FnMut(u16) + Send>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    vblank_callback: Option<VblankCallback>,
+    #[cfg_attr(feature = "serde", serde(skip, default = "LayerMask::all"))]
+    layer_enable: LayerMask,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    debug_render: DebugRender,
+    // Frontend settings, not part of savestates
+    #[cfg_attr(feature = "serde", serde(skip))]
+    relaxed_memory_access: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    no_sprite_limit: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    no_dram_refresh: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    headless: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    overlay: Overlay,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    video_filter: VideoFilter,
+    // The frame before `frame` while frame blending is on, empty otherwise
+    #[cfg_attr(feature = "serde", serde(skip))]
+    blend_frame: Vec<u16>,
+    frame_info: FrameInfo,
+    // Master clock at which the last vblank started
+    vblank_timestamp: u64,
 }
 
 #[bitfield(bits = 8)]
 #[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct VramAddrIncMode {
     increment_step: B2,
     transration: B2,
@@ -144,6 +249,7 @@ impl Default for Ppu {
             counter: 0,
             main_screen: [Default::default(); FRAME_WIDTH],
             sub_screen: [Default::default(); FRAME_WIDTH],
+            render_x: FRAME_WIDTH,
 
             x: 0,
             y: 0,
@@ -154,10 +260,14 @@ impl Default for Ppu {
             is_hdma_transfer: false,
         
             vram: [0; 0x10000],
+            tile_cache: TileCache::default(),
+            bg_lines: BgPixel::lines(),
             cgram: [0; 0x100],
             oam: [0; 0x220],
 
             open_bus1: 0,
+            ppu1_version: ChipVersions::default().ppu1_version,
+            ppu2_version: ChipVersions::default().ppu2_version,
             open_bus2: 0,
 
             
@@ -179,6 +289,8 @@ impl Default for Ppu {
             oam_addr_and_priority_rotation: Default::default(),
             oam_addr: 0,
             oam_lsb: 0,
+            obj_evaluated: false,
+            obj_line: Vec::new(),
 
             vram_mode: Default::default(),
             vram_addr: 0,
@@ -199,43 +311,236 @@ impl Default for Ppu {
             color_math_sub_screen_backdrop_color: Default::default(),
 
             mpy: 1,
+            mpy_prev: 1,
+            mpy_ready_at: 0,
 
             h_counter_latch: 0,
             v_counter_latch: 0,
             hv_latched: false,
             h_flipflopped: false,
             v_flipflopped: false,
+            wrio_latch: true,
+            light_gun: None,
             obj_range_overflow: false,
             obj_time_overflow: false,
 
             auto_joypad_read: false,
+
+            scanline_callback: None,
+            vblank_callback: None,
+            layer_enable: LayerMask::all(),
+            debug_render: DebugRender::Off,
+            relaxed_memory_access: false,
+            no_sprite_limit: false,
+            no_dram_refresh: false,
+            headless: false,
+            overlay: Overlay::default(),
+            video_filter: VideoFilter::default(),
+            blend_frame: Vec::new(),
+            frame_info: Default::default(),
+            vblank_timestamp: 0,
         }
         
     }
 }
 
 impl Ppu {
+    pub fn set_scanline_callback(&mut self, callback: Box<dyn FnMut(u16) + Send>) {
+        self.scanline_callback = Some(callback);
+    }
+
+    pub fn set_vblank_callback(&mut self, callback: Option<VblankCallback>) {
+        self.vblank_callback = callback;
+    }
+
+    // Exchange what a savestate doesn't contain. The tile cache of both is stale afterwards
+    pub fn swap_host_state(&mut self, other: &mut Ppu) {
+        core::mem::swap(&mut self.scanline_callback, &mut other.scanline_callback);
+        core::mem::swap(&mut self.vblank_callback, &mut other.vblank_callback);
+        core::mem::swap(&mut self.layer_enable, &mut other.layer_enable);
+        core::mem::swap(&mut self.debug_render, &mut other.debug_render);
+        core::mem::swap(&mut self.headless, &mut other.headless);
+        core::mem::swap(&mut self.overlay, &mut other.overlay);
+        core::mem::swap(&mut self.video_filter, &mut other.video_filter);
+        core::mem::swap(&mut self.blend_frame, &mut other.blend_frame);
+        core::mem::swap(&mut self.light_gun, &mut other.light_gun);
+        core::mem::swap(&mut self.relaxed_memory_access, &mut other.relaxed_memory_access);
+        core::mem::swap(&mut self.no_sprite_limit, &mut other.no_sprite_limit);
+        core::mem::swap(&mut self.no_dram_refresh, &mut other.no_dram_refresh);
+        self.tile_cache.invalidate_all();
+        other.tile_cache.invalidate_all();
+    }
+
+    pub fn vram(&self) -> &[u8; 0x10000] {
+        &self.vram
+    }
+
+    // For debuggers and tools, ignores the access rules of $2118/$2119
+    pub fn write_vram(&mut self, addr: u16, data: u8) {
+        self.vram[addr as usize] = data;
+        self.tile_cache.invalidate(addr as usize);
+    }
+
+    pub fn set_version(&mut self, ppu1: u8, ppu2: u8) {
+        self.ppu1_version = ppu1;
+        self.ppu2_version = ppu2;
+    }
+
+    pub fn init_ram(&mut self, power_on: &PowerOnState) {
+        power_on.fill(&mut self.vram, 1);
+        self.tile_cache.invalidate_all();
+    }
+
+    // Reset button: the registers stay except for the forced blank
+    pub fn reset(&mut self) {
+        self.display_control.set_force_blank(true);
+        // WRIO goes back to $FF
+        self.wrio_latch = true;
+    }
+
+    // Starts over from dot 0 of frame 0 at master clock `now`. The console model and the
+    // frontend settings are kept
+    pub fn power_cycle(&mut self, power_on: &PowerOnState, now: u64) {
+        let mut fresh: Box<Ppu> = Box::default();
+        fresh.swap_host_state(self);
+        fresh.counter = now;
+        fresh.ppu1_version = self.ppu1_version;
+        fresh.ppu2_version = self.ppu2_version;
+        fresh.init_ram(power_on);
+        core::mem::swap(self, &mut fresh);
+    }
+
+    pub fn set_layer_enable(&mut self, mask: LayerMask) {
+        self.layer_enable = mask;
+    }
+
+    pub fn set_debug_render(&mut self, mode: DebugRender) {
+        self.debug_render = mode;
+    }
+
+    pub fn set_relaxed_memory_access(&mut self, relaxed: bool) {
+        self.relaxed_memory_access = relaxed;
+    }
+
+    pub fn set_no_sprite_limit(&mut self, no_limit: bool) {
+        self.no_sprite_limit = no_limit;
+    }
+
+    pub fn set_no_dram_refresh(&mut self, no_refresh: bool) {
+        self.no_dram_refresh = no_refresh;
+    }
+
+    // Draw into the overlay and show it on the current frame right away
+    pub fn draw_overlay(&mut self, draw: impl FnOnce(&mut Overlay)) {
+        draw(&mut self.overlay);
+        self.overlay.composite(&mut self.frame, 0..FRAME_WIDTH * FRAME_HEIGHT);
+    }
+
+    // The current frame keeps the overlay until it is rendered again
+    pub fn clear_overlay(&mut self) {
+        self.overlay.clear();
+    }
+
+    pub fn set_video_filter(&mut self, filter: VideoFilter) {
+        self.video_filter = filter;
+        if !filter.frame_blend {
+            self.blend_frame = Vec::new();
+        } else if self.blend_frame.is_empty() {
+            self.blend_frame = self.frame.to_vec();
+        }
+    }
+
+    pub fn set_headless(&mut self, headless: bool) {
+        self.headless = headless;
+    }
+
+    // VRAM and OAM are only accessible during vblank or force blank
+    fn is_vram_accessible(&self) -> bool {
+        self.relaxed_memory_access || self.is_vblank || self.display_control.force_blank()
+    }
+
+    fn is_oam_accessible(&self) -> bool {
+        self.is_vram_accessible()
+    }
+
+    // CGRAM is also accessible during hblank, which is used for per-line palette changes
+    fn is_cgram_accessible(&self) -> bool {
+        self.is_vram_accessible() || self.is_hblank
+    }
+
+    fn vram_byte(&self, addr: usize) -> u8 {
+        vram_byte(&self.vram, addr)
+    }
+
+    fn vram_word(&self, addr: usize) -> [u8; 2] {
+        vram_word(&self.vram, addr)
+    }
+
+    fn cgram_color(&self, index: usize) -> u16 {
+        cgram_color(&self.cgram, index)
+    }
+
+    // $200-$3FF mirror the 32 byte high table
+    fn oam_byte(&self, addr: usize) -> u8 {
+        let addr = addr & 0x3FF;
+        if addr < 0x200 {
+            self.oam[addr]
+        } else {
+            self.oam[0x200 | addr & 0x1F]
+        }
+    }
+
+    // Low table entry of sprite `i`
+    fn oam_entry(&self, i: usize) -> OamEntry {
+        let addr = (i & 0x7F) * 4;
+        OamEntry::from_bytes(self.oam[addr..addr + 4].try_into().unwrap())
+    }
+
+    fn load_vram_prefetch(&mut self) {
+        // The prefetch keeps its stale value while the PPU is fetching
+        if !self.is_vram_accessible() {
+            return;
+        }
+        let vram_addr = self.vram_mode.get_transration(self.vram_addr) as usize * 2;
+        self.vram_prefetch = self.vram_word(vram_addr);
+    }
+
+    // Signed M7A times the signed byte last written to M7B, which is the high byte of M7B.
+    // The multiplier takes a dot
+    fn update_mpy(&mut self, now: u64) {
+        self.mpy_prev = self.mpy(now);
+        let m7b = (self.rotation_scaling_param.b >> 8) as i8;
+        self.mpy = self.rotation_scaling_param.a as i16 as i32 * m7b as i32;
+        self.mpy_ready_at = now + 4;
+    }
+
+    fn mpy(&self, now: u64) -> i32 {
+        if now < self.mpy_ready_at {
+            self.mpy_prev
+        } else {
+            self.mpy
+        }
+    }
+
     pub(crate) fn read(&mut self, addr: u16, ctx: &mut impl Context, cpu_open_bus: u8) -> u8 {
         let data = match addr {
-            0x2134 => self.mpy as u8,
-            0x2135 => (self.mpy >> 8) as u8,
-            0x2136 => (self.mpy >> 16) as u8,
+            0x2134 => self.mpy(ctx.now()) as u8,
+            0x2135 => (self.mpy(ctx.now()) >> 8) as u8,
+            0x2136 => (self.mpy(ctx.now()) >> 16) as u8,
             0x2137 => {
-                // TODO Three situations that load H/V counter values into the latch
-                //  Doing a dummy-read from SLHV (Port 2137h) by software
-                //  Switching WRIO (Port 4201h) Bit7 from 1-to-0 by software
-                //  Lightgun High-to-Low transition (Pin6 of 2nd Controller connector)
-
-                self.h_counter_latch = self.x;
-                self.v_counter_latch = self.y;
-                self.hv_latched = true;
+                // Pulls pin 6 low, which only has an effect while WRIO holds it high
+                if self.wrio_latch {
+                    self.tick(ctx);
+                    self.latch_hv_counter();
+                }
                 cpu_open_bus
             }
             0x2138 => {
-                let ret = if self.oam_addr < 0x200 {
-                    self.oam[self.oam_addr as usize]
+                let ret = if !self.is_oam_accessible() {
+                    // TODO Return the byte the sprite evaluation is currently reading
+                    self.open_bus1
                 } else {
-                    self.oam[(self.oam_addr & 0x21F) as usize]
+                    self.oam_byte(self.oam_addr as usize)
                 };
                 self.oam_addr = (self.oam_addr + 1) & 0x3FF;
                 // TODO Check whether to use the open bus value due to reading a value less than 8 bits.
@@ -245,15 +550,18 @@ impl Ppu {
                 let index = (addr - 0x2139) as usize;
                 let ret = self.vram_prefetch[index];
                 if self.vram_mode.is_incremet_after_high_bit() == (index == 1) {
-                    let vram_addr = self.vram_mode.get_transration(self.vram_addr) as usize * 2;
-                    self.vram_prefetch[0] = self.vram[vram_addr];
-                    self.vram_prefetch[1] = self.vram[vram_addr + 1];
+                    self.load_vram_prefetch();
                     self.vram_addr = (self.vram_addr + self.vram_mode.get_inc()) & 0x7FFF;
                 }
                 ret
             }
             0x213B => {
-                let cgram_data = self.cgram[self.palette_cgram_addr as usize / 2];
+                // TODO Return the color being rendered during active display
+                let cgram_data = if self.is_cgram_accessible() {
+                    self.cgram_color(self.palette_cgram_addr as usize / 2)
+                } else {
+                    0
+                };
                 let ret = if self.palette_cgram_addr & 1 == 0 {
                     cgram_data as u8
                 } else {
@@ -282,9 +590,9 @@ impl Ppu {
                 }
             }
             0x213E => {
-                // bit0..=3 ppu1 5C77 version number = 1
+                // bit0..=3 ppu1 5C77 version number
                 // bit5     Always read back as main processor (0: Main, 1: Helper)
-                let mut ret = 1;
+                let mut ret = self.ppu1_version & 0x0F;
 
                 ret |= (self.obj_range_overflow as u8) << 6;
                 ret |= (self.obj_time_overflow as u8) << 7;
@@ -292,14 +600,17 @@ impl Ppu {
                 ret | self.open_bus1 & 0x10
             }
             0x213F => {
-                // Ppu version = 1;
+                // bit0..=3 ppu2 5C78 version number
                 // Frame rate = 0 (60Hz)
-                let mut ret = 1;
+                let mut ret = self.ppu2_version & 0x0F;
 
-                ret |= (self.hv_latched as u8) << 6;
+                // With pin 6 held low the latch flag reads as set and isn't cleared
+                ret |= ((self.hv_latched || !self.wrio_latch) as u8) << 6;
                 ret |= (self.frame_number as u8 & 1) << 7;
 
-                self.hv_latched = false;
+                if self.wrio_latch {
+                    self.hv_latched = false;
+                }
                 self.h_flipflopped = false;
                 self.v_flipflopped = false;
 
@@ -336,25 +647,41 @@ impl Ppu {
         data
     }
 
-    pub fn write(&mut self, addr: u16, data: u8, ctx: &mut impl Context) {
+    pub(crate) fn write(&mut self, addr: u16, data: u8, ctx: &mut impl Context) {
         debug!("PPU write, addr: {:x}, data: {:x}", addr, data);
+        if let 0x2100..=0x2133 = addr {
+            // Pixels before this dot use the old register values
+            self.sync_render(ctx.now());
+        }
         match addr {
-            0x2100 => self.display_control.bytes[0] = data,
+            0x2100 => {
+                // Touching INIDISP on the first vblank line while in force blank also causes
+                // the OAM address reload
+                if self.y == 225 && self.display_control.force_blank() {
+                    self.reload_oam_addr();
+                }
+                self.display_control.bytes[0] = data;
+            }
             0x2101 => self.object_size_and_base.bytes[0] = data,
             0x2102 | 0x2103 => {
                 let index = (addr - 0x2102) as usize;
                 self.oam_addr_and_priority_rotation.bytes[index] = data;
-                self.oam_addr = self.oam_addr_and_priority_rotation.addr() << 1;
+                self.reload_oam_addr();
             }
             0x2104 => {
-                if self.oam_addr < 0x200 {
-                    if self.oam_addr & 1 == 0 {
-                        self.oam_lsb = data;
-                    } else {
+                // Even addresses always update the latch, also in the high table
+                if self.oam_addr & 1 == 0 {
+                    self.oam_lsb = data;
+                }
+                if !self.is_oam_accessible() {
+                    // TODO Writes during active display go to the address used by sprite evaluation
+                } else if self.oam_addr < 0x200 {
+                    if self.oam_addr & 1 == 1 {
                         self.oam[self.oam_addr as usize - 1] = self.oam_lsb;
                         self.oam[self.oam_addr as usize] = data;
                     }
                 } else {
+                    // 0x220..=0x3FF mirrors the 32 byte high table
                     self.oam[(self.oam_addr & 0x21F) as usize] = data;
                 }
                 self.oam_addr = (self.oam_addr + 1) & 0x3FF;
@@ -397,13 +724,11 @@ impl Ppu {
             0x2115 => self.vram_mode.bytes[0] = data,
             0x2116 => {
                 self.vram_addr = self.vram_addr & 0x7F00 | data as u16;
-                self.vram_prefetch[0] = self.vram[self.vram_addr as usize * 2];
-                self.vram_prefetch[1] = self.vram[self.vram_addr as usize * 2 + 1];
+                self.load_vram_prefetch();
             }
             0x2117 => {
                 self.vram_addr = self.vram_addr & 0x00FF | ((data & 0x7F) as u16) << 8;
-                self.vram_prefetch[0] = self.vram[self.vram_addr as usize * 2];
-                self.vram_prefetch[1] = self.vram[self.vram_addr as usize * 2 + 1];
+                self.load_vram_prefetch();
             }
             0x2118 | 0x2119 => {
                 let offset = addr - 0x2118;
@@ -413,7 +738,11 @@ impl Ppu {
                     "VRAM: {:04X} = {data:02X}, addr: {:04X}",
                     self.vram_addr, vram_addr
                 );
-                self.vram[vram_addr as usize] = data;
+                // Writes during active display are dropped, but the address still increments
+                if self.is_vram_accessible() {
+                    self.vram[vram_addr as usize] = data;
+                    self.tile_cache.invalidate(vram_addr as usize);
+                }
                 if self.vram_mode.is_incremet_after_high_bit() == (offset == 1) {
                     self.vram_addr = (self.vram_addr + self.vram_mode.get_inc()) & 0x7FFF;
                 }
@@ -422,14 +751,12 @@ impl Ppu {
             0x211B => {
                 self.rotation_scaling_param.a = (data as u16) << 8 | self.m7_old as u16;
                 self.m7_old = data;
-                self.mpy = (self.rotation_scaling_param.a as i16 as i32)
-                    * (self.rotation_scaling_param.b as i8 as i32);
+                self.update_mpy(ctx.now());
             }
             0x211C => {
                 self.rotation_scaling_param.b = (data as u16) << 8 | self.m7_old as u16;
                 self.m7_old = data;
-                self.mpy = (self.rotation_scaling_param.a as i16 as i32)
-                    * (self.rotation_scaling_param.b as i8 as i32);
+                self.update_mpy(ctx.now());
             }
             0x211D => {
                 self.rotation_scaling_param.c = (data as u16) << 8 | self.m7_old as u16;
@@ -451,7 +778,8 @@ impl Ppu {
             0x2122 => {
                 if self.palette_cgram_addr & 1 == 0 {
                     self.palette_cgram_lsb = data;
-                } else {
+                } else if self.is_cgram_accessible() {
+                    // TODO Writes during active display go to the color being rendered
                     self.cgram[self.palette_cgram_addr as usize / 2] =
                         (data as u16) << 8 | self.palette_cgram_lsb as u16;
                 }
@@ -497,6 +825,7 @@ impl Ppu {
             0x2133 => self.display_control.bytes[1] = data,
             0x2134..=0x213F => {
                 warn!("Write PPU read only register, addr: {:x}, data: {:x}", addr, data);
+                ctx.report(Diagnostic::ReadOnlyRegisterWrite { addr, data });
             }
 
             _ => {
@@ -505,25 +834,32 @@ impl Ppu {
         }
     }
 
-    pub fn tick(&mut self, ctx: &mut impl Context) {
+    pub(crate) fn tick(&mut self, ctx: &mut impl Context) {
         loop {
-            if self.counter + 4 > ctx.now() {
+            let dots = ctx.now().saturating_sub(self.counter) / 4;
+            if dots == 0 {
                 break;
             }
 
-            self.counter += 4;
-
-            self.x += 1;
+            // Jump straight to the next dot where something happens
+            let step = ((self.next_event_dot(ctx) - self.x) as u64).min(dots) as u16;
+            self.counter += step as u64 * 4;
+            self.x += step;
             if self.x == 340 {
                 self.x = 0;
                 self.y += 1;
 
 
-                if self.y == 262 {
+                if self.y == LINES_PER_FRAME {
                     self.y = 0;
+                    self.frame_info = self.capture_frame_info();
 
                     self.is_vblank = false;
-                    ctx.set_nmi_flag(false);
+                    ctx.set_nmi_flag(false, self.counter);
+                    if !self.display_control.force_blank() {
+                        self.obj_range_overflow = false;
+                        self.obj_time_overflow = false;
+                    }
 
                     self.frame_number += 1;
                     debug!("frame_number: {}", self.frame_number);
@@ -537,21 +873,37 @@ impl Ppu {
                 }
             }
 
+            if self.x == 0 && (1..225).contains(&self.y) {
+                if let Some(callback) = self.scanline_callback.as_mut() {
+                    callback(self.y);
+                }
+            }
+
             if self.x == 0 && self.y == 225 {
-                ctx.set_nmi_flag(true);
+                self.vblank_timestamp = self.counter;
+                if let Some(callback) = self.vblank_callback.as_mut() {
+                    callback(&self.frame);
+                }
+                ctx.set_nmi_flag(true, self.counter);
             }
 
             if self.x == 1 {
                 self.is_hblank = false;
             }
 
+            // The gun's photodiode sees the beam and pulls pin 6 low
+            if self.wrio_latch && self.light_gun_dot() == Some((self.x, self.y)) {
+                self.latch_hv_counter();
+            }
+
             if (self.x, self.y) == (6, 0) {
                 self.is_hdma_reload = true;
             }
 
             if self.x == 10 && self.y == 225 {
+                // OAM address reload at the start of vblank
                 if !self.display_control.force_blank() {
-                    self.oam_addr = self.oam_addr_and_priority_rotation.addr() << 1;
+                    self.reload_oam_addr();
                 }
             }
 
@@ -559,7 +911,7 @@ impl Ppu {
                 self.auto_joypad_read = true;
             }
 
-            if self.x == 134 {
+            if self.x == 134 && !self.no_dram_refresh {
                 // DRAM refresh
                 ctx.elapse(40);
             }
@@ -571,30 +923,40 @@ impl Ppu {
                 self.is_hblank = true;
             }
 
-            if self.x == 10 && self.y == 225 {
-                if !self.display_control.force_blank() {
-                    self.oam_addr = self.oam_addr_and_priority_rotation.addr() << 1;
+            if (1..225).contains(&self.y) {
+                if self.x == FIRST_VISIBLE_DOT {
+                    self.render_x = 0;
+                    // The last frame has been presented by now
+                    if self.y == 1 && !self.blend_frame.is_empty() {
+                        self.blend_frame.copy_from_slice(&self.frame);
+                    }
+                }
+                if self.x == FIRST_VISIBLE_DOT + FRAME_WIDTH as u16 {
+                    self.render_until(FRAME_WIDTH);
                 }
             }
-
-            if self.x == 22 && (1..225).contains(&self.y) {
-                self.render_line(self.y);
+            if self.x == FIRST_VISIBLE_DOT + FRAME_WIDTH as u16 && self.y < 224 {
+                // Leaving force blank mid-frame shows no sprites on the first line after it
+                self.obj_evaluated = !self.display_control.force_blank();
+                if self.obj_evaluated {
+                    self.evaluate_obj(self.y);
+                }
             }
 
             match ctx.get_hv_irq_enable() {
                 1 => {
                     if self.x == ctx.get_h_count() {
-                        ctx.set_irq(true);
+                        ctx.set_irq(true, self.counter);
                     }
                 }
                 2 => {
                     if self.x == 0 && self.y == ctx.get_v_count() {
-                        ctx.set_irq(true);
+                        ctx.set_irq(true, self.counter);
                     }
                 }
                 3 => {
                     if self.x == ctx.get_h_count() && self.y == ctx.get_v_count() {
-                        ctx.set_irq(true);
+                        ctx.set_irq(true, self.counter);
                     }
                 }
                 _ => {}
@@ -607,106 +969,149 @@ impl Ppu {
         counter.y = self.y as u64;
     }
 
-    fn render_line(&mut self, y: u16) {
-        self.render_bg(y);
-        self.render_obj(y-1);
-        self.color_math(y-1);
+    // Nothing in tick fires between these dots (and the H-IRQ dot), so they can be skipped
+    fn next_event_dot(&self, ctx: &impl Context) -> u16 {
+        const EVENT_DOTS: [u16; 9] = [
+            1,
+            6,
+            10,
+            FIRST_VISIBLE_DOT,
+            33,
+            134,
+            274,
+            278,
+            FIRST_VISIBLE_DOT + FRAME_WIDTH as u16,
+        ];
+
+        let mut next = 340;
+        for dot in EVENT_DOTS {
+            if dot > self.x {
+                next = next.min(dot);
+            }
+        }
+        if ctx.get_hv_irq_enable() & 1 != 0 && ctx.get_h_count() > self.x {
+            next = next.min(ctx.get_h_count());
+        }
+        if let Some((dot, y)) = self.light_gun_dot() {
+            if y == self.y && dot > self.x {
+                next = next.min(dot);
+            }
+        }
+        next
+    }
+
+    // Catch the renderer up to the dot at master clock `now`
+    fn sync_render(&mut self, now: u64) {
+        let dot = self.x as u64 + now.saturating_sub(self.counter) / 4;
+        let end = dot.saturating_sub(FIRST_VISIBLE_DOT as u64).min(FRAME_WIDTH as u64);
+        self.render_until(end as usize);
+    }
+
+    #[cfg(feature = "bench")]
+    pub fn render_line(&mut self, y: u16) {
+        let (prev_y, prev_render_x) = (self.y, self.render_x);
+        self.y = y;
+        self.render_x = 0;
+        self.render_until(FRAME_WIDTH);
+        self.y = prev_y;
+        self.render_x = prev_render_x;
+    }
+
+    fn render_until(&mut self, end: usize) {
+        if end <= self.render_x {
+            return;
+        }
+        let range = self.render_x..end;
+        self.render_x = end;
+        // Rendering has no effect on timing or registers
+        if self.headless {
+            return;
+        }
+
+        let y = self.y;
+        self.render_bg(y, range.clone());
+        self.render_obj(y - 1, range.clone());
+        self.color_math(y - 1, range);
     }
 
-    fn render_bg(&mut self, y: u16) {
+    fn render_bg(&mut self, y: u16, range: Range<usize>) {
         let bg_mode = self.bg_ctrl.bg_mode();
         let bpp_mode = BG_MODE_BPP[bg_mode as usize];
 
 
-        for i in 0..FRAME_WIDTH {
-            self.main_screen[i] = PixelInfo::new(self.cgram[0], 13, Layer::Backdrop);
-            self.sub_screen[i] = PixelInfo::new(self.color_math_sub_screen_backdrop_color.get_bgr(), 13, Layer::Backdrop);
+        for i in range.clone() {
+            self.main_screen[i] = PixelInfo::new(self.cgram[0], BACKDROP_PRIORITY, Layer::Backdrop);
+            self.sub_screen[i] = PixelInfo::new(self.color_math_sub_screen_backdrop_color.get_bgr(), BACKDROP_PRIORITY, Layer::Backdrop);
         }
         if bg_mode == 7 {
-        self.render_bg_mode7(y, 8);
+            if self.layer_enable.bg1() {
+                self.render_bg_mode7(y, BG_PRIORITY[7][0][0], range);
+            }
             return;
         }
 
+        let mut layers = [None; 4];
         for (bg_index, &bpp) in bpp_mode.iter().enumerate() {
-            let tile_size = self.bg_ctrl.get_tile_size(bg_index);
-            let tile_base_addr = self.bg_tile_base_addr[bg_index] as usize * 8 * 1024;
-            debug!("tile base addr: 0x{:x}", tile_base_addr);
-
-
-            for x in 0..FRAME_WIDTH {
-                let screen_x = x + self.bg_hofs[bg_index] as usize;
-                let screen_y = y as usize + self.bg_vofs[bg_index] as usize;
-
-                let map_entry = self.get_map_entry(bg_index, screen_x, screen_y, tile_size);
+            if self.layer_enable.get_bg_enable(bg_index) {
+                self.tile_cache.refresh(&self.vram, bpp);
+                layers[bg_index] = Some(self.bg_layer(bg_index, bpp));
+            }
+        }
 
-                let mut tile_index = map_entry.character_number() as usize;
-                let mut pixel_x = (screen_x % tile_size) ^ if map_entry.flip_x() { tile_size -1 } else { 0 };
-                let mut pixel_y = (screen_y % tile_size) ^ if map_entry.flip_y() { tile_size -1 } else { 0 };
-                if pixel_x >= 8 {
-                    tile_index += 0x01;
-                    pixel_x %= 8;
+        // The layers only meet in the priority merge below, so they can be drawn separately
+        let (vram, tiles, cgram) = (&self.vram, &self.tile_cache, &self.cgram);
+        let lines = &mut *self.bg_lines;
+        #[cfg(feature = "parallel-render")]
+        {
+            use rayon::prelude::*;
+            lines.par_iter_mut().zip(layers.par_iter()).for_each(|(line, layer)| {
+                if let Some(layer) = layer {
+                    layer.render(vram, tiles, cgram, y, range.clone(), line);
                 }
-                if pixel_y >= 8 {
-                    tile_index += 0x10;
-                    pixel_y %= 8;
-                }
-
-                let tile_addr  = tile_base_addr + tile_index * bpp  * 8;
-                let mut color_index = 0;
-                for i in 0..bpp/2 {
-                    let bit_addr = (tile_addr + i * 16 + pixel_y * 2) & 0xFFFE;
-                    let low = (self.vram[bit_addr] >> (7 - pixel_x)) & 1;
-                    let high = (self.vram[bit_addr + 1] >> (7 - pixel_x)) & 1;
-                    color_index |= low << (i * 2);
-                    color_index |= high << (i * 2 + 1);
-                } 
+            });
+        }
+        #[cfg(not(feature = "parallel-render"))]
+        for (line, layer) in lines.iter_mut().zip(layers.iter()) {
+            if let Some(layer) = layer {
+                layer.render(vram, tiles, cgram, y, range.clone(), line);
+            }
+        }
 
-                let is_high = map_entry.bg_priority();
-                if color_index != 0 {
-                    let cgram_base_addr = if self.bg_ctrl.bg_mode() == 0 {
-                        bg_index * 0x20
-                    } else {
-                        0
-                    };
-                    let cgram_addr = (cgram_base_addr + map_entry.pallet_number() as usize * (1 << bpp) + color_index as usize) & 0xFF;
-                    let color = self.cgram[cgram_addr];
-                    if self.screen_main_designation.get_bg_enable(bg_index) {
-                        let priority = self.get_bg_layer_priority(bg_index as u8, is_high);
-                        if priority < self.main_screen[x].priority {
-                            self.main_screen[x] = PixelInfo::new(color, priority, Layer::BG(bg_index as u8));
-                        }
-                    }
-                    if self.screen_sub_designation.get_bg_enable(bg_index) {
-                        let priority = self.get_bg_layer_priority(bg_index as u8, is_high);
-                        if priority < self.sub_screen[x].priority {
-                            self.sub_screen[x] = PixelInfo::new(color, priority, Layer::BG(bg_index as u8));
-                        }
-                    }
-                    // self.frame[y as usize * FRAME_WIDTH + x] = color;
+        for (bg_index, layer) in layers.iter().enumerate() {
+            if layer.is_none() {
+                continue;
+            }
+            let main = self.screen_main_designation.get_bg_enable(bg_index);
+            let sub = self.screen_sub_designation.get_bg_enable(bg_index);
+            let layer = Layer::BG(bg_index as u8);
+            for x in range.clone() {
+                let BgPixel { color, priority } = self.bg_lines[bg_index][x];
+                if main && priority < self.main_screen[x].priority {
+                    self.main_screen[x] = PixelInfo::new(color, priority, layer);
+                }
+                if sub && priority < self.sub_screen[x].priority {
+                    self.sub_screen[x] = PixelInfo::new(color, priority, layer);
                 }
             }
         }
     }
 
-    fn get_map_entry(&self, bg_index: usize, x: usize, y: usize, tile_size: usize) -> BGMapEntry {
-        let (screen_w, screen_h) = self.bg_screen_base_and_size[bg_index].get_screen_size();
-        let base_addr = self.bg_screen_base_and_size[bg_index].get_bg_map_base_addr();
-
-        let sc_x = x / tile_size / 32 % screen_w;
-        let sc_y = y / tile_size / 32 % screen_h;
-        let tile_x = x / tile_size % 32;
-        let tile_y = y / tile_size % 32;
-
-        let screen_addr = base_addr + (sc_x + sc_y * screen_w) * 2 * 1024;
-        let map_entry_addr = (screen_addr + (tile_x + tile_y * 32) * 2) & 0xFFFE;
-
-        BGMapEntry::from_bytes([
-            self.vram[map_entry_addr],
-            self.vram[map_entry_addr + 1],
-        ])
+    fn bg_layer(&self, bg_index: usize, bpp: usize) -> BgLayer {
+        let priority = |is_high| self.get_bg_layer_priority(bg_index as u8, is_high);
+        BgLayer {
+            bpp,
+            tile_size: self.bg_ctrl.get_tile_size(bg_index),
+            tile_base_addr: self.bg_tile_base_addr[bg_index] as usize * 8 * 1024,
+            map_base_addr: self.bg_screen_base_and_size[bg_index].get_bg_map_base_addr(),
+            screen_size: self.bg_screen_base_and_size[bg_index].get_screen_size(),
+            hofs: self.bg_hofs[bg_index] as usize,
+            vofs: self.bg_vofs[bg_index] as usize,
+            cgram_base_addr: if self.bg_ctrl.bg_mode() == 0 { bg_index * 0x20 } else { 0 },
+            priority: [priority(false), priority(true)],
+        }
     }
 
-    fn render_bg_mode7(&mut self, y: u16, z: u8) {
+    fn render_bg_mode7(&mut self, y: u16, z: u8, range: Range<usize>) {
         let x_flip = if self.rotation_scaling_setting.h_flip() { 0xFF } else { 0 };
         let y_flip = if self.rotation_scaling_setting.v_flip() { 0xFF } else { 0 };
         let screen_over = self.rotation_scaling_setting.screen_over();
@@ -758,7 +1163,7 @@ impl Ppu {
     
     
         // 各ピクセルの描画ループ
-        for x in 0..FRAME_WIDTH {
+        for x in range {
             // 現在のピクセルのVRAM座標を計算
             let sx = (x ^ x_flip) as i32;
             let vx = lx + m7a * sx;
@@ -771,12 +1176,7 @@ impl Ppu {
                 // 画面内またはラップアラウンド
                 let tile_x = ((vx >> SHIFT_11) & 0x7F) as usize;
                 let tile_y = ((vy >> SHIFT_11) & 0x7F) as usize;
-                let tile_addr = (tile_x + tile_y * 128) * 2;
-                if tile_addr >= self.vram.len() {
-                    // VRAMの範囲外アクセスを防止
-                    continue;
-                }
-                self.vram[tile_addr] as usize
+                self.vram_byte((tile_x + tile_y * 128) * 2) as usize
             } else if screen_over == 3 {
                 // タイル0x00で塗りつぶす
                 0
@@ -786,21 +1186,16 @@ impl Ppu {
             };
     
             // キャラクターアドレスの計算
-            let char_addr = char_num * 128 + ofs_y * 16 + ofs_x * 2 + 1;
-            if char_addr >= self.vram.len() {
-                // VRAMの範囲外アクセスを防止
-                continue;
-            }
-            let pixel = self.vram[char_addr];
+            let pixel = self.vram_byte(char_num * 128 + ofs_y * 16 + ofs_x * 2 + 1);
     
             // ピクセルの描画
             if pixel != 0 {
-                let col: u16 = self.cgram.get(pixel as usize).copied().unwrap_or_default();
+                let col = self.cgram_color(pixel as usize);
                 if  self.screen_main_designation.bg1_enable() && z < self.main_screen[x].priority {
-                    self.main_screen[x] = PixelInfo::new(col, z, Layer::BG(1));
+                    self.main_screen[x] = PixelInfo::new(col, z, Layer::BG(0));
                 }
                 if self.screen_sub_designation.bg1_enable() && z < self.sub_screen[x].priority {
-                    self.sub_screen[x] = PixelInfo::new(col, z, Layer::BG(1));
+                    self.sub_screen[x] = PixelInfo::new(col, z, Layer::BG(0));
                 }
             }
     
@@ -808,134 +1203,208 @@ impl Ppu {
     }
 
 
-    fn render_obj(&mut self, y: u16) {
+    // Pick the sprites of line `y` during the line before, like the PPU: the first 32 in
+    // range, then up to 34 8 pixel slivers fetched from the last of them backwards. The
+    // overflow flags are set either way, the sprites are only dropped with the limit on
+    fn evaluate_obj(&mut self, y: u16) {
+        // The first sprite comes from the reload address ($2102), not the internal address
         let priority_rotation = if self.oam_addr_and_priority_rotation.priority_rotation() {
-            (self.oam_addr >> 2) & 0x7F
+            (self.oam_addr_and_priority_rotation.addr() >> 1) & 0x7F
         } else {
             0
         };
+        self.obj_line.clear();
         for i in 0..128 {
             let i = ((i + priority_rotation) & 0x7F) as usize;
-            let oam_entry = OamEntry::from_bytes(self.oam[i * 4..i * 4 + 4].try_into().unwrap());
-            let addition_addr = 0x200 + (i / 4) ;
-            let addition_offset = i % 4;
-            let upper_x = ((self.oam[addition_addr] >> (addition_offset * 2)) & 1) as usize;
-            let obj_size_index = ((self.oam[addition_addr] >> (addition_offset * 2 + 1)) & 1) as usize;
+            let (oam_entry, _, _, obj_height) = self.obj_entry(i);
+            let obj_height = obj_height >> self.display_control.obj_v_direction_display() as usize;
+            // Y wraps at 256, so sprites near the bottom also show up at the top of the screen
+            if (y as usize).wrapping_sub(oam_entry.y() as usize) & 0xFF >= obj_height {
+                continue;
+            }
+            if self.obj_line.len() == OBJ_RANGE_LIMIT {
+                self.obj_range_overflow = true;
+                if !self.no_sprite_limit {
+                    break;
+                }
+            }
+            self.obj_line.push((i as u8, 0));
+        }
 
-            let obj_pos_x = (upper_x << 8) | oam_entry.x() as usize;
-            let obj_pos_y =  oam_entry.y() as usize;
+        let mut slivers = 0;
+        for n in (0..self.obj_line.len()).rev() {
+            let (i, _) = self.obj_line[n];
+            let (_, obj_pos_x, obj_width, _) = self.obj_entry(i as usize);
+            let mut columns = 0;
+            for column in 0..obj_width / 8 {
+                // Slivers entirely off screen aren't fetched
+                if (256..=504).contains(&((obj_pos_x + column * 8) % 512)) {
+                    continue;
+                }
+                if slivers == OBJ_TIME_LIMIT {
+                    self.obj_time_overflow = true;
+                    if !self.no_sprite_limit {
+                        break;
+                    }
+                }
+                slivers += 1;
+                columns |= 1 << column;
+            }
+            self.obj_line[n].1 = columns;
+        }
+    }
 
-            let obj_size = self.object_size_and_base.obj_size()[obj_size_index];
+    // OAM entry `i` with its X position and size
+    fn obj_entry(&self, i: usize) -> (OamEntry, usize, usize, usize) {
+        let oam_entry = self.oam_entry(i);
+        let addition_addr = 0x200 + (i / 4) ;
+        let addition_offset = i % 4;
+        let upper_x = ((self.oam_byte(addition_addr) >> (addition_offset * 2)) & 1) as usize;
+        let obj_size_index = ((self.oam_byte(addition_addr) >> (addition_offset * 2 + 1)) & 1) as usize;
+
+        let obj_pos_x = (upper_x << 8) | oam_entry.x() as usize;
+        let (obj_width, obj_height) = self.object_size_and_base.obj_size()[obj_size_index];
+        (oam_entry, obj_pos_x, obj_width, obj_height)
+    }
 
-            for offset_y in 0..obj_size {
-                let pixel_y = (obj_pos_y + offset_y) % 256;
-                if pixel_y != y as usize {
+    fn render_obj(&mut self, y: u16, range: Range<usize>) {
+        if !self.layer_enable.obj() || !self.obj_evaluated {
+            return;
+        }
+        for n in 0..self.obj_line.len() {
+            let (i, columns) = self.obj_line[n];
+            let (oam_entry, obj_pos_x, obj_width, _) = self.obj_entry(i as usize);
+            let mut offset_y = (y as usize).wrapping_sub(oam_entry.y() as usize) & 0xFF;
+            // OBJ interlace ($2133 bit1) shows sprites at half height, each field takes every
+            // other row starting at the field's parity
+            if self.display_control.obj_v_direction_display() {
+                offset_y = offset_y << 1 | (self.frame_number & 1) as usize;
+            }
+            for offset_x in 0..obj_width {
+                let pixel_x = (obj_pos_x + offset_x) % 512;
+                if columns >> (offset_x / 8) & 1 == 0 || !range.contains(&pixel_x) {
                     continue;
                 }
-                for offset_x in 0..obj_size {
-                    let pixel_x = (obj_pos_x + offset_x) % 512;
-                    if pixel_x >= 256 {
-                        continue;
-                    }
 
-                    let mut tile_x = if oam_entry.attribute().x_flip() { (obj_size -1) ^ offset_x } else { offset_x };
-                    let mut tile_y = if oam_entry.attribute().y_flip() { (obj_size -1) ^ offset_y } else { offset_y };
+                let mut tile_x = if oam_entry.attribute().x_flip() { (obj_width - 1) ^ offset_x } else { offset_x };
+                // Rectangular sprites flip each square half separately
+                let mut tile_y = if oam_entry.attribute().y_flip() {
+                    ((obj_width - 1) ^ (offset_y % obj_width)) | (offset_y / obj_width * obj_width)
+                } else {
+                    offset_y
+                };
 
-                    let mut tile_index = ((oam_entry.attribute().tile_page() as usize) << 8) |  oam_entry.tile_number() as usize;
-                    // x方向は0x01ずれる
-                    tile_index = (tile_index & 0x1F0) | (((tile_index & 0x0F) + tile_x / 8 ) & 0x0F);
-                    // y方向は0x10ずれる
-                    // tile_index = (((tile_index & 0x1F0) + tile_y / 8 * 0x10) & 0x1F0) | (tile_index & 0x0F);
-                    tile_index = (tile_index & 0x10F) | (((tile_index & 0xF0) + tile_y / 8 * 0x10) & 0xF0);
+                let mut tile_index = ((oam_entry.attribute().tile_page() as usize) << 8) |  oam_entry.tile_number() as usize;
+                // x方向は0x01ずれる
+                tile_index = (tile_index & 0x1F0) | (((tile_index & 0x0F) + tile_x / 8 ) & 0x0F);
+                // y方向は0x10ずれる
+                // tile_index = (((tile_index & 0x1F0) + tile_y / 8 * 0x10) & 0x1F0) | (tile_index & 0x0F);
+                tile_index = (tile_index & 0x10F) | (((tile_index & 0xF0) + tile_y / 8 * 0x10) & 0xF0);
 
-                    tile_x %= 8;
-                    tile_y %= 8;
+                tile_x %= 8;
+                tile_y %= 8;
 
-                    let mut tile_base_addr = self.object_size_and_base.base_addr_for_obj_tiles() as usize * 16 * 1024;
-                    if oam_entry.attribute().tile_page() == 1 {
-                        tile_base_addr += self.object_size_and_base.gap_between_obj() as usize * 8 * 1024;
-                    }
-                    tile_base_addr &= 0xFFFF;
-
-
-                    let tile_addr = tile_base_addr + tile_index * 32;
-                    let mut color_index = 0;
-                    for i in 0..2 {
-                        let bit_addr = (tile_addr + i * 16 + tile_y * 2) & 0xFFFE;
-                        let low = (self.vram[bit_addr] >> (7 - tile_x)) & 1;
-                        let high = (self.vram[bit_addr + 1] >> (7 - tile_x)) & 1;
-                        color_index |= low << (i * 2);
-                        color_index |= high << (i * 2 + 1);
-                    } 
-                    
-                    if color_index == 0 {
-                        continue;
-                    }
-                    let obj_priority = OBJ_PRIORITY[oam_entry.attribute().priority() as usize];
-                    if obj_priority < self.main_screen[pixel_x].priority {
-                        let cgram_addr =  128 + oam_entry.attribute().palette_number() as usize * 16 + color_index as usize;
-                        let color = self.cgram[cgram_addr];
-                        let layer = if (0..=3).contains(&oam_entry.attribute().palette_number()) {
-                            Layer::ObjPallete0_3
-                        } else {
-                            Layer::ObjPallete4_7
-                        };
-                        self.main_screen[pixel_x] = PixelInfo::new(color, obj_priority, layer);
-                    } 
-                    if obj_priority < self.sub_screen[pixel_x].priority {
-                        let cgram_addr =  128 + oam_entry.attribute().palette_number() as usize * 16 + color_index as usize;
-                        let color = self.cgram[cgram_addr];
-                        let layer = if (0..=3).contains(&oam_entry.attribute().palette_number()) {
-                            Layer::ObjPallete0_3
-                        } else {
-                            Layer::ObjPallete4_7
-                        };
-                        self.sub_screen[pixel_x] = PixelInfo::new(color, obj_priority, layer);
-                    }
+                let mut tile_base_addr = self.object_size_and_base.base_addr_for_obj_tiles() as usize * 16 * 1024;
+                if oam_entry.attribute().tile_page() == 1 {
+                    tile_base_addr += self.object_size_and_base.gap_between_obj() as usize * 8 * 1024;
+                }
+                tile_base_addr &= 0xFFFF;
 
+
+                let tile_addr = tile_base_addr + tile_index * 32;
+                let color_index = self.tile_cache.tile(&self.vram, 4, tile_addr)[tile_y][tile_x];
+                if color_index == 0 {
+                    continue;
                 }
+                let obj_priority = OBJ_PRIORITY[oam_entry.attribute().priority() as usize];
+                if self.screen_main_designation.obj_enable() && obj_priority < self.main_screen[pixel_x].priority {
+                    let cgram_addr =  128 + oam_entry.attribute().palette_number() as usize * 16 + color_index as usize;
+                    let color = self.cgram_color(cgram_addr);
+                    let layer = if (0..=3).contains(&oam_entry.attribute().palette_number()) {
+                        Layer::ObjPallete0_3
+                    } else {
+                        Layer::ObjPallete4_7
+                    };
+                    self.main_screen[pixel_x] = PixelInfo::new(color, obj_priority, layer);
+                } 
+                if self.screen_sub_designation.obj_enable() && obj_priority < self.sub_screen[pixel_x].priority {
+                    let cgram_addr =  128 + oam_entry.attribute().palette_number() as usize * 16 + color_index as usize;
+                    let color = self.cgram_color(cgram_addr);
+                    let layer = if (0..=3).contains(&oam_entry.attribute().palette_number()) {
+                        Layer::ObjPallete0_3
+                    } else {
+                        Layer::ObjPallete4_7
+                    };
+                    self.sub_screen[pixel_x] = PixelInfo::new(color, obj_priority, layer);
+                }
+
             }
         }
     }
 
-    fn color_math(&mut self, y: u16) {
-        let bright_ness = self.display_control.brightness();
-        for i in 0..FRAME_WIDTH {
-            let mut main_color = self.main_screen[i];
-            let mut sub_color = self.sub_screen[i];
+    fn color_math(&mut self, y: u16, range: Range<usize>) {
+        // Force blank outputs black whatever the brightness is
+        let bright_ness = if self.display_control.force_blank() {
+            0
+        } else {
+            self.display_control.brightness()
+        };
+        let line = y as usize * FRAME_WIDTH;
+        for i in range.clone() {
+            let main_color = self.main_screen[i];
+            if let Some(color) = self.debug_render.color(main_color) {
+                self.frame[line + i] = color;
+                continue;
+            }
+            let in_math_window = self.is_inside_window(
+                &self.window_mask_settings.math,
+                self.window_mask_logic.math(),
+                i,
+            );
+
+            let force_black = match self.color_math_ctrl.force_main_screen_black() {
+                ForceMainScreenBlack::Never => false,
+                ForceMainScreenBlack::NotMathWin => !in_math_window,
+                ForceMainScreenBlack::MathWindow => in_math_window,
+                ForceMainScreenBlack::Always => true,
+            };
+            let math_enable = match self.color_math_ctrl.enable() {
+                ColorMathEnable::Always => true,
+                ColorMathEnable::MathWindow => in_math_window,
+                ColorMathEnable::NotMathWin => !in_math_window,
+                ColorMathEnable::Never => false,
+            };
 
-            if bright_ness == 0 {
-                main_color.r = 0;
-                main_color.g = 0;
-                main_color.b = 0;
-                sub_color.r = 0;
-                sub_color.g = 0;
-                sub_color.b = 0;
+            let (mut color_r, mut color_g, mut color_b) = if force_black {
+                (0, 0, 0)
             } else {
-                main_color.r = ((main_color.r as u16 * (bright_ness + 1) as u16) / 16) as u8;
-                main_color.g = ((main_color.g as u16 * (bright_ness + 1) as u16) / 16) as u8;
-                main_color.b = ((main_color.b as u16 * (bright_ness + 1) as u16) / 16) as u8;
-                sub_color.r = ((sub_color.r as u16 * (bright_ness + 1) as u16) / 16) as u8;
-                sub_color.g = ((sub_color.g as u16 * (bright_ness + 1) as u16) / 16) as u8;
-                sub_color.b = ((sub_color.b as u16 * (bright_ness + 1) as u16) / 16) as u8;
-            }
-            // let color = self.color_math_ctrl.calc_color(main_color, sub_color);
-
-            if (self.color_math_ctrl.kind() >> (main_color.layer as u8)) & 1 == 1 {
-                let mut color_r = 0;
-                let mut color_g = 0;
-                let mut color_b = 0;
-                // main_color = self.color_math_ctrl.calc_color(main_color, sub_color);
+                (main_color.r, main_color.g, main_color.b)
+            };
+
+            if self.layer_enable.color_math()
+                && math_enable
+                && (self.color_math_ctrl.kind() >> (main_color.layer as u8)) & 1 == 1
+            {
+                // The sub screen backdrop is the fixed color, so only real sub screen pixels
+                // count as a sub layer for halving
+                let (sub_color, sub_is_layer) = if self.color_math_ctrl.sub_screen_enable() {
+                    let sub_color = self.sub_screen[i];
+                    let sub_is_layer = !matches!(sub_color.layer, Layer::Backdrop);
+                    (sub_color, sub_is_layer)
+                } else {
+                    let fixed_color = self.color_math_sub_screen_backdrop_color.get_bgr();
+                    (PixelInfo::new(fixed_color, BACKDROP_PRIORITY, Layer::Backdrop), true)
+                };
                 if self.color_math_ctrl.subtract() {
-                    color_r = main_color.r.saturating_sub(sub_color.r);
-                    color_g = main_color.g.saturating_sub(sub_color.g);
-                    color_b = main_color.b.saturating_sub(sub_color.b);
+                    color_r = color_r.saturating_sub(sub_color.r);
+                    color_g = color_g.saturating_sub(sub_color.g);
+                    color_b = color_b.saturating_sub(sub_color.b);
                 } else {
-                    color_r = main_color.r + sub_color.r;
-                    color_g = main_color.g + sub_color.g;
-                    color_b = main_color.b + sub_color.b;
+                    color_r += sub_color.r;
+                    color_g += sub_color.g;
+                    color_b += sub_color.b;
                 }
-                if self.color_math_ctrl.half_color() {
+                if self.color_math_ctrl.half_color() && sub_is_layer && !force_black {
                     color_r >>= 1;
                     color_g >>= 1;
                     color_b >>= 1;
@@ -943,11 +1412,44 @@ impl Ppu {
                 color_r = color_r.min(31);
                 color_g = color_g.min(31);
                 color_b = color_b.min(31);
-                self.frame[y as usize * FRAME_WIDTH + i] = (color_b as u16) << 10 | (color_g as u16) << 5 | color_r as u16;
+            }
+
+            // Brightness applies to the final output
+            if bright_ness == 0 {
+                color_r = 0;
+                color_g = 0;
+                color_b = 0;
             } else {
-                self.frame[y as usize * FRAME_WIDTH + i] = (main_color.b as u16) << 10 | (main_color.g as u16) << 5 | main_color.r as u16;
+                color_r = ((color_r as u16 * (bright_ness + 1) as u16) / 16) as u8;
+                color_g = ((color_g as u16 * (bright_ness + 1) as u16) / 16) as u8;
+                color_b = ((color_b as u16 * (bright_ness + 1) as u16) / 16) as u8;
             }
+            self.frame[y as usize * FRAME_WIDTH + i] = (color_b as u16) << 10 | (color_g as u16) << 5 | color_r as u16;
+        }
+        self.overlay.composite(&mut self.frame, line + range.start..line + range.end);
+    }
 
+    fn is_inside_window(&self, settings: &MaskSettings, logic: MaskLogic, x: usize) -> bool {
+        let window = |index: usize, setting: MaskSetting| {
+            let position = &self.window_position[index];
+            let inside = (position.left as usize..=position.right as usize).contains(&x);
+            inside != setting.outside()
+        };
+        let window1 = settings.window1();
+        let window2 = settings.window2();
+        match (window1.enable(), window2.enable()) {
+            (false, false) => false,
+            (true, false) => window(0, window1),
+            (false, true) => window(1, window2),
+            (true, true) => {
+                let (w1, w2) = (window(0, window1), window(1, window2));
+                match logic {
+                    MaskLogic::Or => w1 | w2,
+                    MaskLogic::And => w1 & w2,
+                    MaskLogic::Xor => w1 ^ w2,
+                    MaskLogic::Xnor => !(w1 ^ w2),
+                }
+            }
         }
     }
 
@@ -959,13 +1461,10 @@ impl Ppu {
             & 0xFFFE;
         // debug!("x: {}, y: {}, bg_map_base: 0x{:x}", x, y, bg_map_addr);
 
-        let bg_map_entry = BGMapEntry::from_bytes([
-            self.vram[bg_map_addr as usize],
-            self.vram[bg_map_addr as usize + 1],
-        ]);
+        let bg_map_entry = BGMapEntry::from_bytes(self.vram_word(bg_map_addr));
         // debug!("bg_map_entry: {:?}", bg_map_entry);
-        let tile_addr = (self.bg_tile_base_addr[0] as u16 & 0x000F) * 4096
-            + bg_map_entry.character_number() as u16 * 16;
+        let tile_addr = (self.bg_tile_base_addr[0] as usize & 0x000F) * 4096
+            + bg_map_entry.character_number() as usize * 16;
         // (self.bg_character_base & 0x000F) * 8 * 1024 + bg_map_entry.character_number() as u16 * 16;
         // debug!("x: {x}, y: {y}, tile_addr: 0x{:x}", tile_addr);
         // debug!("character_number: {:x}", bg_map_entry.character_number());
@@ -986,54 +1485,56 @@ impl Ppu {
             ty = 7 - ty;
         }
 
-        let bit_addr = tile_addr + ty * 2;
-        let pallet_index = ((self.vram[bit_addr as usize + 1] >> tx) & 1) << 1
-            | (self.vram[bit_addr as usize] >> tx) & 1;
+        let bit_addr = tile_addr + ty as usize * 2;
+        let pallet_index = ((self.vram_byte(bit_addr + 1) >> tx) & 1) << 1
+            | (self.vram_byte(bit_addr) >> tx) & 1;
 
         let pallet_addr = 8 * bg_map_entry.pallet_number() as u16 + pallet_index as u16;
         // let color = self.cgram[pallet_addr as usize + 1] << 8 | self.cgram[pallet_addr as usize];
-        let color = self.cgram[pallet_addr as usize];
+        let color = self.cgram_color(pallet_addr as usize);
         color
     }
 
-    #[rustfmt::skip]
     fn get_bg_layer_priority(&self, layer: u8, is_high: bool) -> u8 {
-        match self.bg_ctrl.bg_mode() {
-            0 => match layer {
-                0 => if is_high { 2 } else {  5 },  // BG1
-                1 => if is_high { 3 } else {  6 },  // BG2
-                2 => if is_high { 8 } else { 11 },  // BG3
-                3 => if is_high { 9 } else { 12 },  // BG4
-                _ => unreachable!(),
-            },
-            1 => match layer {
-                0 => if is_high { 2 } else { 5 },  // BG1
-                1 => if is_high { 3 } else { 6 },  // BG2
-                2 => match (self.bg_ctrl.is_bg3_priority_high(), is_high) {
-                    (true, true)   =>  0,           // BG3.1a
-                    (true, false)  => 11,           // BG3.0a
-                    (false, true)  =>  8,           // BG3.1b
-                    (false, false) => 12,           // BG3.0b
-                },
-                _ => unreachable!(),
-            },
-            2..=5 => match layer {
-                0 => if is_high { 2 } else {  8 },  // BG1
-                1 => if is_high { 5 } else { 11 },  // BG2
-                _ => unreachable!(),
-            },
-            6 => match layer {
-                0 => if is_high { 2 } else { 8 },  // BG1
-                _ => unreachable!(),
-            },
-            7 => match layer {
-                0 => 7,
-                // TODO EXTBG
-                1 => 11,
-                _ => unreachable!(),
-            }
-            _ => unreachable!(),
-        }    
+        let mode = self.bg_ctrl.bg_mode() as usize;
+        let priority = if mode == 1 && layer == 2 && self.bg_ctrl.is_bg3_priority_high() {
+            MODE1_BG3_PRIORITY
+        } else {
+            BG_PRIORITY[mode][layer as usize]
+        };
+        debug_assert!(priority != [0, 0], "BG{} is not available in mode {}", layer + 1, mode);
+        priority[is_high as usize]
+    }
+
+    // Latch the H/V counters at the current dot, whichever source pulled pin 6 low
+    pub fn latch_hv_counter(&mut self) {
+        self.h_counter_latch = self.x;
+        self.v_counter_latch = self.y;
+        self.hv_latched = true;
+    }
+
+    // WRIO ($4201) bit7 was written, latches on a high to low transition
+    pub(crate) fn set_wrio_latch(&mut self, high: bool) {
+        if self.wrio_latch && !high {
+            self.latch_hv_counter();
+        }
+        self.wrio_latch = high;
+    }
+
+    // Aim a light gun at pixel (x, y) of the picture, None while it points off screen
+    pub fn set_light_gun(&mut self, target: Option<(u16, u16)>) {
+        self.light_gun = target;
+    }
+
+    // Dot and line at which the beam passes the light gun's pixel
+    fn light_gun_dot(&self) -> Option<(u16, u16)> {
+        self.light_gun
+            .filter(|&(x, y)| (x as usize) < FRAME_WIDTH && y < 239)
+            .map(|(x, y)| (FIRST_VISIBLE_DOT + x, y + 1))
+    }
+
+    fn reload_oam_addr(&mut self) {
+        self.oam_addr = self.oam_addr_and_priority_rotation.addr() << 1;
     }
 
     pub fn is_auto_joypad_read(&mut self) -> bool {
@@ -1043,7 +1544,334 @@ impl Ppu {
     }
 }
 
+// Debugger helpers
+impl Ppu {
+    fn decode_tile_pixel(&self, tile_addr: usize, bpp: usize, x: usize, y: usize) -> u8 {
+        let mut color_index = 0;
+        for i in 0..bpp / 2 {
+            let [low, high] = self.vram_word(tile_addr + i * 16 + y * 2);
+            let low = (low >> (7 - x)) & 1;
+            let high = (high >> (7 - x)) & 1;
+            color_index |= low << (i * 2);
+            color_index |= high << (i * 2 + 1);
+        }
+        color_index
+    }
+
+    // Tiles starting at `base` laid out 16 per row, one color index per pixel (128 pixels wide).
+    // None unless `bpp` is 2, 4 or 8
+    pub fn debug_tileset(&self, bpp: usize, base: usize) -> Option<Vec<u8>> {
+        if !matches!(bpp, 2 | 4 | 8) {
+            return None;
+        }
+        let base = base & 0xFFFF;
+        let tile_bytes = bpp * 8;
+        let tile_count = (0x10000 - base) / tile_bytes;
+        let rows = tile_count.div_ceil(16);
+        let width = 16 * 8;
+
+        let mut ret = vec![0; width * rows * 8];
+        for tile in 0..tile_count {
+            let tile_addr = base + tile * tile_bytes;
+            let origin_x = (tile % 16) * 8;
+            let origin_y = (tile / 16) * 8;
+            for y in 0..8 {
+                for x in 0..8 {
+                    ret[(origin_y + y) * width + origin_x + x] = self.decode_tile_pixel(tile_addr, bpp, x, y);
+                }
+            }
+        }
+        Some(ret)
+    }
+
+    // Tilemap of BG1..=BG4 as 0..4, None for any other `bg`
+    pub fn debug_tilemap(&self, bg: usize) -> Option<TilemapView> {
+        let screen = self.bg_screen_base_and_size.get(bg)?;
+        let (screen_w, screen_h) = screen.get_screen_size();
+        let base_addr = screen.get_bg_map_base_addr();
+        let width = screen_w * 32;
+        let height = screen_h * 32;
+
+        let mut entries = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let screen_addr = base_addr + (x / 32 + y / 32 * screen_w) * 2 * 1024;
+                let map_entry_addr = (screen_addr + (x % 32 + y % 32 * 32) * 2) & 0xFFFE;
+                let map_entry = BGMapEntry::from_bytes(self.vram_word(map_entry_addr));
+                entries.push(TilemapEntry {
+                    tile: map_entry.character_number(),
+                    palette: map_entry.pallet_number(),
+                    priority: map_entry.bg_priority(),
+                    flip_x: map_entry.flip_x(),
+                    flip_y: map_entry.flip_y(),
+                });
+            }
+        }
+
+        Some(TilemapView {
+            width,
+            height,
+            tile_size: self.bg_ctrl.get_tile_size(bg),
+            tile_base: self.bg_tile_base_addr[bg] as usize * 8 * 1024,
+            entries,
+        })
+    }
+
+    pub fn debug_palette(&self) -> [[u8; 3]; 256] {
+        let mut ret = [[0; 3]; 256];
+        for (i, &color) in self.cgram.iter().enumerate() {
+            let r = (color & 0x1F) as u8;
+            let g = ((color >> 5) & 0x1F) as u8;
+            let b = ((color >> 10) & 0x1F) as u8;
+            ret[i] = [r << 3 | r >> 2, g << 3 | g >> 2, b << 3 | b >> 2];
+        }
+        ret
+    }
+
+    pub fn debug_oam(&self) -> Vec<SpriteInfo> {
+        let mut ret = Vec::with_capacity(128);
+        for i in 0..128 {
+            let oam_entry = self.oam_entry(i);
+            let addition = self.oam_byte(0x200 + i / 4) >> ((i % 4) * 2);
+            let upper_x = (addition & 1) as u16;
+            let obj_size_index = ((addition >> 1) & 1) as usize;
+
+            // 9bit signed
+            let x = ((upper_x << 8 | oam_entry.x() as u16) << 7) as i16 >> 7;
+            let (width, height) = self.object_size_and_base.obj_size()[obj_size_index];
+            ret.push(SpriteInfo {
+                x,
+                y: oam_entry.y(),
+                tile: (oam_entry.attribute().tile_page() as u16) << 8 | oam_entry.tile_number() as u16,
+                palette: oam_entry.attribute().palette_number(),
+                priority: oam_entry.attribute().priority(),
+                flip_x: oam_entry.attribute().x_flip(),
+                flip_y: oam_entry.attribute().y_flip(),
+                width,
+                height,
+            });
+        }
+        ret
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TilemapView {
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: usize,
+    pub tile_base: usize,
+    pub entries: Vec<TilemapEntry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TilemapEntry {
+    pub tile: u16,
+    pub palette: u8,
+    pub priority: bool,
+    pub flip_x: bool,
+    pub flip_y: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpriteInfo {
+    pub x: i16,
+    pub y: u8,
+    pub tile: u16,
+    pub palette: u8,
+    pub priority: u8,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    pub width: usize,
+    pub height: usize,
+}
+
+// PPU state at the end of a frame
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameInfo {
+    pub frame_number: u64,
+    pub bg_mode: u8,
+    pub brightness: u8,
+    pub force_blank: bool,
+    pub interlace: bool,
+    pub overscan: bool,
+    pub pseudo_hires: bool,
+    // Bit0..=4: BG1..BG4, OBJ ($212C, $212D)
+    pub main_screen_layers: u8,
+    pub sub_screen_layers: u8,
+}
+
+// How a frontend should present the frame, derived from the last FrameInfo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayInfo {
+    // Size of `frame`
+    pub width: u16,
+    pub height: u16,
+    // Lines the game displays, 224 or 239 with overscan ($2133 bit2). Only the first 224
+    // are rendered
+    pub active_lines: u16,
+    // Width:height of one pixel, 8:7 for NTSC
+    pub pixel_aspect: (u32, u32),
+    // The part of `frame` a typical TV shows, as x, y, width, height
+    pub crop: (u16, u16, u16, u16),
+    // BG mode 5/6 or pseudo hires, which are output at 256 pixels wide
+    pub hires: bool,
+    pub interlace: bool,
+}
+
+impl DisplayInfo {
+    // Width:height of the cropped picture on screen
+    pub fn aspect_ratio(&self) -> f32 {
+        let (_, _, width, height) = self.crop;
+        let (num, den) = self.pixel_aspect;
+        width as f32 * num as f32 / (height as f32 * den as f32)
+    }
+}
+
+// Pixel layouts for Ppu::write_frame, all little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // 0bbbbbgggggrrrrr, the native format of `frame`
+    Bgr555,
+    // rrrrrggggggbbbbb
+    Rgb565,
+    // 0x00RRGGBB as u32
+    Xrgb8888,
+    // R, G, B, 0xFF bytes
+    Rgba8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Bgr555 | PixelFormat::Rgb565 => 2,
+            PixelFormat::Xrgb8888 | PixelFormat::Rgba8888 => 4,
+        }
+    }
+
+    // Whether a frame fits in `len` bytes with rows `pitch` bytes apart
+    pub fn fits(self, len: usize, pitch: usize) -> bool {
+        let row = FRAME_WIDTH * self.bytes_per_pixel();
+        pitch >= row
+            && pitch
+                .checked_mul(FRAME_HEIGHT - 1)
+                .and_then(|rows| rows.checked_add(row))
+                .is_some_and(|size| len >= size)
+    }
+}
+
 impl Ppu {
+    // Convert the frame into `buf`, with rows `pitch` bytes apart. Panics unless `format.fits`
+    pub fn write_frame(&self, buf: &mut [u8], format: PixelFormat, pitch: usize) {
+        let bpp = format.bytes_per_pixel();
+        assert!(format.fits(buf.len(), pitch), "buffer or pitch is smaller than a frame");
+
+        if self.video_filter.is_enabled() {
+            let mut line = [[0; 3]; FRAME_WIDTH];
+            let mut blended = [0; FRAME_WIDTH];
+            let rows = self.frame.chunks_exact(FRAME_WIDTH).zip(buf.chunks_mut(pitch));
+            for (y, (src, dst)) in rows.enumerate() {
+                let src = if self.blend_frame.is_empty() {
+                    src
+                } else {
+                    let prev = &self.blend_frame[y * FRAME_WIDTH..(y + 1) * FRAME_WIDTH];
+                    video_filter::blend_line(src, prev, &mut blended);
+                    &blended[..]
+                };
+                self.video_filter.filter_line(src, y, &mut line);
+                for (&[r, g, b], dst) in line.iter().zip(dst.chunks_exact_mut(bpp)) {
+                    let (r5, g5, b5) = ((r >> 3) as u16, (g >> 3) as u16, (b >> 3) as u16);
+                    match format {
+                        PixelFormat::Bgr555 => {
+                            dst.copy_from_slice(&(b5 << 10 | g5 << 5 | r5).to_le_bytes());
+                        }
+                        PixelFormat::Rgb565 => {
+                            let color = r5 << 11 | ((g >> 2) as u16) << 5 | b5;
+                            dst.copy_from_slice(&color.to_le_bytes());
+                        }
+                        PixelFormat::Xrgb8888 => dst.copy_from_slice(&[b, g, r, 0]),
+                        PixelFormat::Rgba8888 => dst.copy_from_slice(&[r, g, b, 0xFF]),
+                    }
+                }
+            }
+            return;
+        }
+
+        let to_8bit = |c: u16| (c << 3 | c >> 2) as u8;
+        for (src, dst) in self.frame.chunks_exact(FRAME_WIDTH).zip(buf.chunks_mut(pitch)) {
+            for (&color, dst) in src.iter().zip(dst.chunks_exact_mut(bpp)) {
+                let r = color & 0x1F;
+                let g = (color >> 5) & 0x1F;
+                let b = (color >> 10) & 0x1F;
+                match format {
+                    PixelFormat::Bgr555 => dst.copy_from_slice(&color.to_le_bytes()),
+                    PixelFormat::Rgb565 => {
+                        let color = r << 11 | g << 6 | (g >> 4) << 5 | b;
+                        dst.copy_from_slice(&color.to_le_bytes());
+                    }
+                    PixelFormat::Xrgb8888 => {
+                        dst.copy_from_slice(&[to_8bit(b), to_8bit(g), to_8bit(r), 0]);
+                    }
+                    PixelFormat::Rgba8888 => {
+                        dst.copy_from_slice(&[to_8bit(r), to_8bit(g), to_8bit(b), 0xFF]);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn frame_info(&self) -> FrameInfo {
+        self.frame_info
+    }
+
+    pub fn vblank_timestamp(&self) -> u64 {
+        self.vblank_timestamp
+    }
+
+    pub fn display_info(&self) -> DisplayInfo {
+        let info = &self.frame_info;
+        let pixel_aspect = match VIDEO_STANDARD {
+            VideoStandard::Ntsc => (8, 7),
+            // 7.375MHz square pixels over the 5.32MHz dot clock
+            VideoStandard::Pal => (7_375_000, 5_320_342),
+        };
+        // TVs hide about 8 lines at the top and the bottom of the picture
+        let (active_lines, crop) = if info.overscan {
+            (239, (0, 8, FRAME_WIDTH as u16, FRAME_HEIGHT as u16 - 8))
+        } else {
+            (224, (0, 8, FRAME_WIDTH as u16, FRAME_HEIGHT as u16 - 16))
+        };
+        DisplayInfo {
+            width: FRAME_WIDTH as u16,
+            height: FRAME_HEIGHT as u16,
+            active_lines,
+            pixel_aspect,
+            crop,
+            hires: info.pseudo_hires || matches!(info.bg_mode, 5 | 6),
+            interlace: info.interlace,
+        }
+    }
+
+    fn capture_frame_info(&self) -> FrameInfo {
+        FrameInfo {
+            frame_number: self.frame_number,
+            bg_mode: self.bg_ctrl.bg_mode(),
+            brightness: self.display_control.brightness(),
+            force_blank: self.display_control.force_blank(),
+            interlace: self.display_control.v_scanning(),
+            overscan: self.display_control.bg_v_direction_display(),
+            pseudo_hires: self.display_control.horizontal_pseudo_512mode(),
+            main_screen_layers: self.screen_main_designation.bytes[0] & 0x1F,
+            sub_screen_layers: self.screen_sub_designation.bytes[0] & 0x1F,
+        }
+    }
+
+    // (frame, dot, line)
+    pub fn position(&self) -> (u64, u16, u16) {
+        (self.frame_number, self.x, self.y)
+    }
+
     pub fn is_hblank(&self) -> bool {
         self.is_hblank
     }
@@ -1064,8 +1892,228 @@ impl Ppu {
     }
 }
 
+// VRAM and CGRAM reads wrap like the PPU's address lines, so corrupt register values can't
+// index out of bounds
+fn vram_byte(vram: &[u8; 0x10000], addr: usize) -> u8 {
+    vram[addr & 0xFFFF]
+}
+
+// The little endian word containing `addr`
+fn vram_word(vram: &[u8; 0x10000], addr: usize) -> [u8; 2] {
+    let addr = addr & 0xFFFE;
+    [vram[addr], vram[addr + 1]]
+}
+
+fn cgram_color(cgram: &[u16; 0x100], index: usize) -> u16 {
+    cgram[index & 0xFF]
+}
+
+// Spreads the bits of a bitplane byte into one byte per pixel, leftmost pixel (bit7) first
+const BITPLANE_LUT: [u64; 256] = {
+    let mut lut = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut pixel_x = 0;
+        while pixel_x < 8 {
+            lut[i] |= ((i as u64 >> (7 - pixel_x)) & 1) << (pixel_x * 8);
+            pixel_x += 1;
+        }
+        i += 1;
+    }
+    lut
+};
+
+// Palette indices of decoded tiles per color depth (2, 4 and 8bpp), refreshed lazily after
+// VRAM writes
+struct TileCache {
+    tiles: [Vec<[[u8; 8]; 8]>; 3],
+    dirty: [Vec<bool>; 3],
+    // Some tile of the depth is dirty
+    stale: [bool; 3],
+}
+
+impl Default for TileCache {
+    fn default() -> Self {
+        let tiles = |bpp: usize| vec![[[0; 8]; 8]; 0x10000 / (bpp * 8)];
+        let dirty = |bpp: usize| vec![true; 0x10000 / (bpp * 8)];
+        TileCache {
+            tiles: [tiles(2), tiles(4), tiles(8)],
+            dirty: [dirty(2), dirty(4), dirty(8)],
+            stale: [true; 3],
+        }
+    }
+}
+
+impl TileCache {
+    fn depth_index(bpp: usize) -> usize {
+        match bpp {
+            2 => 0,
+            4 => 1,
+            8 => 2,
+            _ => unreachable!(),
+        }
+    }
+
+    fn invalidate(&mut self, vram_addr: usize) {
+        for (i, bpp) in [2, 4, 8].into_iter().enumerate() {
+            self.dirty[i][vram_addr / (bpp * 8)] = true;
+        }
+        self.stale = [true; 3];
+    }
+
+    fn invalidate_all(&mut self) {
+        for dirty in self.dirty.iter_mut() {
+            dirty.fill(true);
+        }
+        self.stale = [true; 3];
+    }
+
+    // Decode every dirty tile of the depth, so `decoded` can be used from other threads
+    fn refresh(&mut self, vram: &[u8; 0x10000], bpp: usize) {
+        let depth = Self::depth_index(bpp);
+        if !core::mem::take(&mut self.stale[depth]) {
+            return;
+        }
+        for index in 0..self.dirty[depth].len() {
+            if self.dirty[depth][index] {
+                self.tile(vram, bpp, index * bpp * 8);
+            }
+        }
+    }
+
+    fn decoded(&self, bpp: usize, tile_addr: usize) -> &[[u8; 8]; 8] {
+        let depth = Self::depth_index(bpp);
+        &self.tiles[depth][(tile_addr & 0xFFFF) / (bpp * 8)]
+    }
+
+    // `tile_addr` is the byte address of the tile in VRAM and wraps at 64KB
+    fn tile(&mut self, vram: &[u8; 0x10000], bpp: usize, tile_addr: usize) -> &[[u8; 8]; 8] {
+        let depth = Self::depth_index(bpp);
+        let tile_addr = tile_addr & 0xFFFF;
+        let index = tile_addr / (bpp * 8);
+
+        if self.dirty[depth][index] {
+            self.dirty[depth][index] = false;
+            let tile = &mut self.tiles[depth][index];
+            for (pixel_y, row) in tile.iter_mut().enumerate() {
+                let mut color_indices = 0;
+                for i in 0..bpp / 2 {
+                    let [low, high] = vram_word(vram, tile_addr + i * 16 + pixel_y * 2);
+                    let (low, high) = (BITPLANE_LUT[low as usize], BITPLANE_LUT[high as usize]);
+                    color_indices |= (low | high << 1) << (i * 2);
+                }
+                *row = color_indices.to_le_bytes();
+            }
+        }
+        &self.tiles[depth][index]
+    }
+}
+
+// A BG's pixel before the merge with the other layers
+#[derive(Clone, Copy)]
+struct BgPixel {
+    color: u16,
+    priority: u8,
+}
+
+impl BgPixel {
+    // Loses against the backdrop
+    const TRANSPARENT: BgPixel = BgPixel {
+        color: 0,
+        priority: u8::MAX,
+    };
+
+    fn lines() -> Box<[[BgPixel; FRAME_WIDTH]; 4]> {
+        Box::new([[BgPixel::TRANSPARENT; FRAME_WIDTH]; 4])
+    }
+}
+
+// Registers of one BG in modes 0-6, taken at the start of a render_bg call
+#[derive(Clone, Copy)]
+struct BgLayer {
+    bpp: usize,
+    tile_size: usize,
+    tile_base_addr: usize,
+    map_base_addr: usize,
+    screen_size: (usize, usize),
+    hofs: usize,
+    vofs: usize,
+    cgram_base_addr: usize,
+    priority: [u8; 2],
+}
+
+impl BgLayer {
+    // `tiles` has to be refreshed for `bpp`
+    fn render(
+        &self,
+        vram: &[u8; 0x10000],
+        tiles: &TileCache,
+        cgram: &[u16; 0x100],
+        y: u16,
+        range: Range<usize>,
+        line: &mut [BgPixel; FRAME_WIDTH],
+    ) {
+        let tile_size = self.tile_size;
+        // Tile row under the current 8 pixel column of the BG
+        let mut cached_column = usize::MAX;
+        let mut row = [0u8; 8];
+        let mut map_entry = BGMapEntry::new();
+
+        for x in range {
+            let screen_x = x + self.hofs;
+            let screen_y = y as usize + self.vofs;
+
+            if screen_x / 8 != cached_column {
+                cached_column = screen_x / 8;
+                map_entry = self.map_entry(vram, screen_x, screen_y);
+
+                let mut tile_index = map_entry.character_number() as usize;
+                let pixel_x = (screen_x % tile_size) ^ if map_entry.flip_x() { tile_size - 1 } else { 0 };
+                let mut pixel_y = (screen_y % tile_size) ^ if map_entry.flip_y() { tile_size - 1 } else { 0 };
+                if pixel_x >= 8 {
+                    tile_index += 0x01;
+                }
+                if pixel_y >= 8 {
+                    tile_index += 0x10;
+                    pixel_y %= 8;
+                }
+
+                let tile_addr = self.tile_base_addr + tile_index * self.bpp * 8;
+                row = tiles.decoded(self.bpp, tile_addr)[pixel_y];
+                if map_entry.flip_x() {
+                    row.reverse();
+                }
+            }
+            let color_index = row[screen_x % 8];
+
+            line[x] = if color_index != 0 {
+                let cgram_addr = self.cgram_base_addr + map_entry.pallet_number() as usize * (1 << self.bpp) + color_index as usize;
+                BgPixel {
+                    color: cgram_color(cgram, cgram_addr),
+                    priority: self.priority[map_entry.bg_priority() as usize],
+                }
+            } else {
+                BgPixel::TRANSPARENT
+            };
+        }
+    }
+
+    fn map_entry(&self, vram: &[u8; 0x10000], x: usize, y: usize) -> BGMapEntry {
+        let (screen_w, screen_h) = self.screen_size;
+        let tile_size = self.tile_size;
+
+        let sc_x = x / tile_size / 32 % screen_w;
+        let sc_y = y / tile_size / 32 % screen_h;
+        let tile_x = x / tile_size % 32;
+        let tile_y = y / tile_size % 32;
+
+        let screen_addr = self.map_base_addr + (sc_x + sc_y * screen_w) * 2 * 1024;
+        BGMapEntry::from_bytes(vram_word(vram, screen_addr + (tile_x + tile_y * 32) * 2))
+    }
+}
 
 #[derive(Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct PixelInfo {
     r: u8,
     g: u8,
@@ -1084,6 +2132,7 @@ impl PixelInfo {
 }
 
 #[derive(Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum Layer {
     Bg1 = 0,
     Bg2 = 1,
@@ -1109,6 +2158,7 @@ impl Layer {
 
 #[bitfield(bits = 16)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct DisplayCtrl {
     brightness: B4,
     #[skip]
@@ -1126,6 +2176,7 @@ struct DisplayCtrl {
 
 #[bitfield(bits = 8)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ScreenDesignation {
     bg1_enable: bool,
     bg2_enable: bool,
@@ -1136,6 +2187,89 @@ struct ScreenDesignation {
     __: B3,
 }
 
+// Debug is implemented by hand, the one the bitfield macro derives warns about unused parens
+#[bitfield(bits = 8)]
+#[derive(Clone, Copy)]
+pub struct LayerMask {
+    pub bg1: bool,
+    pub bg2: bool,
+    pub bg3: bool,
+    pub bg4: bool,
+    pub obj: bool,
+    pub color_math: bool,
+    #[skip]
+    __: B2,
+}
+
+impl core::fmt::Debug for LayerMask {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("LayerMask")
+            .field("bg1", &self.bg1())
+            .field("bg2", &self.bg2())
+            .field("bg3", &self.bg3())
+            .field("bg4", &self.bg4())
+            .field("obj", &self.obj())
+            .field("color_math", &self.color_math())
+            .finish()
+    }
+}
+
+impl LayerMask {
+    pub fn all() -> Self {
+        LayerMask::from_bytes([0x3F])
+    }
+
+    fn get_bg_enable(&self, bg_index: usize) -> bool {
+        match bg_index {
+            0 => self.bg1(),
+            1 => self.bg2(),
+            2 => self.bg3(),
+            3 => self.bg4(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Default for LayerMask {
+    fn default() -> Self {
+        LayerMask::all()
+    }
+}
+
+// Replace the picture with where each pixel comes from, for debugging the priority rules.
+// Color math, brightness and force blank are ignored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DebugRender {
+    #[default]
+    Off,
+    // BG1 red, BG2 green, BG3 blue, BG4 yellow, OBJ palettes 0-3 magenta, 4-7 cyan and
+    // the backdrop black
+    LayerId,
+    // Red for the frontmost priority slot fading to blue for the backdrop
+    Priority,
+}
+
+impl DebugRender {
+    fn color(self, pixel: PixelInfo) -> Option<u16> {
+        match self {
+            DebugRender::Off => None,
+            DebugRender::LayerId => Some(match pixel.layer {
+                Layer::Bg1 => 0x001F,
+                Layer::Bg2 => 0x03E0,
+                Layer::Bg3 => 0x7C00,
+                Layer::Bg4 => 0x03FF,
+                Layer::ObjPallete0_3 => 0x7C1F,
+                Layer::ObjPallete4_7 => 0x7FE0,
+                Layer::Backdrop => 0,
+            }),
+            DebugRender::Priority => {
+                let back = (pixel.priority.min(BACKDROP_PRIORITY) as u16 * 31) / BACKDROP_PRIORITY as u16;
+                Some(back << 10 | (31 - back))
+            }
+        }
+    }
+}
+
 impl ScreenDesignation {
     fn get_bg_enable(&self, bg_index: usize) -> bool {
         match bg_index {
@@ -1150,6 +2284,7 @@ impl ScreenDesignation {
 
 #[bitfield(bits = 8)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct BgCtrl {
     bg_mode: B3,
     is_bg3_priority_high: bool,
@@ -1178,6 +2313,7 @@ struct BGMapEntry {
 
 #[bitfield(bits = 8)]
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct BGScreenBaseSize {
     screen_size: B2,
     screen_base: B6,
@@ -1224,6 +2360,7 @@ struct Attribute {
 
 #[bitfield(bits = 8)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ObjectSizeAndBase {
     base_addr_for_obj_tiles: B3,
     gap_between_obj: B2,
@@ -1231,16 +2368,17 @@ struct ObjectSizeAndBase {
 }
 
 impl ObjectSizeAndBase {
-    fn obj_size(&self) -> [usize; 2] {
+    // [small, large] as (width, height)
+    fn obj_size(&self) -> [(usize, usize); 2] {
         match self.obj_size_selection() {
-            ObjectSizeSelection::Size8x8_16x16 => [8, 16],
-            ObjectSizeSelection::Size8x8_32x32 => [8, 32],
-            ObjectSizeSelection::Size8x8_64x64 => [8, 64],
-            ObjectSizeSelection::Size16x16_32x32 => [16, 32],
-            ObjectSizeSelection::Size16x16_64x64 => [16, 64],
-            ObjectSizeSelection::Size32x32_64x64 => [32, 64],
-            ObjectSizeSelection::Size16x32_32x64 => [16, 32],
-            ObjectSizeSelection::Size16x32_32x32 => [16, 32],
+            ObjectSizeSelection::Size8x8_16x16 => [(8, 8), (16, 16)],
+            ObjectSizeSelection::Size8x8_32x32 => [(8, 8), (32, 32)],
+            ObjectSizeSelection::Size8x8_64x64 => [(8, 8), (64, 64)],
+            ObjectSizeSelection::Size16x16_32x32 => [(16, 16), (32, 32)],
+            ObjectSizeSelection::Size16x16_64x64 => [(16, 16), (64, 64)],
+            ObjectSizeSelection::Size32x32_64x64 => [(32, 32), (64, 64)],
+            ObjectSizeSelection::Size16x32_32x64 => [(16, 32), (32, 64)],
+            ObjectSizeSelection::Size16x32_32x32 => [(16, 32), (32, 32)],
         }
     }
 }
@@ -1260,6 +2398,7 @@ enum ObjectSizeSelection {
 
 #[bitfield(bits = 16)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct OamAddrAndPriorityRotation {
     addr: B9,
     __: B6,
@@ -1268,6 +2407,7 @@ struct OamAddrAndPriorityRotation {
 
 #[bitfield(bits = 8)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct RotatinScalingSetting {
     h_flip: bool,
     v_flip: bool,
@@ -1276,6 +2416,7 @@ struct RotatinScalingSetting {
 }
 
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct RotationScalingParam {
     a: u16,
     b: u16,
@@ -1286,12 +2427,14 @@ struct RotationScalingParam {
 }
 
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct WindowPosition {
     left: u8,
     right: u8,
 }
 
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct WindowMask {
     bg: [MaskSettings; 4],
     obj: MaskSettings,
@@ -1300,6 +2443,7 @@ struct WindowMask {
 
 #[bitfield(bits = 8)]
 #[derive(BitfieldSpecifier, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct MaskSettings {
     window1: MaskSetting,
     window2: MaskSetting,
@@ -1309,12 +2453,13 @@ struct MaskSettings {
 #[bitfield(bits = 2)]
 #[derive(BitfieldSpecifier)]
 struct MaskSetting {
-    enable: bool,
     outside: bool,
+    enable: bool,
 }
 
 #[bitfield(bits = 16)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct WindowMaskLogic {
     bg1: MaskLogic,
     bg2: MaskLogic,
@@ -1338,6 +2483,7 @@ enum MaskLogic {
 
 #[bitfield(bits = 8)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct MosaicSizeAndEnable {
     enable: B4,
     size: B4,
@@ -1345,6 +2491,7 @@ struct MosaicSizeAndEnable {
 
 #[bitfield(bits = 16)]
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ColorMathCtrl {
     direct_color: bool,
     sub_screen_enable: bool,
@@ -1372,12 +2519,13 @@ enum ColorMathEnable {
 enum ForceMainScreenBlack {
     #[default]
     Never = 0,
-    MathWindow = 1,
-    NotMathWin = 2,
+    NotMathWin = 1,
+    MathWindow = 2,
     Always = 3,
 }
 
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ColorMathSubscreenBackdropColor {
     r: u8,
     g: u8,
@@ -1388,4 +2536,127 @@ impl ColorMathSubscreenBackdropColor {
     fn get_bgr(&self) -> u16 {
         (self.b as u16) << 10 | (self.g as u16) << 5 | self.r as u16
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    // Layers from front to back for a $2105 value, like the table comment above BG_PRIORITY
+    fn layer_order(bgmode: u8) -> String {
+        let mut ppu = Ppu::default();
+        ppu.bg_ctrl.bytes[0] = bgmode;
+        let mode = (bgmode & 7) as usize;
+        let mut layers: Vec<(u8, String)> = (0..4)
+            .map(|obj| (OBJ_PRIORITY[obj], format!("OBJ{}", obj)))
+            .collect();
+        for bg in 0..BG_MODE_BPP[mode].len() {
+            let [low, high] = [false, true].map(|h| ppu.get_bg_layer_priority(bg as u8, h));
+            if low == high {
+                layers.push((low, format!("BG{}", bg + 1)));
+            } else {
+                layers.push((low, format!("BG{}L", bg + 1)));
+                layers.push((high, format!("BG{}H", bg + 1)));
+            }
+        }
+        if mode == 7 {
+            // EXTBG isn't in BG_MODE_BPP yet
+            layers.push((BG_PRIORITY[7][1][0], "BG2L".into()));
+            layers.push((BG_PRIORITY[7][1][1], "BG2H".into()));
+        }
+        layers.sort();
+        let priorities: Vec<u8> = layers.iter().map(|(p, _)| *p).collect();
+        let mut unique = priorities.clone();
+        unique.dedup();
+        assert_eq!(
+            priorities, unique,
+            "mode {} has layers sharing a priority",
+            mode
+        );
+        let names: Vec<String> = layers.into_iter().map(|(_, name)| name).collect();
+        names.join(" ")
+    }
+
+    #[test]
+    fn bg_priority_per_mode() {
+        assert_eq!(
+            layer_order(0),
+            "OBJ3 BG1H BG2H OBJ2 BG1L BG2L OBJ1 BG3H BG4H OBJ0 BG3L BG4L"
+        );
+        assert_eq!(
+            layer_order(1),
+            "OBJ3 BG1H BG2H OBJ2 BG1L BG2L OBJ1 BG3H OBJ0 BG3L"
+        );
+        for mode in 2..=5 {
+            assert_eq!(
+                layer_order(mode),
+                "OBJ3 BG1H OBJ2 BG2H OBJ1 BG1L OBJ0 BG2L",
+                "mode {}",
+                mode
+            );
+        }
+        assert_eq!(layer_order(6), "OBJ3 BG1H OBJ2 OBJ1 BG1L OBJ0");
+        assert_eq!(layer_order(7), "OBJ3 OBJ2 BG2H OBJ1 BG1 OBJ0 BG2L");
+    }
+
+    #[test]
+    fn mode1_bg3_priority() {
+        assert_eq!(
+            layer_order(0x09),
+            "BG3H OBJ3 BG1H BG2H OBJ2 BG1L BG2L OBJ1 OBJ0 BG3L"
+        );
+        // The bit only applies to mode 1
+        assert_eq!(
+            layer_order(0x08),
+            "OBJ3 BG1H BG2H OBJ2 BG1L BG2L OBJ1 BG3H BG4H OBJ0 BG3L BG4L"
+        );
+    }
+
+    #[test]
+    fn debug_tileset_bpp() {
+        let ppu = Ppu::default();
+        for bpp in [2, 4, 8] {
+            let tileset = ppu.debug_tileset(bpp, 0).unwrap();
+            assert_eq!(tileset.len(), 0x10000 / (bpp * 8) * 64, "{} bpp", bpp);
+        }
+        for bpp in [0, 1, 3, 16] {
+            assert!(ppu.debug_tileset(bpp, 0).is_none(), "{} bpp", bpp);
+        }
+    }
+
+    #[test]
+    fn debug_tilemap_out_of_range() {
+        let ppu = Ppu::default();
+        assert_eq!(ppu.debug_tilemap(3).map(|view| view.width), Some(32));
+        assert!(ppu.debug_tilemap(4).is_none());
+    }
+
+    // Color 1 (red 10) everywhere in mode 7, plus the fixed color (red 5) where `kind` ($2131)
+    // enables color math. Returns red at the first pixel
+    fn mode7_color_math(kind: u8) -> u16 {
+        let mut ppu = Ppu::default();
+        ppu.bg_ctrl.bytes[0] = 7;
+        ppu.display_control.set_brightness(15);
+        ppu.screen_main_designation.set_bg1_enable(true);
+        ppu.rotation_scaling_param.a = 0x100;
+        ppu.rotation_scaling_param.d = 0x100;
+        ppu.color_math_ctrl.set_kind(kind);
+        ppu.color_math_sub_screen_backdrop_color.r = 5;
+        ppu.cgram[1] = 10;
+        // Tile 0 is all color 1, and the whole tilemap is tile 0
+        for pixel in 0..64 {
+            ppu.write_vram(pixel * 2 + 1, 1);
+        }
+        ppu.render_bg(0, 0..FRAME_WIDTH);
+        ppu.color_math(0, 0..FRAME_WIDTH);
+        ppu.frame[0] & 0x1F
+    }
+
+    #[test]
+    fn mode7_color_math_uses_bg1() {
+        assert_eq!(mode7_color_math(0b01), 15);
+        assert_eq!(mode7_color_math(0b10), 10);
+    }
+}
@@ -0,0 +1,150 @@
+// Satellaview (BS-X) receiver on the expansion port, as seen through its stream registers
+// at $2188-$219F. Broadcasts aren't received but scheduled by the frontend, e.g. from a
+// preserved broadcast dump, see Satellaview::schedule
+//
+// Each stream has a channel number (+0, +1), the number of pending packets (+2), the prefix
+// of the next packet (+3), its 22 data bytes (+4) and the prefixes read since the last status
+// read (+5). Stream 1 is at $2188, stream 2 at $218E. $2194-$219F are control registers that
+// are only latched
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+const PACKET_SIZE: usize = 22;
+// Prefix bits
+const FIRST_PACKET: u8 = 0x10;
+const LAST_PACKET: u8 = 0x80;
+// Read while no channel is selected
+const NO_CHANNEL_PREFIX: u8 = 0x8F;
+
+struct DataUnit {
+    // Master clock at which the unit is on air
+    at: u64,
+    channel: u16,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Stream {
+    channel: u16,
+    // Packets of the unit being read, prefix first
+    packets: VecDeque<(u8, [u8; PACKET_SIZE])>,
+    data: [u8; PACKET_SIZE],
+    offset: usize,
+    status: u8,
+}
+
+#[derive(Default)]
+pub struct Satellaview {
+    schedule: Vec<DataUnit>,
+    streams: [Stream; 2],
+    control: [u8; 12],
+}
+
+impl Satellaview {
+    pub fn new() -> Satellaview {
+        Satellaview::default()
+    }
+
+    // Put `data` on air on `channel` from master clock `at` on. It is split into 22 byte
+    // packets and received once, by the first stream tuned to the channel after `at`
+    pub fn schedule(&mut self, at: u64, channel: u16, data: Vec<u8>) {
+        let index = self.schedule.partition_point(|unit| unit.at <= at);
+        self.schedule.insert(index, DataUnit { at, channel, data });
+    }
+
+    // Units still waiting to be received
+    pub fn pending(&self) -> usize {
+        self.schedule.len()
+    }
+
+    pub(crate) fn read(&mut self, addr: u16, now: u64) -> u8 {
+        match addr {
+            0x2194..=0x219F => self.control[(addr - 0x2194) as usize],
+            _ => {
+                let index = (addr - 0x2188) as usize / 6;
+                let register = (addr - 0x2188) as usize % 6;
+                self.read_stream(index, register, now)
+            }
+        }
+    }
+
+    pub(crate) fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x2194..=0x219F => self.control[(addr - 0x2194) as usize] = data,
+            _ => {
+                let stream = &mut self.streams[(addr - 0x2188) as usize / 6];
+                match (addr - 0x2188) % 6 {
+                    0 => stream.channel = stream.channel & 0xFF00 | data as u16,
+                    1 => stream.channel = stream.channel & 0x00FF | (data as u16) << 8,
+                    _ => return,
+                }
+                // Retuning drops the unit being received
+                stream.packets.clear();
+                stream.offset = PACKET_SIZE;
+            }
+        }
+    }
+
+    fn read_stream(&mut self, index: usize, register: usize, now: u64) -> u8 {
+        if self.streams[index].packets.is_empty() {
+            self.tune(index, now);
+        }
+        let stream = &mut self.streams[index];
+        match register {
+            0 => stream.channel as u8,
+            1 => (stream.channel >> 8) as u8,
+            2 => stream.packets.len().min(0x7F) as u8,
+            3 => {
+                if stream.channel == 0 {
+                    return NO_CHANNEL_PREFIX;
+                }
+                match stream.packets.pop_front() {
+                    Some((prefix, data)) => {
+                        stream.data = data;
+                        stream.offset = 0;
+                        stream.status |= prefix;
+                        prefix
+                    }
+                    None => 0,
+                }
+            }
+            4 => {
+                let data = stream.data.get(stream.offset).copied().unwrap_or(0);
+                stream.offset = (stream.offset + 1).min(PACKET_SIZE);
+                data
+            }
+            _ => core::mem::take(&mut stream.status),
+        }
+    }
+
+    // Start receiving the next unit on air on the stream's channel
+    fn tune(&mut self, index: usize, now: u64) {
+        let stream = &mut self.streams[index];
+        if stream.channel == 0 {
+            return;
+        }
+        let Some(position) = self
+            .schedule
+            .iter()
+            .take_while(|unit| unit.at <= now)
+            .position(|unit| unit.channel == stream.channel)
+        else {
+            return;
+        };
+        let unit = self.schedule.remove(position);
+        let count = unit.data.len().div_ceil(PACKET_SIZE).max(1);
+        for i in 0..count {
+            let mut packet = [0; PACKET_SIZE];
+            let chunk = unit.data.chunks(PACKET_SIZE).nth(i).unwrap_or(&[]);
+            packet[..chunk.len()].copy_from_slice(chunk);
+            let mut prefix = 0;
+            if i == 0 {
+                prefix |= FIRST_PACKET;
+            }
+            if i == count - 1 {
+                prefix |= LAST_PACKET;
+            }
+            stream.packets.push_back((prefix, packet));
+        }
+    }
+}
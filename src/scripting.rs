@@ -0,0 +1,230 @@
+// Rhai scripting for tooling (RAM watches, TAS helpers, romhack debugging)
+//
+// let mut script = Script::load(&mut snes, &fs::read_to_string("lives.rhai")?)?;
+// loop {
+//     snes.exec_frame()?;
+//     script.run_frame(&mut snes)?;
+// }
+//
+// Functions available to scripts. Addresses are 24bit CPU addresses that map to WRAM
+// ($7E0000-$7FFFFF and the $0000-$1FFF mirrors), colors are BGR555:
+//
+//   on_frame(|| ...)              called after every frame
+//   on_write(addr, |value| ...)   called after the frame for every write to `addr`, in order
+//   read_u8(addr) / read_u16(addr)
+//   write_u8(addr, value)         applied once all callbacks of the frame have run
+//   frame()                       current frame number
+//   draw_pixel(x, y, color) / draw_rect(x, y, w, h, color)
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use rhai::{Engine, EvalAltResult, FnPtr, Scope, AST};
+
+use crate::context::Bus;
+use crate::Snes;
+
+const FRAME_WIDTH: i64 = 256;
+const FRAME_HEIGHT: i64 = 224;
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Parse(rhai::ParseError),
+    Runtime(Box<EvalAltResult>),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Parse(err) => write!(f, "Script parse error: {}", err),
+            ScriptError::Runtime(err) => write!(f, "Script error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<rhai::ParseError> for ScriptError {
+    fn from(err: rhai::ParseError) -> Self {
+        ScriptError::Parse(err)
+    }
+}
+
+impl From<Box<EvalAltResult>> for ScriptError {
+    fn from(err: Box<EvalAltResult>) -> Self {
+        ScriptError::Runtime(err)
+    }
+}
+
+// Shared between the registered functions and Script. Scripts see a copy of WRAM taken
+// before the callbacks run, so nothing borrows the Snes while Rhai code executes
+#[derive(Default)]
+struct Host {
+    wram: Vec<u8>,
+    frame: u64,
+    writes: Vec<(usize, u8)>,
+    pixels: Vec<(usize, u16)>,
+    frame_callbacks: Vec<FnPtr>,
+    write_callbacks: Vec<(usize, FnPtr)>,
+    new_watches: Vec<usize>,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    host: Rc<RefCell<Host>>,
+}
+
+impl Script {
+    // Compile `source` and run its top level, which usually registers the callbacks
+    pub fn load(snes: &mut Snes, source: &str) -> Result<Script, ScriptError> {
+        let host = Rc::new(RefCell::new(Host::default()));
+        let mut engine = Engine::new();
+        register_functions(&mut engine, &host);
+
+        let ast = engine.compile(source)?;
+        let mut script = Script {
+            engine,
+            ast,
+            scope: Scope::new(),
+            host,
+        };
+
+        script.begin(snes);
+        script.engine.run_ast_with_scope(&mut script.scope, &script.ast)?;
+        script.end(snes);
+        Ok(script)
+    }
+
+    // Call after every Snes::exec_frame
+    pub fn run_frame(&mut self, snes: &mut Snes) -> Result<(), ScriptError> {
+        self.begin(snes);
+        let writes = snes.context.inner1.take_wram_writes();
+
+        let (write_callbacks, frame_callbacks) = {
+            let host = self.host.borrow();
+            (host.write_callbacks.clone(), host.frame_callbacks.clone())
+        };
+        for (offset, data) in writes {
+            for (_, callback) in write_callbacks.iter().filter(|(o, _)| *o == offset as usize) {
+                callback.call::<()>(&self.engine, &self.ast, (data as i64,))?;
+            }
+        }
+        for callback in frame_callbacks.iter() {
+            callback.call::<()>(&self.engine, &self.ast, ())?;
+        }
+
+        self.end(snes);
+        Ok(())
+    }
+
+    fn begin(&mut self, snes: &Snes) {
+        let mut host = self.host.borrow_mut();
+        host.wram.clear();
+        host.wram.extend_from_slice(snes.wram());
+        host.frame = snes.context.inner1.inner2.ppu.frame_number;
+    }
+
+    fn end(&mut self, snes: &mut Snes) {
+        let mut host = self.host.borrow_mut();
+        for offset in host.new_watches.drain(..) {
+            snes.context.inner1.watch_wram_write(offset);
+        }
+
+        let wram = snes.wram_mut();
+        for (offset, data) in host.writes.drain(..) {
+            wram[offset] = data;
+        }
+
+        let frame = &mut snes.context.inner1.inner2.ppu.frame;
+        for (index, color) in host.pixels.drain(..) {
+            frame[index] = color;
+        }
+    }
+}
+
+fn wram_offset(addr: i64) -> Result<usize, Box<EvalAltResult>> {
+    let bank = (addr >> 16) & 0xFF;
+    let offset = addr & 0xFFFF;
+    match bank {
+        0x7E..=0x7F => Ok((addr & 0x1FFFF) as usize),
+        0x00..=0x3F | 0x80..=0xBF if offset < 0x2000 => Ok(offset as usize),
+        _ => Err(format!("Not a WRAM address: {:06X}", addr).into()),
+    }
+}
+
+fn register_functions(engine: &mut Engine, host: &Rc<RefCell<Host>>) {
+    let h = host.clone();
+    engine.register_fn("on_frame", move |callback: FnPtr| {
+        h.borrow_mut().frame_callbacks.push(callback);
+    });
+
+    let h = host.clone();
+    engine.register_fn(
+        "on_write",
+        move |addr: i64, callback: FnPtr| -> Result<(), Box<EvalAltResult>> {
+            let offset = wram_offset(addr)?;
+            let mut host = h.borrow_mut();
+            host.write_callbacks.push((offset, callback));
+            host.new_watches.push(offset);
+            Ok(())
+        },
+    );
+
+    let h = host.clone();
+    engine.register_fn("read_u8", move |addr: i64| -> Result<i64, Box<EvalAltResult>> {
+        Ok(h.borrow().wram[wram_offset(addr)?] as i64)
+    });
+
+    let h = host.clone();
+    engine.register_fn("read_u16", move |addr: i64| -> Result<i64, Box<EvalAltResult>> {
+        let host = h.borrow();
+        let low = host.wram[wram_offset(addr)?] as i64;
+        let high = host.wram[wram_offset(addr + 1)?] as i64;
+        Ok(high << 8 | low)
+    });
+
+    let h = host.clone();
+    engine.register_fn(
+        "write_u8",
+        move |addr: i64, value: i64| -> Result<(), Box<EvalAltResult>> {
+            let offset = wram_offset(addr)?;
+            let mut host = h.borrow_mut();
+            host.wram[offset] = value as u8;
+            host.writes.push((offset, value as u8));
+            Ok(())
+        },
+    );
+
+    let h = host.clone();
+    engine.register_fn("frame", move || h.borrow().frame as i64);
+
+    let h = host.clone();
+    engine.register_fn("draw_pixel", move |x: i64, y: i64, color: i64| {
+        draw_pixel(&mut h.borrow_mut(), x, y, color);
+    });
+
+    let h = host.clone();
+    engine.register_fn(
+        "draw_rect",
+        move |x: i64, y: i64, width: i64, height: i64, color: i64| {
+            let mut host = h.borrow_mut();
+            for i in x..x + width {
+                draw_pixel(&mut host, i, y, color);
+                draw_pixel(&mut host, i, y + height - 1, color);
+            }
+            for j in y..y + height {
+                draw_pixel(&mut host, x, j, color);
+                draw_pixel(&mut host, x + width - 1, j, color);
+            }
+        },
+    );
+}
+
+fn draw_pixel(host: &mut Host, x: i64, y: i64, color: i64) {
+    if (0..FRAME_WIDTH).contains(&x) && (0..FRAME_HEIGHT).contains(&y) {
+        host.pixels.push(((y * FRAME_WIDTH + x) as usize, color as u16 & 0x7FFF));
+    }
+}
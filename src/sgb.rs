@@ -0,0 +1,318 @@
+// Super Game Boy: the SGB BIOS on a LoROM board plus the ICD2 chip bridging it to a Game
+// Boy. The Game Boy itself is left to a core implementing GameBoy, which also owns the GB
+// cartridge. Borders, palettes and the picture itself are drawn by the BIOS from what the
+// ICD2 hands over, so nothing is composited here
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use crate::cartridge::{LoRom, Mapper, RomData};
+use crate::coprocessor::ClockDomain;
+use crate::error::SnesError;
+
+// Read from $600F
+const ICD2_VERSION: u8 = 0x21;
+// Packets the BIOS hasn't picked up yet are dropped beyond this
+const PACKET_QUEUE_SIZE: usize = 64;
+// 20 tiles of 8x8 2bpp per character row, the rest of each buffer is unused
+const ROW_BUFFER_SIZE: usize = 512;
+
+pub trait GameBoy: Send {
+    // Power cycle the Game Boy, keeping its cartridge
+    fn reset(&mut self);
+    // Run for about `clocks` cycles of the 4MHz GB clock, reporting the LCD and joypad
+    // through `io`. Overshoot is fine, the core carries it over
+    fn run(&mut self, clocks: u32, io: &mut dyn GameBoyIo);
+}
+
+// The ICD2 side of the Game Boy's LCD and joypad lines
+pub trait GameBoyIo {
+    // A finished LCD line. `ly` is 0-143, shades go from 0 (lightest) to 3
+    fn lcd_line(&mut self, ly: u8, pixels: &[u8; 160]);
+    // The GB wrote P14 (bit 4) and P15 (bit 5) of P1 ($FF00). Command packets arrive this way
+    fn joyp_write(&mut self, p14: bool, p15: bool);
+    // What P1 bits 0-3 read, active low
+    fn joyp_read(&mut self) -> u8;
+}
+
+pub struct SuperGameBoy {
+    bios: LoRom,
+    gameboy: Box<dyn GameBoy>,
+    icd2: Icd2,
+    country: u8,
+
+    // $6003: bit 7 runs the GB, bits 5-4 are the number of pads, bits 1-0 the clock divider
+    control: u8,
+    clock: ClockDomain,
+}
+
+impl SuperGameBoy {
+    pub fn new(bios: RomData, gameboy: Box<dyn GameBoy>) -> Result<SuperGameBoy, SnesError> {
+        let (bios, country) = LoRom::without_sram(bios)?;
+        Ok(SuperGameBoy {
+            bios,
+            gameboy,
+            icd2: Icd2::default(),
+            country,
+            control: 0,
+            clock: ClockDomain::new(1, 4, 0),
+        })
+    }
+
+    // Header country code of the BIOS, for Snes::with_mapper
+    pub fn country(&self) -> u8 {
+        self.country
+    }
+
+    fn read_icd2(&mut self, offset: u16) -> Option<u8> {
+        let icd2 = &mut self.icd2;
+        match offset {
+            // Current character row being written and its buffer
+            0x6000 => Some(icd2.ly & !7 | icd2.write_buffer),
+            0x6002 => match icd2.packets.pop_front() {
+                Some(packet) => {
+                    icd2.packet = packet;
+                    Some(1)
+                }
+                None => Some(0),
+            },
+            0x600F => Some(ICD2_VERSION),
+            0x7000..=0x700F => Some(icd2.packet[offset as usize & 0xF]),
+            0x7800 => {
+                let data = icd2.rows[icd2.read_buffer * ROW_BUFFER_SIZE + icd2.read_addr];
+                icd2.read_addr = (icd2.read_addr + 1).min(ROW_BUFFER_SIZE - 1);
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    fn write_icd2(&mut self, offset: u16, data: u8) {
+        match offset {
+            0x6001 => {
+                self.icd2.read_buffer = (data & 3) as usize;
+                self.icd2.read_addr = 0;
+            }
+            0x6003 => {
+                // Leaving reset starts the GB from power on
+                if self.control & 0x80 == 0 && data & 0x80 != 0 {
+                    self.gameboy.reset();
+                    self.icd2.reset();
+                }
+                self.icd2.pads = match data >> 4 & 3 {
+                    0 => 1,
+                    1 => 2,
+                    _ => 4,
+                };
+                self.control = data;
+                self.clock.set_ratio(1, [4, 5, 7, 9][(data & 3) as usize]);
+            }
+            0x6004..=0x6007 => self.icd2.joypads[(offset - 0x6004) as usize] = data,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for SuperGameBoy {
+    fn read(&mut self, addr: u32) -> Option<u8> {
+        if self.is_shared(addr) {
+            return self.read_icd2(addr as u16);
+        }
+        self.bios.read(addr)
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        if self.is_shared(addr) {
+            return self.write_icd2(addr as u16, data);
+        }
+        self.bios.write(addr, data)
+    }
+
+    fn backup(&self) -> &[u8] {
+        &[]
+    }
+
+    fn backup_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    fn tick(&mut self, now: u64) {
+        if self.control & 0x80 == 0 {
+            return self.clock.idle(now);
+        }
+        let clocks = self.clock.advance(now);
+        if clocks != 0 {
+            self.gameboy.run(clocks as u32, &mut self.icd2);
+        }
+    }
+
+    // The ICD2 reflects what the GB has done so far
+    fn is_shared(&self, addr: u32) -> bool {
+        addr >> 16 & 0x40 == 0 && (0x6000..=0x7FFF).contains(&(addr as u16))
+    }
+
+    // The GB is held in reset until the BIOS starts it again through $6003
+    fn power_cycle(&mut self) {
+        self.icd2 = Icd2::default();
+        self.control = 0;
+        self.clock.set_ratio(1, 4);
+    }
+}
+
+struct Icd2 {
+    // LCD lines as 2bpp tiles, one buffer per character row. The BIOS reads the row
+    // before the one being written
+    rows: Box<[u8; 4 * ROW_BUFFER_SIZE]>,
+    ly: u8,
+    write_buffer: u8,
+    read_buffer: usize,
+    read_addr: usize,
+
+    joypads: [u8; 4],
+    pads: u8,
+    pad: u8,
+    p14: bool,
+    p15: bool,
+    // Both lines went high since the pad index last advanced
+    next_pad_armed: bool,
+
+    // Packets are sent one bit per P14/P15 pulse, LSB first, after a reset pulse
+    receiving: bool,
+    bit_taken: bool,
+    bits: u8,
+    bit_count: u8,
+    received: [u8; 16],
+    byte_count: usize,
+    // Waiting for the stop bit after 16 bytes
+    stop_bit: bool,
+    packets: VecDeque<[u8; 16]>,
+    packet: [u8; 16],
+}
+
+impl Default for Icd2 {
+    fn default() -> Self {
+        Icd2 {
+            rows: Box::new([0; 4 * ROW_BUFFER_SIZE]),
+            ly: 0,
+            write_buffer: 0,
+            read_buffer: 0,
+            read_addr: 0,
+            joypads: [0xFF; 4],
+            pads: 1,
+            pad: 0,
+            p14: true,
+            p15: true,
+            next_pad_armed: false,
+            receiving: false,
+            bit_taken: false,
+            bits: 0,
+            bit_count: 0,
+            received: [0; 16],
+            byte_count: 0,
+            stop_bit: false,
+            packets: VecDeque::new(),
+            packet: [0; 16],
+        }
+    }
+}
+
+impl Icd2 {
+    // GB side only, what the BIOS configured stays
+    fn reset(&mut self) {
+        *self = Icd2 {
+            joypads: self.joypads,
+            pads: self.pads,
+            ..Icd2::default()
+        };
+    }
+
+    fn receive_bit(&mut self, bit: bool) {
+        if self.stop_bit {
+            // The stop bit is a 0, anything else drops the packet
+            if !bit && self.packets.len() < PACKET_QUEUE_SIZE {
+                self.packets.push_back(self.received);
+            }
+            self.receiving = false;
+            return;
+        }
+        self.bits = self.bits >> 1 | (bit as u8) << 7;
+        self.bit_count += 1;
+        if self.bit_count < 8 {
+            return;
+        }
+        self.received[self.byte_count] = self.bits;
+        self.bit_count = 0;
+        self.byte_count += 1;
+        self.stop_bit = self.byte_count == self.received.len();
+    }
+}
+
+impl GameBoyIo for Icd2 {
+    fn lcd_line(&mut self, ly: u8, pixels: &[u8; 160]) {
+        if ly >= 144 {
+            return;
+        }
+        self.ly = ly;
+        let base = self.write_buffer as usize * ROW_BUFFER_SIZE + (ly as usize & 7) * 2;
+        for (tile, pixels) in pixels.chunks_exact(8).enumerate() {
+            let (mut low, mut high) = (0, 0);
+            for &shade in pixels {
+                low = low << 1 | (shade & 1);
+                high = high << 1 | (shade >> 1 & 1);
+            }
+            self.rows[base + tile * 16] = low;
+            self.rows[base + tile * 16 + 1] = high;
+        }
+        if ly & 7 == 7 {
+            self.write_buffer = (self.write_buffer + 1) & 3;
+        }
+    }
+
+    fn joyp_write(&mut self, p14: bool, p15: bool) {
+        self.p14 = p14;
+        self.p15 = p15;
+
+        match (p14, p15) {
+            // Reset pulse starting a packet
+            (false, false) => {
+                self.receiving = true;
+                self.bit_taken = true;
+                self.bits = 0;
+                self.bit_count = 0;
+                self.byte_count = 0;
+                self.stop_bit = false;
+            }
+            (true, true) => {
+                self.bit_taken = false;
+                // With multiple pads, releasing both lines selects the next one
+                if self.next_pad_armed {
+                    self.next_pad_armed = false;
+                    self.pad = (self.pad + 1) % self.pads;
+                }
+            }
+            // P14 low sends a 0, P15 low a 1
+            (false, true) | (true, false) => {
+                self.next_pad_armed = true;
+                if self.receiving && !self.bit_taken {
+                    self.bit_taken = true;
+                    self.receive_bit(!p15);
+                }
+            }
+        }
+    }
+
+    fn joyp_read(&mut self) -> u8 {
+        let joypad = self.joypads[self.pad as usize];
+        let mut input = 0xF;
+        if !self.p14 {
+            input &= joypad & 0xF;
+        }
+        if !self.p15 {
+            input &= joypad >> 4;
+        }
+        // With both lines high the low bits tell which pad is selected
+        if self.p14 && self.p15 {
+            input -= self.pad;
+        }
+        input
+    }
+}
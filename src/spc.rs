@@ -1,13 +1,24 @@
-use log::debug;
+use log::{debug, warn};
 use modular_bitfield::bitfield;
 
-use crate::context;
-use crate::dsp;
+use crate::dsp::{self, AudioOutputMode};
+use crate::power_on::PowerOnState;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
-trait Context: context::Timing {}
-impl<T: context::Timing> Context for T {}
+// Receives samples as the DSP generates them. `timestamp` is in master clock cycles
+pub trait AudioSink {
+    fn push_sample(&mut self, timestamp: u64, left: i16, right: i16);
+}
+
+impl<F: FnMut(u64, i16, i16)> AudioSink for F {
+    fn push_sample(&mut self, timestamp: u64, left: i16, right: i16) {
+        self(timestamp, left, right)
+    }
+}
 
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Spc {
     registers: Registers,
     pub io_registers: IORegisters,
@@ -15,13 +26,47 @@ pub struct Spc {
     prev_counter: u64,
     dsp_counter: u64,
 
-    sleep: bool,
-    stop: bool,
+    state: SpcState,
+    // Extra cycles of a taken conditional branch in the current instruction
+    branch_cycles: u64,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    audio_buffer: Vec<(i16, i16)>,
+    // SPC700 clock of audio_buffer[0]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    audio_buffer_clock: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    audio_sink: Option<Box<dyn AudioSink + Send>>,
 
-    // for debug
     instruction_counter: u64,
+
+    // Opt-in, see set_fast_boot
+    #[cfg_attr(feature = "serde", serde(skip))]
+    fast_boot: bool,
+    // Replaces ROM, see set_ipl_rom
+    #[cfg_attr(feature = "serde", serde(skip))]
+    ipl_rom: Option<Box<[u8; 0x40]>>,
+    ipl: Option<IplHle>,
+    // SPC700 clock of a port 0 write the emulated IPL hasn't looked at yet
+    ipl_port0_pending: Option<u64>,
 }
 
+// Position in the IPL upload protocol while it is emulated at a high level
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum IplHle {
+    // Waiting for $CC on port 0
+    WaitKick,
+    // Waiting for the first byte of a block, sent with index 0
+    WaitFirst { addr: u16 },
+    Transfer { addr: u16, index: u8 },
+}
+
+const IPL_ENTRY: u16 = 0xFFC0;
+// The IPL reads port 1 about this many cycles after it sees port 0 change, long after the
+// second byte of a 16 bit STA $2140
+const IPL_POLL_CYCLES: u64 = 5;
+
 const ROM: [u8; 0x40] = [
     0xCD, 0xEF, 0xBD, 0xE8, 0x00, 0xC6, 0x1D, 0xD0, 0xFC, 0x8F, 0xAA, 0xF4, 0x8F, 0xBB, 0xF5, 0x78,
     0xCC, 0xF4, 0xD0, 0xFB, 0x2F, 0x19, 0xEB, 0xF4, 0xD0, 0xFC, 0x7E, 0xF4, 0xD0, 0x0B, 0xE4, 0xF5,
@@ -29,11 +74,159 @@ const ROM: [u8; 0x40] = [
     0xF6, 0xDA, 0x00, 0xBA, 0xF4, 0xC4, 0xF4, 0xDD, 0x5D, 0xD0, 0xDB, 0x1F, 0x00, 0x00, 0xC0, 0xFF,
 ];
 
+fn master_clock(spc_clock: u64) -> u64 {
+    spc_clock * 2147727 / 102400
+}
+
+// Master clock of the `index`th sample after the one made at SPC700 clock `first`
+pub(crate) fn sample_timestamp(first: u64, index: usize) -> u64 {
+    master_clock(first + index as u64 * 32)
+}
+
+// Register file for debuggers and the single instruction tests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub psw: u8,
+    pub pc: u16,
+}
+
+impl Spc {
+    pub fn registers(&self) -> RegisterSnapshot {
+        RegisterSnapshot {
+            a: self.registers.a,
+            x: self.registers.x,
+            y: self.registers.y,
+            sp: self.registers.sp,
+            psw: self.registers.psw.into_bytes()[0],
+            pc: self.registers.pc,
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: &RegisterSnapshot) {
+        self.registers.a = registers.a;
+        self.registers.x = registers.x;
+        self.registers.y = registers.y;
+        self.registers.sp = registers.sp;
+        self.registers.psw = Psw::from_bytes([registers.psw]);
+        self.registers.pc = registers.pc;
+    }
+
+    #[cfg(feature = "opcode-tests")]
+    // The whole 64KB of RAM, with the IPL ROM unmapped. $F0-$FF stay I/O registers
+    pub fn flat_ram(&mut self) -> &mut [u8] {
+        self.io_registers.is_rom_read_enabled = false;
+        &mut self.io_registers.dsp.ram
+    }
+
+    #[cfg(feature = "opcode-tests")]
+    // Execute one instruction and return the cycles it took
+    pub fn step(&mut self) -> u64 {
+        let start = self.counter;
+        self.execute_instruction();
+        self.counter - start
+    }
+}
+
+// SLEEP and STOP halt the SPC700 until reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SpcState {
+    #[default]
+    Running,
+    Sleeping,
+    Stopped,
+}
+
+// Cycles per opcode, without the +2 of taken conditional branches
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    2, 8, 4, 5, 3, 4, 3, 6, 2, 6, 5, 4, 5, 4, 6, 8, // 0
+    2, 8, 4, 5, 4, 5, 5, 6, 5, 5, 6, 5, 2, 2, 4, 6, // 1
+    2, 8, 4, 5, 3, 4, 3, 6, 2, 6, 5, 4, 5, 4, 5, 4, // 2
+    2, 8, 4, 5, 4, 5, 5, 6, 5, 5, 6, 5, 2, 2, 3, 8, // 3
+    2, 8, 4, 5, 3, 4, 3, 6, 2, 6, 4, 4, 5, 4, 6, 6, // 4
+    2, 8, 4, 5, 4, 5, 5, 6, 5, 5, 4, 5, 2, 2, 4, 3, // 5
+    2, 8, 4, 5, 3, 4, 3, 6, 2, 6, 4, 4, 5, 4, 5, 5, // 6
+    2, 8, 4, 5, 4, 5, 5, 6, 5, 5, 5, 5, 2, 2, 3, 6, // 7
+    2, 8, 4, 5, 3, 4, 3, 6, 2, 6, 5, 4, 5, 2, 4, 5, // 8
+    2, 8, 4, 5, 4, 5, 5, 6, 5, 5, 5, 5, 2, 2,12, 5, // 9
+    3, 8, 4, 5, 3, 4, 3, 6, 2, 6, 4, 4, 5, 2, 4, 4, // A
+    2, 8, 4, 5, 4, 5, 5, 6, 5, 5, 5, 5, 2, 2, 3, 4, // B
+    3, 8, 4, 5, 4, 5, 4, 7, 2, 5, 6, 4, 5, 2, 4, 9, // C
+    2, 8, 4, 5, 5, 6, 6, 7, 4, 5, 5, 5, 2, 2, 6, 3, // D
+    2, 8, 4, 5, 3, 4, 3, 6, 2, 4, 5, 3, 4, 3, 4, 3, // E
+    2, 8, 4, 5, 4, 5, 5, 6, 3, 4, 5, 4, 2, 2, 4, 3, // F
+];
+
 impl Spc {
-    pub fn tick(&mut self, ctx: &mut impl Context) {
-        let clock_from_master = ctx.now() * 102400 / 2147727;
+    pub fn init_ram(&mut self, power_on: &PowerOnState) {
+        power_on.fill(&mut self.io_registers.dsp.ram, 2);
+    }
+
+    // Reset line: the SPC700 restarts in the IPL ROM and the DSP comes up muted with echo
+    // writes off, like at power on. Audio RAM stays
+    pub fn reset(&mut self) {
+        self.registers = Registers {
+            pc: self.reset_vector(),
+            ..Default::default()
+        };
+        let dsp = core::mem::take(&mut self.io_registers.dsp);
+        self.io_registers = IORegisters {
+            dsp,
+            ..Default::default()
+        };
+        self.io_registers.dsp.write(0x6C, 0xE0);
+        self.state = SpcState::Running;
+        self.branch_cycles = 0;
+        self.ipl = None;
+        self.ipl_port0_pending = None;
+    }
+
+    // Starts over at master clock `now`, keeping the frontend settings
+    pub fn power_cycle(&mut self, power_on: &PowerOnState, now: u64) {
+        let mut fresh: Box<Spc> = Box::default();
+        fresh.swap_host_state(self);
+        fresh.registers.pc = fresh.reset_vector();
+        fresh.counter = now * 102400 / 2147727;
+        fresh.prev_counter = fresh.counter;
+        fresh.init_ram(power_on);
+        core::mem::swap(self, &mut fresh);
+    }
+
+    // Run up to master clock `now`
+    pub(crate) fn tick(&mut self, now: u64) {
+        let clock_from_master = now * 102400 / 2147727;
+
+        if let Some(at) = self.ipl_port0_pending {
+            if clock_from_master >= at + IPL_POLL_CYCLES {
+                self.ipl_port0_pending = None;
+                self.ipl_port0_written(self.io_registers.cpu_in[0]);
+            }
+        }
 
         while self.counter < clock_from_master {
+            if self.state != SpcState::Running {
+                // Halted until reset. Timers and the DSP keep running
+                self.counter = clock_from_master;
+                break;
+            }
+            if self.fast_boot
+                && self.ipl_rom.is_none()
+                && self.registers.pc == IPL_ENTRY
+                && self.ipl.is_none()
+            {
+                self.enter_ipl();
+            }
+            if self.ipl.is_some() {
+                // Nothing to execute until the CPU starts the uploaded code
+                self.counter = clock_from_master;
+                break;
+            }
             self.execute_instruction();
         }
 
@@ -44,31 +237,213 @@ impl Spc {
         self.dsp_counter += elapsed;
         while self.dsp_counter >= 32 {
             self.dsp_counter -= 32;
-            self.io_registers.dsp.tick();
+            let (left, right) = self.io_registers.dsp.tick();
+            let clock = self.counter - self.dsp_counter;
+            match &mut self.audio_sink {
+                Some(sink) => sink.push_sample(master_clock(clock), left, right),
+                None => {
+                    if self.audio_buffer.is_empty() {
+                        self.audio_buffer_clock = clock;
+                    }
+                    self.audio_buffer.push((left, right));
+                }
+            }
         }
     }
 
     pub fn audio_buffer(&self) -> &[(i16, i16)] {
-        self.io_registers.dsp.get_audio_buffer()
+        &self.audio_buffer
     }
 
     pub fn clear_audio_buffer(&mut self) {
-        self.io_registers.dsp.clear_audio_buffer();
+        self.audio_buffer.clear();
+    }
+
+    // Master clock at which the DSP made audio_buffer()[index]
+    pub fn audio_timestamp(&self, index: usize) -> Option<u64> {
+        (index < self.audio_buffer.len()).then(|| sample_timestamp(self.audio_buffer_clock, index))
+    }
+
+    // For the APU thread
+    #[cfg(feature = "std")]
+    pub(crate) fn audio_buffer_clock(&self) -> u64 {
+        self.audio_buffer_clock
+    }
+
+    // Exchange what a savestate doesn't contain
+    pub fn swap_host_state(&mut self, other: &mut Spc) {
+        core::mem::swap(&mut self.audio_sink, &mut other.audio_sink);
+        core::mem::swap(&mut self.fast_boot, &mut other.fast_boot);
+        core::mem::swap(&mut self.ipl_rom, &mut other.ipl_rom);
+        core::mem::swap(&mut self.io_registers.dsp.output, &mut other.io_registers.dsp.output);
+    }
+
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink + Send>>) {
+        self.audio_sink = sink;
+    }
+
+    pub fn set_audio_output_mode(&mut self, mode: AudioOutputMode) {
+        self.io_registers.dsp.output.mode = mode;
+    }
+
+    pub fn set_volume(&mut self, volume: u16) {
+        self.io_registers.dsp.output.volume = volume;
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.io_registers.dsp.output.muted = muted;
+    }
+
+    pub fn state(&self) -> SpcState {
+        self.state
+    }
+
+    pub fn dsp_registers(&self) -> [u8; 0x80] {
+        self.io_registers.dsp.registers()
+    }
+
+    // The 64KB of audio RAM as the DSP sees it, without the IPL ROM and the I/O registers
+    pub fn ram(&self) -> &[u8] {
+        &self.io_registers.dsp.ram
+    }
+
+    // For tooling. Goes straight to RAM, so writes to $F0-$FF don't reach the I/O registers
+    pub fn write_ram(&mut self, addr: u16, data: u8) {
+        self.io_registers.dsp.ram[addr as usize] = data;
+    }
+
+    pub fn write_dsp_register(&mut self, addr: u8, data: u8) {
+        self.io_registers.dsp.write(addr, data);
+    }
+
+    #[cfg(feature = "bench")]
+    pub fn dsp_mut(&mut self) -> &mut dsp::Dsp {
+        &mut self.io_registers.dsp
+    }
+
+    // Total instructions executed since power on
+    pub fn instructions(&self) -> u64 {
+        self.instruction_counter
     }
 
     pub fn write_port(&mut self, port: u16, data: u8) {
         self.io_registers.cpu_in[port as usize] = data;
+        if port == 0 && self.ipl.is_some() {
+            self.ipl_port0_pending = Some(self.counter);
+        }
+    }
+
+    // Boot from `rom` instead of the built-in IPL, None restores it. Takes effect at the next
+    // reset, or right away if the SPC700 hasn't executed anything yet. Fast boot only
+    // knows the built-in IPL, so it is off while a custom one is set
+    pub fn set_ipl_rom(&mut self, rom: Option<[u8; 0x40]>) {
+        self.ipl_rom = rom.map(Box::new);
+        if self.instruction_counter == 0 {
+            self.registers.pc = self.reset_vector();
+        }
+    }
+
+    // The 64 bytes at $FFC0-$FFFF while mapped in
+    pub fn ipl_rom(&self) -> &[u8; 0x40] {
+        self.ipl_rom.as_deref().unwrap_or(&ROM)
+    }
+
+    // $F1 bit 7. While clear, $FFC0-$FFFF reads the RAM beneath the IPL ROM. Writes always
+    // go to that RAM
+    pub fn is_ipl_rom_mapped(&self) -> bool {
+        self.io_registers.is_rom_read_enabled
+    }
+
+    fn reset_vector(&self) -> u16 {
+        let rom = self.ipl_rom();
+        u16::from_le_bytes([rom[0x3E], rom[0x3F]])
+    }
+
+    // Answer the IPL upload handshake instantly instead of running the IPL ROM. Takes effect
+    // the next time the SPC700 enters the IPL, which is at power on if set before the first frame
+    pub fn set_fast_boot(&mut self, fast_boot: bool) {
+        self.fast_boot = fast_boot;
+    }
+
+    // Same result as the IPL's initialization loop
+    fn enter_ipl(&mut self) {
+        if !self.io_registers.is_rom_read_enabled {
+            return;
+        }
+        self.registers.sp = 0xEF;
+        self.registers.a = 0;
+        self.registers.x = 0;
+        self.registers.psw.set_z(true);
+        self.registers.psw.set_n(false);
+        self.io_registers.dsp.ram[0x01..=0xEF].fill(0);
+        self.io_registers.cpu_out[0] = 0xAA;
+        self.io_registers.cpu_out[1] = 0xBB;
+        self.ipl = Some(IplHle::WaitKick);
+    }
+
+    fn ipl_port0_written(&mut self, data: u8) {
+        let ports = self.io_registers.cpu_in;
+        let next = match self.ipl {
+            Some(IplHle::WaitKick) if data == 0xCC => self.ipl_command(data),
+            Some(IplHle::WaitFirst { addr }) if data == 0 => self.ipl_store(addr, 0),
+            Some(IplHle::Transfer { addr, index }) if data == index => self.ipl_store(addr, index),
+            // The IPL treats a port 0 value ahead of the byte counter as a new command
+            Some(IplHle::Transfer { index, .. }) if (data.wrapping_sub(index) as i8) > 0 => {
+                self.ipl_command(data)
+            }
+            ipl => ipl,
+        };
+        self.ipl = next;
+        debug!("IPL HLE: {:?} ports {:02X?}", self.ipl, ports);
+    }
+
+    // Port 2-3 hold the address, port 1 is zero to jump there or nonzero to upload a block
+    fn ipl_command(&mut self, data: u8) -> Option<IplHle> {
+        let ports = self.io_registers.cpu_in;
+        let addr = u16::from_le_bytes([ports[2], ports[3]]);
+        self.io_registers.dsp.ram[0x00] = ports[2];
+        self.io_registers.dsp.ram[0x01] = ports[3];
+        self.io_registers.cpu_out[0] = data;
+        if ports[1] != 0 {
+            return Some(IplHle::WaitFirst { addr });
+        }
+
+        // Registers as left by the IPL's final `jmp [!$0000+x]`
+        self.registers.a = 0;
+        self.registers.x = 0;
+        self.registers.y = 0;
+        self.registers.psw.set_z(true);
+        self.registers.psw.set_n(false);
+        self.registers.pc = addr;
+        None
+    }
+
+    fn ipl_store(&mut self, addr: u16, index: u8) -> Option<IplHle> {
+        let data = self.io_registers.cpu_in[1];
+        self.io_registers.cpu_out[0] = index;
+        self.write_8(
+            WrapAddr {
+                addr,
+                wrap_mode: WrapMode::NoWrap,
+            },
+            data,
+        );
+        Some(IplHle::Transfer {
+            addr: addr.wrapping_add(1),
+            index: index.wrapping_add(1),
+        })
     }
 
     pub fn read_port(&mut self, port: u16) -> u8 {
         self.io_registers.cpu_out[port as usize]
     }
 
-    fn increment_counter(&mut self, count: u64) {
-        self.counter += count;
+    fn branch_taken(&mut self) {
+        self.branch_cycles += 2;
     }
 
     fn execute_instruction(&mut self) {
+        let start = self.counter;
         let pc = self.registers.pc;
         let op = self.fetch_8();
         match op {
@@ -344,6 +719,14 @@ impl Spc {
             0xFE => self.dbnz_y(),
             0xFF => self.stop(),
         }
+
+        // TODO Waitstates from the TEST register ($F0) apply per access, approximated here
+        let waitstate = self
+            .io_registers
+            .waitstate_on_ram_access
+            .max(self.io_registers.waitstate_on_io_and_rom_access);
+        self.counter = start + (CYCLES[op as usize] as u64 + self.branch_cycles) * waitstate;
+        self.branch_cycles = 0;
         // debug!(
         //     "SPC: Councter: {}, PC:{:04X} op: {:02X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{}{}{}{}{}{}{}{} CYC:{}",
         //     self.instruction_counter,
@@ -370,19 +753,15 @@ impl Spc {
         let addr = addr.addr;
         let data = match addr {
             0x0000..=0x00EF | 0x0100..=0xFFBF => {
-                self.counter += self.io_registers.waitstate_on_ram_access;
                 self.io_registers.dsp.ram[addr as usize]
             }
             0x00F0..=0x00FF => {
-                self.counter += self.io_registers.waitstate_on_io_and_rom_access;
                 self.io_registers.read((addr - 0xF0) as u8)
             }
             0xFFC0..=0xFFFF => {
                 if self.io_registers.is_rom_read_enabled {
-                    self.counter += self.io_registers.waitstate_on_io_and_rom_access;
-                    ROM[(addr - 0xFFC0) as usize]
+                    self.ipl_rom()[(addr - 0xFFC0) as usize]
                 } else {
-                    self.counter += self.io_registers.waitstate_on_ram_access;
                     self.io_registers.dsp.ram[addr as usize]
                 }
             }
@@ -400,9 +779,7 @@ impl Spc {
         }
         if addr & 0xFFF0 == 0x00F0 {
             self.io_registers.write((addr & 0xF) as u8, data);
-            self.counter += self.io_registers.waitstate_on_io_and_rom_access;
         } else {
-            self.counter += self.io_registers.waitstate_on_ram_access;
         }
     }
 
@@ -504,7 +881,6 @@ impl Spc {
     fn lda(&mut self, mode: AddressingMode) {
         let addr = self.get_warp_address(mode);
         self.registers.a = self.read_8(addr);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.set_nz(self.registers.a);
     }
 
@@ -521,103 +897,78 @@ impl Spc {
     }
 
     fn sta(&mut self, mode: AddressingMode) {
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let addr = self.get_warp_address(mode);
         self.write_8(addr, self.registers.a);
     }
 
     fn stx(&mut self, mode: AddressingMode) {
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let addr = self.get_warp_address(mode);
         self.write_8(addr, self.registers.x);
     }
 
     fn sty(&mut self, mode: AddressingMode) {
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let addr = self.get_warp_address(mode);
         self.write_8(addr, self.registers.y);
     }
 
     fn txa(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.a = self.registers.x;
         self.set_nz(self.registers.a);
     }
 
     fn tya(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.a = self.registers.y;
         self.set_nz(self.registers.a);
     }
 
     fn tax(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.x = self.registers.a;
         self.set_nz(self.registers.x);
     }
 
     fn tay(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.y = self.registers.a;
         self.set_nz(self.registers.y);
     }
 
     fn tsx(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.x = self.registers.sp;
         self.set_nz(self.registers.x);
     }
 
     fn txs(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.sp = self.registers.x;
     }
 
     fn pha(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.push_8(self.registers.a);
     }
 
     fn phx(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.push_8(self.registers.x);
     }
 
     fn phy(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.push_8(self.registers.y);
     }
 
     fn php(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.push_8(self.registers.psw.into());
     }
 
     fn pla(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.a = self.pop_8();
     }
 
     fn plx(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.x = self.pop_8();
     }
 
     fn ply(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.y = self.pop_8();
     }
 
     fn plp(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.psw = self.pop_8().into();
     }
 
@@ -634,8 +985,6 @@ impl Spc {
             self.registers.psw.set_c(true);
         }
         self.set_nz(self.registers.a);
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
     }
 
     fn das(&mut self) {
@@ -648,19 +997,16 @@ impl Spc {
             self.registers.psw.set_c(false);
         }
         self.set_nz(self.registers.a);
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
     }
 
     fn bb_sc(&mut self, bit: u8, is_set: bool) {
         let addr = self.get_warp_address(AddressingMode::DirectPage);
         let v = self.read_8(addr);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let offset = self.fetch_8() as i8 as u16;
         let dest = self.registers.pc.wrapping_add(offset);
         if (v & (1 << bit) != 0) == is_set {
             self.registers.pc = dest;
-            self.increment_counter(self.io_registers.waitstate_on_ram_access * 2);
+            self.branch_taken();
         }
     }
 
@@ -800,7 +1146,6 @@ impl Spc {
     fn mov_dp_imm(&mut self) {
         let val = self.fetch_8();
         let addr = self.get_warp_address(AddressingMode::DirectPage);
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.write_8(addr, val);
     }
 
@@ -821,7 +1166,6 @@ impl Spc {
     }
 
     fn asl_a(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         let c = self.registers.a & 0x80 != 0;
         self.registers.a <<= 1;
         self.registers.psw.set_c(c);
@@ -842,7 +1186,6 @@ impl Spc {
         let val = self.registers.a << 1 | self.registers.psw.c() as u8;
         self.registers.psw.set_c(self.registers.a & 0x80 != 0);
         self.set_nz(val);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.a = val;
     }
 
@@ -876,7 +1219,6 @@ impl Spc {
         let val = (self.registers.a >> 1) | ((self.registers.psw.c() as u8) << 7);
         self.registers.psw.set_c(self.registers.a & 0x01 != 0);
         self.set_nz(val);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.a = val;
     }
 
@@ -897,7 +1239,6 @@ impl Spc {
             _ => unreachable!("dec_reg, reg: {:?}", reg),
         };
         val = val.wrapping_sub(1);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.set_nz(val);
         match reg {
             Register::A => self.registers.a = val,
@@ -922,7 +1263,6 @@ impl Spc {
             _ => unreachable!("inc_reg, reg: {:?}", reg),
         };
         val = val.wrapping_add(1);
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.set_nz(val);
         match reg {
             Register::A => self.registers.a = val,
@@ -941,7 +1281,6 @@ impl Spc {
 
     fn movw_ya_dp(&mut self) {
         let addr = self.get_warp_address(AddressingMode::DirectPage);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let val = self.read_16(addr);
         self.set_nz16(val);
         self.set_ya(val);
@@ -949,7 +1288,6 @@ impl Spc {
 
     fn movw_dp_ya(&mut self) {
         let addr = self.get_warp_address(AddressingMode::DirectPage);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let ya = self.get_ya();
         self.write_16(addr, ya);
     }
@@ -957,7 +1295,6 @@ impl Spc {
     fn addw(&mut self) {
         let addr = self.get_warp_address(AddressingMode::DirectPage);
         let operand = self.read_16(addr) as u32;
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let ya = self.get_ya() as u32;
         let v = ya.wrapping_add(operand);
         self.registers.psw.set_c(v > 0xFFFF);
@@ -973,7 +1310,6 @@ impl Spc {
     fn subw(&mut self) {
         let addr = self.get_warp_address(AddressingMode::DirectPage);
         let operand = self.read_16(addr) as u32;
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let ya = self.get_ya() as u32;
         let v = ya.wrapping_sub(operand);
         self.registers.psw.set_c(!(v > 0xFFFF));
@@ -1021,8 +1357,6 @@ impl Spc {
     }
 
     fn div(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 10);
         let ya = self.get_ya();
         let x = u16::from(self.registers.x);
         if x > 0 {
@@ -1042,8 +1376,6 @@ impl Spc {
     }
 
     fn mul(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 7);
         let val = (self.registers.a as u16) * (self.registers.y as u16);
         self.set_ya(val);
         self.set_nz(self.registers.y);
@@ -1051,7 +1383,6 @@ impl Spc {
 
     fn set_n_bit(&mut self, bit: u8) {
         let addr = self.get_warp_address(AddressingMode::DirectPage);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let val = self.read_8(addr) | (1 << bit);
         self.write_8(addr, val);
     }
@@ -1085,7 +1416,6 @@ impl Spc {
         };
         let operand = self.read_8(addr);
         let val = operand & !(1 << b) | (self.registers.psw.c() as u8) << b;
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.write_8(addr, val);
     }
 
@@ -1110,7 +1440,6 @@ impl Spc {
             wrap_mode: WrapMode::NoWrap,
         };
         let operand = self.read_8(addr);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers
             .psw
             .set_c(self.registers.psw.c() || operand & (1 << b) != 0);
@@ -1124,7 +1453,6 @@ impl Spc {
             addr: aaa,
             wrap_mode: WrapMode::NoWrap,
         };
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let operand = self.read_8(addr);
         self.registers
             .psw
@@ -1168,38 +1496,29 @@ impl Spc {
             wrap_mode: WrapMode::NoWrap,
         };
         let operand = self.read_8(addr);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers
             .psw
             .set_c(self.registers.psw.c() ^ (operand & (1 << b) != 0));
     }
 
     fn clr_c(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.psw.set_c(false);
     }
 
     fn set_c(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.psw.set_c(true);
     }
 
     fn notc(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.psw.set_c(!self.registers.psw.c());
     }
 
     fn clr_hv(&mut self) {
         self.registers.psw.set_h(false);
         self.registers.psw.set_v(false);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
     }
 
     fn xcn(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 3);
         self.registers.a = self.registers.a.rotate_right(4);
         self.set_nz(self.registers.a);
     }
@@ -1207,7 +1526,6 @@ impl Spc {
     fn tclr(&mut self) {
         let addr = self.get_warp_address(AddressingMode::Absolute);
         let val = self.read_8(addr);
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.set_nz(self.registers.a.wrapping_sub(val));
         self.write_8(addr, val & !self.registers.a);
     }
@@ -1221,8 +1539,12 @@ impl Spc {
 
     fn br(&mut self, branch_type: BranchType) {
         let offset = self.fetch_8() as i8 as u16;
+        // BRA is always taken, and the table already counts it
+        let is_bra = matches!(branch_type, BranchType::Bra);
         if self.check_branch_condition(branch_type) {
-            self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 2);
+            if !is_bra {
+                self.branch_taken();
+            }
             self.registers.pc = self.registers.pc.wrapping_add(offset);
         }
     }
@@ -1243,22 +1565,19 @@ impl Spc {
 
     fn cbne(&mut self, addressing_mode: AddressingMode) {
         let addr = self.get_warp_address(addressing_mode);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         let operand = self.read_8(addr);
         let offset = self.fetch_8() as i8 as u16;
         if self.registers.a != operand {
-            self.increment_counter(self.io_registers.waitstate_on_ram_access);
+            self.branch_taken();
             self.registers.pc = self.registers.pc.wrapping_add(offset);
         }
     }
 
     fn dbnz_y(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.y = self.registers.y.wrapping_sub(1);
         let offset = self.fetch_8() as i8 as u16;
         if self.registers.y != 0 {
-            self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 2);
+            self.branch_taken();
             self.registers.pc = self.registers.pc.wrapping_add(offset);
         }
     }
@@ -1269,7 +1588,7 @@ impl Spc {
         self.write_8(addr, val);
         let offset = self.fetch_8() as i8 as u16;
         if val != 0 {
-            self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 2);
+            self.branch_taken();
             self.registers.pc = self.registers.pc.wrapping_add(offset);
         }
     }
@@ -1287,13 +1606,11 @@ impl Spc {
 
     fn call(&mut self) {
         let addr = self.fetch_16();
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 3);
         self.push_16(self.registers.pc);
         self.registers.pc = addr;
     }
 
     fn tcall_n(&mut self, bit: u16) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access * 3);
         self.push_16(self.registers.pc);
         let addr = WrapAddr {
             addr: 0xFFDE - 2 * bit,
@@ -1304,20 +1621,15 @@ impl Spc {
 
     fn pcall(&mut self) {
         let n = self.fetch_8() as u16;
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 2);
         self.push_16(self.registers.pc);
         self.registers.pc = 0xFF00 | n;
     }
 
     fn ret(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.pc = self.pop_16();
     }
 
     fn reti(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.psw = self.pop_8().into();
         self.registers.pc = self.pop_16();
     }
@@ -1331,45 +1643,35 @@ impl Spc {
             addr: 0xFFDE,
             wrap_mode: WrapMode::NoWrap,
         };
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access * 2);
         self.registers.pc = self.read_16(addr);
     }
 
-    fn nop(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-    }
+    fn nop(&mut self) {}
 
     fn sleep(&mut self) {
-        self.counter += self.io_registers.waitstate_on_ram_access;
-        self.sleep = true;
-        panic!("SPC sleep occurred");
+        // Waits for an interrupt, which the SNES never raises
+        self.state = SpcState::Sleeping;
+        warn!("SPC sleep occurred");
     }
 
     fn stop(&mut self) {
-        self.counter += self.io_registers.waitstate_on_ram_access;
-        self.stop = true;
-        panic!("SPC stop occurred");
+        self.state = SpcState::Stopped;
+        warn!("SPC stop occurred");
     }
 
     fn clrp(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.psw.set_p(false);
     }
 
     fn setp(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
         self.registers.psw.set_p(true);
     }
 
     fn ei(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.psw.set_i(true);
     }
 
     fn di(&mut self) {
-        self.increment_counter(self.io_registers.waitstate_on_ram_access);
-        self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
         self.registers.psw.set_i(false);
     }
 
@@ -1393,7 +1695,6 @@ impl Spc {
             AddressingMode::XIndexedDirectPage => {
                 let addr = (self.registers.psw.p() as u16) << 8
                     | u16::from(self.fetch_8().wrapping_add(self.registers.x));
-                self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
                 WrapAddr {
                     addr,
                     wrap_mode: WrapMode::Wrap8bit,
@@ -1409,7 +1710,6 @@ impl Spc {
             }
             AddressingMode::IndirectX => {
                 let addr = (self.registers.psw.p() as u16) << 8 | u16::from(self.registers.x);
-                self.increment_counter(self.io_registers.waitstate_on_ram_access);
                 WrapAddr {
                     addr,
                     wrap_mode: WrapMode::Wrap8bit,
@@ -1417,7 +1717,6 @@ impl Spc {
             }
             AddressingMode::IndirectY => {
                 let addr = (self.registers.psw.p() as u16) << 8 | u16::from(self.registers.y);
-                self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
                 WrapAddr {
                     addr,
                     wrap_mode: WrapMode::Wrap8bit,
@@ -1425,7 +1724,6 @@ impl Spc {
             }
             AddressingMode::IndirectAutoIncrement => {
                 let addr = (self.registers.psw.p() as u16) << 8 | u16::from(self.registers.x);
-                self.increment_counter(self.io_registers.waitstate_on_ram_access);
                 self.registers.x = self.registers.x.wrapping_add(1);
                 WrapAddr {
                     addr,
@@ -1443,7 +1741,6 @@ impl Spc {
             }
             AddressingMode::XIndexedAbsolute => {
                 let addr = self.fetch_16().wrapping_add(u16::from(self.registers.x));
-                self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
                 WrapAddr {
                     addr,
                     wrap_mode: WrapMode::NoWrap,
@@ -1451,7 +1748,6 @@ impl Spc {
             }
             AddressingMode::YIndexedAbsolute => {
                 let addr = self.fetch_16().wrapping_add(u16::from(self.registers.y));
-                self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
                 WrapAddr {
                     addr,
                     wrap_mode: WrapMode::NoWrap,
@@ -1464,7 +1760,6 @@ impl Spc {
                     wrap_mode: WrapMode::NoWrap,
                 };
                 let addr = self.read_16(wrap_addr);
-                self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
                 WrapAddr {
                     addr,
                     wrap_mode: WrapMode::NoWrap,
@@ -1475,7 +1770,6 @@ impl Spc {
                     addr: (self.registers.psw.p() as u16) << 8 | self.fetch_8() as u16,
                     wrap_mode: WrapMode::NoWrap,
                 };
-                self.increment_counter(self.io_registers.waitstate_on_io_and_rom_access);
                 let addr = self
                     .read_16(wrap_addr)
                     .wrapping_add(u16::from(self.registers.y));
@@ -1571,6 +1865,7 @@ enum WrapMode {
     Wrap8bit,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Registers {
     a: u8,
     x: u8,
@@ -1596,6 +1891,7 @@ impl Default for Registers {
 #[bitfield(bits = 8)]
 #[repr(u8)]
 #[derive(Default, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Psw {
     c: bool,
     z: bool,
@@ -1609,6 +1905,7 @@ struct Psw {
     n: bool,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct IORegisters {
     waitstate_on_ram_access: u64,
     waitstate_on_io_and_rom_access: u64,
@@ -1660,6 +1957,8 @@ impl IORegisters {
             }
             8 | 9 => self.external_io_port[(index - 8) as usize],
             0xD..=0xF => self.timer[(index - 0xD) as usize].output(),
+            // $F0, $F1 (including the IPL ROM mapping) and the timer dividers are write-only
+            0 | 1 | 0xA..=0xC => 0,
             _ => unreachable!("IORegisters Invalid read index: {:#X}", index),
         }
     }
@@ -1734,6 +2033,7 @@ impl IORegisters {
 }
 
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct Timer {
     is_enabled: bool,
     counter: u8,
@@ -1777,3 +2077,148 @@ impl Timer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cycles per opcode with conditional branches taken, as in snes_spc
+    #[rustfmt::skip]
+    const TAKEN: [u8; 256] = [
+    //  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+        2, 8, 4, 7, 3, 4, 3, 6, 2, 6, 5, 4, 5, 4, 6, 8, // 0
+        4, 8, 4, 7, 4, 5, 5, 6, 5, 5, 6, 5, 2, 2, 4, 6, // 1
+        2, 8, 4, 7, 3, 4, 3, 6, 2, 6, 5, 4, 5, 4, 7, 4, // 2
+        4, 8, 4, 7, 4, 5, 5, 6, 5, 5, 6, 5, 2, 2, 3, 8, // 3
+        2, 8, 4, 7, 3, 4, 3, 6, 2, 6, 4, 4, 5, 4, 6, 6, // 4
+        4, 8, 4, 7, 4, 5, 5, 6, 5, 5, 4, 5, 2, 2, 4, 3, // 5
+        2, 8, 4, 7, 3, 4, 3, 6, 2, 6, 4, 4, 5, 4, 7, 5, // 6
+        4, 8, 4, 7, 4, 5, 5, 6, 5, 5, 5, 5, 2, 2, 3, 6, // 7
+        2, 8, 4, 7, 3, 4, 3, 6, 2, 6, 5, 4, 5, 2, 4, 5, // 8
+        4, 8, 4, 7, 4, 5, 5, 6, 5, 5, 5, 5, 2, 2,12, 5, // 9
+        3, 8, 4, 7, 3, 4, 3, 6, 2, 6, 4, 4, 5, 2, 4, 4, // A
+        4, 8, 4, 7, 4, 5, 5, 6, 5, 5, 5, 5, 2, 2, 3, 4, // B
+        3, 8, 4, 7, 4, 5, 4, 7, 2, 5, 6, 4, 5, 2, 4, 9, // C
+        4, 8, 4, 7, 5, 6, 6, 7, 4, 5, 5, 5, 2, 2, 8, 3, // D
+        2, 8, 4, 7, 3, 4, 3, 6, 2, 4, 5, 3, 4, 3, 4, 3, // E
+        4, 8, 4, 7, 4, 5, 5, 6, 3, 4, 5, 4, 2, 2, 6, 3, // F
+    ];
+
+    fn is_conditional_branch(op: u8) -> bool {
+        let row_branch = op & 0x1F == 0x10;
+        let bbs_bbc = op & 0x0F == 0x03;
+        row_branch || bbs_bbc || matches!(op, 0x2E | 0x6E | 0xDE | 0xFE)
+    }
+
+    // Runs `code` at $0200 with `value` at direct page $10, returns cycles taken and the new PC
+    fn run(code: &[u8], registers: RegisterSnapshot, value: u8) -> (u64, u16) {
+        let mut spc = Spc::default();
+        let ram = &mut spc.io_registers.dsp.ram;
+        ram[0x0200..0x0200 + code.len()].copy_from_slice(code);
+        ram[0x0010] = value;
+        spc.set_registers(&RegisterSnapshot {
+            pc: 0x0200,
+            ..registers
+        });
+        let start = spc.counter;
+        spc.execute_instruction();
+        (spc.counter - start, spc.registers.pc)
+    }
+
+    #[test]
+    fn cycle_table() {
+        for op in 0..=0xFF_u8 {
+            let penalty = if is_conditional_branch(op) { 2 } else { 0 };
+            assert_eq!(
+                CYCLES[op as usize] + penalty,
+                TAKEN[op as usize],
+                "opcode {:02X}",
+                op
+            );
+        }
+    }
+
+    #[test]
+    fn relative_branches() {
+        let regs = RegisterSnapshot::default();
+        // BPL/BMI, BVC/BVS, BCC/BCS, BNE/BEQ
+        for (pair, flag) in [0x80, 0x40, 0x01, 0x02].into_iter().enumerate() {
+            for (op, taken_when_set) in [
+                (0x10 + pair as u8 * 0x40, false),
+                (0x30 + pair as u8 * 0x40, true),
+            ] {
+                let set = RegisterSnapshot { psw: flag, ..regs };
+                let (taken, not_taken) = if taken_when_set {
+                    (set, regs)
+                } else {
+                    (regs, set)
+                };
+                assert_eq!(run(&[op, 0x10], taken, 0), (4, 0x0212), "opcode {:02X}", op);
+                assert_eq!(
+                    run(&[op, 0x10], not_taken, 0),
+                    (2, 0x0202),
+                    "opcode {:02X}",
+                    op
+                );
+            }
+        }
+        assert_eq!(run(&[0x2F, 0x10], regs, 0), (4, 0x0212));
+    }
+
+    #[test]
+    fn bbs_bbc() {
+        let regs = RegisterSnapshot::default();
+        for bit in 0..8 {
+            let bbs = 0x03 + bit * 0x20;
+            let bbc = bbs + 0x10;
+            let set = 1 << bit;
+            let clear = !set;
+            assert_eq!(
+                run(&[bbs, 0x10, 0x10], regs, set),
+                (7, 0x0213),
+                "BBS {}",
+                bit
+            );
+            assert_eq!(
+                run(&[bbs, 0x10, 0x10], regs, clear),
+                (5, 0x0203),
+                "BBS {}",
+                bit
+            );
+            assert_eq!(
+                run(&[bbc, 0x10, 0x10], regs, clear),
+                (7, 0x0213),
+                "BBC {}",
+                bit
+            );
+            assert_eq!(
+                run(&[bbc, 0x10, 0x10], regs, set),
+                (5, 0x0203),
+                "BBC {}",
+                bit
+            );
+        }
+    }
+
+    #[test]
+    fn cbne_dbnz() {
+        let a = RegisterSnapshot {
+            a: 0x55,
+            ..Default::default()
+        };
+        // CBNE dp, CBNE dp+X
+        assert_eq!(run(&[0x2E, 0x10, 0x10], a, 0x00), (7, 0x0213));
+        assert_eq!(run(&[0x2E, 0x10, 0x10], a, 0x55), (5, 0x0203));
+        assert_eq!(run(&[0xDE, 0x10, 0x10], a, 0x00), (8, 0x0213));
+        assert_eq!(run(&[0xDE, 0x10, 0x10], a, 0x55), (6, 0x0203));
+        // DBNZ dp, DBNZ Y
+        assert_eq!(run(&[0x6E, 0x10, 0x10], a, 2), (7, 0x0213));
+        assert_eq!(run(&[0x6E, 0x10, 0x10], a, 1), (5, 0x0203));
+        let y = |y| RegisterSnapshot {
+            y,
+            ..Default::default()
+        };
+        assert_eq!(run(&[0xFE, 0x10], y(2), 0), (6, 0x0212));
+        assert_eq!(run(&[0xFE, 0x10], y(1), 0), (4, 0x0202));
+    }
+}
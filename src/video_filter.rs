@@ -0,0 +1,87 @@
+// Optional post-processing of the frame on its way out, see Snes::set_video_filter. The
+// frame itself stays untouched, so CRCs, A/V dumps and scripts see the raw output
+
+// 5bit channel to 8bit through the gamma of a CRT TV, which makes dark colors darker and
+// the picture less washed out than a linear ramp
+const GAMMA_RAMP: [u8; 32] = [
+    0x00, 0x01, 0x03, 0x06, 0x0A, 0x0F, 0x15, 0x1C, 0x24, 0x2D, 0x37, 0x42, 0x4E, 0x5B, 0x69, 0x78,
+    0x88, 0x90, 0x98, 0xA0, 0xA8, 0xB0, 0xB8, 0xC0, 0xC8, 0xD0, 0xD8, 0xE0, 0xE8, 0xF0, 0xF8, 0xFF,
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VideoFilter {
+    // Map colors through the gamma ramp of a TV instead of scaling them linearly
+    pub color_correction: bool,
+    // Smear chroma over neighbouring pixels like a composite signal does, which blends
+    // dithering into the colors games drew it for
+    pub ntsc: bool,
+    // Darken every other line by scanlines/256, 0 is off
+    pub scanlines: u8,
+    // Average each frame with the one before it, which turns 30Hz flicker used for
+    // transparency into the intended see-through look
+    pub frame_blend: bool,
+}
+
+impl VideoFilter {
+    pub fn is_enabled(&self) -> bool {
+        *self != VideoFilter::default()
+    }
+
+    // Filter line `y` of BGR555 `src` into 8bit RGB
+    pub(crate) fn filter_line(&self, src: &[u16], y: usize, out: &mut [[u8; 3]]) {
+        for (&color, out) in src.iter().zip(out.iter_mut()) {
+            let channel = |shift: u16| {
+                let c = (color >> shift & 0x1F) as usize;
+                if self.color_correction {
+                    GAMMA_RAMP[c]
+                } else {
+                    (c << 3 | c >> 2) as u8
+                }
+            };
+            *out = [channel(0), channel(5), channel(10)];
+        }
+
+        if self.ntsc {
+            smear_chroma(&mut out[..src.len()]);
+        }
+
+        if self.scanlines != 0 && y & 1 == 1 {
+            let scale = 256 - self.scanlines as u16;
+            for pixel in out[..src.len()].iter_mut() {
+                for c in pixel.iter_mut() {
+                    *c = ((*c as u16 * scale) >> 8) as u8;
+                }
+            }
+        }
+    }
+}
+
+// Average of two BGR555 lines, per channel and rounded up
+pub(crate) fn blend_line(a: &[u16], b: &[u16], out: &mut [u16]) {
+    for ((&a, &b), out) in a.iter().zip(b).zip(out.iter_mut()) {
+        *out = ((a & 0x7BDE) >> 1) + ((b & 0x7BDE) >> 1) + ((a | b) & 0x0421);
+    }
+}
+
+// Keep each pixel's luma and replace its chroma with a 1-2-1 average of the neighbours
+fn smear_chroma(line: &mut [[u8; 3]]) {
+    let luma = |[r, g, b]: [u8; 3]| (77 * r as i32 + 150 * g as i32 + 29 * b as i32) >> 8;
+    let chroma = |pixel: [u8; 3]| {
+        let y = luma(pixel);
+        [pixel[0] as i32 - y, pixel[1] as i32 - y, pixel[2] as i32 - y]
+    };
+
+    // Chroma of the unfiltered pixel to the left, as it is overwritten by then
+    let mut left = chroma(line[0]);
+    let last = line.len() - 1;
+    for x in 0..line.len() {
+        let center = chroma(line[x]);
+        let right = chroma(line[(x + 1).min(last)]);
+        let y = luma(line[x]);
+        for c in 0..3 {
+            let smeared = (left[c] + 2 * center[c] + right[c]) / 4;
+            line[x][c] = (y + smeared).clamp(0, 255) as u8;
+        }
+        left = center;
+    }
+}
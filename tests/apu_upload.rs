@@ -0,0 +1,115 @@
+// Uploads a tiny sound program through the IPL handshake the way commercial drivers do,
+// with 16-bit writes to $2140, and checks that it runs
+//
+// cargo test --test apu_upload
+use rust_snes::{PowerOnState, Snes};
+
+// mov $F5, #$5A; bra *
+const SPC_PROGRAM: [u8; 5] = [0x8F, 0x5A, 0xF5, 0x2F, 0xFE];
+const SPC_ADDRESS: u16 = 0x0200;
+
+struct Code {
+    bytes: Vec<u8>,
+}
+
+impl Code {
+    fn op(&mut self, bytes: &[u8]) -> &mut Code {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    fn here(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // BNE back to `target`
+    fn bne(&mut self, target: usize) -> &mut Code {
+        let offset = target as isize - (self.here() + 2) as isize;
+        self.op(&[0xD0, offset as i8 as u8])
+    }
+}
+
+fn upload_rom() -> Vec<u8> {
+    let mut code = Code { bytes: vec![] };
+    let [addr_lo, addr_hi] = SPC_ADDRESS.to_le_bytes();
+    // Native mode, 16-bit A
+    code.op(&[0x78, 0x18, 0xFB, 0xC2, 0x20]);
+    // Wait for $BBAA
+    let wait_ready = code.here();
+    code.op(&[0xAD, 0x40, 0x21, 0xC9, 0xAA, 0xBB])
+        .bne(wait_ready);
+    // Upload to SPC_ADDRESS: port 0 = $CC, port 1 = nonzero in a single 16-bit store
+    code.op(&[0xA9, addr_lo, addr_hi, 0x8D, 0x42, 0x21]);
+    code.op(&[0xA9, 0xCC, 0x01, 0x8D, 0x40, 0x21]);
+    code.op(&[0xE2, 0x20]);
+    let wait_cc = code.here();
+    code.op(&[0xAD, 0x40, 0x21, 0xC9, 0xCC]).bne(wait_cc);
+
+    // LDX #0, then per byte: LDA program,X; XBA; TXA; REP #$20; STA $2140; SEP #$20
+    code.op(&[0xA2, 0x00]);
+    let program_operand = code.here() + 1;
+    let send = code.here();
+    code.op(&[
+        0xBD, 0x00, 0x00, 0xEB, 0x8A, 0xC2, 0x20, 0x8D, 0x40, 0x21, 0xE2, 0x20,
+    ]);
+    let wait_ack = code.here();
+    code.op(&[0xCD, 0x40, 0x21]).bne(wait_ack);
+    code.op(&[0xE8, 0xE0, SPC_PROGRAM.len() as u8]).bne(send);
+
+    // Jump: port 1 = 0, port 0 = last index + 2
+    code.op(&[0xC2, 0x20, 0xA9, addr_lo, addr_hi, 0x8D, 0x42, 0x21]);
+    code.op(&[0x8A, 0x1A, 0x8D, 0x40, 0x21, 0xE2, 0x20]);
+    // Wait for the program's $5A, then flag success in WRAM
+    let wait_run = code.here();
+    code.op(&[0xAD, 0x41, 0x21, 0xC9, 0x5A]).bne(wait_run);
+    code.op(&[0xA9, 0x55, 0x8F, 0x00, 0x02, 0x7E, 0x80, 0xFE]);
+
+    let program_addr = 0x8000 + code.here() as u16;
+    code.bytes[program_operand..program_operand + 2].copy_from_slice(&program_addr.to_le_bytes());
+    code.op(&SPC_PROGRAM);
+
+    let mut rom = vec![0; 0x8000];
+    rom[..code.bytes.len()].copy_from_slice(&code.bytes);
+    let header = 0x7FC0;
+    rom[header..header + 21].copy_from_slice(b"RUST-SNES APU UPLOAD ");
+    rom[header + 0x15] = 0x20; // LoROM
+    rom[header + 0x17] = 0x05; // 32KB
+    rom[header + 0x19] = 0x01; // North America
+    rom[0x7FFC..0x7FFE].copy_from_slice(&0x8000u16.to_le_bytes()); // RESET
+    rom[header + 0x1C..header + 0x20].copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+    rom
+}
+
+fn run_upload(fast_boot: bool, threaded: bool) {
+    let mut snes = Snes::new_with_power_on_state(upload_rom(), None, PowerOnState::Zero)
+        .expect("Failed to load ROM");
+    snes.set_fast_apu_boot(fast_boot);
+    snes.set_threaded_apu(threaded);
+    for _ in 0..30 {
+        snes.exec_frame().expect("Emulation halted");
+        if snes.wram()[0x200] == 0x55 {
+            let ram = &snes.apu_ram()[SPC_ADDRESS as usize..][..SPC_PROGRAM.len()];
+            assert_eq!(ram, SPC_PROGRAM);
+            return;
+        }
+    }
+    panic!(
+        "uploaded program didn't run, fast boot: {}, threaded: {}",
+        fast_boot, threaded
+    );
+}
+
+#[test]
+fn ipl_upload() {
+    run_upload(false, false);
+}
+
+#[test]
+fn ipl_upload_fast_boot() {
+    run_upload(true, false);
+}
+
+#[test]
+fn ipl_upload_threaded() {
+    run_upload(false, true);
+}
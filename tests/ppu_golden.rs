@@ -0,0 +1,190 @@
+// Golden frame CRCs for the PPU. Every case assembles a tiny LoROM that fills VRAM, CGRAM
+// and OAM with fixed pseudo random data, sets up the registers under test and spins. A
+// mismatch lists the new CRCs, so an intended rendering change only needs the table updated
+//
+// cargo test --test ppu_golden
+use rust_snes::{PowerOnState, Snes};
+
+// Just enough 65816 for register setup, in emulation mode with 8bit registers
+struct Asm {
+    code: Vec<u8>,
+}
+
+impl Asm {
+    fn new() -> Asm {
+        // SEI
+        Asm { code: vec![0x78] }
+    }
+
+    // LDA #value, STA addr
+    fn set(&mut self, addr: u16, value: u8) -> &mut Asm {
+        let [lo, hi] = addr.to_le_bytes();
+        self.code.extend([0xA9, value, 0x8D, lo, hi]);
+        self
+    }
+
+    // Write-twice registers like the scroll and mode 7 ones
+    fn set16(&mut self, addr: u16, value: u16) -> &mut Asm {
+        let [lo, hi] = value.to_le_bytes();
+        self.set(addr, lo).set(addr, hi)
+    }
+
+    // General DMA on channel 0 from `bank`:`src` to B bus register `dest`
+    fn dma(&mut self, mode: u8, dest: u8, bank: u8, src: u16, len: u16) -> &mut Asm {
+        let [src_lo, src_hi] = src.to_le_bytes();
+        let [len_lo, len_hi] = len.to_le_bytes();
+        self.set(0x4300, mode).set(0x4301, dest);
+        self.set(0x4302, src_lo).set(0x4303, src_hi).set(0x4304, bank);
+        self.set(0x4305, len_lo).set(0x4306, len_hi).set(0x420B, 0x01)
+    }
+
+    // 128KB LoROM: the code in bank 0, VRAM contents in banks 1 and 2, CGRAM and OAM in bank 3
+    fn rom(&mut self) -> Vec<u8> {
+        // BRA *
+        self.code.extend([0x80, 0xFE]);
+
+        let mut rom = vec![0; 0x20000];
+        let mut x = 0x1234_5678u32;
+        for b in rom[0x8000..].iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        rom[..self.code.len()].copy_from_slice(&self.code);
+
+        let header = 0x7FC0;
+        rom[header..header + 21].copy_from_slice(b"RUST-SNES PPU GOLDEN ");
+        rom[header + 0x15] = 0x20; // LoROM
+        rom[header + 0x17] = 0x07; // 128KB
+        rom[header + 0x19] = 0x01; // North America
+        rom[0x7FFC..0x7FFE].copy_from_slice(&0x8000u16.to_le_bytes()); // RESET
+        rom[header + 0x1C..header + 0x20].copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+        rom
+    }
+}
+
+// Force blank, upload the data banks and leave the PPU in `mode` with all layers on the
+// main screen, BG1-4 maps at $0000-$6FFF and their tiles from $8000
+fn setup(mode: u8) -> Asm {
+    let mut asm = Asm::new();
+    asm.set(0x2100, 0x80).set(0x2105, mode);
+    asm.set(0x2107, 0x00).set(0x2108, 0x11).set(0x2109, 0x21).set(0x210A, 0x31);
+    asm.set(0x210B, 0x44).set(0x210C, 0x44);
+    asm.set(0x2101, 0x02);
+
+    asm.set(0x2115, 0x80).set16(0x2116, 0x0000);
+    asm.dma(0x01, 0x18, 1, 0x8000, 0x8000);
+    asm.set16(0x2116, 0x4000);
+    asm.dma(0x01, 0x18, 2, 0x8000, 0x8000);
+    asm.set(0x2121, 0x00);
+    asm.dma(0x00, 0x22, 3, 0x8000, 0x200);
+    asm.set16(0x2102, 0x0000);
+    asm.dma(0x00, 0x04, 3, 0xA000, 0x220);
+
+    asm.set16(0x210D, 0x0013).set16(0x2110, 0x0107);
+    asm.set(0x212C, 0x1F);
+    asm
+}
+
+fn turn_on(asm: &mut Asm) -> Vec<u8> {
+    asm.set(0x2100, 0x0F);
+    asm.rom()
+}
+
+fn bg_mode(mode: u8) -> Vec<u8> {
+    let mut asm = setup(mode);
+    if mode == 7 {
+        // Rotate by 30 degrees around the screen center
+        asm.set16(0x211B, 0x00DD).set16(0x211C, 0xFF80).set16(0x211D, 0x0080).set16(0x211E, 0x00DD);
+        asm.set16(0x211F, 0x0080).set16(0x2120, 0x0070);
+    }
+    turn_on(&mut asm)
+}
+
+fn windows() -> Vec<u8> {
+    let mut asm = setup(1);
+    // Window 1 masks BG1 inside 40-120, window 2 masks BG2 and OBJ outside 80-200 and BG3
+    // uses both combined with XOR
+    asm.set(0x2126, 40).set(0x2127, 120).set(0x2128, 80).set(0x2129, 200);
+    asm.set(0x2123, 0xC2).set(0x2124, 0x0A).set(0x2125, 0xAC);
+    asm.set(0x212A, 0x20).set(0x212B, 0x08);
+    asm.set(0x212E, 0x17);
+    // Clip the main screen to black inside the color window, also W1 XOR W2
+    asm.set(0x2130, 0x80);
+    turn_on(&mut asm)
+}
+
+fn color_math_add_half() -> Vec<u8> {
+    let mut asm = setup(1);
+    // BG1 and OBJ on the main screen, BG2 and BG3 on the sub screen, added and halved
+    asm.set(0x212C, 0x11).set(0x212D, 0x06);
+    asm.set(0x2130, 0x02).set(0x2131, 0x51);
+    turn_on(&mut asm)
+}
+
+fn color_math_sub_fixed() -> Vec<u8> {
+    let mut asm = setup(1);
+    // Subtract the fixed color from every layer and the backdrop
+    asm.set(0x2132, 0x2C).set(0x2132, 0x48).set(0x2132, 0x90);
+    asm.set(0x2130, 0x00).set(0x2131, 0xBF);
+    turn_on(&mut asm)
+}
+
+fn color_math_windowed() -> Vec<u8> {
+    let mut asm = setup(3);
+    // Color math only inside window 1, black outside it
+    asm.set(0x2126, 64).set(0x2127, 191).set(0x2125, 0x20);
+    asm.set(0x212C, 0x01).set(0x212D, 0x02);
+    asm.set(0x2130, 0x62).set(0x2131, 0x01);
+    turn_on(&mut asm)
+}
+
+fn sprites() -> Vec<u8> {
+    let mut asm = setup(1);
+    // OBJ alone, 16x16 and 32x32
+    asm.set(0x2101, 0x62).set(0x212C, 0x10);
+    turn_on(&mut asm)
+}
+
+fn frame_crc(rom: Vec<u8>) -> u32 {
+    let mut snes =
+        Snes::new_with_power_on_state(rom, None, PowerOnState::Zero).expect("Failed to load ROM");
+    for _ in 0..3 {
+        snes.exec_frame().expect("Emulation halted");
+    }
+    snes.frame_crc32()
+}
+
+// Name, ROM builder and CRC-32 of the third frame
+type GoldenCase = (&'static str, fn() -> Vec<u8>, u32);
+
+#[test]
+fn ppu_golden_frames() {
+    let cases: [GoldenCase; 15] = [
+        ("mode0", || bg_mode(0), 0x0836F69C),
+        ("mode1", || bg_mode(1), 0x73DDCE34),
+        ("mode1_bg3_priority", || bg_mode(0x09), 0x719BE38A),
+        ("mode1_16x16_tiles", || bg_mode(0x31), 0x5D427C42),
+        ("mode2", || bg_mode(2), 0x77DAB5C1),
+        ("mode3", || bg_mode(3), 0xE2A60152),
+        ("mode4", || bg_mode(4), 0x31DA0EE3),
+        ("mode5", || bg_mode(5), 0xD40534C6),
+        ("mode6", || bg_mode(6), 0x462B1F09),
+        ("mode7", || bg_mode(7), 0xBA5E5B2D),
+        ("windows", windows, 0x5CF12084),
+        ("color_math_add_half", color_math_add_half, 0x3421F00E),
+        ("color_math_sub_fixed", color_math_sub_fixed, 0xAFAC6B04),
+        ("color_math_windowed", color_math_windowed, 0x157BB617),
+        ("sprites", sprites, 0xF1AD6A29),
+    ];
+
+    let mut mismatches = Vec::new();
+    for (name, rom, golden) in cases {
+        let crc = frame_crc(rom());
+        if crc != golden {
+            mismatches.push(format!("{}: {:08X}, expected {:08X}", name, crc, golden));
+        }
+    }
+    assert!(mismatches.is_empty(), "frame CRCs changed:\n{}", mismatches.join("\n"));
+}